@@ -38,12 +38,12 @@ async fn main() {
 
     let args = Args::parse();
 
-    let config = SupabaseAuthConfig {
-        api_key: args.annon_key,
-        max_reconnect_attempts: 5,
-        reconnect_interval: Duration::from_secs(3),
-        url: args.supabase_api_url.clone(),
-    };
+    let config = SupabaseAuthConfig::builder()
+        .api_key(args.annon_key)
+        .max_reconnect_attempts(5)
+        .reconnect_interval(Duration::from_secs(3))
+        .url(args.supabase_api_url.clone())
+        .build();
     let login_credentials = LoginCredentials::builder()
         .email(args.email)
         .password(args.pass)
@@ -5,7 +5,6 @@ use rp_supabase_auth::jwt_stream::SupabaseAuthConfig;
 use rp_supabase_auth::types::LoginCredentials;
 use rp_supabase_auth::url;
 use rp_supabase_realtime::futures::StreamExt as _;
-use rp_supabase_realtime::message::broadcast::Broadcast;
 use rp_supabase_realtime::message::phx_join;
 use rp_supabase_realtime::realtime;
 use tracing_subscriber::EnvFilter;
@@ -43,12 +42,12 @@ async fn main() {
 
     let args = Args::parse();
 
-    let config = SupabaseAuthConfig {
-        api_key: args.annon_key,
-        max_reconnect_attempts: 5,
-        reconnect_interval: Duration::from_secs(3),
-        url: args.supabase_api_url.clone(),
-    };
+    let config = SupabaseAuthConfig::builder()
+        .api_key(args.annon_key)
+        .max_reconnect_attempts(5)
+        .reconnect_interval(Duration::from_secs(3))
+        .url(args.supabase_api_url.clone())
+        .build();
     let login_credentials = LoginCredentials::builder()
         .email(args.email)
         .password(args.pass)
@@ -66,16 +65,13 @@ async fn main() {
             },
             presence: phx_join::PresenceConfig { key: String::new() },
             postgres_changes: vec![],
+            private: false,
         },
         access_token: None,
     };
     client.subscribe_to_changes(payload).await.unwrap();
     client
-        .broadcast(Broadcast {
-            r#type: "broadcast".to_owned(),
-            event: "update".to_owned(),
-            payload: simd_json::json!({"aaa": "bbbb"}),
-        })
+        .broadcast_typed("update", &simd_json::json!({"aaa": "bbbb"}))
         .await
         .unwrap();
     tracing::info!("pooling realtime connection");
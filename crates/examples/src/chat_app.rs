@@ -0,0 +1,259 @@
+//! Small terminal chat application exercising auth, PostgREST and realtime together.
+//!
+//! Requires the `public.messages` table from `supabase/migrations`. Every line typed on stdin is
+//! stored as a message in the selected room; new messages from other users arrive through
+//! `postgres_changes`, and a `typing` broadcast is sent before each message.
+//!
+//! Avatars are not covered: the workspace does not ship a Supabase Storage client yet.
+
+use core::time::Duration;
+
+use clap::Parser;
+use rp_supabase_auth::auth_client::{requests, ApiClient};
+use rp_supabase_auth::futures::StreamExt as _;
+use rp_supabase_auth::jwt_stream::SupabaseAuthConfig;
+use rp_supabase_auth::types::{LoginCredentials, SignupPayload};
+use rp_supabase_auth::url;
+use rp_supabase_client::prepared::{param, PreparedQuery};
+use rp_supabase_client::{new_authenticated, PostgerstResponse};
+use rp_supabase_realtime::message::broadcast::Broadcast;
+use rp_supabase_realtime::message::{phx_join, ProtocolPayload};
+use rp_supabase_realtime::realtime;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncBufReadExt as _;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    #[arg(short, long)]
+    supabase_api_url: url::Url,
+
+    #[arg(short, long)]
+    annon_key: String,
+
+    #[arg(short, long)]
+    email: String,
+
+    #[arg(short, long)]
+    pass: String,
+
+    /// The chat room to join
+    #[arg(short, long, default_value = "general")]
+    room: String,
+
+    /// Create the account before logging in
+    #[arg(long, default_value_t = false)]
+    signup: bool,
+
+    /// How many messages of history to load on start
+    #[arg(long, default_value_t = 20)]
+    history: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Message {
+    id: i64,
+    author_email: String,
+    body: String,
+    created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+struct NewMessage<'a> {
+    room: &'a str,
+    body: &'a str,
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .from_env()
+                .unwrap()
+                .add_directive("rp_supabase_auth=info".to_owned().parse().unwrap())
+                .add_directive("rp_supabase_realtime=info".to_owned().parse().unwrap())
+                .add_directive("chat_app=info".to_owned().parse().unwrap()),
+        )
+        .init();
+    color_eyre::install().unwrap();
+
+    let args = Args::parse();
+
+    let config = SupabaseAuthConfig::builder()
+        .api_key(args.annon_key.clone())
+        .max_reconnect_attempts(5)
+        .reconnect_interval(Duration::from_secs(3))
+        .url(args.supabase_api_url.clone())
+        .build();
+
+    if args.signup {
+        let signup = ApiClient::new_unauthenticated(args.supabase_api_url.clone(), &args.annon_key)
+            .unwrap()
+            .build_request(&requests::SignupRequest {
+                payload: SignupPayload::builder()
+                    .email(args.email.clone())
+                    .password(args.pass.clone())
+                    .build(),
+            })
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        tracing::info!(?signup, "signup result");
+    }
+
+    let login_credentials = LoginCredentials::builder()
+        .email(args.email.clone())
+        .password(args.pass.clone())
+        .build();
+
+    // PostgREST client, refreshed in the background
+    let mut client_stream = new_authenticated(config.clone(), login_credentials.clone()).unwrap();
+    let mut db = loop {
+        match client_stream.next().await {
+            Some(Ok((client, _token))) => break client,
+            Some(Err(err)) => tracing::warn!(?err, "login failed"),
+            None => panic!("auth stream closed before login succeeded"),
+        }
+    };
+
+    // History
+    let history_query = PreparedQuery::select("messages", "id,author_email,body,created_at")
+        .eq("room", param(1))
+        .order("created_at.desc")
+        .limit(param(2));
+    let history = history_query
+        .bind(&db, &[args.room.clone(), args.history.to_string()])
+        .unwrap()
+        .build()
+        .send()
+        .await
+        .map(PostgerstResponse::<Vec<Message>>::new)
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+        .unwrap();
+    for message in history.iter().rev() {
+        print_message(message);
+    }
+
+    // Realtime: new messages, presence and typing indicators on one channel
+    let (mut realtime, mut realtime_client) =
+        realtime::RealtimeConnection::new(config, &format!("chat:{}", args.room))
+            .connect(login_credentials)
+            .await
+            .unwrap();
+    realtime_client
+        .subscribe_to_changes(phx_join::PhxJoin {
+            config: phx_join::JoinConfig {
+                broadcast: phx_join::BroadcastConfig {
+                    self_item: false,
+                    ack: false,
+                },
+                presence: phx_join::PresenceConfig {
+                    key: args.email.clone(),
+                },
+                postgres_changes: vec![phx_join::PostgrsChanges {
+                    event: phx_join::PostgresChangetEvent::Insert,
+                    schema: "public".to_owned(),
+                    table: "messages".to_owned(),
+                    filter: Some(format!("room=eq.{}", args.room)),
+                }],
+                private: false,
+            },
+            access_token: None,
+        })
+        .await
+        .unwrap();
+
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = stdin.next_line() => {
+                let Ok(Some(line)) = line else {
+                    break;
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                realtime_client
+                    .broadcast(Broadcast {
+                        r#type: "broadcast".to_owned(),
+                        event: "typing".to_owned(),
+                        payload: simd_json::json!({ "email": args.email }),
+                    })
+                    .await
+                    .unwrap();
+                let body = simd_json::to_string(&NewMessage {
+                    room: &args.room,
+                    body: &line,
+                })
+                .unwrap();
+                let res = db
+                    .from("messages")
+                    .insert(body)
+                    .build()
+                    .send()
+                    .await
+                    .map(PostgerstResponse::<()>::new)
+                    .unwrap()
+                    .json_err()
+                    .await;
+                tracing::debug!(?res, "message stored");
+            }
+            Some(item) = client_stream.next() => {
+                // keep the PostgREST client authorized with the refreshed token
+                if let Ok((client, _token)) = item {
+                    db = client;
+                }
+            }
+            Some(msg) = realtime.next() => {
+                let Ok(msg) = msg else {
+                    tracing::warn!(?msg, "realtime error");
+                    continue;
+                };
+                match msg.payload {
+                    ProtocolPayload::PostgresChanges(changes) => {
+                        let Ok(change) = changes.data.parse_record::<Message>() else {
+                            continue;
+                        };
+                        if let Some(message) = change.record {
+                            print_message(&message);
+                        }
+                    }
+                    ProtocolPayload::Broadcast(broadcast) if broadcast.event == "typing" => {
+                        tracing::info!(who = ?broadcast.payload, "is typing...");
+                    }
+                    ProtocolPayload::PresenceState(state) => {
+                        tracing::info!(online = state.0.len(), "presence state");
+                    }
+                    ProtocolPayload::PresenceDiff(diff) => {
+                        tracing::info!(
+                            joined = diff.joins.len(),
+                            left = diff.leaves.len(),
+                            "presence changed"
+                        );
+                    }
+                    other => {
+                        tracing::debug!(?other, "realtime message");
+                    }
+                }
+            }
+            else => break,
+        }
+    }
+    tracing::info!("chat closed");
+}
+
+fn print_message(message: &Message) {
+    println!(
+        "[{}] #{} {}: {}",
+        message.created_at, message.id, message.author_email, message.body
+    );
+}
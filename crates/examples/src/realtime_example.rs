@@ -51,18 +51,18 @@ async fn main() {
 
     let args = Args::parse();
 
-    let config = SupabaseAuthConfig {
-        api_key: args.annon_key,
-        max_reconnect_attempts: 5,
-        reconnect_interval: Duration::from_secs(3),
-        url: args.supabase_api_url.clone(),
-    };
+    let config = SupabaseAuthConfig::builder()
+        .api_key(args.annon_key)
+        .max_reconnect_attempts(5)
+        .reconnect_interval(Duration::from_secs(3))
+        .url(args.supabase_api_url.clone())
+        .build();
     let login_credentials = LoginCredentials::builder()
         .email(args.email)
         .password(args.pass)
         .build();
     let (mut realtime, mut client) = realtime::RealtimeConnection::new_db_updates(config)
-        .connect(login_credentials)
+        .connect_typed::<simd_json::OwnedValue>(login_credentials)
         .await
         .unwrap();
 
@@ -79,30 +79,16 @@ async fn main() {
                 table: args.table,
                 filter: args.filter,
             }],
+            private: false,
         },
         access_token: None,
     };
     client.subscribe_to_changes(payload).await.unwrap();
     tracing::info!("pooling realtime connection");
-    while let Some(msg) = realtime.next().await {
-        match msg {
-            Ok(msg) => {
-                use rp_supabase_realtime::message::ProtocolPayload::*;
-                match msg.payload {
-                    PostgresChanges(postgres_changes_payload) => {
-                        let changes = postgres_changes_payload
-                            .data
-                            .parse_record::<simd_json::OwnedValue>()
-                            .unwrap()
-                            .parse_old_record::<simd_json::OwnedValue>()
-                            .unwrap();
-
-                        tracing::info!(?changes, "reading protocol message");
-                    }
-                    msg => {
-                        tracing::debug!(?msg, "reading protocol message");
-                    }
-                }
+    while let Some(change) = realtime.next().await {
+        match change {
+            Ok(change) => {
+                tracing::info!(?change, "reading postgres change");
             }
             Err(err) => {
                 tracing::warn!(?err, "realtime error");
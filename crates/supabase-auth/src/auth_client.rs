@@ -1,22 +1,94 @@
+pub mod admin;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod identities;
+pub mod mfa;
+pub mod otp;
+pub mod pkce;
 pub mod requests;
+pub mod resend;
+pub mod sso;
+pub mod user;
 use core::marker::PhantomData;
+use std::sync::{Arc, Mutex};
 
 use futures::{Stream, StreamExt as _};
 use requests::AuthModuleRequest;
 use reqwest::header;
 use tracing::instrument;
+use web_time::Instant;
 
-use crate::error::AuthError;
-use crate::jwt_stream::{RefreshStreamError, SupabaseAuthConfig};
-use crate::types::LoginCredentials;
+use crate::error::{is_empty_api_error, non_json_body_snippet, AuthApiError, AuthError};
+use crate::jwt_stream::{
+    ProxyConfig, RefreshStreamError, RetryPolicy, SupabaseAuthConfig, DEFAULT_CONNECT_TIMEOUT,
+    DEFAULT_REQUEST_TIMEOUT,
+};
+use crate::types::{AccessTokenResponseSchema, ErrorSchema, LoginCredentials, Provider};
 use crate::{jwt_stream, SUPABASE_KEY};
 
 #[derive(Clone, Debug)]
 pub struct ApiClient {
     inner: reqwest::Client,
     url: url::Url,
+    /// `apikey` and (if authenticated) `Authorization` headers, sent with every request
+    headers: header::HeaderMap,
+    retry_policy: RetryPolicy,
+    rate_limit: RateLimitState,
+    /// Users fetched by [`ApiClient::get_user`], shared between clones
+    user_cache: Option<user::UserCache>,
 }
 
+/// The `Retry-After` of the latest `429 Too Many Requests` response, shared between clones of an
+/// [`ApiClient`].
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitState(Arc<Mutex<Option<Instant>>>);
+
+impl RateLimitState {
+    /// How long the server asked to wait; `None` if not rate limited
+    #[must_use]
+    pub fn retry_after(&self) -> Option<core::time::Duration> {
+        let until = (*self.0.lock().ok()?)?;
+        let remaining = until.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    /// Updates the state from `response`, returning its `Retry-After` if it is a `429`
+    fn observe(&self, response: &reqwest::Response) -> Option<core::time::Duration> {
+        let retry_after = (response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS)
+            .then(|| parse_retry_after(response.headers()))
+            .flatten();
+        if let Ok(mut until) = self.0.lock() {
+            if let Some(retry_after) = retry_after {
+                *until = Some(Instant::now() + retry_after);
+            } else if response.status().is_success() {
+                *until = None;
+            }
+        }
+        retry_after
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(headers: &header::HeaderMap) -> Option<core::time::Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(core::time::Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// Signs in and yields an [`ApiClient`] authenticated with every new access token.
+///
+/// All yielded clients, as well as the refresh requests of the underlying stream, share one
+/// HTTP client (and with it the connection pool); only the `Authorization` header differs.
+///
+/// # Errors
+///
+/// Returns an error if the url cannot be joined, the proxy is invalid or the key is not a valid
+/// header value.
 pub fn new_authenticated_stream(
     config: SupabaseAuthConfig,
     login_info: LoginCredentials,
@@ -24,26 +96,19 @@ pub fn new_authenticated_stream(
     impl Stream<Item = Result<Result<ApiClient, AuthError>, RefreshStreamError>>,
     RefreshStreamError,
 > {
-    let url = config.url.clone();
-    let api_key = config.api_key.clone();
+    let base = ApiClient::from_config(&config)?;
     let auth_stream = jwt_stream::JwtStream::new(config)
+        .with_http_client(base.inner.clone())
         .sign_in(login_info)
         .unwrap();
     let client_stream = auth_stream
         .map(move |item| {
-            let url = url.clone();
-            let api_key = api_key.clone();
-
-            let res = item
-                .map(|item| {
-                    if let Some(access_token) = item.access_token.as_ref() {
-                        let client = ApiClient::new_authenticated(url, &api_key, access_token);
-                        return Some(client);
-                    }
-                    None
-                })
-                .transpose();
-            res
+            item.map(|item| {
+                item.access_token
+                    .as_ref()
+                    .map(|access_token| base.authenticated(access_token.expose_secret()))
+            })
+            .transpose()
         })
         .filter_map(futures::future::ready);
 
@@ -52,36 +117,216 @@ pub fn new_authenticated_stream(
 
 impl ApiClient {
     pub fn new_unauthenticated(url: url::Url, api_key: &str) -> Result<Self, AuthError> {
-        let url = url.join("/auth/v1/")?;
-        let authenticated_client = unauthenticated_client(api_key)?;
-        Ok(Self {
+        Self::with_http_client(
+            default_http_client(&HttpSettings::default())?,
             url,
-            inner: authenticated_client,
-        })
+            api_key,
+            None,
+        )
     }
 
     pub fn new_authenticated(url: url::Url, api_key: &str, token: &str) -> Result<Self, AuthError> {
+        Self::with_http_client(
+            default_http_client(&HttpSettings::default())?,
+            url,
+            api_key,
+            Some(token),
+        )
+    }
+
+    /// Creates a client authenticated with the project's service-role key.
+    ///
+    /// The key is sent both as the `apikey` and the bearer token. It does not expire, so no
+    /// sign-in or refresh stream is needed; never ship it to end-user devices.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined or the key is not a valid header value.
+    pub fn new_service_role(url: url::Url, service_role_key: &str) -> Result<Self, AuthError> {
+        Self::new_authenticated(url, service_role_key, service_role_key)
+    }
+
+    /// Creates an unauthenticated client for the project in `config`, honouring its proxy,
+    /// timeout and retry settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined, the proxy is invalid or the key is not a
+    /// valid header value.
+    pub fn from_config(config: &SupabaseAuthConfig) -> Result<Self, AuthError> {
+        let http_client = default_http_client(&HttpSettings::from(config))?;
+        Ok(Self::with_http_client(
+            http_client,
+            config.url.clone(),
+            config.api_key.expose_secret(),
+            None,
+        )?
+        .with_retry_policy(config.retry_policy.clone()))
+    }
+
+    /// Retries transient failures according to `retry_policy`.
+    ///
+    /// Rate limited requests are retried after their `Retry-After`, unless it exceeds
+    /// [`RetryPolicy::max_backoff`].
+    #[must_use]
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Whether (and for how long) the server rate limits this client.
+    #[must_use]
+    pub const fn rate_limit(&self) -> &RateLimitState {
+        &self.rate_limit
+    }
+
+    /// Creates a client that sends its requests through `http_client`, e.g. one configured with
+    /// custom DNS resolution, root certificates or timeouts.
+    ///
+    /// The Supabase headers are added to every request, so `http_client` does not need any
+    /// default headers.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined or the key or token are not valid header
+    /// values.
+    pub fn with_http_client(
+        http_client: reqwest::Client,
+        url: url::Url,
+        api_key: &str,
+        token: Option<&str>,
+    ) -> Result<Self, AuthError> {
         let url = url.join("/auth/v1/")?;
-        let authenticated_client = authenticated_client(api_key, token)?;
+        let mut headers = base_headers(api_key)?;
+        if let Some(token) = token {
+            headers.insert(header::AUTHORIZATION, bearer(token)?);
+        }
         Ok(Self {
             url,
-            inner: authenticated_client,
+            inner: http_client,
+            headers,
+            retry_policy: RetryPolicy::none(),
+            rate_limit: RateLimitState::default(),
+            user_cache: None,
+        })
+    }
+
+    /// Returns a client for the same project and HTTP client, authenticated with `token`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is not a valid header value.
+    pub fn authenticated(&self, token: &str) -> Result<Self, AuthError> {
+        let mut headers = self.headers.clone();
+        headers.insert(header::AUTHORIZATION, bearer(token)?);
+        Ok(Self {
+            url: self.url.clone(),
+            inner: self.inner.clone(),
+            headers,
+            retry_policy: self.retry_policy.clone(),
+            rate_limit: self.rate_limit.clone(),
+            user_cache: self.user_cache.clone(),
         })
     }
 
+    /// Starts an OAuth sign-in with the given provider (e.g. [`Provider::Github`]).
+    ///
+    /// Returns the `/authorize` URL the user has to be sent to, together with the freshly
+    /// generated PKCE challenge of this flow. Keep the challenge (or at least its verifier) until
+    /// the redirect comes back and pass it to [`ApiClient::exchange_code_for_session`] with the
+    /// `code`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the URL cannot be constructed.
+    pub fn sign_in_with_oauth(
+        &self,
+        provider: Provider,
+        options: pkce::OAuthOptions,
+    ) -> Result<(url::Url, pkce::PkceChallenge), AuthError> {
+        let challenge = pkce::PkceChallenge::generate();
+        let mut url = pkce::PkceFlow::with_challenge(self.clone(), challenge.clone())
+            .authorize_url(provider, &options.scopes, options.redirect_to)?;
+        if !options.query_params.is_empty() {
+            url.query_pairs_mut().extend_pairs(options.query_params);
+        }
+        Ok((url, challenge))
+    }
+
+    /// Exchanges the `code` received on the OAuth redirect for a session, using the challenge
+    /// returned by [`ApiClient::sign_in_with_oauth`] (or [`ApiClient::link_identity`]).
+    ///
+    /// The challenge is only borrowed, so a failed exchange can be retried.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails; the inner error if the code is rejected.
+    pub async fn exchange_code_for_session(
+        &self,
+        challenge: &pkce::PkceChallenge,
+        code: &str,
+    ) -> Result<Result<AccessTokenResponseSchema, AuthApiError>, AuthError> {
+        pkce::PkceFlow::with_challenge(self.clone(), challenge.clone())
+            .exchange_code(code)
+            .await
+    }
+
+    /// Signs out the session of the access token this client is authenticated with, revoking
+    /// the sessions selected by `scope`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails; the inner error if the server rejects it.
+    pub async fn logout(
+        &self,
+        scope: requests::LogoutScope,
+    ) -> Result<Result<(), AuthApiError>, AuthError> {
+        let request = requests::LogoutRequest::builder()
+            .scope(Some(scope))
+            .build();
+        self.build_request(&request)?
+            .execute()
+            .await?
+            .json_err()
+            .await
+    }
+
     #[instrument(name = "build_request", skip(self, request))]
     pub fn build_request<T>(&self, request: &T) -> Result<Request<T::Res, T::Error>, AuthError>
     where
         T: AuthModuleRequest + core::fmt::Debug,
     {
         let endpoint = request.path(&self.url)?;
-        let method = T::METHOD;
-        let client = self.inner.clone();
-        let payload = simd_json::to_vec(&request.payload())?;
-        let reqwest_req = client.request(method, endpoint.as_str()).body(payload);
+        self.build_url_request(T::METHOD, &endpoint, request.payload())
+    }
+
+    /// Builds a request to any `url` of the project (e.g. another Supabase service), sent with
+    /// the headers, HTTP client and retry policy of this client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `payload` cannot be serialized.
+    #[instrument(name = "build_url_request", skip(self, payload))]
+    pub fn build_url_request<T, E, P>(
+        &self,
+        method: reqwest::Method,
+        url: &url::Url,
+        payload: &P,
+    ) -> Result<Request<T, E>, AuthError>
+    where
+        P: serde::Serialize + ?Sized,
+    {
+        let payload = simd_json::to_vec(payload)?;
+        let reqwest_req = self
+            .inner
+            .request(method, url.as_str())
+            .headers(self.headers.clone())
+            .body(payload);
 
         Ok(Request {
             request: reqwest_req,
+            retry_policy: self.retry_policy.clone(),
+            rate_limit: self.rate_limit.clone(),
             result: PhantomData,
             err: PhantomData,
         })
@@ -91,6 +336,8 @@ impl ApiClient {
 /// Encalpsulated HTTP request for the  API
 pub struct Request<T, E> {
     request: reqwest::RequestBuilder,
+    retry_policy: RetryPolicy,
+    rate_limit: RateLimitState,
     result: PhantomData<T>,
     err: PhantomData<E>,
 }
@@ -107,8 +354,46 @@ impl<T, E> Request<T, E> {
         span.record("method", request.method().as_str());
         span.record("url", request.url().as_str());
 
-        // execute the request
-        let response = client.execute(request).await?;
+        // execute the request, retrying transient failures
+        let mut attempt = 0;
+        let response = loop {
+            let retry = (attempt < self.retry_policy.max_retries)
+                .then(|| request.try_clone())
+                .flatten();
+            let Some(retry) = retry else {
+                let response = client.execute(request).await?;
+                self.rate_limit.observe(&response);
+                break response;
+            };
+            let delay = match client.execute(retry).await {
+                Ok(response) if RetryPolicy::is_transient_status(response.status()) => {
+                    tracing::warn!(status = %response.status(), attempt, "retrying request");
+                    self.retry_policy.backoff(attempt)
+                }
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    let delay = self
+                        .rate_limit
+                        .observe(&response)
+                        .unwrap_or_else(|| self.retry_policy.backoff(attempt));
+                    if delay > self.retry_policy.max_backoff {
+                        break response;
+                    }
+                    tracing::warn!(?delay, attempt, "rate limited; retrying request");
+                    delay
+                }
+                Err(err) if RetryPolicy::is_transient_error(&err) => {
+                    tracing::warn!(?err, attempt, "retrying request");
+                    self.retry_policy.backoff(attempt)
+                }
+                res => {
+                    let response = res?;
+                    self.rate_limit.observe(&response);
+                    break response;
+                }
+            };
+            crate::runtime::sleep(delay).await;
+            attempt += 1;
+        };
 
         Ok(Response {
             response,
@@ -129,6 +414,17 @@ pub struct Response<T, E> {
 }
 
 impl<T, E> Response<T, E> {
+    #[must_use]
+    pub fn status(&self) -> reqwest::StatusCode {
+        self.response.status()
+    }
+
+    /// The body as text, whatever the status; e.g. for error bodies that are not JSON
+    #[instrument(name = "response_text", skip(self), err, parent = &self.span)]
+    pub async fn text(self) -> Result<String, AuthError> {
+        Ok(self.response.text().await?)
+    }
+
     /// Only check if the returtned HTTP response is of error type; don't parse the data
     ///
     /// Useful when you don't care about the actual response besides if it was an error.
@@ -151,8 +447,9 @@ impl<T, E> Response<T, E> {
         if status.is_success() {
             Ok(Ok(()))
         } else {
+            let content_type = content_type(self.response.headers());
             let bytes = self.response.bytes().await?.to_vec();
-            let res = parse_error::<E>(bytes, status)?;
+            let res = parse_error::<E>(bytes, status, content_type.as_deref())?;
             Ok(Err(res))
         }
     }
@@ -165,21 +462,33 @@ impl<T, E> Response<T, E> {
         E: serde::de::DeserializeOwned,
     {
         let status = self.response.status();
+        let content_type = content_type(self.response.headers());
         let mut bytes = self.response.bytes().await?.to_vec();
         if status.is_success() {
-            let json = String::from_utf8_lossy(bytes.as_ref());
-            tracing::debug!(response_body = %json, "Response JSON");
+            // the body is not logged, it may contain tokens
+            tracing::debug!(response_len = bytes.len(), "Response JSON");
 
             let result = simd_json::from_slice::<T>(bytes.as_mut())?;
             Ok(Ok(result))
         } else {
-            let res = parse_error::<E>(bytes, status)?;
+            let res = parse_error::<E>(bytes, status, content_type.as_deref())?;
             Ok(Err(res))
         }
     }
 }
 
-fn parse_error<E>(mut bytes: Vec<u8>, status: reqwest::StatusCode) -> Result<E, AuthError>
+fn content_type(headers: &header::HeaderMap) -> Option<String> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned)
+}
+
+fn parse_error<E>(
+    mut bytes: Vec<u8>,
+    status: reqwest::StatusCode,
+    content_type: Option<&str>,
+) -> Result<E, AuthError>
 where
     E: serde::de::DeserializeOwned,
 {
@@ -190,40 +499,84 @@ where
         "Failed to execute request"
     );
 
+    if is_empty_api_error(status.as_u16(), &bytes) {
+        let schema = ErrorSchema::builder()
+            .code(i32::from(status.as_u16()))
+            .msg(status.canonical_reason().unwrap_or_default().to_owned())
+            .build();
+        bytes = simd_json::to_vec(&schema)?;
+    } else if let Some(body_snippet) = non_json_body_snippet(content_type, &bytes) {
+        return Err(AuthError::GatewayError {
+            status,
+            body_snippet,
+        });
+    }
+
     let error = simd_json::from_slice::<E>(bytes.as_mut())?;
     Ok(error)
 }
 
-fn unauthenticated_client(api_key: &str) -> Result<reqwest::Client, AuthError> {
+/// The HTTP client used unless one is passed to [`ApiClient::with_http_client`]
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn default_http_client(
+    settings: &HttpSettings<'_>,
+) -> Result<reqwest::Client, AuthError> {
     const KEEP_ALIVE_INTERVAL: core::time::Duration = core::time::Duration::from_secs(15);
 
-    let headers = base_headers(api_key)?;
-
-    let temp_client = reqwest::Client::builder()
+    let mut builder = reqwest::Client::builder()
         .use_rustls_tls()
         .http2_keep_alive_interval(KEEP_ALIVE_INTERVAL)
-        .http2_keep_alive_while_idle(true)
-        .default_headers(headers)
-        .build()?;
-    Ok(temp_client)
+        .http2_keep_alive_while_idle(true);
+    if let Some(proxy) = settings.proxy {
+        builder = builder.proxy(proxy.to_reqwest()?);
+    }
+    if let Some(timeout) = settings.request_timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = settings.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    Ok(builder.build()?)
 }
 
-fn authenticated_client(api_key: &str, token: &str) -> Result<reqwest::Client, AuthError> {
-    const KEEP_ALIVE_INTERVAL: core::time::Duration = core::time::Duration::from_secs(15);
+/// The browser owns TLS, connection reuse, proxies and timeouts, so `settings` do not apply
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn default_http_client(
+    _settings: &HttpSettings<'_>,
+) -> Result<reqwest::Client, AuthError> {
+    Ok(reqwest::Client::builder().build()?)
+}
 
-    let mut headers = base_headers(api_key)?;
-    headers.insert(
-        "Authorization",
-        header::HeaderValue::from_str(&format!("Bearer {token}"))?,
-    );
+/// The parts of [`SupabaseAuthConfig`] that shape the HTTP client
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub(crate) struct HttpSettings<'a> {
+    proxy: Option<&'a ProxyConfig>,
+    request_timeout: Option<core::time::Duration>,
+    connect_timeout: Option<core::time::Duration>,
+}
 
-    let temp_client = reqwest::Client::builder()
-        .use_rustls_tls()
-        .http2_keep_alive_interval(KEEP_ALIVE_INTERVAL)
-        .http2_keep_alive_while_idle(true)
-        .default_headers(headers)
-        .build()?;
-    Ok(temp_client)
+impl Default for HttpSettings<'_> {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+        }
+    }
+}
+
+impl<'a> From<&'a SupabaseAuthConfig> for HttpSettings<'a> {
+    fn from(config: &'a SupabaseAuthConfig) -> Self {
+        Self {
+            proxy: config.proxy.as_ref(),
+            request_timeout: config.request_timeout,
+            connect_timeout: config.connect_timeout,
+        }
+    }
+}
+
+fn bearer(token: &str) -> Result<header::HeaderValue, AuthError> {
+    Ok(header::HeaderValue::from_str(&format!("Bearer {token}"))?)
 }
 
 fn base_headers(api_key: &str) -> Result<header::HeaderMap, AuthError> {
@@ -243,3 +596,349 @@ fn base_headers(api_key: &str) -> Result<header::HeaderMap, AuthError> {
     );
     Ok(headers)
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use test_log::test;
+
+    use super::*;
+    use crate::auth_client::requests::{HealthCheckRequest, LogoutScope, UserGetRequest};
+
+    #[test(tokio::test)]
+    async fn test_html_gateway_error_is_surfaced() {
+        let mut m = SupabaseMockServer::new().await;
+        let _m1 = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .with_status(502)
+            .with_header("content-type", "text/html")
+            .with_body("<html><body><h1>502 Bad Gateway</h1></body></html>")
+            .create();
+
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key").unwrap();
+        let err = client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap_err();
+
+        let AuthError::GatewayError {
+            status,
+            body_snippet,
+        } = err
+        else {
+            panic!("expected a gateway error, got {err:?}");
+        };
+        assert_eq!(status, reqwest::StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body_snippet,
+            "<html><body><h1>502 Bad Gateway</h1></body></html>"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_empty_error_is_classified_by_status() {
+        let mut m = SupabaseMockServer::new().await;
+        let _m1 = m
+            .mockito_server
+            .mock("GET", "/auth/v1/user")
+            .with_status(401)
+            .create();
+        let _m2 = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .with_status(504)
+            .create();
+
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key").unwrap();
+        let err = client
+            .build_request(&UserGetRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.schema().code, Some(401));
+        assert_eq!(err.schema().msg.as_deref(), Some("Unauthorized"));
+
+        // a gateway without a body is still a gateway error
+        let err = client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, AuthError::GatewayError { status, .. } if status == reqwest::StatusCode::GATEWAY_TIMEOUT),
+            "{err:?}"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_service_role_client() {
+        let mut m = SupabaseMockServer::new().await;
+        let health = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .match_header(SUPABASE_KEY, "service-role-key")
+            .match_header("authorization", "Bearer service-role-key")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"version": "v2", "name": "GoTrue", "description": "auth"}"#)
+            .expect(1)
+            .create();
+
+        let client = ApiClient::new_service_role(m.server_url(), "service-role-key").unwrap();
+        client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .ok()
+            .unwrap();
+        health.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_custom_http_client() {
+        let mut m = SupabaseMockServer::new().await;
+        let health = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .match_header(SUPABASE_KEY, "api-key")
+            .match_header("authorization", "Bearer token")
+            .match_header("x-custom", "custom")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"version": "v2", "name": "GoTrue", "description": "auth"}"#)
+            .expect(1)
+            .create();
+
+        let mut default_headers = header::HeaderMap::new();
+        default_headers.insert("x-custom", header::HeaderValue::from_static("custom"));
+        let http_client = reqwest::Client::builder()
+            .default_headers(default_headers)
+            .build()
+            .unwrap();
+        let client = ApiClient::with_http_client(http_client, m.server_url(), "api-key", None)
+            .unwrap()
+            .authenticated("token")
+            .unwrap();
+        client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .ok()
+            .unwrap();
+        health.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_requests_go_through_proxy() {
+        let mut m = SupabaseMockServer::new().await;
+        let health = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"version": "v2", "name": "GoTrue", "description": "auth"}"#)
+            .expect(1)
+            .create();
+
+        let config = SupabaseAuthConfig::builder()
+            .api_key("api-key".to_owned())
+            .max_reconnect_attempts(1)
+            .reconnect_interval(core::time::Duration::from_secs(1))
+            .url("http://supabase.invalid".parse().unwrap())
+            .proxy(Some(ProxyConfig::builder().url(m.server_url()).build()))
+            .build();
+        let client = ApiClient::from_config(&config).unwrap();
+        client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .ok()
+            .unwrap();
+        health.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_transient_errors_are_retried() {
+        let mut m = SupabaseMockServer::new().await;
+        let unavailable = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .with_status(503)
+            .expect(2)
+            .create();
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key")
+            .unwrap()
+            .with_retry_policy(
+                RetryPolicy::builder()
+                    .max_retries(1)
+                    .initial_backoff(core::time::Duration::from_millis(10))
+                    .build(),
+            );
+        let response = client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap();
+        assert!(response.ok().is_err());
+        unavailable.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_rate_limited_requests_honour_retry_after() {
+        let mut m = SupabaseMockServer::new().await;
+        let limited = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .with_status(429)
+            .with_header("retry-after", "0")
+            .expect(1)
+            .create();
+        let health = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"version": "v2", "name": "GoTrue", "description": "auth"}"#)
+            .expect(1)
+            .create();
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key")
+            .unwrap()
+            .with_retry_policy(RetryPolicy::builder().max_retries(1).build());
+        client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .ok()
+            .unwrap();
+        limited.assert();
+        health.assert();
+        assert_eq!(client.rate_limit().retry_after(), None);
+    }
+
+    #[test(tokio::test)]
+    async fn test_long_retry_after_is_exposed() {
+        let mut m = SupabaseMockServer::new().await;
+        let limited = m
+            .mockito_server
+            .mock("GET", "/auth/v1/health")
+            .with_status(429)
+            .with_header("retry-after", "120")
+            .expect(1)
+            .create();
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key")
+            .unwrap()
+            .with_retry_policy(RetryPolicy::builder().max_retries(1).build());
+        let response = client
+            .build_request(&HealthCheckRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap();
+        assert!(response.ok().is_err());
+        limited.assert();
+        let retry_after = client.rate_limit().retry_after().unwrap();
+        assert!(retry_after > core::time::Duration::from_secs(110));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(header::RETRY_AFTER, header::HeaderValue::from_static("30"));
+        assert_eq!(
+            parse_retry_after(&headers),
+            Some(core::time::Duration::from_secs(30))
+        );
+        headers.insert(
+            header::RETRY_AFTER,
+            header::HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        // in the past
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[test(tokio::test)]
+    async fn test_authenticated_stream_yields_clients_for_each_token() {
+        let mut m = SupabaseMockServer::new().await;
+        let access_token = rp_supabase_mock::make_jwt(core::time::Duration::from_secs(3600));
+        m.register_jwt_password(&access_token);
+        let _user = m
+            .mockito_server
+            .mock("GET", "/auth/v1/user")
+            .match_header("authorization", format!("Bearer {access_token}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "user-id"}"#)
+            .create();
+        let config = SupabaseAuthConfig::builder()
+            .api_key("api-key".to_owned())
+            .max_reconnect_attempts(1)
+            .reconnect_interval(core::time::Duration::from_millis(20))
+            .url(m.server_url())
+            .retry_policy(RetryPolicy::none())
+            .build();
+        let credentials = LoginCredentials::builder()
+            .email("user@example.com".to_owned())
+            .password("password".to_owned())
+            .build();
+
+        let mut clients = Box::pin(new_authenticated_stream(config, credentials).unwrap());
+        let client = clients.next().await.unwrap().unwrap().unwrap();
+        let user = client
+            .build_request(&UserGetRequest)
+            .unwrap()
+            .execute()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id.unwrap(), "user-id");
+    }
+
+    #[test(tokio::test)]
+    async fn test_logout_with_scope() {
+        let mut m = SupabaseMockServer::new().await;
+        let logout = m
+            .mockito_server
+            .mock("POST", "/auth/v1/logout")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "scope".to_owned(),
+                "others".to_owned(),
+            ))
+            .match_header("authorization", "Bearer access-token")
+            .with_status(204)
+            .create();
+
+        let client =
+            ApiClient::new_authenticated(m.server_url(), "api-key", "access-token").unwrap();
+        client.logout(LogoutScope::Others).await.unwrap().unwrap();
+        logout.assert();
+    }
+}
@@ -0,0 +1,467 @@
+//! Ergonomic wrapper around the `/admin/users`, `/admin/generate_link` and `/invite` endpoints.
+//!
+//! Requires the project's service-role key; never ship it to end-user devices.
+
+use futures::{Stream, TryStreamExt as _};
+use redact::Secret;
+use thiserror::Error;
+
+use super::requests::{
+    AdminGenerateLinkRequest, AdminUserCreateRequest, AdminUserDeleteRequest,
+    AdminUserFactorsRequest, AdminUserGetRequest, AdminUserUpdateRequest, AdminUsersRequest,
+    InviteRequest,
+};
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::types::{
+    AdminGenerateLinkResponse, AdminUserAttributes, MFAFactorSchema, UserMetadata, UserSchema,
+};
+
+/// Page size used by [`AdminClient::list_users`] when none is given
+pub const DEFAULT_PER_PAGE: u32 = 50;
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    /// An invite was sent to an address that already belongs to a confirmed user
+    #[error("A user with this email address has already been registered")]
+    AlreadyRegistered,
+}
+
+/// The kind of link [`AdminClient::generate_link`] creates, with the data it requires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkType {
+    /// Confirms the signup of a new user with this password
+    Signup {
+        password: Secret<String>,
+    },
+    Invite,
+    MagicLink,
+    Recovery,
+    /// Confirms an email change from the current address
+    EmailChangeCurrent {
+        new_email: String,
+    },
+    /// Confirms an email change from the new address
+    EmailChangeNew {
+        new_email: String,
+    },
+}
+
+impl LinkType {
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match *self {
+            Self::Signup { .. } => "signup",
+            Self::Invite => "invite",
+            Self::MagicLink => "magiclink",
+            Self::Recovery => "recovery",
+            Self::EmailChangeCurrent { .. } => "email_change_current",
+            Self::EmailChangeNew { .. } => "email_change_new",
+        }
+    }
+}
+
+/// User management with a service-role key.
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    client: ApiClient,
+}
+
+impl AdminClient {
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined or the key is not a valid header value.
+    pub fn new(url: url::Url, service_role_key: &str) -> Result<Self, AuthError> {
+        Ok(Self {
+            client: ApiClient::new_service_role(url, service_role_key)?,
+        })
+    }
+
+    /// Fetches a single page of users; pages start at 1.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn list_users_page(
+        &self,
+        page: u32,
+        per_page: u32,
+    ) -> Result<Vec<UserSchema>, AdminError> {
+        let request = AdminUsersRequest::builder()
+            .page(Some(page))
+            .per_page(Some(per_page))
+            .build();
+        let response = self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        Ok(response.users)
+    }
+
+    /// Streams all users, fetching `per_page` users at a time.
+    ///
+    /// The stream ends after the first page that is not full, or after the first error.
+    pub fn list_users(
+        &self,
+        per_page: Option<u32>,
+    ) -> impl Stream<Item = Result<UserSchema, AdminError>> + '_ {
+        let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).max(1);
+        let pages = futures::stream::try_unfold(Some(1_u32), move |page| async move {
+            let Some(page) = page else {
+                return Ok::<_, AdminError>(None);
+            };
+            let users = self.list_users_page(page, per_page).await?;
+            let is_last = users.len() < per_page as usize;
+            let next = (!is_last).then(|| page.saturating_add(1));
+            Ok(Some((users, next)))
+        });
+        pages
+            .map_ok(|users| futures::stream::iter(users.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the user does not exist.
+    pub async fn get_user(&self, user_id: &str) -> Result<UserSchema, AdminError> {
+        let request = AdminUserGetRequest::builder()
+            .user_id(user_id.to_owned())
+            .build();
+        Ok(self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected (e.g. the email is taken).
+    pub async fn create_user(&self, user: AdminUserAttributes) -> Result<UserSchema, AdminError> {
+        let request = AdminUserCreateRequest::builder().user(user).build();
+        Ok(self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn update_user(
+        &self,
+        user_id: &str,
+        user: UserSchema,
+    ) -> Result<UserSchema, AdminError> {
+        let request = AdminUserUpdateRequest::builder()
+            .user_id(user_id.to_owned())
+            .user(user)
+            .build();
+        Ok(self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the user does not exist.
+    pub async fn delete_user(&self, user_id: &str) -> Result<UserSchema, AdminError> {
+        let request = AdminUserDeleteRequest::builder()
+            .user_id(user_id.to_owned())
+            .build();
+        Ok(self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// Generates an email action link (and OTP) without sending the email, e.g. to deliver it
+    /// through your own mailer.
+    ///
+    /// `data` is stored as user metadata for signups and invites.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn generate_link(
+        &self,
+        link_type: LinkType,
+        email: &str,
+        data: Option<UserMetadata>,
+        redirect_to: Option<String>,
+    ) -> Result<AdminGenerateLinkResponse, AdminError> {
+        let link_type_str = link_type.as_str().to_owned();
+        let (password, new_email) = match link_type {
+            LinkType::Signup { password } => (Some(password), None),
+            LinkType::EmailChangeCurrent { new_email } | LinkType::EmailChangeNew { new_email } => {
+                (None, Some(new_email))
+            }
+            LinkType::Invite | LinkType::MagicLink | LinkType::Recovery => (None, None),
+        };
+        let request = AdminGenerateLinkRequest::builder()
+            .link_type(link_type_str)
+            .email(email.to_owned())
+            .new_email(new_email)
+            .password(password)
+            .data(data)
+            .redirect_to(redirect_to)
+            .build();
+        Ok(self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// Creates a user for `email` and sends them an invite email; `metadata` is stored as their
+    /// user metadata.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AdminError::AlreadyRegistered`] if a confirmed user with this email exists, or
+    /// an error if the request fails or is rejected.
+    pub async fn invite_user(
+        &self,
+        email: &str,
+        metadata: Option<UserMetadata>,
+    ) -> Result<UserSchema, AdminError> {
+        let request = InviteRequest::builder()
+            .email(email.to_owned())
+            .data(metadata)
+            .build();
+        match self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await?
+        {
+            Ok(user) => Ok(user),
+            Err(AuthApiError::UserAlreadyExists(_)) => Err(AdminError::AlreadyRegistered),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Sends the invite email again to a user that has not accepted their invite yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AdminError::AlreadyRegistered`] if the user has accepted the invite in the
+    /// meantime, or an error if the request fails or is rejected.
+    pub async fn resend_invite(&self, email: &str) -> Result<UserSchema, AdminError> {
+        self.invite_user(email, None).await
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the user does not exist.
+    pub async fn list_factors(&self, user_id: &str) -> Result<Vec<MFAFactorSchema>, AdminError> {
+        let request = AdminUserFactorsRequest::builder()
+            .user_id(user_id.to_owned())
+            .build();
+        Ok(self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use test_log::test;
+
+    use super::*;
+
+    #[test(tokio::test)]
+    async fn test_list_users_paginates() {
+        let mut m = SupabaseMockServer::new().await;
+        let _page1 = m
+            .mockito_server
+            .mock("GET", "/auth/v1/admin/users")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("page".to_owned(), "1".to_owned()),
+                Matcher::UrlEncoded("per_page".to_owned(), "2".to_owned()),
+            ]))
+            .match_header("authorization", "Bearer service-key")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"users": [{"id": "a"}, {"id": "b"}]}"#)
+            .create();
+        let _page2 = m
+            .mockito_server
+            .mock("GET", "/auth/v1/admin/users")
+            .match_query(Matcher::UrlEncoded("page".to_owned(), "2".to_owned()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"users": [{"id": "c"}]}"#)
+            .create();
+
+        let admin = AdminClient::new(m.server_url(), "service-key").unwrap();
+        let users = admin
+            .list_users(Some(2))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let ids = users
+            .into_iter()
+            .map(|user| user.id.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["a", "b", "c"]);
+    }
+
+    #[test(tokio::test)]
+    async fn test_create_user() {
+        let mut m = SupabaseMockServer::new().await;
+        let _create = m
+            .mockito_server
+            .mock("POST", "/auth/v1/admin/users")
+            .match_body(Matcher::JsonString(
+                r#"{"email": "new@example.com", "password": "secret", "email_confirm": true}"#
+                    .to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "new-id", "email": "new@example.com"}"#)
+            .create();
+
+        let admin = AdminClient::new(m.server_url(), "service-key").unwrap();
+        let user = admin
+            .create_user(
+                AdminUserAttributes::builder()
+                    .email("new@example.com".to_owned())
+                    .password("secret".to_owned())
+                    .email_confirm(true)
+                    .build(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(user.id.unwrap(), "new-id");
+    }
+
+    #[test(tokio::test)]
+    async fn test_generate_email_change_link() {
+        let mut m = SupabaseMockServer::new().await;
+        let _generate = m
+            .mockito_server
+            .mock("POST", "/auth/v1/admin/generate_link")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"type": "email_change_new", "email": "old@example.com", "new_email": "new@example.com", "password": null}"#
+                    .to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"action_link": "http://localhost/verify?token=abc", "email_otp": "123456", "verification_type": "email_change"}"#,
+            )
+            .create();
+
+        let admin = AdminClient::new(m.server_url(), "service-key").unwrap();
+        let link = admin
+            .generate_link(
+                LinkType::EmailChangeNew {
+                    new_email: "new@example.com".to_owned(),
+                },
+                "old@example.com",
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(link.action_link, "http://localhost/verify?token=abc");
+        assert_eq!(link.email_otp.unwrap(), "123456");
+    }
+
+    #[test(tokio::test)]
+    async fn test_generate_signup_link() {
+        let mut m = SupabaseMockServer::new().await;
+        let _generate = m
+            .mockito_server
+            .mock("POST", "/auth/v1/admin/generate_link")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"type": "signup", "email": "new@example.com", "password": "secret"}"#
+                    .to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"action_link": "http://localhost/verify?token=abc"}"#)
+            .expect(1)
+            .create();
+
+        let link_type = LinkType::Signup {
+            password: Secret::new("secret".to_owned()),
+        };
+        assert!(!format!("{link_type:?}").contains("secret"));
+        let admin = AdminClient::new(m.server_url(), "service-key").unwrap();
+        let link = admin
+            .generate_link(link_type, "new@example.com", None, None)
+            .await
+            .unwrap();
+        assert_eq!(link.action_link, "http://localhost/verify?token=abc");
+    }
+
+    #[test(tokio::test)]
+    async fn test_invite_user() {
+        let mut m = SupabaseMockServer::new().await;
+        let _invite = m
+            .mockito_server
+            .mock("POST", "/auth/v1/invite")
+            .match_header("authorization", "Bearer service-key")
+            .match_body(Matcher::JsonString(
+                r#"{"email": "new@example.com", "data": {"team": "blue"}}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "invited-id", "email": "new@example.com"}"#)
+            .create();
+        let _registered = m
+            .mockito_server
+            .mock("POST", "/auth/v1/invite")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"email": "taken@example.com"}"#.to_owned(),
+            ))
+            .with_status(422)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"code": 422, "error_code": "email_exists", "msg": "A user with this email address has already been registered"}"#,
+            )
+            .create();
+
+        let admin = AdminClient::new(m.server_url(), "service-key").unwrap();
+        let user = admin
+            .invite_user("new@example.com", Some(simd_json::json!({"team": "blue"})))
+            .await
+            .unwrap();
+        assert_eq!(user.id.unwrap(), "invited-id");
+
+        let err = admin.resend_invite("taken@example.com").await.unwrap_err();
+        assert!(matches!(err, AdminError::AlreadyRegistered));
+    }
+}
@@ -0,0 +1,280 @@
+//! Synchronous counterpart of [`super::ApiClient`], for CLI tools and scripts that do not run an
+//! async runtime.
+//!
+//! Uses the same [`AuthModuleRequest`] types:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use rp_supabase_auth::auth_client::blocking::ApiClient;
+//! use rp_supabase_auth::auth_client::requests::HealthCheckRequest;
+//!
+//! let client = ApiClient::new_unauthenticated("http://127.0.0.1:54321".parse()?, "anon-key")?;
+//! let health = client.build_request(&HealthCheckRequest)?.execute()?.json()??;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Must not be called from within an async runtime; use [`super::ApiClient`] there.
+
+use core::marker::PhantomData;
+
+use reqwest::header;
+use tracing::instrument;
+
+use super::requests::AuthModuleRequest;
+use super::{base_headers, bearer, content_type, parse_error};
+use crate::error::AuthError;
+use crate::jwt_stream::{SupabaseAuthConfig, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT};
+
+#[derive(Clone, Debug)]
+pub struct ApiClient {
+    inner: reqwest::blocking::Client,
+    url: url::Url,
+    /// `apikey` and (if authenticated) `Authorization` headers, sent with every request
+    headers: header::HeaderMap,
+}
+
+impl ApiClient {
+    pub fn new_unauthenticated(url: url::Url, api_key: &str) -> Result<Self, AuthError> {
+        let http_client = reqwest::blocking::Client::builder()
+            .timeout(DEFAULT_REQUEST_TIMEOUT)
+            .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+            .build()?;
+        Self::with_http_client(http_client, url, api_key, None)
+    }
+
+    pub fn new_authenticated(url: url::Url, api_key: &str, token: &str) -> Result<Self, AuthError> {
+        Self::new_unauthenticated(url, api_key)?.authenticated(token)
+    }
+
+    /// See [`super::ApiClient::new_service_role`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined or the key is not a valid header value.
+    pub fn new_service_role(url: url::Url, service_role_key: &str) -> Result<Self, AuthError> {
+        Self::new_authenticated(url, service_role_key, service_role_key)
+    }
+
+    /// Creates an unauthenticated client for the project in `config`, honouring its proxy and
+    /// timeout settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined, the proxy is invalid or the key is not a
+    /// valid header value.
+    pub fn from_config(config: &SupabaseAuthConfig) -> Result<Self, AuthError> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(config.request_timeout)
+            .connect_timeout(config.connect_timeout);
+        if let Some(proxy) = config.proxy.as_ref() {
+            builder = builder.proxy(proxy.to_reqwest()?);
+        }
+        Self::with_http_client(
+            builder.build()?,
+            config.url.clone(),
+            config.api_key.expose_secret(),
+            None,
+        )
+    }
+
+    /// See [`super::ApiClient::with_http_client`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined or the key or token are not valid header
+    /// values.
+    pub fn with_http_client(
+        http_client: reqwest::blocking::Client,
+        url: url::Url,
+        api_key: &str,
+        token: Option<&str>,
+    ) -> Result<Self, AuthError> {
+        let url = url.join("/auth/v1/")?;
+        let mut headers = base_headers(api_key)?;
+        if let Some(token) = token {
+            headers.insert(header::AUTHORIZATION, bearer(token)?);
+        }
+        Ok(Self {
+            inner: http_client,
+            url,
+            headers,
+        })
+    }
+
+    /// Returns a client for the same project and HTTP client, authenticated with `token`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is not a valid header value.
+    pub fn authenticated(&self, token: &str) -> Result<Self, AuthError> {
+        let mut headers = self.headers.clone();
+        headers.insert(header::AUTHORIZATION, bearer(token)?);
+        Ok(Self {
+            inner: self.inner.clone(),
+            url: self.url.clone(),
+            headers,
+        })
+    }
+
+    #[instrument(name = "build_blocking_request", skip(self, request))]
+    pub fn build_request<T>(&self, request: &T) -> Result<Request<T::Res, T::Error>, AuthError>
+    where
+        T: AuthModuleRequest + core::fmt::Debug,
+    {
+        let endpoint = request.path(&self.url)?;
+        let payload = simd_json::to_vec(&request.payload())?;
+        let reqwest_req = self
+            .inner
+            .request(T::METHOD, endpoint.as_str())
+            .headers(self.headers.clone())
+            .body(payload);
+
+        Ok(Request {
+            request: reqwest_req,
+            result: PhantomData,
+            err: PhantomData,
+        })
+    }
+}
+
+/// Encapsulated blocking HTTP request for the API
+pub struct Request<T, E> {
+    request: reqwest::blocking::RequestBuilder,
+    result: PhantomData<T>,
+    err: PhantomData<E>,
+}
+
+impl<T, E> Request<T, E> {
+    /// execute an API request, blocking the current thread
+    #[instrument(name = "execute_blocking_request", skip(self))]
+    pub fn execute(self) -> Result<Response<T, E>, AuthError> {
+        let response = self.request.send()?;
+        Ok(Response {
+            response,
+            result: PhantomData,
+            err: PhantomData,
+        })
+    }
+}
+
+/// The raw response of the blocking API request
+pub struct Response<T, E> {
+    response: reqwest::blocking::Response,
+    result: PhantomData<T>,
+    err: PhantomData<E>,
+}
+
+impl<T, E> Response<T, E> {
+    /// See [`super::Response::ok`].
+    pub fn ok(self) -> Result<(), AuthError> {
+        self.response.error_for_status()?;
+        Ok(())
+    }
+
+    /// See [`super::Response::json_err`].
+    pub fn json_err(self) -> Result<Result<(), E>, AuthError>
+    where
+        E: serde::de::DeserializeOwned,
+    {
+        let status = self.response.status();
+        if status.is_success() {
+            return Ok(Ok(()));
+        }
+        let content_type = content_type(self.response.headers());
+        let bytes = self.response.bytes()?.to_vec();
+        Ok(Err(parse_error::<E>(
+            bytes,
+            status,
+            content_type.as_deref(),
+        )?))
+    }
+
+    /// Parse the response json
+    pub fn json(self) -> Result<Result<T, E>, AuthError>
+    where
+        T: serde::de::DeserializeOwned,
+        E: serde::de::DeserializeOwned,
+    {
+        let status = self.response.status();
+        let content_type = content_type(self.response.headers());
+        let mut bytes = self.response.bytes()?.to_vec();
+        if status.is_success() {
+            Ok(Ok(simd_json::from_slice::<T>(bytes.as_mut())?))
+        } else {
+            Ok(Err(parse_error::<E>(
+                bytes,
+                status,
+                content_type.as_deref(),
+            )?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::auth_client::requests::{GrantType, TokenRequest};
+    use crate::types::TokenRequestBody;
+
+    #[test_log::test]
+    fn test_blocking_password_login() {
+        let mut server = mockito::Server::new();
+        let _login = server
+            .mock("POST", "/auth/v1/token")
+            .match_query(Matcher::UrlEncoded(
+                "grant_type".to_owned(),
+                "password".to_owned(),
+            ))
+            .match_header(crate::SUPABASE_KEY, "api-key")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"access_token": "access", "refresh_token": "refresh"}"#)
+            .create();
+        let _rejected = server
+            .mock("GET", "/auth/v1/user")
+            .with_status(401)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"code": 401, "msg": "invalid JWT"}"#)
+            .create();
+
+        let client =
+            ApiClient::new_unauthenticated(server.url().parse().unwrap(), "api-key").unwrap();
+        let request = TokenRequest::builder()
+            .grant_type(GrantType::Password)
+            .payload(
+                TokenRequestBody::builder()
+                    .email(Some("user@example.com".to_owned()))
+                    .password(Some("password".to_owned().into()))
+                    .build(),
+            )
+            .build();
+        let session = client
+            .build_request(&request)
+            .unwrap()
+            .execute()
+            .unwrap()
+            .json()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            "access"
+        );
+
+        let err = client
+            .authenticated("expired")
+            .unwrap()
+            .build_request(&crate::auth_client::requests::UserGetRequest)
+            .unwrap()
+            .execute()
+            .unwrap()
+            .json()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.schema().code, Some(401));
+    }
+}
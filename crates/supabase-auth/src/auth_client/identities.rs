@@ -0,0 +1,193 @@
+//! Linking and unlinking the external identities of the signed in user.
+//!
+//! Linking is an OAuth flow: [`ApiClient::link_identity`] returns the provider URL and the PKCE
+//! challenge of the flow, the `code` from the redirect is then exchanged with that challenge
+//! through [`ApiClient::exchange_code_for_session`].
+
+use thiserror::Error;
+
+use super::pkce::{OAuthOptions, PkceChallenge, CODE_CHALLENGE_METHOD};
+use super::requests::{LinkIdentityRequest, UnlinkIdentityRequest, UserGetRequest};
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::types::{IdentitySchema, Provider};
+
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    #[error("Invalid provider URL: {0}")]
+    InvalidProviderUrl(#[from] url::ParseError),
+}
+
+impl ApiClient {
+    /// The identities linked to the signed in user, as listed by `GET /user`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn get_user_identities(&self) -> Result<Vec<IdentitySchema>, IdentityError> {
+        let user = self
+            .build_request(&UserGetRequest)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        Ok(user.identities.unwrap_or_default())
+    }
+
+    /// Starts linking an identity of `provider` (e.g. [`Provider::Github`]) to the signed
+    /// in user.
+    ///
+    /// Returns the URL the user has to be sent to and the PKCE challenge of the flow; see
+    /// [`ApiClient::sign_in_with_oauth`] for how the flow is completed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected (e.g. manual linking is disabled).
+    pub async fn link_identity(
+        &self,
+        provider: Provider,
+        options: OAuthOptions,
+    ) -> Result<(url::Url, PkceChallenge), IdentityError> {
+        let challenge = PkceChallenge::generate();
+        let request = LinkIdentityRequest::builder()
+            .provider(provider)
+            .scopes((!options.scopes.is_empty()).then_some(options.scopes))
+            .redirect_to(options.redirect_to)
+            .code_challenge_method(Some(CODE_CHALLENGE_METHOD.to_owned()))
+            .code_challenge(Some(challenge.challenge().to_owned()))
+            .build();
+        let response = self
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        let mut url = url::Url::parse(&response.url)?;
+        if !options.query_params.is_empty() {
+            url.query_pairs_mut().extend_pairs(options.query_params);
+        }
+        Ok((url, challenge))
+    }
+
+    /// Removes the identity with `identity_id` (see [`IdentitySchema::identity_id`]) from the
+    /// signed in user; the user has to keep at least one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn unlink_identity(&self, identity_id: &str) -> Result<(), IdentityError> {
+        let request = UnlinkIdentityRequest::builder()
+            .identity_id(identity_id.to_owned())
+            .build();
+        self.build_request(&request)?
+            .execute()
+            .await?
+            .json_err()
+            .await??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use test_log::test;
+
+    use super::*;
+
+    #[test(tokio::test)]
+    async fn test_list_link_and_unlink_identities() {
+        let mut m = SupabaseMockServer::new().await;
+        let _user = m
+            .mockito_server
+            .mock("GET", "/auth/v1/user")
+            .match_header("authorization", "Bearer access-token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id": "user-id", "identities": [
+                    {"identity_id": "email-identity", "id": "user-id", "provider": "email"},
+                    {"identity_id": "github-identity", "id": "12345", "provider": "github"}
+                ]}"#,
+            )
+            .create();
+        let _link = m
+            .mockito_server
+            .mock("GET", "/auth/v1/user/identities/authorize")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("provider".to_owned(), "google".to_owned()),
+                Matcher::UrlEncoded("skip_http_redirect".to_owned(), "true".to_owned()),
+                Matcher::UrlEncoded("code_challenge_method".to_owned(), "s256".to_owned()),
+            ]))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"url": "https://accounts.google.com/o/oauth2/auth?state=abc"}"#)
+            .create();
+        let unlink = m
+            .mockito_server
+            .mock("DELETE", "/auth/v1/user/identities/github-identity")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{}")
+            .expect(1)
+            .create();
+
+        let client =
+            ApiClient::new_authenticated(m.server_url(), "api-key", "access-token").unwrap();
+        let identities = client.get_user_identities().await.unwrap();
+        assert_eq!(
+            identities
+                .iter()
+                .map(|identity| identity.provider.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["email", "github"]
+        );
+
+        let (url, challenge) = client
+            .link_identity(Provider::Google, OAuthOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(url.host_str(), Some("accounts.google.com"));
+
+        // the link is completed by the client of the next access token
+        let _exchange = m
+            .mockito_server
+            .mock("POST", "/auth/v1/token")
+            .match_query(Matcher::UrlEncoded(
+                "grant_type".to_owned(),
+                "pkce".to_owned(),
+            ))
+            .match_header("authorization", "Bearer rotated-token")
+            .match_body(Matcher::PartialJsonString(format!(
+                r#"{{"auth_code": "link-code", "code_verifier": "{}"}}"#,
+                challenge.verifier()
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"access_token": "linked-token", "refresh_token": "refresh"}"#)
+            .expect(1)
+            .create();
+        let rotated = client.authenticated("rotated-token").unwrap();
+        let session = rotated
+            .exchange_code_for_session(&challenge, "link-code")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            "linked-token"
+        );
+
+        client
+            .unlink_identity(identities[1].identity_id.as_deref().unwrap())
+            .await
+            .unwrap();
+        unlink.assert();
+    }
+}
@@ -0,0 +1,460 @@
+//! Multi-factor authentication flows for the signed in user.
+//!
+//! Verifying a factor returns a new session with an upgraded authenticator assurance level
+//! (`aal2`); when the [`MfaClient`] was created from an [`AuthSession`] that session is pushed
+//! through the refresh stream so every holder of the session sees the upgraded tokens.
+
+use thiserror::Error;
+
+use super::requests::{
+    FactorsChallengeRequest, FactorsRequest, FactorsVerifyRequest, FactorsWebAuthnVerifyRequest,
+};
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::jwt_stream::SupabaseAuthConfig;
+use crate::session::AuthSession;
+use crate::token_provider::TokenProvider as _;
+use crate::types::{
+    AccessTokenResponseSchema, ChallengeResponse, FactorsResponse, WebAuthnCeremony,
+    WebAuthnChallengeResponse, WebAuthnParams, WebAuthnVerifyParams,
+};
+
+const TOTP_FACTOR_TYPE: &str = "totp";
+const WEBAUTHN_FACTOR_TYPE: &str = "webauthn";
+
+#[derive(Debug, Error)]
+pub enum MfaError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    #[error("Expected a WebAuthn challenge")]
+    NotWebAuthnChallenge,
+}
+
+/// MFA operations on behalf of the signed in user.
+#[derive(Debug, Clone)]
+pub struct MfaClient {
+    client: ApiClient,
+    session: Option<AuthSession>,
+}
+
+impl MfaClient {
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined or the token is not a valid header value.
+    pub fn new(url: url::Url, api_key: &str, access_token: &str) -> Result<Self, AuthError> {
+        Ok(Self {
+            client: ApiClient::new_authenticated(url, api_key, access_token)?,
+            session: None,
+        })
+    }
+
+    /// Acts with the session's current token, read anew for every request, and replaces the
+    /// session after a verification.
+    ///
+    /// Uses the URL, API key, proxy, timeouts and retry policy of the auth config.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the url cannot be joined, the proxy is invalid or the key is not a
+    /// valid header value.
+    pub fn for_session(
+        config: &SupabaseAuthConfig,
+        session: &AuthSession,
+    ) -> Result<Self, AuthError> {
+        Ok(Self {
+            client: ApiClient::from_config(config)?,
+            session: Some(session.clone()),
+        })
+    }
+
+    /// The client to send the next request with; waits for the session's first token
+    async fn client(&self) -> Result<ApiClient, MfaError> {
+        match self.session {
+            Some(ref session) => Ok(self.client.authenticated(&session.token().await?)?),
+            None => Ok(self.client.clone()),
+        }
+    }
+
+    /// Starts a TOTP enrollment; the response carries the QR code (SVG), secret and URI to show
+    /// to the user.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn enroll_totp(
+        &self,
+        friendly_name: Option<String>,
+        issuer: Option<String>,
+    ) -> Result<FactorsResponse, MfaError> {
+        let request = FactorsRequest::builder()
+            .factor_type(TOTP_FACTOR_TYPE.to_owned())
+            .friendly_name(friendly_name)
+            .issuer(issuer)
+            .phone(None)
+            .build();
+        Ok(self
+            .client()
+            .await?
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// Creates a challenge that the next [`MfaClient::verify`] call answers.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the factor does not exist.
+    pub async fn challenge(&self, factor_id: &str) -> Result<ChallengeResponse, MfaError> {
+        let request = FactorsChallengeRequest::builder()
+            .factor_id(factor_id.to_owned())
+            .channel(None)
+            .build();
+        Ok(self
+            .client()
+            .await?
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// Verifies the code for a challenge and returns the upgraded session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the code is rejected.
+    pub async fn verify(
+        &self,
+        factor_id: &str,
+        challenge_id: &str,
+        code: &str,
+    ) -> Result<AccessTokenResponseSchema, MfaError> {
+        let request = FactorsVerifyRequest::builder()
+            .factor_id(factor_id.to_owned())
+            .challenge_id(challenge_id.to_owned())
+            .code(code.to_owned())
+            .build();
+        let session = self
+            .client()
+            .await?
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        self.propagate(&session);
+        Ok(session)
+    }
+
+    /// Starts a `WebAuthn` enrollment; follow up with [`MfaClient::challenge_webauthn`] to get
+    /// the credential creation options for the browser.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn enroll_webauthn(
+        &self,
+        friendly_name: Option<String>,
+    ) -> Result<FactorsResponse, MfaError> {
+        let request = FactorsRequest::builder()
+            .factor_type(WEBAUTHN_FACTOR_TYPE.to_owned())
+            .friendly_name(friendly_name)
+            .issuer(None)
+            .phone(None)
+            .build();
+        Ok(self
+            .client()
+            .await?
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// Creates a `WebAuthn` challenge. Pass `credential_creation_options` (for a new factor) or
+    /// `credential_request_options` (for a verified one) to the browser's `WebAuthn` API.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the factor is not a `WebAuthn` factor.
+    pub async fn challenge_webauthn(
+        &self,
+        factor_id: &str,
+        relying_party: WebAuthnParams,
+    ) -> Result<WebAuthnChallengeResponse, MfaError> {
+        let request = FactorsChallengeRequest::builder()
+            .factor_id(factor_id.to_owned())
+            .channel(None)
+            .webauthn(Some(relying_party))
+            .build();
+        let challenge = self
+            .client()
+            .await?
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        match challenge {
+            ChallengeResponse::WebAuthn(challenge) => Ok(challenge),
+            ChallengeResponse::TOTPPhone(_) => Err(MfaError::NotWebAuthnChallenge),
+        }
+    }
+
+    /// Completes a `WebAuthn` challenge with the credential the browser produced and returns the
+    /// upgraded session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the credential is rejected.
+    pub async fn verify_webauthn(
+        &self,
+        factor_id: &str,
+        challenge_id: &str,
+        relying_party: WebAuthnParams,
+        ceremony: WebAuthnCeremony,
+        credential_response: simd_json::OwnedValue,
+    ) -> Result<AccessTokenResponseSchema, MfaError> {
+        let request = FactorsWebAuthnVerifyRequest::builder()
+            .factor_id(factor_id.to_owned())
+            .challenge_id(challenge_id.to_owned())
+            .webauthn(
+                WebAuthnVerifyParams::builder()
+                    .rp_id(relying_party.rp_id)
+                    .rp_origins(relying_party.rp_origins)
+                    .ceremony(ceremony)
+                    .credential_response(credential_response)
+                    .build(),
+            )
+            .build();
+        let session = self
+            .client()
+            .await?
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        self.propagate(&session);
+        Ok(session)
+    }
+
+    /// Pushes an upgraded session through the refresh stream
+    fn propagate(&self, session: &AccessTokenResponseSchema) {
+        if let Some(auth_session) = self.session.as_ref() {
+            if !auth_session.replace_session(session.clone()) {
+                tracing::warn!("auth session stopped; upgraded session was not propagated");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::{make_jwt, SupabaseMockServer};
+    use test_log::test;
+
+    use super::*;
+    use crate::jwt_stream::{test_config, JwtStream, RetryPolicy};
+    use crate::types::LoginCredentials;
+
+    #[test(tokio::test)]
+    async fn test_totp_enroll_and_verify_upgrades_session() {
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let mut m = SupabaseMockServer::new().await;
+        m.register_jwt_password(&access_token);
+        let _enroll = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors")
+            .match_header("authorization", format!("Bearer {access_token}").as_str())
+            .match_body(Matcher::PartialJsonString(r#"{"factor_type": "totp"}"#.to_owned()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id": "factor-1", "type": "totp", "totp": {"qr_code": "<svg/>", "secret": "SECRET", "uri": "otpauth://totp/x"}}"#,
+            )
+            .create();
+        let _challenge = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors/factor-1/challenge")
+            .match_header("authorization", format!("Bearer {access_token}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "challenge-1", "type": "totp", "expires_at": 1732000000}"#)
+            .create();
+        let upgraded_challenge = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors/factor-1/challenge")
+            .match_header("authorization", "Bearer aal2-token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "challenge-2", "type": "totp", "expires_at": 1732000000}"#)
+            .create();
+        let _verify = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors/factor-1/verify")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"challenge_id": "challenge-1", "code": "123456"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"access_token": "aal2-token", "refresh_token": "aal2-refresh", "expires_in": 3600}"#,
+            )
+            .create();
+
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(1),
+            ..test_config(m.server_url())
+        };
+        let credentials = LoginCredentials::builder()
+            .email("user@example.com".to_owned())
+            .password("password".to_owned())
+            .build();
+        let session = JwtStream::new(config.clone())
+            .sign_in(credentials)
+            .unwrap()
+            .into_session();
+
+        // requests wait for the first token
+        let mfa = MfaClient::for_session(&config, &session).unwrap();
+        let factor = mfa.enroll_totp(None, None).await.unwrap();
+        assert_eq!(factor.totp.unwrap().secret.unwrap(), "SECRET");
+        mfa.challenge(&factor.id).await.unwrap();
+        let upgraded = mfa
+            .verify(&factor.id, "challenge-1", "123456")
+            .await
+            .unwrap();
+        assert_eq!(
+            upgraded.access_token.unwrap().expose_secret().as_str(),
+            "aal2-token"
+        );
+
+        // the background task picks up the replacement asynchronously
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while session.current_token().as_deref() != Some("aal2-token") {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // later requests use the upgraded token
+        mfa.challenge(&factor.id).await.unwrap();
+        upgraded_challenge.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_client_uses_config() {
+        let mut m = SupabaseMockServer::new().await;
+        let unavailable = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors/factor-1/challenge")
+            .with_status(503)
+            .expect(1)
+            .create();
+        let challenge = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors/factor-1/challenge")
+            .match_header("authorization", "Bearer user-token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "challenge-1", "type": "totp", "expires_at": 1732000000}"#)
+            .expect(1)
+            .create();
+
+        let config = SupabaseAuthConfig {
+            retry_policy: RetryPolicy::builder()
+                .max_retries(1)
+                .initial_backoff(Duration::from_millis(10))
+                .build(),
+            ..test_config(m.server_url())
+        };
+        let existing = AccessTokenResponseSchema::builder()
+            .access_token("user-token".to_owned())
+            .refresh_token("user-refresh".to_owned())
+            .expires_in(3600)
+            .build();
+        let session = JwtStream::new(config.clone())
+            .from_session(existing)
+            .unwrap()
+            .into_session();
+
+        // the transient failure is retried with the policy of the config
+        let mfa = MfaClient::for_session(&config, &session).unwrap();
+        mfa.challenge("factor-1").await.unwrap();
+        unavailable.assert();
+        challenge.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_webauthn_verify() {
+        let mut m = SupabaseMockServer::new().await;
+        let _challenge = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors/factor-1/challenge")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"webauthn": {"rp_id": "example.com"}}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"id": "challenge-1", "type": "webauthn", "expires_at": 1732000000, "credential_request_options": {"challenge": "abc", "rpId": "example.com"}}"#,
+            )
+            .create();
+        let _verify = m
+            .mockito_server
+            .mock("POST", "/auth/v1/factors/factor-1/verify")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"challenge_id": "challenge-1", "webauthn": {"rp_id": "example.com", "type": "request", "credential_response": {"id": "cred"}}}"#
+                    .to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"access_token": "aal2-token", "refresh_token": "aal2-refresh"}"#)
+            .create();
+
+        let mfa = MfaClient::new(m.server_url(), "api-key", "aal1-token").unwrap();
+        let relying_party = WebAuthnParams::builder()
+            .rp_id("example.com".to_owned())
+            .rp_origins(vec!["https://example.com".to_owned()])
+            .build();
+        let challenge = mfa
+            .challenge_webauthn("factor-1", relying_party.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            challenge
+                .credential_request_options
+                .unwrap()
+                .challenge
+                .unwrap(),
+            "abc"
+        );
+
+        let session = mfa
+            .verify_webauthn(
+                "factor-1",
+                &challenge.id,
+                relying_party,
+                WebAuthnCeremony::Request,
+                simd_json::json!({ "id": "cred" }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            "aal2-token"
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! One-time password and magic link sign-in flows.
+//!
+//! A code (or link) is sent to the user through [`OtpRequest`] / [`MagicLinkRequest`]; once the
+//! user comes back with it, it is verified via [`VerifyPostRequest`] and the resulting session is
+//! handed to a [`JwtRefreshStream`] so it gets refreshed the same way as a password login.
+
+use thiserror::Error;
+
+use super::requests::{MagicLinkRequest, OtpRequest, VerifyPostRequest};
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::jwt_stream::{JwtRefreshStream, JwtStream, SignInError, SupabaseAuthConfig};
+use crate::types::{AccessTokenResponseSchema, OtpResponse};
+
+/// `type` used when verifying a code that was sent by SMS or WhatsApp
+const PHONE_VERIFICATION_TYPE: &str = "sms";
+/// `type` used when verifying a magic link token
+const MAGIC_LINK_VERIFICATION_TYPE: &str = "magiclink";
+
+#[derive(Debug, Error)]
+pub enum OtpFlowError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    #[error(transparent)]
+    SignIn(#[from] SignInError),
+}
+
+/// Phone based OTP login.
+#[derive(Debug, Clone)]
+pub struct PhoneOtpFlow {
+    config: SupabaseAuthConfig,
+    client: ApiClient,
+    phone: String,
+}
+
+impl PhoneOtpFlow {
+    /// # Errors
+    ///
+    /// Returns an error if the supabase url cannot be joined with the auth suffix.
+    pub fn new(config: SupabaseAuthConfig, phone: String) -> Result<Self, AuthError> {
+        let client = ApiClient::from_config(&config)?;
+        Ok(Self {
+            config,
+            client,
+            phone,
+        })
+    }
+
+    /// Sends the code to the phone number. `channel` is either `sms` (the default) or `whatsapp`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request cannot be executed or the API rejects it.
+    pub async fn send_code(&self, channel: Option<String>) -> Result<OtpResponse, OtpFlowError> {
+        let request = OtpRequest::builder()
+            .email(None)
+            .phone(Some(self.phone.clone()))
+            .channel(channel)
+            .create_user(None)
+            .data(None)
+            .code_challenge_method(None)
+            .code_challenge(None)
+            .gotrue_meta_security(None)
+            .build();
+        let response = self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        Ok(response)
+    }
+
+    /// Verifies the code the user received and starts refreshing the resulting session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the code is rejected or the session cannot be refreshed.
+    pub async fn verify(&self, code: &str) -> Result<JwtRefreshStream, OtpFlowError> {
+        let request = VerifyPostRequest::builder()
+            .verification_type(PHONE_VERIFICATION_TYPE.to_owned())
+            .token(Some(code.to_owned()))
+            .token_hash(None)
+            .email(None)
+            .phone(Some(self.phone.clone()))
+            .redirect_to(None)
+            .gotrue_meta_security(None)
+            .build();
+        let session = self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        let stream = JwtStream::new(self.config.clone()).from_session(session)?;
+        Ok(stream)
+    }
+}
+
+/// What the magic link callback handed back to the application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MagicLinkToken {
+    /// The short OTP token contained in the email
+    Token(String),
+    /// The `token_hash` query parameter of the link
+    TokenHash(String),
+}
+
+/// Email magic link login.
+#[derive(Debug, Clone)]
+pub struct MagicLinkFlow {
+    config: SupabaseAuthConfig,
+    client: ApiClient,
+    email: String,
+}
+
+impl MagicLinkFlow {
+    /// # Errors
+    ///
+    /// Returns an error if the supabase url cannot be joined with the auth suffix.
+    pub fn new(config: SupabaseAuthConfig, email: String) -> Result<Self, AuthError> {
+        let client = ApiClient::from_config(&config)?;
+        Ok(Self {
+            config,
+            client,
+            email,
+        })
+    }
+
+    /// Sends the magic link to the email address.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request cannot be executed or the API rejects it.
+    pub async fn send_link(&self) -> Result<(), OtpFlowError> {
+        let request = MagicLinkRequest::builder()
+            .email(self.email.clone())
+            .data(None)
+            .gotrue_meta_security(None)
+            .build();
+        self.client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json_err()
+            .await??;
+        Ok(())
+    }
+
+    /// Completes the verification with the token from the callback.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is rejected.
+    pub async fn verify(
+        &self,
+        token: MagicLinkToken,
+    ) -> Result<AccessTokenResponseSchema, OtpFlowError> {
+        let (token, token_hash, email) = match token {
+            MagicLinkToken::Token(token) => (Some(token), None, Some(self.email.clone())),
+            MagicLinkToken::TokenHash(token_hash) => (None, Some(token_hash), None),
+        };
+        let request = VerifyPostRequest::builder()
+            .verification_type(MAGIC_LINK_VERIFICATION_TYPE.to_owned())
+            .token(token)
+            .token_hash(token_hash)
+            .email(email)
+            .phone(None)
+            .redirect_to(None)
+            .gotrue_meta_security(None)
+            .build();
+        let session = self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        Ok(session)
+    }
+
+    /// Same as [`MagicLinkFlow::verify`], but keeps the session refreshed afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is rejected or the session cannot be refreshed.
+    pub async fn sign_in(&self, token: MagicLinkToken) -> Result<JwtRefreshStream, OtpFlowError> {
+        let session = self.verify(token).await?;
+        let stream = JwtStream::new(self.config.clone()).from_session(session)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use futures::StreamExt as _;
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::{make_jwt, SupabaseMockServer};
+    use test_log::test;
+
+    use super::*;
+    use crate::jwt_stream::test_config;
+
+    #[test(tokio::test)]
+    async fn test_phone_otp_flow() {
+        let mut m = SupabaseMockServer::new().await;
+        let _otp = m
+            .mockito_server
+            .mock("POST", "/auth/v1/otp")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"phone": "+37120000000"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"message_id": "msg-1"}"#)
+            .create();
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let _verify = m
+            .mockito_server
+            .mock("POST", "/auth/v1/verify")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"type": "sms", "token": "123456", "phone": "+37120000000"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"access_token": "{access_token}", "refresh_token": "otp-refresh", "expires_in": 3600}}"#
+            ))
+            .create();
+
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(1),
+            ..test_config(m.server_url())
+        };
+        let flow = PhoneOtpFlow::new(config, "+37120000000".to_owned()).unwrap();
+
+        let sent = flow.send_code(None).await.unwrap();
+        assert_eq!(sent.message_id.unwrap(), "msg-1");
+
+        let mut stream = flow.verify("123456").await.unwrap();
+        let session = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            access_token
+        );
+        assert_eq!(
+            session.refresh_token.unwrap().expose_secret().as_str(),
+            "otp-refresh"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_magic_link_flow() {
+        let mut m = SupabaseMockServer::new().await;
+        let _link = m
+            .mockito_server
+            .mock("POST", "/auth/v1/magiclink")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"email": "user@example.com"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{}")
+            .create();
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let _verify = m
+            .mockito_server
+            .mock("POST", "/auth/v1/verify")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"type": "magiclink", "token_hash": "the-hash"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"access_token": "{access_token}", "refresh_token": "link-refresh", "expires_in": 3600}}"#
+            ))
+            .create();
+
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(1),
+            ..test_config(m.server_url())
+        };
+        let flow = MagicLinkFlow::new(config, "user@example.com".to_owned()).unwrap();
+        flow.send_link().await.unwrap();
+
+        let mut stream = flow
+            .sign_in(MagicLinkToken::TokenHash("the-hash".to_owned()))
+            .await
+            .unwrap();
+        let session = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            access_token
+        );
+        assert_eq!(
+            session.refresh_token.unwrap().expose_secret().as_str(),
+            "link-refresh"
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! PKCE (Proof Key for Code Exchange) auth code flow.
+//!
+//! 1. [`PkceFlow::authorize_url`] builds the `/authorize` URL that the user has to visit.
+//! 2. The provider redirects back to `redirect_to` with a `code` query parameter.
+//! 3. [`PkceFlow::exchange_code`] trades that code (together with the kept verifier) for a session
+//!    using `grant_type=pkce`.
+
+use base64::prelude::*;
+use rand::RngCore as _;
+use sha2::{Digest as _, Sha256};
+
+use super::requests::{AuthModuleRequest as _, AuthorizeRequest, GrantType, TokenRequest};
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::types::{AccessTokenResponseSchema, Provider, TokenRequestBody};
+
+/// Options for [`ApiClient::sign_in_with_oauth`].
+#[derive(Debug, Clone, Default, typed_builder::TypedBuilder)]
+pub struct OAuthOptions {
+    /// Space separated list of scopes to request from the provider
+    #[builder(default, setter(into))]
+    pub scopes: String,
+    /// Where the provider should redirect to after the sign-in
+    #[builder(default, setter(strip_option, into))]
+    pub redirect_to: Option<String>,
+    /// Extra query parameters forwarded to the provider (e.g. `access_type=offline`)
+    #[builder(default)]
+    pub query_params: Vec<(String, String)>,
+}
+
+/// The `code_challenge_method` used for generated challenges.
+pub const CODE_CHALLENGE_METHOD: &str = "s256";
+
+/// A PKCE code verifier and its derived S256 code challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PkceChallenge {
+    verifier: String,
+    challenge: String,
+}
+
+impl PkceChallenge {
+    /// Generates a new random code verifier (64 random bytes, base64url encoded).
+    #[must_use]
+    pub fn generate() -> Self {
+        let mut bytes = [0_u8; 64];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self::from_verifier(BASE64_URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// Derives the challenge for an existing verifier (e.g. one restored after a redirect).
+    #[must_use]
+    pub fn from_verifier(verifier: String) -> Self {
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self {
+            verifier,
+            challenge,
+        }
+    }
+
+    #[must_use]
+    pub fn verifier(&self) -> &str {
+        &self.verifier
+    }
+
+    #[must_use]
+    pub fn challenge(&self) -> &str {
+        &self.challenge
+    }
+}
+
+/// An in-progress PKCE sign-in.
+///
+/// Keep the flow (or at least [`PkceChallenge::verifier`]) around until the redirect comes back.
+#[derive(Debug, Clone)]
+pub struct PkceFlow {
+    client: ApiClient,
+    pkce: PkceChallenge,
+}
+
+impl PkceFlow {
+    /// Starts a new flow with a freshly generated verifier.
+    #[must_use]
+    pub fn new(client: ApiClient) -> Self {
+        Self::with_challenge(client, PkceChallenge::generate())
+    }
+
+    #[must_use]
+    pub const fn with_challenge(client: ApiClient, pkce: PkceChallenge) -> Self {
+        Self { client, pkce }
+    }
+
+    #[must_use]
+    pub const fn challenge(&self) -> &PkceChallenge {
+        &self.pkce
+    }
+
+    /// Builds the `/authorize` URL for the given provider that the user should be sent to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the URL cannot be constructed.
+    pub fn authorize_url(
+        &self,
+        provider: Provider,
+        scopes: &str,
+        redirect_to: Option<String>,
+    ) -> Result<url::Url, AuthError> {
+        AuthorizeRequest::builder()
+            .provider(provider)
+            .scopes(scopes.to_owned())
+            .invite_token(None)
+            .redirect_to(redirect_to)
+            .code_challenge_method(Some(CODE_CHALLENGE_METHOD.to_owned()))
+            .code_challenge(Some(self.pkce.challenge().to_owned()))
+            .build()
+            .path(&self.client.url)
+    }
+
+    /// Exchanges the auth code received on the redirect for a session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request cannot be executed or the response cannot be parsed.
+    pub async fn exchange_code(
+        &self,
+        auth_code: &str,
+    ) -> Result<Result<AccessTokenResponseSchema, AuthApiError>, AuthError> {
+        let request = TokenRequest::builder()
+            .grant_type(GrantType::Pkce)
+            .payload(
+                TokenRequestBody::builder()
+                    .auth_code(auth_code.to_owned())
+                    .code_verifier(self.pkce.verifier().to_owned())
+                    .build(),
+            )
+            .build();
+        self.client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use simd_json::OwnedValue;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn test_rfc7636_challenge() {
+        let pkce =
+            PkceChallenge::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_owned());
+        assert_eq!(
+            pkce.challenge(),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_pkce_flow() {
+        let mut m = SupabaseMockServer::new().await;
+        let _m1 = m
+            .mockito_server
+            .mock("POST", "/auth/v1/token")
+            .match_query(Matcher::Regex("grant_type=pkce".to_owned()))
+            .match_body(Matcher::PartialJsonString(
+                r#"{"auth_code": "the-code", "code_verifier": "the-verifier"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"access_token": "token", "refresh_token": "refresh"}"#)
+            .create();
+
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key").unwrap();
+        let flow = PkceFlow::with_challenge(
+            client,
+            PkceChallenge::from_verifier("the-verifier".to_owned()),
+        );
+
+        let url = flow
+            .authorize_url(
+                Provider::Github,
+                "email",
+                Some("http://localhost/cb".to_owned()),
+            )
+            .unwrap();
+        let query = url.query_pairs().into_owned().collect::<Vec<_>>();
+        assert!(query.contains(&("code_challenge_method".to_owned(), "s256".to_owned())));
+        assert!(query.contains(&(
+            "code_challenge".to_owned(),
+            flow.challenge().challenge().to_owned()
+        )));
+
+        let session = flow.exchange_code("the-code").await.unwrap().unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            "token"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_sign_in_with_oauth() {
+        let mut m = SupabaseMockServer::new().await;
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key").unwrap();
+        let (url, challenge) = client
+            .sign_in_with_oauth(
+                Provider::Github,
+                OAuthOptions::builder()
+                    .scopes("repo")
+                    .redirect_to("http://localhost/cb")
+                    .query_params(vec![("access_type".to_owned(), "offline".to_owned())])
+                    .build(),
+            )
+            .unwrap();
+        // a concurrent flow does not affect the first one
+        let (_, other) = client
+            .sign_in_with_oauth(Provider::Github, OAuthOptions::default())
+            .unwrap();
+        assert_ne!(challenge, other);
+        let query = url.query_pairs().into_owned().collect::<Vec<_>>();
+        assert!(query.contains(&("provider".to_owned(), "github".to_owned())));
+        assert!(query.contains(&("access_type".to_owned(), "offline".to_owned())));
+        assert!(query.contains(&(
+            "code_challenge".to_owned(),
+            challenge.challenge().to_owned()
+        )));
+
+        let rejected = m
+            .mockito_server
+            .mock("POST", "/auth/v1/token")
+            .match_query(Matcher::Regex("grant_type=pkce".to_owned()))
+            .with_status(500)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"code": 500, "msg": "unexpected failure"}"#)
+            .expect(1)
+            .create();
+        assert!(client
+            .exchange_code_for_session(&challenge, "the-code")
+            .await
+            .unwrap()
+            .is_err());
+        rejected.remove();
+
+        let _m1 = m
+            .mockito_server
+            .mock("POST", "/auth/v1/token")
+            .match_query(Matcher::Regex("grant_type=pkce".to_owned()))
+            .match_body(Matcher::PartialJsonString(format!(
+                r#"{{"auth_code": "the-code", "code_verifier": "{}"}}"#,
+                challenge.verifier()
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"access_token": "token", "refresh_token": "refresh", "provider_token": "gh-token", "provider_refresh_token": "gh-refresh", "provider_type": "github"}"#,
+            )
+            .expect(1)
+            .create();
+
+        // the failed exchange can be retried, from any client of the project
+        let session = client
+            .authenticated("other-token")
+            .unwrap()
+            .exchange_code_for_session(&challenge, "the-code")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            "token"
+        );
+        assert_eq!(
+            session.provider_token.unwrap().expose_secret().as_str(),
+            "gh-token"
+        );
+        assert_eq!(
+            session
+                .provider_refresh_token
+                .unwrap()
+                .expose_secret()
+                .as_str(),
+            "gh-refresh"
+        );
+        assert_eq!(
+            session.extra.get("provider_type"),
+            Some(&OwnedValue::from("github"))
+        );
+    }
+}
@@ -1,8 +1,9 @@
+use redact::Secret;
 use reqwest::Method;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::error::AuthError;
+use crate::error::{AuthApiError, AuthError};
 use crate::types;
 
 pub trait AuthModuleRequest {
@@ -27,7 +28,7 @@ pub struct HealthCheckRequest;
 
 impl AuthModuleRequest for HealthCheckRequest {
     type Res = String;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -41,6 +42,28 @@ impl AuthModuleRequest for HealthCheckRequest {
     }
 }
 
+/// JWKS Request
+#[derive(Debug, Clone)]
+pub struct JwksRequest;
+
+impl AuthModuleRequest for JwksRequest {
+    type Res = types::JwksResponse;
+    type Error = AuthApiError;
+    type Payload = ();
+
+    const METHOD: Method = Method::GET;
+
+    fn path(&self, base_url: &Url) -> Result<Url, AuthError> {
+        base_url
+            .join(".well-known/jwks.json")
+            .map_err(AuthError::from)
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        &()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum GrantType {
@@ -48,6 +71,7 @@ pub enum GrantType {
     RefreshToken,
     IdToken,
     Pkce,
+    Web3,
 }
 
 /// Token Request
@@ -59,7 +83,7 @@ pub struct TokenRequest {
 
 impl AuthModuleRequest for TokenRequest {
     type Res = types::AccessTokenResponseSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = types::TokenRequestBody;
 
     const METHOD: Method = Method::POST;
@@ -71,6 +95,7 @@ impl AuthModuleRequest for TokenRequest {
             GrantType::RefreshToken => "refresh_token",
             GrantType::IdToken => "id_token",
             GrantType::Pkce => "pkce",
+            GrantType::Web3 => "web3",
         };
         url.query_pairs_mut().append_pair("grant_type", grant_type);
         Ok(url)
@@ -81,23 +106,52 @@ impl AuthModuleRequest for TokenRequest {
     }
 }
 
+/// Which sessions a logout revokes.
+///
+/// Revoking a session also revokes its refresh tokens, so every [`JwtRefreshStream`] of a
+/// revoked session fails its next refresh. Streams with login credentials sign in again; others
+/// end once `max_reconnect_attempts` is exhausted.
+///
+/// [`JwtRefreshStream`]: crate::jwt_stream::JwtRefreshStream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogoutScope {
+    /// All sessions of the user
+    #[default]
+    Global,
+    /// Only the current session
+    Local,
+    /// All sessions except the current one
+    Others,
+}
+
+impl LogoutScope {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Global => "global",
+            Self::Local => "local",
+            Self::Others => "others",
+        }
+    }
+}
+
 /// Logout Request
 #[derive(Debug, Clone, typed_builder::TypedBuilder)]
 pub struct LogoutRequest {
-    pub scope: Option<String>,
+    pub scope: Option<LogoutScope>,
 }
 
 impl AuthModuleRequest for LogoutRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::POST;
 
     fn path(&self, base_url: &Url) -> Result<Url, AuthError> {
         let mut url = base_url.join("logout").map_err(AuthError::from)?;
-        if let Some(ref scope) = self.scope {
-            url.query_pairs_mut().append_pair("scope", scope);
+        if let Some(scope) = self.scope {
+            url.query_pairs_mut().append_pair("scope", scope.as_str());
         }
         Ok(url)
     }
@@ -117,7 +171,7 @@ pub struct VerifyGetRequest {
 
 impl AuthModuleRequest for VerifyGetRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -154,7 +208,7 @@ pub struct VerifyPostRequest {
 
 impl AuthModuleRequest for VerifyPostRequest {
     type Res = types::AccessTokenResponseSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -171,16 +225,18 @@ impl AuthModuleRequest for VerifyPostRequest {
 /// Authorize Request
 #[derive(Debug, Clone, typed_builder::TypedBuilder)]
 pub struct AuthorizeRequest {
-    pub provider: String,
+    pub provider: types::Provider,
     pub scopes: String,
     pub invite_token: Option<String>,
     pub redirect_to: Option<String>,
     pub code_challenge_method: Option<String>,
+    #[builder(default)]
+    pub code_challenge: Option<String>,
 }
 
 impl AuthModuleRequest for AuthorizeRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -188,7 +244,7 @@ impl AuthModuleRequest for AuthorizeRequest {
     fn path(&self, base_url: &Url) -> Result<Url, AuthError> {
         let mut url = base_url.join("authorize").map_err(AuthError::from)?;
         url.query_pairs_mut()
-            .append_pair("provider", &self.provider)
+            .append_pair("provider", self.provider.as_str())
             .append_pair("scopes", &self.scopes);
         if let Some(ref invite_token) = self.invite_token {
             url.query_pairs_mut()
@@ -202,6 +258,10 @@ impl AuthModuleRequest for AuthorizeRequest {
             url.query_pairs_mut()
                 .append_pair("code_challenge_method", code_challenge_method);
         }
+        if let Some(ref code_challenge) = self.code_challenge {
+            url.query_pairs_mut()
+                .append_pair("code_challenge", code_challenge);
+        }
         Ok(url)
     }
 
@@ -218,7 +278,7 @@ pub struct SignupRequest {
 
 impl AuthModuleRequest for SignupRequest {
     type Res = types::SignupResponse; // Could be AccessTokenResponseSchema or UserSchema
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = types::SignupPayload;
 
     const METHOD: Method = Method::POST;
@@ -243,7 +303,7 @@ pub struct RecoverRequest {
 
 impl AuthModuleRequest for RecoverRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -269,7 +329,7 @@ pub struct ResendRequest {
 
 impl AuthModuleRequest for ResendRequest {
     type Res = types::ResendResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -293,7 +353,7 @@ pub struct MagicLinkRequest {
 
 impl AuthModuleRequest for MagicLinkRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -322,7 +382,7 @@ pub struct OtpRequest {
 
 impl AuthModuleRequest for OtpRequest {
     type Res = types::OtpResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -342,7 +402,7 @@ pub struct UserGetRequest;
 
 impl AuthModuleRequest for UserGetRequest {
     type Res = types::UserSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -356,13 +416,89 @@ impl AuthModuleRequest for UserGetRequest {
     }
 }
 
+/// Link Identity Request
+///
+/// Starts linking an OAuth identity to the signed in user; returns the provider URL instead of
+/// redirecting to it.
+#[derive(Debug, Clone, typed_builder::TypedBuilder)]
+pub struct LinkIdentityRequest {
+    pub provider: types::Provider,
+    pub scopes: Option<String>,
+    pub redirect_to: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub code_challenge: Option<String>,
+}
+
+impl AuthModuleRequest for LinkIdentityRequest {
+    type Res = types::SsoResponse;
+    type Error = AuthApiError;
+    type Payload = ();
+
+    const METHOD: Method = Method::GET;
+
+    fn path(&self, base_url: &Url) -> Result<Url, AuthError> {
+        let mut url = base_url
+            .join("user/identities/authorize")
+            .map_err(AuthError::from)?;
+        url.query_pairs_mut()
+            .append_pair("provider", self.provider.as_str())
+            .append_pair("skip_http_redirect", "true");
+        if let Some(ref scopes) = self.scopes {
+            url.query_pairs_mut().append_pair("scopes", scopes);
+        }
+        if let Some(ref redirect_to) = self.redirect_to {
+            url.query_pairs_mut()
+                .append_pair("redirect_to", redirect_to);
+        }
+        if let Some(ref code_challenge_method) = self.code_challenge_method {
+            url.query_pairs_mut()
+                .append_pair("code_challenge_method", code_challenge_method);
+        }
+        if let Some(ref code_challenge) = self.code_challenge {
+            url.query_pairs_mut()
+                .append_pair("code_challenge", code_challenge);
+        }
+        Ok(url)
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        &()
+    }
+}
+
+/// Unlink Identity Request
+#[derive(Debug, Clone, typed_builder::TypedBuilder)]
+pub struct UnlinkIdentityRequest {
+    pub identity_id: String,
+}
+
+impl AuthModuleRequest for UnlinkIdentityRequest {
+    type Res = ();
+    type Error = AuthApiError;
+    type Payload = ();
+
+    const METHOD: Method = Method::DELETE;
+
+    fn path(&self, base_url: &Url) -> Result<Url, AuthError> {
+        let endpoint = format!("user/identities/{}", self.identity_id);
+        base_url.join(&endpoint).map_err(AuthError::from)
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        &()
+    }
+}
+
 /// User PUT Request
 #[derive(Debug, Clone, Serialize, typed_builder::TypedBuilder)]
 pub struct UserUpdateRequest {
     pub email: Option<String>,
     pub phone: Option<String>,
-    pub password: Option<String>,
-    pub nonce: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    pub password: Option<Secret<String>>,
+    /// The reauthentication nonce, required for password changes on some projects
+    #[serde(serialize_with = "redact::expose_secret")]
+    pub nonce: Option<Secret<String>>,
     pub data: Option<types::UserMetadata>,
     pub app_metadata: Option<types::AppMetadata>,
     pub channel: Option<String>,
@@ -370,7 +506,7 @@ pub struct UserUpdateRequest {
 
 impl AuthModuleRequest for UserUpdateRequest {
     type Res = types::UserSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::PUT;
@@ -390,7 +526,7 @@ pub struct ReauthenticateRequest;
 
 impl AuthModuleRequest for ReauthenticateRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::POST;
@@ -415,7 +551,7 @@ pub struct FactorsRequest {
 
 impl AuthModuleRequest for FactorsRequest {
     type Res = types::FactorsResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -434,11 +570,14 @@ impl AuthModuleRequest for FactorsRequest {
 pub struct FactorsChallengeRequest {
     pub factor_id: String,
     pub channel: Option<String>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webauthn: Option<types::WebAuthnParams>,
 }
 
 impl AuthModuleRequest for FactorsChallengeRequest {
     type Res = types::ChallengeResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -463,7 +602,32 @@ pub struct FactorsVerifyRequest {
 
 impl AuthModuleRequest for FactorsVerifyRequest {
     type Res = types::AccessTokenResponseSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
+    type Payload = Self;
+
+    const METHOD: Method = Method::POST;
+
+    fn path(&self, base_url: &Url) -> Result<Url, AuthError> {
+        let endpoint = format!("factors/{}/verify", self.factor_id);
+        base_url.join(&endpoint).map_err(AuthError::from)
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        self
+    }
+}
+
+/// Factors Verify Request for `WebAuthn` factors
+#[derive(Debug, Clone, Serialize, typed_builder::TypedBuilder)]
+pub struct FactorsWebAuthnVerifyRequest {
+    pub factor_id: String,
+    pub challenge_id: String,
+    pub webauthn: types::WebAuthnVerifyParams,
+}
+
+impl AuthModuleRequest for FactorsWebAuthnVerifyRequest {
+    type Res = types::AccessTokenResponseSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -486,7 +650,7 @@ pub struct FactorsDeleteRequest {
 
 impl AuthModuleRequest for FactorsDeleteRequest {
     type Res = types::FactorDeleteResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::DELETE;
@@ -507,7 +671,7 @@ pub struct CallbackGetRequest;
 
 impl AuthModuleRequest for CallbackGetRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -527,7 +691,7 @@ pub struct CallbackPostRequest;
 
 impl AuthModuleRequest for CallbackPostRequest {
     type Res = ();
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::POST;
@@ -555,7 +719,7 @@ pub struct SsoRequest {
 
 impl AuthModuleRequest for SsoRequest {
     type Res = types::SsoResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -579,7 +743,7 @@ pub struct SamlMetadataRequest {
 
 impl AuthModuleRequest for SamlMetadataRequest {
     type Res = String; // The response is XML content as a string
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -608,7 +772,7 @@ pub struct SamlAcsRequest {
 
 impl AuthModuleRequest for SamlAcsRequest {
     type Res = (); // The response is a redirect
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::POST;
@@ -642,7 +806,7 @@ pub struct InviteRequest {
 
 impl AuthModuleRequest for InviteRequest {
     type Res = types::UserSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -663,14 +827,15 @@ pub struct AdminGenerateLinkRequest {
     pub link_type: String,
     pub email: String,
     pub new_email: Option<String>,
-    pub password: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    pub password: Option<Secret<String>>,
     pub data: Option<types::UserMetadata>,
     pub redirect_to: Option<String>,
 }
 
 impl AuthModuleRequest for AdminGenerateLinkRequest {
     type Res = types::AdminGenerateLinkResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -695,7 +860,7 @@ pub struct AdminAuditRequest {
 
 impl AuthModuleRequest for AdminAuditRequest {
     type Res = Vec<types::AuditLogEntry>;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -726,7 +891,7 @@ pub struct AdminUsersRequest {
 
 impl AuthModuleRequest for AdminUsersRequest {
     type Res = types::AdminUsersResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -748,6 +913,28 @@ impl AuthModuleRequest for AdminUsersRequest {
     }
 }
 
+/// Admin User Create Request
+#[derive(Debug, Clone, typed_builder::TypedBuilder)]
+pub struct AdminUserCreateRequest {
+    pub user: types::AdminUserAttributes,
+}
+
+impl AuthModuleRequest for AdminUserCreateRequest {
+    type Res = types::UserSchema;
+    type Error = AuthApiError;
+    type Payload = types::AdminUserAttributes;
+
+    const METHOD: Method = Method::POST;
+
+    fn path(&self, base_url: &Url) -> Result<Url, AuthError> {
+        base_url.join("admin/users").map_err(AuthError::from)
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        &self.user
+    }
+}
+
 /// Admin User Get Request
 #[derive(Debug, Clone, typed_builder::TypedBuilder)]
 pub struct AdminUserGetRequest {
@@ -756,7 +943,7 @@ pub struct AdminUserGetRequest {
 
 impl AuthModuleRequest for AdminUserGetRequest {
     type Res = types::UserSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -780,7 +967,7 @@ pub struct AdminUserUpdateRequest {
 
 impl AuthModuleRequest for AdminUserUpdateRequest {
     type Res = types::UserSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = types::UserSchema;
 
     const METHOD: Method = Method::PUT;
@@ -803,7 +990,7 @@ pub struct AdminUserDeleteRequest {
 
 impl AuthModuleRequest for AdminUserDeleteRequest {
     type Res = types::UserSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::DELETE;
@@ -826,7 +1013,7 @@ pub struct AdminUserFactorsRequest {
 
 impl AuthModuleRequest for AdminUserFactorsRequest {
     type Res = Vec<types::MFAFactorSchema>;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -851,7 +1038,7 @@ pub struct AdminUserFactorUpdateRequest {
 
 impl AuthModuleRequest for AdminUserFactorUpdateRequest {
     type Res = types::MFAFactorSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = types::MFAFactorUpdateData;
 
     const METHOD: Method = Method::PUT;
@@ -875,7 +1062,7 @@ pub struct AdminUserFactorDeleteRequest {
 
 impl AuthModuleRequest for AdminUserFactorDeleteRequest {
     type Res = types::MFAFactorSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::DELETE;
@@ -896,7 +1083,7 @@ pub struct AdminSsoProvidersGetRequest;
 
 impl AuthModuleRequest for AdminSsoProvidersGetRequest {
     type Res = types::SsoProvidersResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -925,7 +1112,7 @@ pub struct AdminSsoProviderCreateRequest {
 
 impl AuthModuleRequest for AdminSsoProviderCreateRequest {
     type Res = types::SSOProviderSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::POST;
@@ -949,7 +1136,7 @@ pub struct AdminSsoProviderGetRequest {
 
 impl AuthModuleRequest for AdminSsoProviderGetRequest {
     type Res = types::SSOProviderSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -976,7 +1163,7 @@ pub struct AdminSsoProviderUpdateRequest {
 
 impl AuthModuleRequest for AdminSsoProviderUpdateRequest {
     type Res = types::SSOProviderSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = Self;
 
     const METHOD: Method = Method::PUT;
@@ -999,7 +1186,7 @@ pub struct AdminSsoProviderDeleteRequest {
 
 impl AuthModuleRequest for AdminSsoProviderDeleteRequest {
     type Res = types::SSOProviderSchema;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::DELETE;
@@ -1020,7 +1207,7 @@ pub struct SettingsRequest;
 
 impl AuthModuleRequest for SettingsRequest {
     type Res = types::SettingsResponse;
-    type Error = types::ErrorSchema;
+    type Error = AuthApiError;
     type Payload = ();
 
     const METHOD: Method = Method::GET;
@@ -0,0 +1,136 @@
+//! Resending confirmation messages through `POST /resend`.
+
+use thiserror::Error;
+
+use super::requests::ResendRequest;
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::types::ResendResponse;
+
+#[derive(Debug, Error)]
+pub enum ResendError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+}
+
+/// Which confirmation message [`ApiClient::resend`] sends again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResendType {
+    /// Signup confirmation email
+    Signup,
+    /// Signup confirmation SMS
+    Sms,
+    /// Confirmation of a pending email change
+    EmailChange,
+    /// Confirmation of a pending phone change
+    PhoneChange,
+}
+
+impl ResendType {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Signup => "signup",
+            Self::Sms => "sms",
+            Self::EmailChange => "email_change",
+            Self::PhoneChange => "phone_change",
+        }
+    }
+
+    /// Whether the message goes to an email address (otherwise to a phone number)
+    #[must_use]
+    pub const fn is_email(self) -> bool {
+        matches!(self, Self::Signup | Self::EmailChange)
+    }
+}
+
+impl ApiClient {
+    /// Sends the confirmation message of `resend_type` again to `contact`, which is an email
+    /// address or a phone number depending on [`ResendType::is_email`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected (e.g. rate limited).
+    pub async fn resend(
+        &self,
+        resend_type: ResendType,
+        contact: String,
+    ) -> Result<ResendResponse, ResendError> {
+        let (email, phone) = if resend_type.is_email() {
+            (Some(contact), None)
+        } else {
+            (None, Some(contact))
+        };
+        let request = ResendRequest::builder()
+            .email(email)
+            .phone(phone)
+            .resend_type(resend_type.as_str().to_owned())
+            .gotrue_meta_security(None)
+            .build();
+        Ok(self
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use test_log::test;
+
+    use super::*;
+
+    #[test(tokio::test)]
+    async fn test_resend_sms() {
+        let mut m = SupabaseMockServer::new().await;
+        let _resend = m
+            .mockito_server
+            .mock("POST", "/auth/v1/resend")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"type": "sms", "phone": "+15555550100", "email": null}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"message_id": "msg-1"}"#)
+            .create();
+
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key").unwrap();
+        let response = client
+            .resend(ResendType::Sms, "+15555550100".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(response.message_id.unwrap(), "msg-1");
+    }
+
+    #[test(tokio::test)]
+    async fn test_resend_signup_rate_limited() {
+        let mut m = SupabaseMockServer::new().await;
+        let _resend = m
+            .mockito_server
+            .mock("POST", "/auth/v1/resend")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"type": "signup", "email": "user@example.com"}"#.to_owned(),
+            ))
+            .with_status(429)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"code": 429, "error_code": "over_email_send_rate_limit"}"#)
+            .create();
+
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key").unwrap();
+        let err = client
+            .resend(ResendType::Signup, "user@example.com".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ResendError::Api(AuthApiError::RateLimited(_))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! SAML single sign-on flow.
+//!
+//! 1. [`SsoFlow::sign_in_with_sso`] asks `/sso` for the identity provider URL the user has to
+//!    visit, identified either by the email domain or the provider ID.
+//! 2. After the identity provider is done, GoTrue redirects to `redirect_to` with a `code` query
+//!    parameter.
+//! 3. [`SsoFlow::complete`] exchanges that code using PKCE and hands the session to a
+//!    [`JwtRefreshStream`].
+
+use thiserror::Error;
+
+use super::pkce::{PkceChallenge, PkceFlow, CODE_CHALLENGE_METHOD};
+use super::requests::SsoRequest;
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::jwt_stream::{JwtRefreshStream, JwtStream, SignInError, SupabaseAuthConfig};
+
+#[derive(Debug, Error)]
+pub enum SsoFlowError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    #[error(transparent)]
+    SignIn(#[from] SignInError),
+    #[error("Invalid identity provider URL: {0}")]
+    InvalidProviderUrl(#[from] url::ParseError),
+}
+
+/// Which SSO provider to sign in with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsoProvider {
+    /// The provider registered for this email domain (e.g. `company.com`)
+    Domain(String),
+    /// The ID of a registered SSO provider
+    ProviderId(String),
+}
+
+/// An in-progress SSO sign-in; keep it around until the redirect comes back.
+#[derive(Debug, Clone)]
+pub struct SsoFlow {
+    config: SupabaseAuthConfig,
+    client: ApiClient,
+    pkce: PkceFlow,
+}
+
+impl SsoFlow {
+    /// # Errors
+    ///
+    /// Returns an error if the supabase url cannot be joined with the auth suffix.
+    pub fn new(config: SupabaseAuthConfig) -> Result<Self, AuthError> {
+        let client = ApiClient::from_config(&config)?;
+        Ok(Self {
+            config,
+            pkce: PkceFlow::new(client.clone()),
+            client,
+        })
+    }
+
+    /// Returns the identity provider URL the user has to be sent to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no provider matches or the request fails.
+    pub async fn sign_in_with_sso(
+        &self,
+        provider: SsoProvider,
+        redirect_to: Option<String>,
+    ) -> Result<url::Url, SsoFlowError> {
+        let (domain, provider_id) = match provider {
+            SsoProvider::Domain(domain) => (Some(domain), None),
+            SsoProvider::ProviderId(provider_id) => (None, Some(provider_id)),
+        };
+        let request = SsoRequest::builder()
+            .domain(domain)
+            .provider_id(provider_id)
+            .redirect_to(redirect_to)
+            // return the URL instead of a 303 to it
+            .skip_http_redirect(Some(true))
+            .code_challenge(Some(self.challenge().challenge().to_owned()))
+            .code_challenge_method(Some(CODE_CHALLENGE_METHOD.to_owned()))
+            .gotrue_meta_security(None)
+            .build();
+        let response = self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        Ok(response.url.parse()?)
+    }
+
+    #[must_use]
+    pub const fn challenge(&self) -> &PkceChallenge {
+        self.pkce.challenge()
+    }
+
+    /// Exchanges the `code` from the redirect for a session and starts refreshing it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the code is rejected or the session cannot be refreshed.
+    pub async fn complete(&self, code: &str) -> Result<JwtRefreshStream, SsoFlowError> {
+        let session = self.pkce.exchange_code(code).await??;
+        let stream = JwtStream::new(self.config.clone()).from_session(session)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use futures::StreamExt as _;
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::{make_jwt, SupabaseMockServer};
+    use test_log::test;
+
+    use super::*;
+    use crate::jwt_stream::test_config;
+
+    #[test(tokio::test)]
+    async fn test_sso_flow() {
+        let mut m = SupabaseMockServer::new().await;
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(1),
+            ..test_config(m.server_url())
+        };
+        let flow = SsoFlow::new(config).unwrap();
+
+        let _sso = m
+            .mockito_server
+            .mock("POST", "/auth/v1/sso")
+            .match_body(Matcher::PartialJsonString(format!(
+                r#"{{"domain": "company.com", "skip_http_redirect": true, "code_challenge": "{}", "code_challenge_method": "s256"}}"#,
+                flow.challenge().challenge()
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"url": "https://idp.company.com/saml?SAMLRequest=abc"}"#)
+            .create();
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let _exchange = m
+            .mockito_server
+            .mock("POST", "/auth/v1/token")
+            .match_query(Matcher::Regex("grant_type=pkce".to_owned()))
+            .match_body(Matcher::PartialJsonString(format!(
+                r#"{{"auth_code": "the-code", "code_verifier": "{}"}}"#,
+                flow.challenge().verifier()
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"access_token": "{access_token}", "refresh_token": "sso-refresh", "expires_in": 3600}}"#
+            ))
+            .create();
+
+        let url = flow
+            .sign_in_with_sso(SsoProvider::Domain("company.com".to_owned()), None)
+            .await
+            .unwrap();
+        assert_eq!(url.host_str(), Some("idp.company.com"));
+
+        let mut stream = flow.complete("the-code").await.unwrap();
+        let session = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            session.access_token.unwrap().expose_secret().as_str(),
+            access_token
+        );
+    }
+}
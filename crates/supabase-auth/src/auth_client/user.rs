@@ -0,0 +1,487 @@
+//! Convenience wrappers around `PUT /user` for the signed in user.
+//!
+//! Projects with "secure password change" enabled reject password updates of sessions that were
+//! not recently authenticated. [`ApiClient::update_password`] detects this, requests a
+//! reauthentication nonce and returns [`UserUpdateError::ReauthenticationNeeded`]; the call is
+//! then repeated with the nonce the user received.
+//!
+//! [`ApiClient::start_password_change`] guides through the same steps upfront: it sends the nonce
+//! and returns a [`PasswordChange`] that submits the new password together with the nonce,
+//! telling a wrong or expired nonce apart from a password that is too weak.
+//!
+//! [`ApiClient::get_user`] asks the server for the user of the session, which (unlike decoding
+//! the JWT) also catches revoked sessions and deleted users.
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use redact::Secret;
+use reqwest::header;
+use thiserror::Error;
+use web_time::Instant;
+
+use super::requests::{ReauthenticateRequest, UserGetRequest, UserUpdateRequest};
+use super::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::types::{UserMetadata, UserSchema, WeakPasswordReason};
+
+#[derive(Debug, Error)]
+pub enum UserUpdateError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    #[error("Reauthentication required; a nonce was sent to the user")]
+    ReauthenticationNeeded,
+}
+
+#[derive(Debug, Error)]
+pub enum PasswordChangeError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(AuthApiError),
+    #[error("The reauthentication nonce is invalid or has expired")]
+    NonceInvalid,
+    #[error("Password too weak: {0:?}")]
+    WeakPassword(Vec<WeakPasswordReason>),
+}
+
+impl From<AuthApiError> for PasswordChangeError {
+    fn from(err: AuthApiError) -> Self {
+        match err {
+            AuthApiError::ReauthenticationNotValid(_) => Self::NonceInvalid,
+            AuthApiError::WeakPassword(schema) => Self::WeakPassword(
+                schema
+                    .weak_password
+                    .map(|weak_password| weak_password.reasons)
+                    .unwrap_or_default(),
+            ),
+            err => Self::Api(err),
+        }
+    }
+}
+
+/// A password change of the signed in user, waiting for the nonce that was sent to them.
+#[derive(Debug, Clone)]
+pub struct PasswordChange<'a> {
+    client: &'a ApiClient,
+}
+
+impl PasswordChange<'_> {
+    /// Changes the password, authorized by the `nonce` the user received.
+    ///
+    /// On [`PasswordChangeError::WeakPassword`] the nonce stays valid and can be submitted again
+    /// with a stronger password.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PasswordChangeError::NonceInvalid`] for a wrong or expired nonce (see
+    /// [`PasswordChange::resend_nonce`]), [`PasswordChangeError::WeakPassword`] if the password
+    /// does not meet the requirements, or an error if the request fails.
+    pub async fn complete(
+        &self,
+        nonce: String,
+        password: String,
+    ) -> Result<UserSchema, PasswordChangeError> {
+        let request = password_update_request(password, Some(nonce));
+        Ok(self
+            .client
+            .build_request(&request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+
+    /// Sends a new nonce, e.g. after the previous one expired.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn resend_nonce(&self) -> Result<(), UserUpdateError> {
+        self.client.reauthenticate().await
+    }
+}
+
+/// Users by the `Authorization` header they were fetched with
+#[derive(Debug, Clone)]
+pub(crate) struct UserCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<header::HeaderValue, (Instant, UserSchema)>>>,
+}
+
+impl UserCache {
+    fn get(&self, key: &header::HeaderValue) -> Option<UserSchema> {
+        let entries = self.entries.lock().ok()?;
+        let (fetched_at, ref user) = *entries.get(key)?;
+        (fetched_at.elapsed() < self.ttl).then(|| user.clone())
+    }
+
+    fn insert(&self, key: header::HeaderValue, user: UserSchema) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|_, &mut (fetched_at, _)| fetched_at.elapsed() < self.ttl);
+            entries.insert(key, (Instant::now(), user));
+        }
+    }
+}
+
+impl ApiClient {
+    /// Caches the users returned by [`ApiClient::get_user`] for `ttl`, per access token.
+    ///
+    /// The cache is shared with clones and clients derived through [`ApiClient::authenticated`].
+    /// A revoked session is only noticed once its entry expired, so keep `ttl` short.
+    #[must_use]
+    pub fn with_user_cache(mut self, ttl: Duration) -> Self {
+        self.user_cache = Some(UserCache {
+            ttl,
+            entries: Arc::default(),
+        });
+        self
+    }
+
+    /// Fetches the user of the session from the server, verifying that the access token is
+    /// still accepted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails; the inner error if the server rejects the token.
+    pub async fn get_user(&self) -> Result<Result<UserSchema, AuthApiError>, AuthError> {
+        let cache_key = self
+            .user_cache
+            .as_ref()
+            .zip(self.headers.get(header::AUTHORIZATION));
+        if let Some((cache, key)) = cache_key {
+            if let Some(user) = cache.get(key) {
+                return Ok(Ok(user));
+            }
+        }
+        let user = self
+            .build_request(&UserGetRequest)?
+            .execute()
+            .await?
+            .json()
+            .await?;
+        if let (Some((cache, key)), Ok(user)) = (cache_key, user.as_ref()) {
+            cache.insert(key.clone(), user.clone());
+        }
+        Ok(user)
+    }
+
+    /// Starts a password change of the signed in user by sending them a reauthentication nonce.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn start_password_change(&self) -> Result<PasswordChange<'_>, UserUpdateError> {
+        self.reauthenticate().await?;
+        Ok(PasswordChange { client: self })
+    }
+
+    /// Changes the password of the signed in user.
+    ///
+    /// Pass the `nonce` the user received after a previous
+    /// [`UserUpdateError::ReauthenticationNeeded`], otherwise `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`UserUpdateError::ReauthenticationNeeded`] if the server requires a nonce (which
+    /// has been sent to the user by then), or an error if the request fails.
+    pub async fn update_password(
+        &self,
+        password: String,
+        nonce: Option<String>,
+    ) -> Result<UserSchema, UserUpdateError> {
+        let has_nonce = nonce.is_some();
+        let request = password_update_request(password, nonce);
+        match self.update_user(&request).await {
+            Err(UserUpdateError::Api(AuthApiError::ReauthenticationNeeded(_))) if !has_nonce => {
+                self.reauthenticate().await?;
+                Err(UserUpdateError::ReauthenticationNeeded)
+            }
+            res => res,
+        }
+    }
+
+    /// Changes the email of the signed in user; depending on the project settings the change
+    /// only takes effect once the new (and old) address is confirmed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn update_email(&self, email: String) -> Result<UserSchema, UserUpdateError> {
+        let request = UserUpdateRequest::builder()
+            .email(Some(email))
+            .phone(None)
+            .password(None)
+            .nonce(None)
+            .data(None)
+            .app_metadata(None)
+            .channel(None)
+            .build();
+        self.update_user(&request).await
+    }
+
+    /// Merges `metadata` into the `user_metadata` of the signed in user.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn update_user_metadata(
+        &self,
+        metadata: UserMetadata,
+    ) -> Result<UserSchema, UserUpdateError> {
+        let request = UserUpdateRequest::builder()
+            .email(None)
+            .phone(None)
+            .password(None)
+            .nonce(None)
+            .data(Some(metadata))
+            .app_metadata(None)
+            .channel(None)
+            .build();
+        self.update_user(&request).await
+    }
+
+    /// Sends a reauthentication nonce to the email or phone of the signed in user.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or is rejected.
+    pub async fn reauthenticate(&self) -> Result<(), UserUpdateError> {
+        self.build_request(&ReauthenticateRequest)?
+            .execute()
+            .await?
+            .json_err()
+            .await??;
+        Ok(())
+    }
+
+    async fn update_user(
+        &self,
+        request: &UserUpdateRequest,
+    ) -> Result<UserSchema, UserUpdateError> {
+        Ok(self
+            .build_request(request)?
+            .execute()
+            .await?
+            .json()
+            .await??)
+    }
+}
+
+fn password_update_request(password: String, nonce: Option<String>) -> UserUpdateRequest {
+    UserUpdateRequest::builder()
+        .email(None)
+        .phone(None)
+        .password(Some(Secret::new(password)))
+        .nonce(nonce.map(Secret::new))
+        .data(None)
+        .app_metadata(None)
+        .channel(None)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use test_log::test;
+
+    use super::*;
+
+    #[test(tokio::test)]
+    async fn test_update_password_requests_nonce() {
+        let mut m = SupabaseMockServer::new().await;
+        let _rejected = m
+            .mockito_server
+            .mock("PUT", "/auth/v1/user")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"password": "new-secret", "nonce": null}"#.to_owned(),
+            ))
+            .with_status(400)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"code": 400, "error_code": "reauthentication_needed", "msg": "Password update requires reauthentication"}"#,
+            )
+            .create();
+        let reauthenticate = m
+            .mockito_server
+            .mock("POST", "/auth/v1/reauthenticate")
+            .match_header("authorization", "Bearer access-token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{}")
+            .expect(1)
+            .create();
+        let _accepted = m
+            .mockito_server
+            .mock("PUT", "/auth/v1/user")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"password": "new-secret", "nonce": "123456"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "user-id", "email": "user@example.com"}"#)
+            .create();
+
+        let client =
+            ApiClient::new_authenticated(m.server_url(), "api-key", "access-token").unwrap();
+        let err = client
+            .update_password("new-secret".to_owned(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserUpdateError::ReauthenticationNeeded));
+        reauthenticate.assert();
+
+        let user = client
+            .update_password("new-secret".to_owned(), Some("123456".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(user.id.unwrap(), "user-id");
+    }
+
+    #[test(tokio::test)]
+    async fn test_guided_password_change() {
+        let mut m = SupabaseMockServer::new().await;
+        let reauthenticate = m
+            .mockito_server
+            .mock("POST", "/auth/v1/reauthenticate")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{}")
+            .expect(2)
+            .create();
+        let _expired = m
+            .mockito_server
+            .mock("PUT", "/auth/v1/user")
+            .match_body(Matcher::PartialJsonString(r#"{"nonce": "111111"}"#.to_owned()))
+            .with_status(422)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"code": 422, "error_code": "reauthentication_not_valid", "msg": "Requires reauthentication"}"#,
+            )
+            .create();
+        let _weak = m
+            .mockito_server
+            .mock("PUT", "/auth/v1/user")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"password": "short", "nonce": "222222"}"#.to_owned(),
+            ))
+            .with_status(422)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"code": 422, "error_code": "weak_password", "msg": "Password is too weak", "weak_password": {"reasons": ["length"]}}"#,
+            )
+            .create();
+        let _accepted = m
+            .mockito_server
+            .mock("PUT", "/auth/v1/user")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"password": "a-much-longer-secret", "nonce": "222222"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "user-id"}"#)
+            .create();
+
+        let client =
+            ApiClient::new_authenticated(m.server_url(), "api-key", "access-token").unwrap();
+        let change = client.start_password_change().await.unwrap();
+        let err = change
+            .complete("111111".to_owned(), "a-much-longer-secret".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PasswordChangeError::NonceInvalid));
+
+        change.resend_nonce().await.unwrap();
+        let err = change
+            .complete("222222".to_owned(), "short".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PasswordChangeError::WeakPassword(ref reasons)
+                if matches!(reasons[..], [WeakPasswordReason::Length])
+        ));
+        let user = change
+            .complete("222222".to_owned(), "a-much-longer-secret".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(user.id.unwrap(), "user-id");
+        reauthenticate.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_update_user_metadata() {
+        let mut m = SupabaseMockServer::new().await;
+        let _update = m
+            .mockito_server
+            .mock("PUT", "/auth/v1/user")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"data": {"name": "New Name"}}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "user-id", "user_metadata": {"name": "New Name"}}"#)
+            .create();
+
+        let client =
+            ApiClient::new_authenticated(m.server_url(), "api-key", "access-token").unwrap();
+        let metadata = simd_json::json!({"name": "New Name"});
+        let user = client.update_user_metadata(metadata).await.unwrap();
+        assert_eq!(user.id.unwrap(), "user-id");
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_user_is_cached_per_token() {
+        let mut m = SupabaseMockServer::new().await;
+        let user = m
+            .mockito_server
+            .mock("GET", "/auth/v1/user")
+            .match_header("authorization", "Bearer access-token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"id": "user-id", "email": "user@example.com"}"#)
+            .expect(1)
+            .create();
+        let revoked = m
+            .mockito_server
+            .mock("GET", "/auth/v1/user")
+            .match_header("authorization", "Bearer revoked-token")
+            .with_status(403)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"code": 403, "error_code": "session_not_found", "msg": "Session not found"}"#,
+            )
+            .expect(2)
+            .create();
+
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key")
+            .unwrap()
+            .with_user_cache(Duration::from_secs(60));
+        for _ in 0..2 {
+            let fetched = client
+                .authenticated("access-token")
+                .unwrap()
+                .get_user()
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(fetched.id.unwrap(), "user-id");
+            // rejections are not cached
+            let err = client
+                .authenticated("revoked-token")
+                .unwrap()
+                .get_user()
+                .await
+                .unwrap()
+                .unwrap_err();
+            assert!(matches!(err, AuthApiError::SessionNotFound(_)));
+        }
+        user.assert();
+        revoked.assert();
+    }
+}
@@ -0,0 +1,144 @@
+//! Typed claims of Supabase access tokens.
+
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::types::{AccessTokenResponseSchema, AppMetadata, UserMetadata};
+
+#[derive(Debug, Error)]
+pub enum ClaimsError {
+    #[error("Token is not a JWT")]
+    MalformedToken,
+    #[error("Base64 decode error: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("JSON error: {0}")]
+    Json(#[from] simd_json::Error),
+}
+
+/// The `aud` claim, which the JWT spec allows to be either a string or a list of strings.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    #[must_use]
+    pub fn contains(&self, audience: &str) -> bool {
+        match *self {
+            Self::Single(ref single) => single == audience,
+            Self::Multiple(ref all) => all.iter().any(|item| item == audience),
+        }
+    }
+}
+
+/// An authentication method reference (`amr` claim entry).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AuthenticationMethod {
+    /// e.g. `password`, `otp`, `oauth`, `totp`
+    pub method: String,
+    /// Unix timestamp of when the method was used
+    pub timestamp: i64,
+}
+
+/// Claims carried by a Supabase access token.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccessTokenClaims {
+    /// The user id
+    pub sub: String,
+    /// The Postgres role used for requests, usually `authenticated` or `anon`
+    pub role: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub session_id: Option<String>,
+    /// Authenticator assurance level, `aal1` or `aal2`
+    pub aal: Option<String>,
+    #[serde(default)]
+    pub amr: Vec<AuthenticationMethod>,
+    pub aud: Option<Audience>,
+    pub iss: Option<String>,
+    pub exp: Option<i64>,
+    pub iat: Option<i64>,
+    pub is_anonymous: Option<bool>,
+    pub app_metadata: Option<AppMetadata>,
+    pub user_metadata: Option<UserMetadata>,
+}
+
+/// Decodes the claims of an access token **without verifying its signature**.
+///
+/// Only use this on tokens that come from a trusted source (e.g. the auth server response); use
+/// [`crate::verify::JwksVerifier`] for tokens received from clients.
+///
+/// # Errors
+///
+/// Returns an error if the token is not a JWT or its payload does not contain the expected claims.
+pub fn decode_claims(token: &str) -> Result<AccessTokenClaims, ClaimsError> {
+    let mut parts = token.split('.');
+    let (Some(_header), Some(payload), Some(_signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ClaimsError::MalformedToken);
+    };
+    let mut payload = BASE64_URL_SAFE_NO_PAD.decode(payload.trim_end_matches('='))?;
+    Ok(simd_json::from_slice(&mut payload)?)
+}
+
+impl AccessTokenResponseSchema {
+    /// Decodes the claims of the contained `access_token`, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the access token cannot be decoded.
+    pub fn claims(&self) -> Result<Option<AccessTokenClaims>, ClaimsError> {
+        self.access_token
+            .as_ref()
+            .map(|token| decode_claims(token.expose_secret()))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_claims() {
+        let payload = r#"{
+            "aud": "authenticated",
+            "exp": 1732000000,
+            "iat": 1731996400,
+            "iss": "http://127.0.0.1:54321/auth/v1",
+            "sub": "6f2c8a8e-5d1b-4c4a-9d43-0e6a8a3c7f11",
+            "email": "user@example.com",
+            "phone": "",
+            "app_metadata": {"provider": "email", "providers": ["email"]},
+            "user_metadata": {"name": "User"},
+            "role": "authenticated",
+            "aal": "aal1",
+            "amr": [{"method": "password", "timestamp": 1731996400}],
+            "session_id": "3b5e1f0c-2a55-4d8e-9a1b-7c2f9e4d6a10",
+            "is_anonymous": false
+        }"#;
+        let token = format!(
+            "{}.{}.signature",
+            BASE64_URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+            BASE64_URL_SAFE_NO_PAD.encode(payload)
+        );
+
+        let claims = decode_claims(&token).unwrap();
+        assert_eq!(claims.sub, "6f2c8a8e-5d1b-4c4a-9d43-0e6a8a3c7f11");
+        assert_eq!(claims.role.as_deref(), Some("authenticated"));
+        assert_eq!(claims.aal.as_deref(), Some("aal1"));
+        assert_eq!(claims.amr[0].method, "password");
+        assert!(claims.aud.unwrap().contains("authenticated"));
+
+        assert!(matches!(
+            decode_claims("not-a-jwt"),
+            Err(ClaimsError::MalformedToken)
+        ));
+    }
+}
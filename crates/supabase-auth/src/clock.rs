@@ -0,0 +1,132 @@
+//! The time source of [`JwtRefreshStream`]s.
+//!
+//! Refreshes are scheduled through a [`Clock`], so tests can swap the [`SystemClock`] for a
+//! [`ManualClock`] and advance time deterministically instead of waiting for real tokens to
+//! expire:
+//!
+//! ```no_run
+//! # async fn run(config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig) {
+//! use std::sync::Arc;
+//!
+//! use rp_supabase_auth::clock::ManualClock;
+//! use rp_supabase_auth::jwt_stream::JwtStream;
+//!
+//! let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+//! let stream = JwtStream::new(config).with_clock(clock.clone());
+//! // ... sign in and receive the first session, then trigger the refresh:
+//! clock.advance(core::time::Duration::from_secs(3600));
+//! # }
+//! ```
+//!
+//! [`JwtRefreshStream`]: crate::jwt_stream::JwtRefreshStream
+
+use core::time::Duration;
+use std::sync::{Mutex, PoisonError};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::oneshot;
+
+use crate::runtime;
+
+/// Future returned by [`Clock::sleep`]
+#[cfg(not(target_arch = "wasm32"))]
+pub type SleepFuture = futures::future::BoxFuture<'static, ()>;
+/// Future returned by [`Clock::sleep`]
+#[cfg(target_arch = "wasm32")]
+pub type SleepFuture = futures::future::LocalBoxFuture<'static, ()>;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Completes once `duration` has passed on this clock.
+    ///
+    /// The deadline is taken when this is called, not when the future is first polled.
+    fn sleep(&self, duration: Duration) -> SleepFuture;
+}
+
+/// The real time, using the timers of the async runtime
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        runtime::boxed(runtime::sleep(duration))
+    }
+}
+
+/// A clock that only moves through [`ManualClock::advance`], for tests.
+#[derive(Debug)]
+pub struct ManualClock {
+    state: Mutex<ManualClockState>,
+}
+
+#[derive(Debug)]
+struct ManualClockState {
+    now: DateTime<Utc>,
+    sleepers: Vec<(DateTime<Utc>, oneshot::Sender<()>)>,
+}
+
+impl ManualClock {
+    #[must_use]
+    pub const fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            state: Mutex::new(ManualClockState {
+                now,
+                sleepers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Moves the clock forward, completing all sleeps that are due by then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.now = add(state.now, duration);
+        let now = state.now;
+        let (due, pending) = state
+            .sleepers
+            .drain(..)
+            .partition::<Vec<_>, _>(|&(deadline, _)| deadline <= now);
+        state.sleepers = pending;
+        drop(state);
+        for (_, sleeper) in due {
+            // the sleep may have been dropped already
+            let _ignored = sleeper.send(());
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .now
+    }
+
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let deadline = add(state.now, duration);
+        if deadline <= state.now {
+            return runtime::boxed(async {});
+        }
+        let (sender, receiver) = oneshot::channel();
+        state.sleepers.push((deadline, sender));
+        runtime::boxed(async move {
+            if receiver.await.is_err() {
+                // the clock was dropped, so time never moves on
+                futures::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+fn add(time: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| time.checked_add_signed(duration))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
@@ -0,0 +1,337 @@
+//! Auth cookies compatible with `@supabase/ssr`, for server-side rendered backends that share
+//! the session with supabase-js in the browser.
+//!
+//! The session is stored as `base64-` followed by the base64url encoded JSON of the session, in a
+//! cookie named after [`storage_key`]. Values longer than [`MAX_CHUNK_SIZE`] are split into
+//! `<key>.0`, `<key>.1`, ... cookies.
+//!
+//! On every request, read the session with [`session_from_cookies`], pass it through
+//! [`refresh_session`] and, if it was refreshed, send the cookies of the new session back.
+
+use base64::prelude::*;
+use chrono::Utc;
+use thiserror::Error;
+
+use crate::auth_client::requests::{GrantType, TokenRequest};
+use crate::auth_client::ApiClient;
+use crate::error::{AuthApiError, AuthError};
+use crate::types::{AccessTokenResponseSchema, TokenRequestBody};
+
+/// Longest cookie value written by `@supabase/ssr` before it splits the value into chunks
+pub const MAX_CHUNK_SIZE: usize = 3180;
+
+/// Marks a base64url encoded cookie value
+pub const BASE64_PREFIX: &str = "base64-";
+
+/// Sessions expiring within this many seconds are refreshed
+const EXPIRY_MARGIN_SECS: i64 = 10;
+
+/// `Max-Age` used by `@supabase/ssr` (400 days, the maximum browsers accept)
+const COOKIE_MAX_AGE_SECS: u64 = 400 * 24 * 60 * 60;
+
+#[derive(Debug, Error)]
+pub enum CookieError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    #[error("Invalid session JSON: {0}")]
+    Json(#[from] simd_json::Error),
+    #[error("Invalid base64 cookie value: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("The session does not contain a refresh token")]
+    MissingRefreshToken,
+}
+
+/// A cookie to set on the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthCookie {
+    pub name: String,
+    pub value: String,
+}
+
+impl AuthCookie {
+    /// A `Set-Cookie` header value with the attributes `@supabase/ssr` uses.
+    ///
+    /// The cookie is readable from JavaScript, since supabase-js needs it in the browser.
+    #[must_use]
+    pub fn to_set_cookie(&self) -> String {
+        format!(
+            "{}={}; Path=/; SameSite=Lax; Max-Age={COOKIE_MAX_AGE_SECS}",
+            self.name, self.value
+        )
+    }
+}
+
+/// The cookie name used by supabase-js for the project at `supabase_url`: `sb-<ref>-auth-token`,
+/// where `<ref>` is the first label of the host.
+#[must_use]
+pub fn storage_key(supabase_url: &url::Url) -> String {
+    let project_ref = supabase_url
+        .host_str()
+        .and_then(|host| host.split('.').next())
+        .unwrap_or_default();
+    format!("sb-{project_ref}-auth-token")
+}
+
+/// Encodes `session` into one cookie, or several chunks if it is too long.
+///
+/// `expires_at` is filled in from `expires_in` if missing, as supabase-js relies on it. Chunks
+/// left over from a previous, longer session should be removed by the caller.
+///
+/// # Errors
+///
+/// Returns an error if the session cannot be serialized.
+pub fn session_to_cookies(
+    storage_key: &str,
+    session: &AccessTokenResponseSchema,
+) -> Result<Vec<AuthCookie>, CookieError> {
+    let mut session = session.clone();
+    if session.expires_at.is_none() {
+        session.expires_at = session
+            .expires_in
+            .map(|expires_in| Utc::now().timestamp().saturating_add(expires_in));
+    }
+    let json = simd_json::to_vec(&session)?;
+    let value = format!("{BASE64_PREFIX}{}", BASE64_URL_SAFE_NO_PAD.encode(json));
+    if value.len() <= MAX_CHUNK_SIZE {
+        return Ok(vec![AuthCookie {
+            name: storage_key.to_owned(),
+            value,
+        }]);
+    }
+    // the value is ASCII, so splitting by bytes is fine
+    Ok(value
+        .as_bytes()
+        .chunks(MAX_CHUNK_SIZE)
+        .enumerate()
+        .map(|(idx, chunk)| AuthCookie {
+            name: format!("{storage_key}.{idx}"),
+            value: String::from_utf8_lossy(chunk).into_owned(),
+        })
+        .collect())
+}
+
+/// Reads the session from the request cookies (name and value pairs), joining chunks.
+///
+/// Returns `None` if there is no auth cookie.
+///
+/// # Errors
+///
+/// Returns an error if the cookie does not contain a valid session.
+pub fn session_from_cookies<'a>(
+    storage_key: &str,
+    cookies: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<Option<AccessTokenResponseSchema>, CookieError> {
+    let mut whole = None;
+    let mut chunks = Vec::new();
+    for (name, value) in cookies {
+        let Some(suffix) = name.strip_prefix(storage_key) else {
+            continue;
+        };
+        if suffix.is_empty() {
+            whole = Some(value);
+        } else if let Some(idx) = suffix
+            .strip_prefix('.')
+            .and_then(|idx| idx.parse::<usize>().ok())
+        {
+            chunks.push((idx, value));
+        }
+    }
+    let value = match whole {
+        Some(value) => value.to_owned(),
+        None if chunks.is_empty() => return Ok(None),
+        None => {
+            chunks.sort_unstable_by_key(|&(idx, _)| idx);
+            // stop at the first gap, like supabase-js
+            chunks
+                .iter()
+                .enumerate()
+                .take_while(|&(expected, &(idx, _))| expected == idx)
+                .map(|(_, &(_, chunk))| chunk)
+                .collect()
+        }
+    };
+    let mut json = match value.strip_prefix(BASE64_PREFIX) {
+        Some(encoded) => BASE64_URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('='))?,
+        // older versions store the JSON URI encoded
+        None => percent_encoding::percent_decode_str(&value).collect(),
+    };
+    Ok(Some(simd_json::from_slice(&mut json)?))
+}
+
+/// Refreshes `session` if it expires within the next seconds.
+///
+/// Returns `None` if the session is still valid, otherwise the new session whose cookies have to
+/// be sent back to the browser.
+///
+/// # Errors
+///
+/// Returns an error if the refresh fails or the refresh token was rejected; the cookies should
+/// then be cleared.
+pub async fn refresh_session(
+    client: &ApiClient,
+    session: &AccessTokenResponseSchema,
+) -> Result<Option<AccessTokenResponseSchema>, CookieError> {
+    let expires_soon = session
+        .expires_at
+        .is_none_or(|expires_at| expires_at - EXPIRY_MARGIN_SECS <= Utc::now().timestamp());
+    if !expires_soon {
+        return Ok(None);
+    }
+    let refresh_token = session
+        .refresh_token
+        .as_ref()
+        .ok_or(CookieError::MissingRefreshToken)?;
+    let request = TokenRequest::builder()
+        .grant_type(GrantType::RefreshToken)
+        .payload(
+            TokenRequestBody::builder()
+                .refresh_token(refresh_token.expose_secret().clone())
+                .build(),
+        )
+        .build();
+    let session = client
+        .build_request(&request)?
+        .execute()
+        .await?
+        .json()
+        .await??;
+    Ok(Some(session))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use test_log::test;
+
+    use super::*;
+    use crate::types::UserSchema;
+
+    fn session(expires_at: i64) -> AccessTokenResponseSchema {
+        AccessTokenResponseSchema::builder()
+            .access_token("access".to_owned())
+            .refresh_token("refresh".to_owned())
+            .expires_in(3600)
+            .expires_at(expires_at)
+            .build()
+    }
+
+    #[test]
+    fn test_storage_key() {
+        assert_eq!(
+            storage_key(&"https://abcdefgh.supabase.co".parse().unwrap()),
+            "sb-abcdefgh-auth-token"
+        );
+        assert_eq!(
+            storage_key(&"http://127.0.0.1:54321".parse().unwrap()),
+            "sb-127-auth-token"
+        );
+    }
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let cookies = session_to_cookies("sb-ref-auth-token", &session(1_700_000_000)).unwrap();
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies[0].name, "sb-ref-auth-token");
+        assert!(cookies[0].value.starts_with(BASE64_PREFIX));
+
+        let parsed = session_from_cookies(
+            "sb-ref-auth-token",
+            cookies
+                .iter()
+                .map(|cookie| (cookie.name.as_str(), cookie.value.as_str())),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(parsed.access_token.unwrap().expose_secret(), "access");
+        assert_eq!(parsed.expires_at, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn test_large_sessions_are_chunked() {
+        let mut session = session(1_700_000_000);
+        session.user = Some(
+            UserSchema::builder()
+                .user_metadata(simd_json::json!({"bio": "x".repeat(5_000)}))
+                .build(),
+        );
+        let cookies = session_to_cookies("sb-ref-auth-token", &session).unwrap();
+        assert_eq!(
+            cookies
+                .iter()
+                .map(|cookie| cookie.name.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "sb-ref-auth-token.0",
+                "sb-ref-auth-token.1",
+                "sb-ref-auth-token.2"
+            ]
+        );
+
+        // chunks may arrive in any order, next to unrelated cookies
+        let mut request_cookies = cookies
+            .iter()
+            .rev()
+            .map(|cookie| (cookie.name.as_str(), cookie.value.as_str()))
+            .collect::<Vec<_>>();
+        request_cookies.push(("theme", "dark"));
+        let parsed = session_from_cookies("sb-ref-auth-token", request_cookies)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            parsed.user.unwrap().user_metadata.unwrap()["bio"],
+            simd_json::OwnedValue::from("x".repeat(5_000))
+        );
+    }
+
+    #[test]
+    fn test_uri_encoded_json_cookie() {
+        let parsed = session_from_cookies(
+            "sb-ref-auth-token",
+            [(
+                "sb-ref-auth-token",
+                "%7B%22access_token%22%3A%22access%22%7D",
+            )],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(parsed.access_token.unwrap().expose_secret(), "access");
+        assert!(
+            session_from_cookies("sb-ref-auth-token", [("theme", "dark")])
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_refresh_expired_session() {
+        let mut m = SupabaseMockServer::new().await;
+        let _refresh = m
+            .mockito_server
+            .mock("POST", "/auth/v1/token")
+            .match_query(mockito::Matcher::Regex(
+                "grant_type=refresh_token".to_owned(),
+            ))
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"refresh_token": "refresh"}"#.to_owned(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"access_token": "new-access", "refresh_token": "new-refresh", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+        let client = ApiClient::new_unauthenticated(m.server_url(), "api-key").unwrap();
+
+        let valid = session(Utc::now().timestamp() + 3600);
+        assert!(refresh_session(&client, &valid).await.unwrap().is_none());
+
+        let expired = session(Utc::now().timestamp() - 1);
+        let refreshed = refresh_session(&client, &expired).await.unwrap().unwrap();
+        assert_eq!(
+            refreshed.access_token.unwrap().expose_secret(),
+            "new-access"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use reqwest::header::InvalidHeaderValue;
 
+use crate::types::ErrorSchema;
+
 #[derive(thiserror::Error, Debug)]
 pub enum AuthError {
     #[error("Reqwest error {0}")]
@@ -10,4 +12,207 @@ pub enum AuthError {
     Json(#[from] simd_json::Error),
     #[error("Invalid header value {0}")]
     InvalidHeaderValue(#[from] InvalidHeaderValue),
+    #[error("Gateway error {status}: {body_snippet}")]
+    GatewayError {
+        status: reqwest::StatusCode,
+        body_snippet: String,
+    },
+    #[error("No access token available; the refresh stream has stopped")]
+    TokenUnavailable,
+}
+
+/// An error response of the auth server, classified by its error code.
+///
+/// Codes without a dedicated variant end up in [`AuthApiError::Other`]; the raw
+/// [`ErrorSchema`] is always available through [`AuthApiError::schema`]. It is boxed so the
+/// results carrying this error stay small.
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum AuthApiError {
+    /// Wrong email/phone and password, or an invalid, expired or reused refresh token
+    #[error("Invalid grant: {0}")]
+    InvalidGrant(Box<ErrorSchema>),
+    #[error("User already exists: {0}")]
+    UserAlreadyExists(Box<ErrorSchema>),
+    #[error("OTP expired or invalid: {0}")]
+    OtpExpired(Box<ErrorSchema>),
+    #[error("Rate limit exceeded: {0}")]
+    RateLimited(Box<ErrorSchema>),
+    /// Inspect [`ErrorSchema::weak_password`] for the reasons
+    #[error("Password too weak: {0}")]
+    WeakPassword(Box<ErrorSchema>),
+    #[error("Email not confirmed: {0}")]
+    EmailNotConfirmed(Box<ErrorSchema>),
+    /// The session was revoked or has expired; the user has to sign in again
+    #[error("Session not found: {0}")]
+    SessionNotFound(Box<ErrorSchema>),
+    /// The operation needs a nonce from `/reauthenticate`
+    #[error("Reauthentication needed: {0}")]
+    ReauthenticationNeeded(Box<ErrorSchema>),
+    /// The reauthentication nonce is wrong or has expired
+    #[error("Reauthentication not valid: {0}")]
+    ReauthenticationNotValid(Box<ErrorSchema>),
+    #[error("Supabase API error: {0}")]
+    Other(Box<ErrorSchema>),
+}
+
+impl AuthApiError {
+    #[must_use]
+    pub const fn schema(&self) -> &ErrorSchema {
+        match *self {
+            Self::InvalidGrant(ref schema) |
+            Self::UserAlreadyExists(ref schema) |
+            Self::OtpExpired(ref schema) |
+            Self::RateLimited(ref schema) |
+            Self::WeakPassword(ref schema) |
+            Self::EmailNotConfirmed(ref schema) |
+            Self::SessionNotFound(ref schema) |
+            Self::ReauthenticationNeeded(ref schema) |
+            Self::ReauthenticationNotValid(ref schema) |
+            Self::Other(ref schema) => schema,
+        }
+    }
+
+    #[must_use]
+    pub fn into_schema(self) -> ErrorSchema {
+        match self {
+            Self::InvalidGrant(schema) |
+            Self::UserAlreadyExists(schema) |
+            Self::OtpExpired(schema) |
+            Self::RateLimited(schema) |
+            Self::WeakPassword(schema) |
+            Self::EmailNotConfirmed(schema) |
+            Self::SessionNotFound(schema) |
+            Self::ReauthenticationNeeded(schema) |
+            Self::ReauthenticationNotValid(schema) |
+            Self::Other(schema) => *schema,
+        }
+    }
+}
+
+impl From<ErrorSchema> for AuthApiError {
+    fn from(schema: ErrorSchema) -> Self {
+        // newer servers send `error_code`, older ones the OAuth style `error`
+        let code = schema.error_code.as_deref().or(schema.error.as_deref());
+        let classify: fn(Box<ErrorSchema>) -> Self = match code {
+            Some(
+                "invalid_grant" |
+                "invalid_credentials" |
+                "refresh_token_not_found" |
+                "refresh_token_already_used",
+            ) => Self::InvalidGrant,
+            Some("user_already_exists" | "email_exists" | "phone_exists") => {
+                Self::UserAlreadyExists
+            }
+            Some("otp_expired") => Self::OtpExpired,
+            Some(
+                "over_request_rate_limit" |
+                "over_email_send_rate_limit" |
+                "over_sms_send_rate_limit",
+            ) => Self::RateLimited,
+            Some("weak_password") => Self::WeakPassword,
+            Some("email_not_confirmed") => Self::EmailNotConfirmed,
+            Some("session_not_found" | "session_expired") => Self::SessionNotFound,
+            Some("reauthentication_needed") => Self::ReauthenticationNeeded,
+            Some("reauthentication_not_valid") => Self::ReauthenticationNotValid,
+            _ if schema.weak_password.is_some() => Self::WeakPassword,
+            _ if schema.code == Some(429) => Self::RateLimited,
+            _ => Self::Other,
+        };
+        classify(Box::new(schema))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for AuthApiError {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        ErrorSchema::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// Maximum number of characters of a non-JSON body that get surfaced in errors
+const BODY_SNIPPET_LEN: usize = 512;
+
+/// Detects bodies that are not JSON (e.g. HTML error pages rendered by a reverse proxy on
+/// 502/504) and returns a trimmed snippet of the body text for them.
+///
+/// The `Content-Type` header is trusted when present; otherwise the first non-whitespace byte of
+/// the body is inspected.
+#[must_use]
+pub fn non_json_body_snippet(content_type: Option<&str>, body: &[u8]) -> Option<String> {
+    let is_json = content_type.map_or_else(
+        || {
+            body.iter()
+                .find(|byte| !byte.is_ascii_whitespace())
+                .is_some_and(|byte| matches!(*byte, b'{' | b'['))
+        },
+        |content_type| content_type.to_ascii_lowercase().contains("json"),
+    );
+    if is_json {
+        return None;
+    }
+    let text = String::from_utf8_lossy(body);
+    Some(text.trim().chars().take(BODY_SNIPPET_LEN).collect())
+}
+
+/// Whether an error response without a body comes from the API rather than a gateway, so it
+/// should be classified by its `status`.
+///
+/// Only `502`, `503` and `504` without a body are left to [`non_json_body_snippet`], as those are
+/// what proxies answer with when the service behind them is down.
+#[must_use]
+pub fn is_empty_api_error(status: u16, body: &[u8]) -> bool {
+    body.trim_ascii().is_empty() && !matches!(status, 502..=504)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> AuthApiError {
+        simd_json::from_slice(body.to_owned().into_bytes().as_mut_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_auth_api_error_classification() {
+        assert!(matches!(
+            parse(
+                r#"{"error": "invalid_grant", "error_description": "Invalid login credentials"}"#
+            ),
+            AuthApiError::InvalidGrant(_)
+        ));
+        assert!(matches!(
+            parse(
+                r#"{"code": 422, "error_code": "user_already_exists", "msg": "User already registered"}"#
+            ),
+            AuthApiError::UserAlreadyExists(_)
+        ));
+        assert!(matches!(
+            parse(
+                r#"{"code": 403, "error_code": "otp_expired", "msg": "Token has expired or is invalid"}"#
+            ),
+            AuthApiError::OtpExpired(_)
+        ));
+        assert!(matches!(
+            parse(r#"{"code": 429, "msg": "Too many requests"}"#),
+            AuthApiError::RateLimited(_)
+        ));
+        assert!(matches!(
+            parse(
+                r#"{"code": 422, "msg": "Password is too weak", "weak_password": {"reasons": ["length"]}}"#
+            ),
+            AuthApiError::WeakPassword(_)
+        ));
+        assert!(matches!(
+            parse(
+                r#"{"code": 422, "error_code": "reauthentication_not_valid", "msg": "Requires reauthentication"}"#
+            ),
+            AuthApiError::ReauthenticationNotValid(_)
+        ));
+
+        let other = parse(r#"{"code": 500, "error_code": "unexpected_failure", "msg": "boom"}"#);
+        assert!(matches!(other, AuthApiError::Other(_)));
+        assert_eq!(other.schema().msg.as_deref(), Some("boom"));
+    }
 }
@@ -0,0 +1,312 @@
+//! Server-side helpers for implementing Supabase auth hooks as HTTP endpoints.
+//!
+//! The auth server signs hook requests in the [Standard Webhooks](https://www.standardwebhooks.com)
+//! format. [`HookVerifier::verify_payload`] checks the `webhook-id`, `webhook-timestamp` and
+//! `webhook-signature` headers against the hook secret (`v1,whsec_...`) and deserializes the body
+//! into one of the typed payloads of this module:
+//!
+//! ```no_run
+//! # fn handle(headers: &reqwest::header::HeaderMap, body: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+//! use rp_supabase_auth::hooks::{HookVerifier, SendEmailInput};
+//!
+//! let verifier = HookVerifier::new("v1,whsec_c2VjcmV0")?;
+//! let input: SendEmailInput = verifier.verify_payload(headers, body)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use core::time::Duration;
+
+use base64::prelude::*;
+use chrono::Utc;
+use redact::Secret;
+use reqwest::header::HeaderMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use simd_json::OwnedValue;
+use thiserror::Error;
+use typed_builder::TypedBuilder;
+
+use crate::types::UserSchema;
+
+pub const WEBHOOK_ID: &str = "webhook-id";
+pub const WEBHOOK_TIMESTAMP: &str = "webhook-timestamp";
+pub const WEBHOOK_SIGNATURE: &str = "webhook-signature";
+
+/// How far the `webhook-timestamp` may be off, to limit replays of captured requests
+const DEFAULT_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("The hook secret is not valid base64: {0}")]
+    InvalidSecret(#[from] base64::DecodeError),
+    #[error("Missing or non-UTF-8 header {0}")]
+    MissingHeader(&'static str),
+    #[error("Invalid webhook timestamp")]
+    InvalidTimestamp,
+    #[error("Webhook timestamp is outside of the tolerance")]
+    TimestampOutOfTolerance,
+    #[error("No matching webhook signature")]
+    InvalidSignature,
+    #[error("Invalid hook payload: {0}")]
+    Json(#[from] simd_json::Error),
+}
+
+/// Verifies the signatures of auth hook requests.
+#[derive(Debug, Clone)]
+pub struct HookVerifier {
+    key: Secret<Vec<u8>>,
+    tolerance: Duration,
+}
+
+impl HookVerifier {
+    /// Accepts the secret as shown in the dashboard (`v1,whsec_<base64>`), with or without the
+    /// `v1,` and `whsec_` prefixes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the secret is not valid base64.
+    pub fn new(secret: &str) -> Result<Self, HookError> {
+        let secret = secret.strip_prefix("v1,").unwrap_or(secret);
+        let secret = secret.strip_prefix("whsec_").unwrap_or(secret);
+        Ok(Self {
+            key: Secret::new(BASE64_STANDARD.decode(secret)?),
+            tolerance: DEFAULT_TOLERANCE,
+        })
+    }
+
+    #[must_use]
+    pub const fn with_tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Checks that `body` was signed with the hook secret and that the request is recent.
+    ///
+    /// `body` must be the raw request body, exactly as received.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a header is missing, the timestamp is outside of the tolerance or no
+    /// signature matches.
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), HookError> {
+        let header = |name: &'static str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(HookError::MissingHeader(name))
+        };
+        let id = header(WEBHOOK_ID)?;
+        let timestamp = header(WEBHOOK_TIMESTAMP)?;
+        let signatures = header(WEBHOOK_SIGNATURE)?;
+
+        let sent_at = timestamp
+            .parse::<i64>()
+            .map_err(|_err| HookError::InvalidTimestamp)?;
+        let drift = Utc::now().timestamp().abs_diff(sent_at);
+        if drift > self.tolerance.as_secs() {
+            return Err(HookError::TimestampOutOfTolerance);
+        }
+
+        let mut hmac = hmac_sha256::HMAC::new(self.key.expose_secret());
+        hmac.update(id.as_bytes());
+        hmac.update(b".");
+        hmac.update(timestamp.as_bytes());
+        hmac.update(b".");
+        hmac.update(body);
+        let expected = hmac.finalize();
+
+        // Several space separated signatures are sent while the secret is being rotated
+        let matches = signatures
+            .split(' ')
+            .filter_map(|signature| signature.strip_prefix("v1,"))
+            .filter_map(|signature| BASE64_STANDARD.decode(signature).ok())
+            .any(|signature| constant_time_eq(&signature, &expected));
+        if !matches {
+            return Err(HookError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// Verifies the request like [`HookVerifier::verify`] and deserializes the body.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the verification fails or the body is not a valid `T`.
+    pub fn verify_payload<T: DeserializeOwned>(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<T, HookError> {
+        self.verify(headers, body)?;
+        Ok(simd_json::from_slice(&mut body.to_vec())?)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Input of the password verification attempt hook.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordVerificationAttemptInput {
+    pub user_id: String,
+    /// Whether the password was correct
+    pub valid: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookDecision {
+    Continue,
+    Reject,
+}
+
+/// Output of the password verification attempt hook.
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct PasswordVerificationAttemptOutput {
+    pub decision: HookDecision,
+    /// Shown to the user when the attempt is rejected
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Signs the user out of all sessions
+    #[builder(default)]
+    pub should_logout_user: bool,
+}
+
+/// Input of the custom access token hook.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CustomAccessTokenInput {
+    pub user_id: String,
+    /// The claims the access token would be issued with
+    pub claims: OwnedValue,
+    /// e.g. `password`, `otp` or `oauth`
+    pub authentication_method: String,
+}
+
+/// Output of the custom access token hook.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CustomAccessTokenOutput {
+    /// The claims to issue the access token with; the required claims must be kept
+    pub claims: OwnedValue,
+}
+
+/// Input of the send SMS hook.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SendSmsInput {
+    pub user: UserSchema,
+    pub sms: SmsData,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmsData {
+    pub otp: String,
+}
+
+/// Input of the send email hook.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SendEmailInput {
+    pub user: UserSchema,
+    pub email_data: EmailData,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmailData {
+    pub token: String,
+    pub token_hash: String,
+    pub redirect_to: String,
+    /// e.g. `signup`, `magiclink`, `recovery` or `email_change`
+    pub email_action_type: String,
+    pub site_url: String,
+    /// The OTP for the new address of a secure email change
+    #[serde(default)]
+    pub token_new: String,
+    #[serde(default)]
+    pub token_hash_new: String,
+}
+
+/// Response body for failing a hook; the auth server reports `message` to the client.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HookErrorResponse {
+    pub error: HookErrorBody,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HookErrorBody {
+    pub http_code: u16,
+    pub message: String,
+}
+
+impl HookErrorResponse {
+    #[must_use]
+    pub const fn new(http_code: u16, message: String) -> Self {
+        Self {
+            error: HookErrorBody { http_code, message },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    const SECRET: &str = "v1,whsec_c2VjcmV0LWhvb2sta2V5";
+
+    fn signed_headers(body: &[u8], timestamp: i64) -> HeaderMap {
+        let key = BASE64_STANDARD.decode("c2VjcmV0LWhvb2sta2V5").unwrap();
+        let mut hmac = hmac_sha256::HMAC::new(&key);
+        hmac.update(format!("msg_1.{timestamp}.").as_bytes());
+        hmac.update(body);
+        let signature = BASE64_STANDARD.encode(hmac.finalize());
+        let mut headers = HeaderMap::new();
+        headers.insert(WEBHOOK_ID, HeaderValue::from_static("msg_1"));
+        headers.insert(WEBHOOK_TIMESTAMP, timestamp.to_string().parse().unwrap());
+        headers.insert(
+            WEBHOOK_SIGNATURE,
+            format!("v1,b2xkLXNpZ25hdHVyZQ== v1,{signature}")
+                .parse()
+                .unwrap(),
+        );
+        headers
+    }
+
+    #[test_log::test]
+    fn test_verify_send_sms_payload() {
+        let body =
+            br#"{"user": {"id": "user-id", "phone": "15555550100"}, "sms": {"otp": "123456"}}"#;
+        let verifier = HookVerifier::new(SECRET).unwrap();
+        let headers = signed_headers(body, Utc::now().timestamp());
+
+        let input: SendSmsInput = verifier.verify_payload(&headers, body).unwrap();
+        assert_eq!(input.user.id.unwrap(), "user-id");
+        assert_eq!(input.sms.otp, "123456");
+
+        let tampered =
+            br#"{"user": {"id": "user-id", "phone": "15555550199"}, "sms": {"otp": "123456"}}"#;
+        assert!(matches!(
+            verifier.verify(&headers, tampered),
+            Err(HookError::InvalidSignature)
+        ));
+    }
+
+    #[test_log::test]
+    fn test_verify_rejects_stale_timestamp() {
+        let body = br#"{"user_id": "user-id", "valid": false}"#;
+        let verifier = HookVerifier::new(SECRET).unwrap();
+        let headers = signed_headers(body, Utc::now().timestamp() - 3600);
+
+        assert!(matches!(
+            verifier.verify(&headers, body),
+            Err(HookError::TimestampOutOfTolerance)
+        ));
+        let input: PasswordVerificationAttemptInput = verifier
+            .with_tolerance(Duration::from_secs(2 * 3600))
+            .verify_payload(&headers, body)
+            .unwrap();
+        assert!(!input.valid);
+    }
+}
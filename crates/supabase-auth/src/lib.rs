@@ -1,9 +1,21 @@
 #![feature(result_flattening)]
 
-pub use {futures, redact, url};
+pub use {futures, jwt_simple, redact, url};
 pub const SUPABASE_KEY: &str = "apikey";
 
 pub mod auth_client;
+pub mod claims;
+pub mod clock;
+pub mod cookies;
 pub mod error;
+pub mod hooks;
 pub mod jwt_stream;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod runtime;
+pub mod session;
+pub mod session_manager;
+pub mod token_provider;
+pub mod token_store;
 pub mod types;
+pub mod verify;
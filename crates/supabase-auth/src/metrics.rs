@@ -0,0 +1,129 @@
+//! Hooks for monitoring the auth requests of [`JwtRefreshStream`]s, enabled by the `metrics`
+//! feature.
+//!
+//! Implement [`AuthMetrics`] on top of the metrics library of your choice (counters for logins,
+//! refreshes and failures, a histogram for the latency) and attach it through
+//! [`JwtStream::with_metrics`](crate::jwt_stream::JwtStream::with_metrics).
+//!
+//! [`JwtRefreshStream`]: crate::jwt_stream::JwtRefreshStream
+
+use core::future::Future;
+use core::time::Duration;
+use std::sync::Arc;
+
+use web_time::Instant;
+
+use crate::jwt_stream::RefreshStreamError;
+
+/// The kind of request a [`JwtRefreshStream`](crate::jwt_stream::JwtRefreshStream) made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthOperation {
+    /// A password or ID token login
+    Login,
+    /// A `refresh_token` grant
+    Refresh,
+}
+
+impl AuthOperation {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::Refresh => "refresh",
+        }
+    }
+}
+
+/// Receives the outcome of every login and refresh request.
+///
+/// Called from the background tasks of the stream, so implementations should not block.
+pub trait AuthMetrics: Send + Sync {
+    /// The request succeeded after `latency`
+    fn succeeded(&self, operation: AuthOperation, latency: Duration);
+
+    /// The request failed after `latency`
+    fn failed(&self, operation: AuthOperation, latency: Duration, error: &RefreshStreamError);
+}
+
+/// Reports the outcome and duration of `request` to `metrics`
+pub(crate) async fn measured<T, F>(
+    metrics: Option<Arc<dyn AuthMetrics>>,
+    operation: AuthOperation,
+    request: F,
+) -> Result<T, RefreshStreamError>
+where
+    F: Future<Output = Result<T, RefreshStreamError>>,
+{
+    let Some(metrics) = metrics else {
+        return request.await;
+    };
+    let started = Instant::now();
+    let res = request.await;
+    match res {
+        Ok(_) => metrics.succeeded(operation, started.elapsed()),
+        Err(ref err) => metrics.failed(operation, started.elapsed(), err),
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures::StreamExt as _;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::{make_jwt, SupabaseMockServer};
+    use test_log::test;
+
+    use super::*;
+    use crate::jwt_stream::{test_config, JwtStream, SupabaseAuthConfig};
+    use crate::types::LoginCredentials;
+
+    #[derive(Default)]
+    struct Recorded(Mutex<Vec<(AuthOperation, bool)>>);
+
+    impl AuthMetrics for Recorded {
+        fn succeeded(&self, operation: AuthOperation, _latency: Duration) {
+            self.0.lock().unwrap().push((operation, true));
+        }
+
+        fn failed(
+            &self,
+            operation: AuthOperation,
+            _latency: Duration,
+            _error: &RefreshStreamError,
+        ) {
+            self.0.lock().unwrap().push((operation, false));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_logins_and_refreshes_are_recorded() {
+        let mut m = SupabaseMockServer::new().await;
+        m.register_jwt_password(&make_jwt(Duration::from_millis(5)));
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(60),
+            ..test_config(m.server_url())
+        };
+        let recorded = Arc::new(Recorded::default());
+        let credentials = LoginCredentials::builder()
+            .email("user@example.com".to_owned())
+            .password("password".to_owned())
+            .build();
+        let mut stream = JwtStream::new(config)
+            .with_metrics(recorded.clone())
+            .sign_in(credentials)
+            .unwrap();
+
+        stream.next().await.unwrap().unwrap();
+        // no refresh endpoint is mocked
+        stream.next().await.unwrap().unwrap_err();
+        assert_eq!(
+            *recorded.0.lock().unwrap(),
+            vec![
+                (AuthOperation::Login, true),
+                (AuthOperation::Refresh, false)
+            ]
+        );
+    }
+}
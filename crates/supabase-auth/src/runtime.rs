@@ -0,0 +1,158 @@
+//! Task and timer primitives for the refresh machinery.
+//!
+//! Native targets run background tasks on tokio. On `wasm32` there is no tokio runtime, so the
+//! tasks are polled by their owner (the [`crate::jwt_stream::JwtRefreshStream`]) and detached
+//! futures are handed to `wasm-bindgen-futures`.
+
+use core::future::Future;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+/// Why a task of a [`TaskSet`] did not produce a value
+#[derive(Debug)]
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub(crate) enum TaskError {
+    /// The task was aborted through [`TaskSet::abort_all`]
+    Cancelled,
+    /// The task panicked
+    Panicked,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use native::*;
+#[cfg(target_arch = "wasm32")]
+pub(crate) use wasm::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use futures::future::BoxFuture;
+    use futures::FutureExt as _;
+
+    use super::*;
+
+    pub(crate) type BoxedFuture<T> = BoxFuture<'static, T>;
+
+    pub(crate) fn boxed<F>(future: F) -> BoxedFuture<F::Output>
+    where
+        F: Future + Send + 'static,
+    {
+        future.boxed()
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Runs `future` in the background until it completes.
+    pub(crate) fn spawn_detached<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(future);
+    }
+
+    pub(crate) struct TaskSet<T> {
+        tasks: tokio::task::JoinSet<T>,
+    }
+
+    impl<T: Send + 'static> TaskSet<T> {
+        pub(crate) fn new() -> Self {
+            Self {
+                tasks: tokio::task::JoinSet::new(),
+            }
+        }
+
+        pub(crate) fn spawn<F>(&mut self, task: F)
+        where
+            F: Future<Output = T> + Send + 'static,
+        {
+            self.tasks.spawn(task);
+        }
+
+        pub(crate) fn abort_all(&mut self) {
+            self.tasks.abort_all();
+        }
+
+        /// Returns `Ready(None)` once no tasks are left
+        pub(crate) fn poll_join_next(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<T, TaskError>>> {
+            self.tasks.poll_join_next(cx).map(|res| {
+                res.map(|res| {
+                    res.map_err(|err| {
+                        if err.is_cancelled() {
+                            TaskError::Cancelled
+                        } else {
+                            tracing::error!(?err, "background task panicked");
+                            TaskError::Panicked
+                        }
+                    })
+                })
+            })
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use futures::future::LocalBoxFuture;
+    use futures::stream::FuturesUnordered;
+    use futures::{FutureExt as _, StreamExt as _};
+
+    use super::*;
+
+    pub(crate) type BoxedFuture<T> = LocalBoxFuture<'static, T>;
+
+    pub(crate) fn boxed<F>(future: F) -> BoxedFuture<F::Output>
+    where
+        F: Future + 'static,
+    {
+        future.boxed_local()
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        gloo_timers::future::sleep(duration).await;
+    }
+
+    /// Runs `future` on the browser event loop until it completes.
+    pub(crate) fn spawn_detached<F>(future: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        wasm_bindgen_futures::spawn_local(future);
+    }
+
+    /// Tasks are polled by the owner of the set instead of a runtime.
+    pub(crate) struct TaskSet<T> {
+        tasks: FuturesUnordered<LocalBoxFuture<'static, T>>,
+    }
+
+    impl<T: 'static> TaskSet<T> {
+        pub(crate) fn new() -> Self {
+            Self {
+                tasks: FuturesUnordered::new(),
+            }
+        }
+
+        pub(crate) fn spawn<F>(&mut self, task: F)
+        where
+            F: Future<Output = T> + 'static,
+        {
+            self.tasks.push(task.boxed_local());
+        }
+
+        /// Dropping a future cancels it, so aborted tasks are never reported
+        pub(crate) fn abort_all(&mut self) {
+            self.tasks.clear();
+        }
+
+        /// Returns `Ready(None)` once no tasks are left
+        pub(crate) fn poll_join_next(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<T, TaskError>>> {
+            self.tasks.poll_next_unpin(cx).map(|res| res.map(Ok))
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! A cheaply clonable handle to a session that is kept fresh in the background.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::StreamExt as _;
+use tokio::sync::watch;
+
+use crate::error::AuthError;
+use crate::jwt_stream::{JwtRefreshStream, SessionUpdater};
+use crate::runtime;
+use crate::token_provider::TokenProvider;
+use crate::types::{AccessTokenResponseSchema, UserSchema};
+
+#[derive(Debug, Clone)]
+struct SessionState {
+    session: AccessTokenResponseSchema,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl SessionState {
+    fn new(session: AccessTokenResponseSchema) -> Self {
+        let expires_at = session
+            .expires_at
+            .and_then(|expires_at| DateTime::from_timestamp(expires_at, 0))
+            .or_else(|| {
+                let expires_in = TimeDelta::try_seconds(session.expires_in?)?;
+                Utc::now().checked_add_signed(expires_in)
+            });
+        Self {
+            session,
+            expires_at,
+        }
+    }
+}
+
+/// Shared view of the session produced by a [`JwtRefreshStream`].
+///
+/// The stream is driven by a background task; every clone observes the latest session. The
+/// task stops when the stream ends or when all handles have been dropped.
+#[derive(Debug, Clone)]
+pub struct AuthSession {
+    state: watch::Receiver<Option<SessionState>>,
+    updater: SessionUpdater,
+}
+
+impl AuthSession {
+    /// Spawns the refresh stream onto the current tokio runtime (the browser event loop on
+    /// `wasm32`).
+    #[must_use]
+    pub fn spawn(mut stream: JwtRefreshStream) -> Self {
+        let (tx, rx) = watch::channel(None);
+        let updater = stream.session_updater();
+        runtime::spawn_detached(async move {
+            loop {
+                tokio::select! {
+                    () = tx.closed() => break,
+                    item = stream.next() => match item {
+                        Some(Ok(session)) => {
+                            tx.send_replace(Some(SessionState::new(session)));
+                        }
+                        Some(Err(err)) => tracing::warn!(?err, "session refresh failed"),
+                        None => break,
+                    },
+                }
+            }
+            tracing::debug!("auth session stopped");
+        });
+        Self { state: rx, updater }
+    }
+
+    /// Replaces the session (e.g. after an MFA verification); all handles observe it once the
+    /// background task has picked it up.
+    ///
+    /// Returns `false` if the background task has stopped.
+    pub fn replace_session(&self, session: AccessTokenResponseSchema) -> bool {
+        self.updater.replace(session)
+    }
+
+    /// The latest session, if one has been established.
+    #[must_use]
+    pub fn current_session(&self) -> Option<AccessTokenResponseSchema> {
+        self.state
+            .borrow()
+            .as_ref()
+            .map(|state| state.session.clone())
+    }
+
+    #[must_use]
+    pub fn current_token(&self) -> Option<String> {
+        self.state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.session.access_token.as_ref())
+            .map(|token| token.expose_secret().clone())
+    }
+
+    #[must_use]
+    pub fn current_user(&self) -> Option<UserSchema> {
+        self.state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.session.user.clone())
+    }
+
+    /// When the current access token expires.
+    #[must_use]
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.expires_at)
+    }
+
+    /// `false` once the background refresh task has stopped.
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.state.has_changed().is_ok()
+    }
+
+    /// Waits until the first session has been established.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AuthError::TokenUnavailable`] if the refresh stream stopped before that.
+    pub async fn wait_for_session(&self) -> Result<AccessTokenResponseSchema, AuthError> {
+        let mut state = self.state.clone();
+        let state = state
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_err| AuthError::TokenUnavailable)?;
+        state
+            .as_ref()
+            .map(|state| state.session.clone())
+            .ok_or(AuthError::TokenUnavailable)
+    }
+}
+
+impl TokenProvider for AuthSession {
+    /// Returns the current access token; once it has expired, waits for the refresh instead of
+    /// handing out the dead token.
+    async fn token(&self) -> Result<String, AuthError> {
+        let mut state = self.state.clone();
+        let state = state
+            .wait_for(|state| {
+                state.as_ref().is_some_and(|state| {
+                    state
+                        .expires_at
+                        .is_none_or(|expires_at| expires_at > Utc::now())
+                })
+            })
+            .await
+            .map_err(|_err| AuthError::TokenUnavailable)?;
+        state
+            .as_ref()
+            .and_then(|state| state.session.access_token.as_ref())
+            .map(|token| token.expose_secret().clone())
+            .ok_or(AuthError::TokenUnavailable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::{make_jwt, SupabaseMockServer};
+    use test_log::test;
+
+    use super::*;
+    use crate::jwt_stream::{test_config, JwtStream, SupabaseAuthConfig};
+    use crate::types::LoginCredentials;
+
+    #[test(tokio::test)]
+    async fn test_shared_session() {
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let mut m = SupabaseMockServer::new().await;
+        m.register_jwt_password(&access_token);
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(1),
+            ..test_config(m.server_url())
+        };
+        let credentials = LoginCredentials::builder()
+            .email("user@example.com".to_owned())
+            .password("password".to_owned())
+            .build();
+        let session = JwtStream::new(config)
+            .sign_in(credentials)
+            .unwrap()
+            .into_session();
+        assert!(session.current_token().is_none());
+
+        let shared = session.clone();
+        shared.wait_for_session().await.unwrap();
+        assert_eq!(session.current_token().unwrap(), access_token);
+        assert_eq!(
+            session.current_user().unwrap().email.unwrap(),
+            "user@example.com"
+        );
+        assert!(session.expires_at().unwrap() > Utc::now());
+        assert!(session.is_active());
+    }
+    #[test(tokio::test)]
+    async fn test_expired_token_waits_for_refresh() {
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let mut m = SupabaseMockServer::new().await;
+        m.register_jwt_refresh(&access_token);
+        let expired = AccessTokenResponseSchema::builder()
+            .access_token("expired-token".to_owned())
+            .refresh_token("refresh-token".to_owned())
+            .expires_in(0)
+            .expires_at((Utc::now() - TimeDelta::seconds(10)).timestamp())
+            .build();
+        let session = JwtStream::new(test_config(m.server_url()))
+            .from_session(expired)
+            .unwrap()
+            .into_session();
+
+        assert_eq!(session.token().await.unwrap(), access_token);
+    }
+}
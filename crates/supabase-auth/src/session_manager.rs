@@ -0,0 +1,247 @@
+//! Several named sessions driven together, e.g. for bots or test tooling acting as multiple
+//! users at once.
+//!
+//! [`SessionManager`] owns one [`JwtRefreshStream`] per name and is itself a [`Stream`] of the
+//! events of all of them. The latest session of every name is kept, so tokens can be read
+//! without polling the individual streams.
+
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::collections::HashMap;
+
+use futures::{Stream, StreamExt as _};
+
+use crate::auth_client::requests::LogoutScope;
+use crate::jwt_stream::{JwtRefreshStream, RefreshStreamError};
+use crate::types::AccessTokenResponseSchema;
+
+/// What happened to one of the sessions of a [`SessionManager`]
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// A new (or refreshed) session
+    Session(AccessTokenResponseSchema),
+    /// A login or refresh failed; the stream retries on its own
+    Error(RefreshStreamError),
+    /// The stream gave up and was removed from the manager
+    Ended,
+}
+
+/// Owns named [`JwtRefreshStream`]s and merges their events.
+///
+/// The manager has to be polled for the sessions to be refreshed. It does not end when it runs
+/// out of sessions, as new ones can be inserted at any time. The streams are polled round-robin,
+/// so a busy session cannot starve the others.
+#[derive(Default)]
+pub struct SessionManager {
+    streams: HashMap<String, JwtRefreshStream>,
+    sessions: HashMap<String, AccessTokenResponseSchema>,
+    /// Position of the stream to poll first, the one after the last stream that yielded
+    next_poll: usize,
+    /// Woken when a session is inserted while nothing was being polled
+    waker: Option<Waker>,
+}
+
+impl SessionManager {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `stream` under `name`, replacing (and dropping) any stream of the same name.
+    pub fn insert(&mut self, name: impl Into<String>, stream: JwtRefreshStream) {
+        let name = name.into();
+        self.sessions.remove(&name);
+        self.streams.insert(name, stream);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Stops refreshing the session of `name` without revoking it.
+    ///
+    /// Returns the stream, e.g. to drive it separately.
+    pub fn remove(&mut self, name: &str) -> Option<JwtRefreshStream> {
+        self.sessions.remove(name);
+        self.streams.remove(name)
+    }
+
+    /// Revokes the session of `name` and removes it.
+    ///
+    /// Returns `Ok(false)` if there is no such session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the logout request fails; the session is removed regardless.
+    pub async fn sign_out(
+        &mut self,
+        name: &str,
+        scope: LogoutScope,
+    ) -> Result<bool, RefreshStreamError> {
+        let Some(mut stream) = self.remove(name) else {
+            return Ok(false);
+        };
+        stream.sign_out(scope).await?;
+        Ok(true)
+    }
+
+    /// The names of all managed sessions.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.streams.keys().map(String::as_str)
+    }
+
+    /// The latest session of `name`, if one has been established.
+    #[must_use]
+    pub fn session(&self, name: &str) -> Option<&AccessTokenResponseSchema> {
+        self.sessions.get(name)
+    }
+
+    /// The latest access token of `name`, if one has been established.
+    #[must_use]
+    pub fn current_token(&self, name: &str) -> Option<String> {
+        self.sessions
+            .get(name)?
+            .access_token
+            .as_ref()
+            .map(|token| token.expose_secret().clone())
+    }
+}
+
+impl Stream for SessionManager {
+    type Item = (String, SessionEvent);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let start = this.next_poll.min(this.streams.len());
+        let mut poll = |(position, (name, stream)): (usize, (&String, &mut JwtRefreshStream))| {
+            let Poll::Ready(item) = stream.poll_next_unpin(cx) else {
+                return None;
+            };
+            Some((position, name.clone(), item))
+        };
+        let ready = this
+            .streams
+            .iter_mut()
+            .enumerate()
+            .skip(start)
+            .find_map(&mut poll)
+            .or_else(|| {
+                this.streams
+                    .iter_mut()
+                    .enumerate()
+                    .take(start)
+                    .find_map(&mut poll)
+            });
+        let Some((position, name, item)) = ready else {
+            this.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        };
+        this.next_poll = position + 1;
+        let event = match item {
+            Some(Ok(session)) => {
+                this.sessions.insert(name.clone(), session.clone());
+                SessionEvent::Session(session)
+            }
+            Some(Err(err)) => SessionEvent::Error(err),
+            None => {
+                this.remove(&name);
+                SessionEvent::Ended
+            }
+        };
+        Poll::Ready(Some((name, event)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::{make_jwt, SupabaseMockServer};
+    use test_log::test;
+
+    use super::*;
+    use crate::jwt_stream::{test_config, JwtStream, SupabaseAuthConfig};
+    use crate::types::LoginCredentials;
+
+    #[test(tokio::test)]
+    async fn test_merged_sessions() {
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let mut m = SupabaseMockServer::new().await;
+        m.register_jwt_password(&access_token);
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(1),
+            ..test_config(m.server_url())
+        };
+        let supabase_auth = JwtStream::new(config);
+        let credentials = LoginCredentials::builder()
+            .email("user@example.com".to_owned())
+            .password("password".to_owned())
+            .build();
+        let existing = AccessTokenResponseSchema::builder()
+            .access_token("bob-token".to_owned())
+            .refresh_token("bob-refresh".to_owned())
+            .expires_in(3600)
+            .build();
+
+        let mut manager = SessionManager::new();
+        manager.insert("alice", supabase_auth.sign_in(credentials).unwrap());
+        manager.insert("bob", supabase_auth.from_session(existing).unwrap());
+        let mut names = manager.names().collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, vec!["alice", "bob"]);
+        assert_eq!(manager.current_token("alice"), None);
+
+        let mut established = Vec::new();
+        for _ in 0..2 {
+            let (name, event) = manager.next().await.unwrap();
+            assert!(matches!(event, SessionEvent::Session(_)));
+            established.push(name);
+        }
+        established.sort_unstable();
+        assert_eq!(established, vec!["alice", "bob"]);
+        assert_eq!(manager.current_token("alice").unwrap(), access_token);
+        assert_eq!(manager.current_token("bob").unwrap(), "bob-token");
+
+        assert!(manager.remove("bob").is_some());
+        assert_eq!(manager.current_token("bob"), None);
+        assert_eq!(manager.names().collect::<Vec<_>>(), vec!["alice"]);
+    }
+
+    #[test(tokio::test)]
+    async fn test_busy_sessions_do_not_starve_others() {
+        let m = SupabaseMockServer::new().await;
+        let supabase_auth = JwtStream::new(test_config(m.server_url()));
+        let session = |token: &str| {
+            AccessTokenResponseSchema::builder()
+                .access_token(token.to_owned())
+                .refresh_token(format!("{token}-refresh"))
+                .expires_in(3600)
+                .build()
+        };
+
+        let mut manager = SessionManager::new();
+        let mut updaters = Vec::new();
+        for name in ["alice", "bob"] {
+            let stream = supabase_auth.from_session(session(name)).unwrap();
+            updaters.push(stream.session_updater());
+            manager.insert(name, stream);
+        }
+        for _ in 0..2 {
+            manager.next().await.unwrap();
+        }
+        // both streams have several sessions ready
+        for updater in &updaters {
+            for idx in 0..3 {
+                assert!(updater.replace(session(&format!("token-{idx}"))));
+            }
+        }
+
+        let mut names = Vec::new();
+        for _ in 0..6 {
+            let (name, event) = manager.next().await.unwrap();
+            assert!(matches!(event, SessionEvent::Session(_)));
+            names.push(name);
+        }
+        assert!(names.windows(2).all(|pair| pair[0] != pair[1]), "{names:?}");
+    }
+}
@@ -0,0 +1,125 @@
+//! On-demand access to the current access token.
+//!
+//! Instead of every consumer driving its own [`JwtRefreshStream`], the stream can be turned into
+//! a [`JwtTokenProvider`] that refreshes in the background and hands out the latest token to any
+//! number of clones.
+
+use core::future::Future;
+
+use crate::error::AuthError;
+use crate::jwt_stream::JwtRefreshStream;
+use crate::session::AuthSession;
+
+/// Something that can produce a valid access token when asked.
+pub trait TokenProvider {
+    /// Returns the current access token, waiting for the first one (or for the refresh, once the
+    /// current one has expired) if necessary.
+    fn token(&self) -> impl Future<Output = Result<String, AuthError>> + Send;
+}
+
+/// A fixed token, e.g. a service role key.
+#[derive(Clone)]
+pub struct StaticToken(pub String);
+
+impl core::fmt::Debug for StaticToken {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("StaticToken([REDACTED])")
+    }
+}
+
+impl TokenProvider for StaticToken {
+    async fn token(&self) -> Result<String, AuthError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Token provider backed by a [`JwtRefreshStream`] running in a background task.
+///
+/// The background task stops once the stream ends (e.g. the reconnect attempts are exhausted)
+/// or when every clone of the provider has been dropped.
+#[derive(Debug, Clone)]
+pub struct JwtTokenProvider {
+    session: AuthSession,
+}
+
+impl JwtTokenProvider {
+    /// Spawns the refresh stream onto the current tokio runtime.
+    #[must_use]
+    pub fn spawn(stream: JwtRefreshStream) -> Self {
+        Self {
+            session: AuthSession::spawn(stream),
+        }
+    }
+
+    /// The session handle the tokens are read from.
+    #[must_use]
+    pub const fn session(&self) -> &AuthSession {
+        &self.session
+    }
+}
+
+impl TokenProvider for JwtTokenProvider {
+    async fn token(&self) -> Result<String, AuthError> {
+        self.session.token().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::{make_jwt, SupabaseMockServer};
+    use test_log::test;
+
+    use super::*;
+    use crate::jwt_stream::{test_config, JwtStream, SupabaseAuthConfig};
+    use crate::types::{AccessTokenResponseSchema, LoginCredentials};
+
+    #[test(tokio::test)]
+    async fn test_token_provider() {
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let mut m = SupabaseMockServer::new().await;
+        m.register_jwt_password(&access_token);
+        let config = SupabaseAuthConfig {
+            reconnect_interval: Duration::from_secs(1),
+            ..test_config(m.server_url())
+        };
+        let credentials = LoginCredentials::builder()
+            .email("user@example.com".to_owned())
+            .password("password".to_owned())
+            .build();
+        let provider = JwtStream::new(config)
+            .sign_in(credentials)
+            .unwrap()
+            .into_token_provider();
+
+        assert_eq!(provider.token().await.unwrap(), access_token);
+        assert_eq!(provider.clone().token().await.unwrap(), access_token);
+    }
+
+    #[test(tokio::test)]
+    async fn test_expired_token_is_not_handed_out() {
+        let access_token = make_jwt(Duration::from_secs(3600));
+        let mut m = SupabaseMockServer::new().await;
+        m.register_jwt_refresh(&access_token);
+        let expired = AccessTokenResponseSchema::builder()
+            .access_token("expired-token".to_owned())
+            .refresh_token("refresh-token".to_owned())
+            .expires_in(0)
+            .expires_at(chrono::Utc::now().timestamp() - 10)
+            .build();
+        let provider = JwtStream::new(test_config(m.server_url()))
+            .from_session(expired)
+            .unwrap()
+            .into_token_provider();
+
+        assert_eq!(provider.token().await.unwrap(), access_token);
+    }
+
+    #[test]
+    fn test_static_token_is_redacted() {
+        let token = StaticToken("service-role-key".to_owned());
+        assert_eq!(format!("{token:?}"), "StaticToken([REDACTED])");
+    }
+}
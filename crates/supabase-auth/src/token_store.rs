@@ -0,0 +1,242 @@
+//! Session persistence for [`crate::jwt_stream::JwtStream`].
+//!
+//! When a [`TokenStore`] is attached, every session produced by the refresh stream is saved, and
+//! the next start resumes from the stored refresh token instead of doing a fresh login.
+
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use thiserror::Error;
+
+use crate::types::AccessTokenResponseSchema;
+
+#[derive(Debug, Error)]
+pub enum TokenStoreError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] simd_json::Error),
+    #[error("Token store lock was poisoned")]
+    Poisoned,
+}
+
+/// Storage for the latest session.
+pub trait TokenStore: Send + Sync {
+    /// Returns the persisted session, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage cannot be read.
+    fn load(&self) -> Result<Option<AccessTokenResponseSchema>, TokenStoreError>;
+
+    /// Persists the session, replacing the previous one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage cannot be written.
+    fn save(&self, session: &AccessTokenResponseSchema) -> Result<(), TokenStoreError>;
+
+    /// Removes the persisted session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage cannot be written.
+    fn clear(&self) -> Result<(), TokenStoreError>;
+}
+
+/// Keeps the session in memory; useful for tests and short lived processes.
+#[derive(Debug, Default)]
+pub struct InMemoryTokenStore {
+    session: Mutex<Option<AccessTokenResponseSchema>>,
+}
+
+impl InMemoryTokenStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TokenStore for InMemoryTokenStore {
+    fn load(&self) -> Result<Option<AccessTokenResponseSchema>, TokenStoreError> {
+        Ok(self
+            .session
+            .lock()
+            .map_err(|_err| TokenStoreError::Poisoned)?
+            .clone())
+    }
+
+    fn save(&self, session: &AccessTokenResponseSchema) -> Result<(), TokenStoreError> {
+        *self
+            .session
+            .lock()
+            .map_err(|_err| TokenStoreError::Poisoned)? = Some(session.clone());
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        *self
+            .session
+            .lock()
+            .map_err(|_err| TokenStoreError::Poisoned)? = None;
+        Ok(())
+    }
+}
+
+/// Stores the session as JSON in a single file.
+///
+/// The file contains a refresh token, so on unix it is only readable and writable by the current
+/// user; elsewhere it should live somewhere only the current user can read.
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> Result<Option<AccessTokenResponseSchema>, TokenStoreError> {
+        let mut bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(simd_json::from_slice(&mut bytes)?))
+    }
+
+    fn save(&self, session: &AccessTokenResponseSchema) -> Result<(), TokenStoreError> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // write to a sibling file first so a crash never leaves a truncated session behind
+        let tmp = tmp_path(&self.path);
+        write_private(&tmp, &simd_json::to_vec(session)?)?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// `path` with `.tmp` appended, e.g. `session.json.tmp`; replacing the extension would make
+/// `session.json` and `session.tmp` share a temporary file.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Writes `contents` to a new file at `path` that, on unix, only the owner can access.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    // the mode only applies to new files, so a leftover file is not reused
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_file_token_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!(
+            "rp-supabase-auth-{}-{}.json",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let store = FileTokenStore::new(&path);
+        assert!(store.load().unwrap().is_none());
+
+        let session = AccessTokenResponseSchema::builder()
+            .access_token("access".to_owned())
+            .refresh_token("refresh".to_owned())
+            .build();
+        store.save(&session).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(
+            loaded.refresh_token.unwrap().expose_secret().as_str(),
+            "refresh"
+        );
+
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_none());
+        store.clear().unwrap();
+    }
+
+    #[test]
+    fn test_stores_with_same_stem_do_not_share_a_temporary_file() {
+        let stem = std::env::temp_dir().join(format!(
+            "rp-supabase-auth-{}-{}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let json = FileTokenStore::new(stem.with_extension("json"));
+        let tmp = FileTokenStore::new(stem.with_extension("tmp"));
+        assert_ne!(tmp_path(&json.path), tmp_path(&tmp.path));
+        assert_ne!(tmp_path(&tmp.path), tmp.path);
+
+        let session = |refresh_token: &str| {
+            AccessTokenResponseSchema::builder()
+                .access_token("access".to_owned())
+                .refresh_token(refresh_token.to_owned())
+                .build()
+        };
+        tmp.save(&session("tmp-refresh")).unwrap();
+        json.save(&session("json-refresh")).unwrap();
+        for (store, refresh_token) in [(&json, "json-refresh"), (&tmp, "tmp-refresh")] {
+            let loaded = store.load().unwrap().unwrap();
+            assert_eq!(
+                loaded.refresh_token.unwrap().expose_secret().as_str(),
+                refresh_token
+            );
+            store.clear().unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_token_store_is_private() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let path = std::env::temp_dir().join(format!(
+            "rp-supabase-auth-{}-{}.json",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        // a leftover temporary file readable by everyone
+        std::fs::write(tmp_path(&path), b"{}").unwrap();
+        std::fs::set_permissions(tmp_path(&path), std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let store = FileTokenStore::new(&path);
+        let session = AccessTokenResponseSchema::builder()
+            .access_token("access".to_owned())
+            .refresh_token("refresh".to_owned())
+            .build();
+        store.save(&session).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        store.clear().unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use chrono::{DateTime, Utc};
+use redact::Secret;
 use serde::{Deserialize, Serialize};
 use simd_json::OwnedValue;
 use typed_builder::TypedBuilder;
@@ -13,10 +14,51 @@ pub type AppMetadata = OwnedValue;
 pub struct LoginCredentials {
     #[builder(setter(strip_option), default)]
     pub email: Option<String>,
-    #[builder(setter(strip_option), default)]
-    pub password: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(setter(transform = |password: String| Some(Secret::new(password))), default)]
+    pub password: Option<Secret<String>>,
     #[builder(setter(strip_option), default)]
     pub phone: Option<String>,
+    /// CAPTCHA token for projects with CAPTCHA protection enabled.
+    ///
+    /// Tokens are single use, so it is only sent with the initial sign-in; later password
+    /// re-logins of the refresh stream go without it.
+    #[builder(setter(strip_option), default)]
+    pub captcha_token: Option<String>,
+}
+
+/// Credentials for the `id_token` grant (e.g. Sign in with Apple / Google).
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct IdTokenCredentials {
+    /// The OIDC provider that issued the token, e.g. `apple` or `google`.
+    pub provider: String,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(setter(transform = |id_token: String| Secret::new(id_token)))]
+    pub id_token: Secret<String>,
+    /// The raw nonce, if one was hashed into the `id_token`.
+    #[builder(setter(strip_option), default)]
+    pub nonce: Option<String>,
+}
+
+/// The blockchain a [`Web3Credentials`] message was signed on.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Web3Chain {
+    Ethereum,
+    Solana,
+}
+
+/// Credentials for the `web3` grant (Sign in with Ethereum / Solana).
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct Web3Credentials {
+    pub chain: Web3Chain,
+    /// The EIP-4361 / Sign in with Solana message that was signed by the wallet.
+    pub message: String,
+    /// The signature over `message`: hex encoded on Ethereum, base58 encoded on Solana.
+    pub signature: String,
+    /// CAPTCHA token for projects with CAPTCHA protection enabled.
+    #[builder(setter(strip_option), default)]
+    pub captcha_token: Option<String>,
 }
 
 /// Token request body for the `/token` endpoint.
@@ -26,10 +68,15 @@ pub struct TokenRequestBody {
     pub email: Option<String>,
     #[builder(default)]
     pub phone: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
     #[builder(default)]
-    pub password: Option<String>,
-    #[builder(setter(strip_option), default)]
-    pub refresh_token: Option<String>,
+    pub password: Option<Secret<String>>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(
+        setter(transform = |refresh_token: String| Some(Secret::new(refresh_token))),
+        default
+    )]
+    pub refresh_token: Option<Secret<String>>,
     #[builder(setter(strip_option), default)]
     pub grant_type: Option<String>,
     #[builder(setter(strip_option), default)]
@@ -42,18 +89,34 @@ pub struct TokenRequestBody {
     pub scope: Option<String>,
     #[builder(setter(strip_option), default)]
     pub client_id: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(setter(transform = |client_secret: String| Some(Secret::new(client_secret))), default)]
+    pub client_secret: Option<Secret<String>>,
     #[builder(setter(strip_option), default)]
-    pub client_secret: Option<String>,
-    #[builder(setter(strip_option), default)]
-    pub id_token: Option<String>,
+    pub provider: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(setter(transform = |id_token: String| Some(Secret::new(id_token))), default)]
+    pub id_token: Option<Secret<String>>,
     #[builder(setter(strip_option), default)]
     pub nonce: Option<String>,
     #[builder(setter(strip_option), default)]
     pub invite_token: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(setter(transform = |provider_token: String| Some(Secret::new(provider_token))), default)]
+    pub provider_token: Option<Secret<String>>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(setter(transform = |code_verifier: String| Some(Secret::new(code_verifier))), default)]
+    pub code_verifier: Option<Secret<String>>,
+    /// The auth code returned by the `/authorize` redirect, used by the `pkce` grant.
+    #[builder(setter(strip_option), default)]
+    pub auth_code: Option<String>,
     #[builder(setter(strip_option), default)]
-    pub provider_token: Option<String>,
+    pub chain: Option<Web3Chain>,
+    /// The signed message of the `web3` grant.
+    #[builder(setter(strip_option), default)]
+    pub message: Option<String>,
     #[builder(setter(strip_option), default)]
-    pub code_verifier: Option<String>,
+    pub signature: Option<String>,
 }
 
 /// Payload for the `/signup` endpoint.
@@ -61,8 +124,9 @@ pub struct TokenRequestBody {
 pub struct SignupPayload {
     #[builder(setter(strip_option), default)]
     pub email: Option<String>,
-    #[builder(setter(strip_option), default)]
-    pub password: Option<String>,
+    #[serde(serialize_with = "redact::expose_secret")]
+    #[builder(setter(transform = |password: String| Some(Secret::new(password))), default)]
+    pub password: Option<Secret<String>>,
     #[builder(setter(strip_option), default)]
     pub phone: Option<String>,
     #[builder(setter(strip_option), default)]
@@ -145,6 +209,44 @@ pub struct AdminUsersResponse {
     pub users: Vec<UserSchema>,
 }
 
+/// Attributes for creating a user through the admin API.
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct AdminUserAttributes {
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    #[builder(setter(transform = |password: String| Some(Secret::new(password))), default)]
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "redact::expose_secret"
+    )]
+    pub password: Option<Secret<String>>,
+    /// Mark the email as confirmed without sending a confirmation email.
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_confirm: Option<bool>,
+    /// Mark the phone as confirmed without sending a confirmation SMS.
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone_confirm: Option<bool>,
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_metadata: Option<UserMetadata>,
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_metadata: Option<AppMetadata>,
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    /// e.g. `24h`, or `none` to lift a ban.
+    #[builder(setter(strip_option), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_duration: Option<String>,
+}
+
 /// Data for updating a user's MFA factor.
 #[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
 pub struct MFAFactorUpdateData {
@@ -197,6 +299,11 @@ pub struct ErrorSchema {
     #[builder(setter(strip_option), default)]
     pub code: Option<i32>,
 
+    /// Machine readable error code, e.g. `reauthentication_needed` or `weak_password`.
+    #[serde(rename = "error_code")]
+    #[builder(setter(strip_option), default)]
+    pub error_code: Option<String>,
+
     /// A basic message describing the problem with the request. Usually missing if `error` is
     /// present.
     #[serde(rename = "msg")]
@@ -231,6 +338,11 @@ impl core::fmt::Display for ErrorSchema {
             write!(f, " (HTTP Code: {code})")?;
         }
 
+        // Append the machine readable error code if available
+        if let Some(ref error_code) = self.error_code {
+            write!(f, " [{error_code}]")?;
+        }
+
         // Append the basic message if available
         if let Some(ref msg) = self.msg {
             write!(f, ". Message: {msg}")?;
@@ -470,14 +582,20 @@ pub struct SAMLConfiguration {
 #[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
 pub struct AccessTokenResponseSchema {
     /// A valid JWT that will expire in `expires_in` seconds.
-    #[serde(rename = "access_token")]
-    #[builder(setter(strip_option), default)]
-    pub access_token: Option<String>,
+    #[serde(rename = "access_token", serialize_with = "redact::expose_secret")]
+    #[builder(
+        setter(transform = |access_token: String| Some(Secret::new(access_token))),
+        default
+    )]
+    pub access_token: Option<Secret<String>>,
 
     /// An opaque string that can be used once to obtain new tokens.
-    #[serde(rename = "refresh_token")]
-    #[builder(setter(strip_option), default)]
-    pub refresh_token: Option<String>,
+    #[serde(rename = "refresh_token", serialize_with = "redact::expose_secret")]
+    #[builder(
+        setter(transform = |refresh_token: String| Some(Secret::new(refresh_token))),
+        default
+    )]
+    pub refresh_token: Option<Secret<String>>,
 
     /// Token type, usually `bearer`.
     #[serde(rename = "token_type")]
@@ -503,6 +621,38 @@ pub struct AccessTokenResponseSchema {
     #[serde(rename = "user")]
     #[builder(setter(strip_option), default)]
     pub user: Option<UserSchema>,
+
+    /// OAuth access token of the upstream provider, for calling its APIs; only returned right
+    /// after an OAuth sign-in.
+    #[serde(
+        rename = "provider_token",
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "redact::expose_secret"
+    )]
+    #[builder(
+        setter(transform = |provider_token: String| Some(Secret::new(provider_token))),
+        default
+    )]
+    pub provider_token: Option<Secret<String>>,
+
+    /// OAuth refresh token of the upstream provider, if it issued one.
+    #[serde(
+        rename = "provider_refresh_token",
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "redact::expose_secret"
+    )]
+    #[builder(
+        setter(transform = |provider_refresh_token: String| Some(Secret::new(provider_refresh_token))),
+        default
+    )]
+    pub provider_refresh_token: Option<Secret<String>>,
+
+    /// Fields not covered by this schema, kept as returned by the server.
+    #[serde(flatten)]
+    #[builder(default)]
+    pub extra: HashMap<String, OwnedValue>,
 }
 
 /// Response indicating a weak password.
@@ -684,6 +834,38 @@ pub struct WebAuthnChallengeResponse {
     pub credential_creation_options: Option<CredentialCreationOptions>,
 }
 
+/// Relying party the `WebAuthn` ceremony is performed for.
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct WebAuthnParams {
+    /// Relying party id, usually the domain of the site (e.g. `example.com`).
+    pub rp_id: String,
+    /// Origins the browser may report, e.g. `https://example.com`.
+    #[builder(default)]
+    pub rp_origins: Vec<String>,
+}
+
+/// The `WebAuthn` ceremony a credential response belongs to.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebAuthnCeremony {
+    /// `navigator.credentials.create()`, used while enrolling.
+    Create,
+    /// `navigator.credentials.get()`, used for subsequent verifications.
+    Request,
+}
+
+/// `WebAuthn` part of a factor verification.
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct WebAuthnVerifyParams {
+    pub rp_id: String,
+    #[builder(default)]
+    pub rp_origins: Vec<String>,
+    #[serde(rename = "type")]
+    pub ceremony: WebAuthnCeremony,
+    /// The `PublicKeyCredential` produced by the browser, serialized as JSON.
+    pub credential_response: OwnedValue,
+}
+
 /// Type of the `WebAuthn` challenge.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -933,9 +1115,181 @@ pub struct SettingsResponse {
     #[builder(setter(strip_option), default)]
     pub sms_provider: Option<String>,
     pub saml_enabled: bool,
+    /// Which sign-in methods are enabled, keyed by [`ExternalProvider::as_str`]
     pub external: std::collections::HashMap<String, bool>,
 }
 
+impl SettingsResponse {
+    /// The enabled sign-in methods, sorted.
+    #[must_use]
+    pub fn enabled_providers(&self) -> Vec<ExternalProvider> {
+        let mut providers = self
+            .external
+            .iter()
+            .filter(|&(_, &enabled)| enabled)
+            .map(|(key, _)| ExternalProvider::from(key.as_str()))
+            .collect::<Vec<_>>();
+        providers.sort();
+        providers
+    }
+
+    #[must_use]
+    pub fn is_provider_enabled(&self, provider: &ExternalProvider) -> bool {
+        self.external
+            .get(provider.as_str())
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Whether new users can not sign up (existing ones can still sign in)
+    #[must_use]
+    pub const fn is_signup_disabled(&self) -> bool {
+        self.disable_signup
+    }
+}
+
+/// A sign-in method listed in [`SettingsResponse::external`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExternalProvider {
+    AnonymousUsers,
+    Email,
+    Phone,
+    OAuth(Provider),
+}
+
+impl ExternalProvider {
+    /// The key used by GoTrue
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match *self {
+            Self::AnonymousUsers => "anonymous_users",
+            Self::Email => "email",
+            Self::Phone => "phone",
+            Self::OAuth(ref provider) => provider.as_str(),
+        }
+    }
+}
+
+impl From<&str> for ExternalProvider {
+    fn from(key: &str) -> Self {
+        match key {
+            "anonymous_users" => Self::AnonymousUsers,
+            "email" => Self::Email,
+            "phone" => Self::Phone,
+            other => Self::OAuth(Provider::from(other)),
+        }
+    }
+}
+
+impl From<Provider> for ExternalProvider {
+    fn from(provider: Provider) -> Self {
+        Self::OAuth(provider)
+    }
+}
+
+impl core::fmt::Display for ExternalProvider {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The provider of OAuth sign-ins and identity links.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Provider {
+    Apple,
+    Azure,
+    Bitbucket,
+    Discord,
+    Facebook,
+    Figma,
+    Fly,
+    Github,
+    Gitlab,
+    Google,
+    Kakao,
+    Keycloak,
+    Linkedin,
+    LinkedinOidc,
+    Notion,
+    Slack,
+    SlackOidc,
+    Spotify,
+    Twitch,
+    Twitter,
+    Workos,
+    Zoom,
+    /// A provider this crate does not know about yet
+    Other(String),
+}
+
+impl Provider {
+    /// The `provider` of OAuth sign-ins, also the key used in [`SettingsResponse::external`]
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match *self {
+            Self::Apple => "apple",
+            Self::Azure => "azure",
+            Self::Bitbucket => "bitbucket",
+            Self::Discord => "discord",
+            Self::Facebook => "facebook",
+            Self::Figma => "figma",
+            Self::Fly => "fly",
+            Self::Github => "github",
+            Self::Gitlab => "gitlab",
+            Self::Google => "google",
+            Self::Kakao => "kakao",
+            Self::Keycloak => "keycloak",
+            Self::Linkedin => "linkedin",
+            Self::LinkedinOidc => "linkedin_oidc",
+            Self::Notion => "notion",
+            Self::Slack => "slack",
+            Self::SlackOidc => "slack_oidc",
+            Self::Spotify => "spotify",
+            Self::Twitch => "twitch",
+            Self::Twitter => "twitter",
+            Self::Workos => "workos",
+            Self::Zoom => "zoom",
+            Self::Other(ref provider) => provider,
+        }
+    }
+}
+
+impl From<&str> for Provider {
+    fn from(key: &str) -> Self {
+        match key {
+            "apple" => Self::Apple,
+            "azure" => Self::Azure,
+            "bitbucket" => Self::Bitbucket,
+            "discord" => Self::Discord,
+            "facebook" => Self::Facebook,
+            "figma" => Self::Figma,
+            "fly" => Self::Fly,
+            "github" => Self::Github,
+            "gitlab" => Self::Gitlab,
+            "google" => Self::Google,
+            "kakao" => Self::Kakao,
+            "keycloak" => Self::Keycloak,
+            "linkedin" => Self::Linkedin,
+            "linkedin_oidc" => Self::LinkedinOidc,
+            "notion" => Self::Notion,
+            "slack" => Self::Slack,
+            "slack_oidc" => Self::SlackOidc,
+            "spotify" => Self::Spotify,
+            "twitch" => Self::Twitch,
+            "twitter" => Self::Twitter,
+            "workos" => Self::Workos,
+            "zoom" => Self::Zoom,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+}
+
+impl core::fmt::Display for Provider {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// Audit log entry
 #[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
 pub struct AuditLogEntry {
@@ -964,3 +1318,87 @@ pub struct AuditLogPayload {
     #[builder(setter(strip_option), default)]
     pub log_type: Option<String>,
 }
+
+/// Response from the `/.well-known/jwks.json` endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct JwksResponse {
+    pub keys: Vec<Jwk>,
+}
+
+/// A single JSON Web Key used to sign access tokens.
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct Jwk {
+    /// Key type, `EC` or `RSA`.
+    pub kty: String,
+    #[builder(setter(strip_option), default)]
+    pub kid: Option<String>,
+    #[builder(setter(strip_option), default)]
+    pub alg: Option<String>,
+    /// Curve of an `EC` key, e.g. `P-256`.
+    #[builder(setter(strip_option), default)]
+    pub crv: Option<String>,
+    #[builder(setter(strip_option), default)]
+    pub x: Option<String>,
+    #[builder(setter(strip_option), default)]
+    pub y: Option<String>,
+    /// Modulus of an `RSA` key.
+    #[builder(setter(strip_option), default)]
+    pub n: Option<String>,
+    /// Exponent of an `RSA` key.
+    #[builder(setter(strip_option), default)]
+    pub e: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_settings_providers() {
+        let mut body = br#"{
+            "disable_signup": true,
+            "mailer_autoconfirm": false,
+            "phone_autoconfirm": false,
+            "saml_enabled": false,
+            "external": {"email": true, "phone": false, "github": true, "new_idp": true}
+        }"#
+        .to_vec();
+        let settings = simd_json::from_slice::<SettingsResponse>(&mut body).unwrap();
+
+        assert!(settings.is_signup_disabled());
+        assert!(settings.is_provider_enabled(&Provider::Github.into()));
+        assert!(!settings.is_provider_enabled(&ExternalProvider::Phone));
+        assert!(!settings.is_provider_enabled(&Provider::Google.into()));
+        assert_eq!(
+            settings.enabled_providers(),
+            vec![
+                ExternalProvider::Email,
+                ExternalProvider::OAuth(Provider::Github),
+                ExternalProvider::OAuth(Provider::Other("new_idp".to_owned())),
+            ]
+        );
+    }
+    #[test]
+    fn test_token_request_secrets_are_redacted() {
+        let body = TokenRequestBody::builder()
+            .client_secret("client-secret".to_owned())
+            .id_token("id-token".to_owned())
+            .provider_token("provider-token".to_owned())
+            .code_verifier("code-verifier".to_owned())
+            .build();
+
+        let debug = format!("{body:?}");
+        let json = simd_json::to_string(&body).unwrap();
+        for secret in [
+            "client-secret",
+            "id-token",
+            "provider-token",
+            "code-verifier",
+        ] {
+            assert!(!debug.contains(secret), "{debug}");
+            assert!(json.contains(secret), "{json}");
+        }
+    }
+}
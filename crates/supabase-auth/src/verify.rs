@@ -0,0 +1,508 @@
+//! Local verification of Supabase access tokens against the project's JWKS.
+//!
+//! [`JwksVerifier`] fetches `/auth/v1/.well-known/jwks.json`, caches the signing keys and checks
+//! the signature, expiry and audience of tokens without a round-trip to the auth server. The
+//! expected issuer and the accepted signing algorithms can be pinned as well.
+//!
+//! Tokens signed with a key id that is not cached trigger a refetch of the JWKS, so rotated
+//! signing keys are picked up without a restart. Refetches for key ids that stay unknown back off
+//! exponentially, so forged key ids cannot be used to flood the auth server.
+
+use core::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use base64::prelude::*;
+pub use jwt_simple::claims::JWTClaims;
+use jwt_simple::prelude::{
+    ECDSAP256PublicKeyLike as _, ES256PublicKey, NoCustomClaims, RS256PublicKey,
+    RSAPublicKeyLike as _, Token, VerificationOptions,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+use web_time::Instant;
+
+use crate::auth_client::requests::JwksRequest;
+use crate::auth_client::ApiClient;
+use crate::claims::{decode_claims, AccessTokenClaims, ClaimsError};
+use crate::error::{AuthApiError, AuthError};
+use crate::types::Jwk;
+
+/// The audience Supabase puts into tokens of signed in users
+pub const DEFAULT_AUDIENCE: &str = "authenticated";
+
+/// How long fetched keys are trusted before the JWKS is fetched again
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// Unknown key ids trigger a refetch at most this often (keys may have been rotated)
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Upper bound for the refetch interval after repeated refetches that did not find the key
+const MAX_REFETCH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Allowed clock drift when checking `exp`
+const DEFAULT_TIME_TOLERANCE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Supabase API error: {0}")]
+    Api(#[from] AuthApiError),
+    #[error("Token header does not contain a key id")]
+    MissingKeyId,
+    #[error("No JWKS key with id {0}")]
+    UnknownKey(String),
+    #[error("Token signed with disallowed algorithm {0}")]
+    DisallowedAlgorithm(String),
+    #[error("Invalid token: {0}")]
+    InvalidToken(jwt_simple::Error),
+    #[error(transparent)]
+    Claims(#[from] ClaimsError),
+}
+
+/// Signing algorithms of JWKS keys that [`JwksVerifier`] can check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Es256,
+    Rs256,
+}
+
+impl Algorithm {
+    /// The `alg` header value
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Es256 => "ES256",
+            Self::Rs256 => "RS256",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum VerificationKey {
+    Es256(ES256PublicKey),
+    Rs256(Box<RS256PublicKey>),
+}
+
+impl VerificationKey {
+    /// Returns `None` for keys of unsupported algorithms or malformed keys
+    fn from_jwk(jwk: &Jwk) -> Option<Self> {
+        let decode = |value: &Option<String>| BASE64_URL_SAFE_NO_PAD.decode(value.as_ref()?).ok();
+        match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("EC", Some("P-256")) => {
+                let mut point = vec![0x04];
+                point.extend(decode(&jwk.x)?);
+                point.extend(decode(&jwk.y)?);
+                ES256PublicKey::from_bytes(&point).ok().map(Self::Es256)
+            }
+            ("RSA", _) => RS256PublicKey::from_components(&decode(&jwk.n)?, &decode(&jwk.e)?)
+                .ok()
+                .map(|key| Self::Rs256(Box::new(key))),
+            _ => None,
+        }
+    }
+
+    fn verify<C>(
+        &self,
+        token: &str,
+        options: VerificationOptions,
+    ) -> Result<JWTClaims<C>, jwt_simple::Error>
+    where
+        C: Serialize + DeserializeOwned,
+    {
+        match *self {
+            Self::Es256(ref key) => key.verify_token(token, Some(options)),
+            Self::Rs256(ref key) => key.verify_token(token, Some(options)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyCache {
+    keys: HashMap<String, Arc<VerificationKey>>,
+    fetched_at: Option<Instant>,
+    /// Refetches in a row that did not yield the requested key id
+    misses: u32,
+}
+
+/// Verifies access tokens with the keys published by the auth server.
+#[derive(Debug, Clone)]
+pub struct JwksVerifier {
+    client: ApiClient,
+    audience: String,
+    issuer: Option<String>,
+    algorithms: HashSet<Algorithm>,
+    cache_ttl: Duration,
+    time_tolerance: Duration,
+    min_refetch_interval: Duration,
+    cache: Arc<Mutex<KeyCache>>,
+}
+
+impl JwksVerifier {
+    /// # Errors
+    ///
+    /// Returns an error if the supabase url cannot be joined with the auth suffix.
+    pub fn new(url: url::Url, api_key: &str) -> Result<Self, AuthError> {
+        Ok(Self {
+            client: ApiClient::new_unauthenticated(url, api_key)?,
+            audience: DEFAULT_AUDIENCE.to_owned(),
+            issuer: None,
+            algorithms: HashSet::from([Algorithm::Es256, Algorithm::Rs256]),
+            cache_ttl: DEFAULT_CACHE_TTL,
+            time_tolerance: DEFAULT_TIME_TOLERANCE,
+            min_refetch_interval: MIN_REFETCH_INTERVAL,
+            cache: Arc::default(),
+        })
+    }
+
+    #[must_use]
+    pub fn with_audience(mut self, audience: String) -> Self {
+        self.audience = audience;
+        self
+    }
+
+    /// Only accepts tokens whose `iss` claim is `issuer`; Supabase issues tokens as
+    /// `<project url>/auth/v1`. Not checked by default.
+    #[must_use]
+    pub fn with_issuer(mut self, issuer: String) -> Self {
+        self.issuer = Some(issuer);
+        self
+    }
+
+    /// Only accepts tokens signed with one of `algorithms`; by default all supported
+    /// algorithms are.
+    #[must_use]
+    pub fn with_algorithms(mut self, algorithms: impl IntoIterator<Item = Algorithm>) -> Self {
+        self.algorithms = algorithms.into_iter().collect();
+        self
+    }
+
+    #[must_use]
+    pub const fn with_cache_ttl(mut self, cache_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_time_tolerance(mut self, time_tolerance: Duration) -> Self {
+        self.time_tolerance = time_tolerance;
+        self
+    }
+
+    /// How long to wait before refetching the JWKS for an unknown key id; doubled after every
+    /// refetch that did not find the key, up to 5 minutes.
+    #[must_use]
+    pub const fn with_min_refetch_interval(mut self, interval: Duration) -> Self {
+        self.min_refetch_interval = interval;
+        self
+    }
+
+    /// Verifies the signature, algorithm, expiry, audience and (if configured) issuer of `token`
+    /// and returns its claims.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the keys cannot be fetched, the signing key is unknown, the algorithm
+    /// is not allowed or the token is invalid.
+    pub async fn verify<C>(&self, token: &str) -> Result<JWTClaims<C>, VerifyError>
+    where
+        C: Serialize + DeserializeOwned,
+    {
+        let metadata = Token::decode_metadata(token).map_err(VerifyError::InvalidToken)?;
+        let allowed = self
+            .algorithms
+            .iter()
+            .any(|algorithm| algorithm.as_str() == metadata.algorithm());
+        if !allowed {
+            return Err(VerifyError::DisallowedAlgorithm(
+                metadata.algorithm().to_owned(),
+            ));
+        }
+        let key_id = metadata.key_id().ok_or(VerifyError::MissingKeyId)?;
+
+        let key = match self.cached_key(key_id) {
+            Some(key) => key,
+            None => self.refetch_key(key_id).await?,
+        };
+
+        let options = VerificationOptions {
+            allowed_audiences: Some(HashSet::from([self.audience.clone()])),
+            allowed_issuers: self
+                .issuer
+                .as_ref()
+                .map(|issuer| HashSet::from([issuer.clone()])),
+            time_tolerance: Some(self.time_tolerance.into()),
+            ..VerificationOptions::default()
+        };
+        key.verify(token, options)
+            .map_err(VerifyError::InvalidToken)
+    }
+
+    /// Same as [`JwksVerifier::verify`], returning the Supabase specific claims.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is invalid or does not contain the expected claims.
+    pub async fn verify_access_token(&self, token: &str) -> Result<AccessTokenClaims, VerifyError> {
+        self.verify::<NoCustomClaims>(token).await?;
+        Ok(decode_claims(token)?)
+    }
+
+    /// Returns the key if it is cached and the cache has not expired
+    fn cached_key(&self, key_id: &str) -> Option<Arc<VerificationKey>> {
+        let cache = self.cache.lock().ok()?;
+        let fresh = cache
+            .fetched_at
+            .is_some_and(|fetched_at| fetched_at.elapsed() < self.cache_ttl);
+        fresh.then(|| cache.keys.get(key_id).cloned()).flatten()
+    }
+
+    /// Refetches the JWKS for a key id that is not cached, unless that happened too recently
+    async fn refetch_key(&self, key_id: &str) -> Result<Arc<VerificationKey>, VerifyError> {
+        let recently_fetched = self.cache.lock().ok().is_some_and(|cache| {
+            let interval = self
+                .min_refetch_interval
+                .saturating_mul(2_u32.saturating_pow(cache.misses))
+                .min(MAX_REFETCH_INTERVAL)
+                .min(self.cache_ttl);
+            cache
+                .fetched_at
+                .is_some_and(|fetched_at| fetched_at.elapsed() < interval)
+        });
+        if recently_fetched {
+            return Err(VerifyError::UnknownKey(key_id.to_owned()));
+        }
+
+        self.refresh_keys().await?;
+        let key = self.cached_key(key_id);
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.misses = match key {
+                Some(_) => 0,
+                None => cache.misses.saturating_add(1),
+            };
+        }
+        key.ok_or_else(|| VerifyError::UnknownKey(key_id.to_owned()))
+    }
+
+    async fn refresh_keys(&self) -> Result<(), VerifyError> {
+        let jwks = self
+            .client
+            .build_request(&JwksRequest)?
+            .execute()
+            .await?
+            .json()
+            .await??;
+        let keys = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let key_id = jwk.kid.clone()?;
+                let Some(key) = VerificationKey::from_jwk(jwk) else {
+                    tracing::debug!(?key_id, kty = jwk.kty, "skipping unsupported JWKS key");
+                    return None;
+                };
+                Some((key_id, Arc::new(key)))
+            })
+            .collect();
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.keys = keys;
+            cache.fetched_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jwt_simple::prelude::{Claims, ECDSAP256KeyPairLike as _, ES256KeyPair};
+    use mockito::Matcher;
+    use pretty_assertions::assert_eq;
+    use rp_supabase_mock::SupabaseMockServer;
+    use test_log::test;
+
+    use super::*;
+
+    fn jwk(key_pair: &ES256KeyPair, key_id: &str) -> String {
+        let point = key_pair.public_key().public_key().to_bytes_uncompressed();
+        let (x, y) = point[1..].split_at(32);
+        format!(
+            r#"{{"kty": "EC", "crv": "P-256", "alg": "ES256", "kid": "{key_id}", "x": "{}", "y": "{}"}}"#,
+            BASE64_URL_SAFE_NO_PAD.encode(x),
+            BASE64_URL_SAFE_NO_PAD.encode(y),
+        )
+    }
+
+    fn jwks_body(key_pair: &ES256KeyPair, key_id: &str) -> String {
+        format!(r#"{{"keys": [{}]}}"#, jwk(key_pair, key_id))
+    }
+
+    fn sign(key_pair: &ES256KeyPair) -> String {
+        let claims = Claims::create(jwt_simple::prelude::Duration::from_hours(1))
+            .with_audience(DEFAULT_AUDIENCE)
+            .with_subject("user-id");
+        key_pair.sign(claims).unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn test_verify_with_jwks() {
+        let key_pair = ES256KeyPair::generate().with_key_id("key-1");
+        let mut m = SupabaseMockServer::new().await;
+        let jwks = m
+            .mockito_server
+            .mock("GET", "/auth/v1/.well-known/jwks.json")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(jwks_body(&key_pair, "key-1"))
+            .expect(1)
+            .create();
+        let verifier = JwksVerifier::new(m.server_url(), "api-key").unwrap();
+
+        let claims = Claims::create(jwt_simple::prelude::Duration::from_hours(1))
+            .with_audience(DEFAULT_AUDIENCE)
+            .with_subject("user-id");
+        let token = key_pair.sign(claims).unwrap();
+        let verified = verifier.verify::<NoCustomClaims>(&token).await.unwrap();
+        assert_eq!(verified.subject.unwrap(), "user-id");
+        let claims = verifier.verify_access_token(&token).await.unwrap();
+        assert_eq!(claims.sub, "user-id");
+
+        // the key is cached
+        jwks.assert();
+
+        let wrong_audience = Claims::create(jwt_simple::prelude::Duration::from_hours(1))
+            .with_audience("anon-service");
+        let token = key_pair.sign(wrong_audience).unwrap();
+        assert!(matches!(
+            verifier.verify::<NoCustomClaims>(&token).await,
+            Err(VerifyError::InvalidToken(_))
+        ));
+
+        let mut expired = Claims::create(jwt_simple::prelude::Duration::from_secs(0))
+            .with_audience(DEFAULT_AUDIENCE);
+        expired.expires_at = expired
+            .issued_at
+            .map(|issued_at| issued_at - jwt_simple::prelude::Duration::from_mins(5));
+        let token = key_pair.sign(expired).unwrap();
+        assert!(matches!(
+            verifier.verify::<NoCustomClaims>(&token).await,
+            Err(VerifyError::InvalidToken(_))
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn test_refetch_on_key_rotation() {
+        let old_key = ES256KeyPair::generate().with_key_id("key-1");
+        let new_key = ES256KeyPair::generate().with_key_id("key-2");
+        let mut m = SupabaseMockServer::new().await;
+        let before_rotation = m
+            .mockito_server
+            .mock("GET", "/auth/v1/.well-known/jwks.json")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(jwks_body(&old_key, "key-1"))
+            .expect(1)
+            .create();
+        let after_rotation = m
+            .mockito_server
+            .mock("GET", "/auth/v1/.well-known/jwks.json")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"keys": [{}, {}]}}"#,
+                jwk(&old_key, "key-1"),
+                jwk(&new_key, "key-2")
+            ))
+            .expect(1)
+            .create();
+        let verifier = JwksVerifier::new(m.server_url(), "api-key")
+            .unwrap()
+            .with_min_refetch_interval(Duration::ZERO);
+
+        verifier.verify_access_token(&sign(&old_key)).await.unwrap();
+        let claims = verifier.verify_access_token(&sign(&new_key)).await.unwrap();
+        assert_eq!(claims.sub, "user-id");
+        before_rotation.assert();
+        after_rotation.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_unknown_key_refetch_backs_off() {
+        let key_pair = ES256KeyPair::generate().with_key_id("key-1");
+        let forged = ES256KeyPair::generate().with_key_id("forged");
+        let mut m = SupabaseMockServer::new().await;
+        let jwks = m
+            .mockito_server
+            .mock("GET", "/auth/v1/.well-known/jwks.json")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(jwks_body(&key_pair, "key-1"))
+            .expect(2)
+            .create();
+        let verifier = JwksVerifier::new(m.server_url(), "api-key")
+            .unwrap()
+            .with_min_refetch_interval(Duration::from_millis(200));
+        let token = sign(&forged);
+
+        // fetched; the next refetch is allowed after 400ms
+        assert!(matches!(
+            verifier.verify_access_token(&token).await,
+            Err(VerifyError::UnknownKey(_))
+        ));
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(matches!(
+            verifier.verify_access_token(&token).await,
+            Err(VerifyError::UnknownKey(_))
+        ));
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(matches!(
+            verifier.verify_access_token(&token).await,
+            Err(VerifyError::UnknownKey(_))
+        ));
+        jwks.assert();
+    }
+
+    #[test(tokio::test)]
+    async fn test_pinned_issuer_and_algorithms() {
+        let key_pair = ES256KeyPair::generate().with_key_id("key-1");
+        let mut m = SupabaseMockServer::new().await;
+        let _jwks = m
+            .mockito_server
+            .mock("GET", "/auth/v1/.well-known/jwks.json")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(jwks_body(&key_pair, "key-1"))
+            .create();
+        let issuer = "https://project.supabase.co/auth/v1";
+        let verifier = JwksVerifier::new(m.server_url(), "api-key")
+            .unwrap()
+            .with_issuer(issuer.to_owned());
+
+        let claims = Claims::create(jwt_simple::prelude::Duration::from_hours(1))
+            .with_audience(DEFAULT_AUDIENCE)
+            .with_issuer(issuer);
+        let token = key_pair.sign(claims).unwrap();
+        verifier.verify::<NoCustomClaims>(&token).await.unwrap();
+
+        let other_project = Claims::create(jwt_simple::prelude::Duration::from_hours(1))
+            .with_audience(DEFAULT_AUDIENCE)
+            .with_issuer("https://other.supabase.co/auth/v1");
+        let forged = key_pair.sign(other_project).unwrap();
+        assert!(matches!(
+            verifier.verify::<NoCustomClaims>(&forged).await,
+            Err(VerifyError::InvalidToken(_))
+        ));
+
+        let rsa_only = verifier.with_algorithms([Algorithm::Rs256]);
+        assert!(matches!(
+            rsa_only.verify::<NoCustomClaims>(&token).await,
+            Err(VerifyError::DisallowedAlgorithm(ref algorithm)) if algorithm == "ES256"
+        ));
+    }
+}
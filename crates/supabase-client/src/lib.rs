@@ -2,12 +2,15 @@ use core::marker::PhantomData;
 
 use futures::{Stream, StreamExt as _};
 use rp_postgrest::{reqwest, Postgrest};
+use rp_supabase_auth::error::{is_empty_api_error, non_json_body_snippet};
 use rp_supabase_auth::jwt_stream::SupabaseAuthConfig;
 use rp_supabase_auth::types::{AccessTokenResponseSchema, LoginCredentials};
 use rp_supabase_auth::url;
 use tracing::instrument;
 pub use {rp_postgrest, rp_postgrest_error, rp_supabase_auth};
 
+pub mod prepared;
+
 pub struct PostgerstResponse<T> {
     response: reqwest::Response,
     result: PhantomData<T>,
@@ -24,13 +27,13 @@ pub fn new_authenticated(
     >,
     SupabaseClientError,
 > {
-    let base = anonymous_client(config.api_key.clone(), config.url.clone())?;
+    let base = anonymous_client(config.api_key.expose_secret().clone(), config.url.clone())?;
     let auth_stream = rp_supabase_auth::jwt_stream::JwtStream::new(config).sign_in(login_info)?;
     let client_stream = auth_stream.map(move |item| {
         item.map(|item| {
             let mut client = base.clone();
             if let Some(access_token) = item.access_token.as_ref() {
-                client = client.auth(access_token);
+                client = client.auth(access_token.expose_secret());
             }
             (client, item)
         })
@@ -46,6 +49,18 @@ pub fn anonymous_client(api_key: String, url: url::Url) -> Result<Postgrest, Sup
     Ok(postgrest)
 }
 
+/// A client authenticated with the project's service-role key, which bypasses row level
+/// security.
+///
+/// The key does not expire, so unlike [`new_authenticated`] no refresh stream is needed.
+pub fn service_role_client(
+    service_role_key: &str,
+    url: url::Url,
+) -> Result<Postgrest, SupabaseClientError> {
+    let client = anonymous_client(service_role_key.to_owned(), url)?;
+    Ok(client.auth(service_role_key))
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum SupabaseClientError {
     #[error("Jwt Stream closed unexpectedly")]
@@ -86,8 +101,9 @@ impl<T> PostgerstResponse<T> {
         if status.is_success() {
             Ok(Ok(()))
         } else {
+            let content_type = content_type(&self.response);
             let bytes = self.response.bytes().await?.to_vec();
-            let error = parse_postgrest_error(bytes, status)?;
+            let error = parse_postgrest_error(bytes, status, content_type.as_deref())?;
             let error = rp_postgrest_error::Error::from_error_response(error);
             Ok(Err(error))
         }
@@ -100,6 +116,7 @@ impl<T> PostgerstResponse<T> {
         T: serde::de::DeserializeOwned,
     {
         let status = self.response.status();
+        let content_type = content_type(&self.response);
         let mut bytes = self.response.bytes().await?.to_vec();
         if status.is_success() {
             let json = String::from_utf8_lossy(bytes.as_ref());
@@ -108,16 +125,25 @@ impl<T> PostgerstResponse<T> {
             let result = simd_json::from_slice::<T>(bytes.as_mut())?;
             Ok(Ok(result))
         } else {
-            let error = parse_postgrest_error(bytes, status)?;
+            let error = parse_postgrest_error(bytes, status, content_type.as_deref())?;
             let error = rp_postgrest_error::Error::from_error_response(error);
             Ok(Err(error))
         }
     }
 }
 
+fn content_type(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned)
+}
+
 fn parse_postgrest_error<E>(
     mut bytes: Vec<u8>,
     status: reqwest::StatusCode,
+    content_type: Option<&str>,
 ) -> Result<E, IntrenalError>
 where
     E: serde::de::DeserializeOwned,
@@ -129,6 +155,22 @@ where
         "Failed to execute request"
     );
 
+    if is_empty_api_error(status.as_u16(), &bytes) {
+        // a three digit code is neither a PostgREST nor a Postgres one, so this is a custom error
+        let response = rp_postgrest_error::ErrorResponse {
+            message: status.canonical_reason().unwrap_or_default().to_owned(),
+            code: status.as_str().to_owned(),
+            details: None,
+            hint: None,
+        };
+        bytes = simd_json::to_vec(&response)?;
+    } else if let Some(body_snippet) = non_json_body_snippet(content_type, &bytes) {
+        return Err(IntrenalError::GatewayError {
+            status,
+            body_snippet,
+        });
+    }
+
     let error = simd_json::from_slice::<E>(bytes.as_mut())?;
     Ok(error)
 }
@@ -139,4 +181,59 @@ pub enum IntrenalError {
     SimdJsonError(#[from] simd_json::Error),
     #[error("reqwest {0}")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("Gateway error {status}: {body_snippet}")]
+    GatewayError {
+        status: reqwest::StatusCode,
+        body_snippet: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn parse(
+        status: u16,
+        content_type: Option<&str>,
+        body: &str,
+    ) -> Result<rp_postgrest_error::Error, IntrenalError> {
+        let status = reqwest::StatusCode::from_u16(status).unwrap();
+        parse_postgrest_error(body.as_bytes().to_vec(), status, content_type)
+            .map(rp_postgrest_error::Error::from_error_response)
+    }
+
+    #[test]
+    fn test_error_mapping() {
+        let err = parse(
+            404,
+            Some("application/json"),
+            r#"{"code": "42P01", "message": "relation \"missing\" does not exist", "details": null, "hint": null}"#,
+        )
+        .unwrap();
+        assert!(
+            matches!(err, rp_postgrest_error::Error::PostgresError(_)),
+            "{err:?}"
+        );
+
+        // an empty body is classified by its status
+        let err = parse(401, None, "").unwrap();
+        let rp_postgrest_error::Error::CustomError(err) = err else {
+            panic!("expected a custom error, got {err:?}");
+        };
+        assert_eq!(err.code, "401");
+        assert_eq!(err.message, "Unauthorized");
+
+        let err = parse(502, Some("text/html"), "<h1>502 Bad Gateway</h1>").unwrap_err();
+        assert!(
+            matches!(err, IntrenalError::GatewayError { ref body_snippet, .. } if body_snippet == "<h1>502 Bad Gateway</h1>"),
+            "{err:?}"
+        );
+        let err = parse(503, None, "").unwrap_err();
+        assert!(
+            matches!(err, IntrenalError::GatewayError { status, .. } if status == reqwest::StatusCode::SERVICE_UNAVAILABLE),
+            "{err:?}"
+        );
+    }
 }
@@ -0,0 +1,402 @@
+//! Prepared query templates with positional placeholders.
+//!
+//! A [`PreparedQuery`] is built once (table, columns, filters, ordering, limit) and can then be
+//! bound to a set of values for every execution:
+//!
+//! ```
+//! use rp_supabase_client::prepared::{param, PreparedQuery};
+//!
+//! let query = PreparedQuery::select("messages", "*")
+//!     .eq("channel_id", param(1))
+//!     .order("created_at.desc")
+//!     .limit(param(2));
+//! assert_eq!(
+//!     query.template_key(),
+//!     "messages?select=*&channel_id=eq.$1&order=created_at.desc&limit=$2"
+//! );
+//! ```
+
+use core::fmt::Write as _;
+
+use rp_postgrest::{Builder, Postgrest};
+
+/// A value inside a query template: either fixed when the template is built or a positional
+/// placeholder that is filled in during [`PreparedQuery::bind`].
+///
+/// Displays as the placeholder (`$1`) or the literal, with a literal `$` doubled so the two cannot
+/// be confused.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Arg {
+    Literal(String),
+    /// 1-based placeholder index (`$1`, `$2`, ...)
+    Param(usize),
+}
+
+/// Creates the `$index` placeholder (1-based, like SQL prepared statements).
+#[must_use]
+pub const fn param(index: usize) -> Arg {
+    Arg::Param(index)
+}
+
+impl From<&str> for Arg {
+    fn from(value: &str) -> Self {
+        Self::Literal(value.to_owned())
+    }
+}
+
+impl From<String> for Arg {
+    fn from(value: String) -> Self {
+        Self::Literal(value)
+    }
+}
+
+impl core::fmt::Display for Arg {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Self::Literal(ref value) => write!(f, "{}", value.replace('$', "$$")),
+            Self::Param(index) => write!(f, "${index}"),
+        }
+    }
+}
+
+/// Filter operators supported in query templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operator {
+    Eq,
+    Neq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+    Ilike,
+    Is,
+    In,
+}
+
+impl Operator {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Eq => "eq",
+            Self::Neq => "neq",
+            Self::Gt => "gt",
+            Self::Gte => "gte",
+            Self::Lt => "lt",
+            Self::Lte => "lte",
+            Self::Like => "like",
+            Self::Ilike => "ilike",
+            Self::Is => "is",
+            Self::In => "in",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Filter {
+    column: String,
+    operator: Operator,
+    arg: Arg,
+}
+
+/// A reusable `select` query with placeholders.
+///
+/// Prepared queries compare and hash by their template, so they can be used as cache keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PreparedQuery {
+    table: String,
+    columns: String,
+    filters: Vec<Filter>,
+    order: Option<String>,
+    limit: Option<Arg>,
+}
+
+impl PreparedQuery {
+    #[must_use]
+    pub fn select(table: impl Into<String>, columns: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            columns: columns.into(),
+            filters: Vec::new(),
+            order: None,
+            limit: None,
+        }
+    }
+
+    #[must_use]
+    pub fn filter(
+        mut self,
+        column: impl Into<String>,
+        operator: Operator,
+        arg: impl Into<Arg>,
+    ) -> Self {
+        self.filters.push(Filter {
+            column: column.into(),
+            operator,
+            arg: arg.into(),
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn eq(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Eq, arg)
+    }
+
+    #[must_use]
+    pub fn neq(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Neq, arg)
+    }
+
+    #[must_use]
+    pub fn gt(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Gt, arg)
+    }
+
+    #[must_use]
+    pub fn gte(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Gte, arg)
+    }
+
+    #[must_use]
+    pub fn lt(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Lt, arg)
+    }
+
+    #[must_use]
+    pub fn lte(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Lte, arg)
+    }
+
+    #[must_use]
+    pub fn like(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Like, arg)
+    }
+
+    #[must_use]
+    pub fn ilike(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Ilike, arg)
+    }
+
+    #[must_use]
+    pub fn is(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::Is, arg)
+    }
+
+    /// `in` filter; the bound value is a comma separated list (`1,2,3`), values containing commas
+    /// are double quoted like in PostgREST (`"Paris, France",Berlin`)
+    #[must_use]
+    pub fn in_(self, column: impl Into<String>, arg: impl Into<Arg>) -> Self {
+        self.filter(column, Operator::In, arg)
+    }
+
+    /// Orders the result, e.g. `created_at.desc`
+    #[must_use]
+    pub fn order(mut self, columns: impl Into<String>) -> Self {
+        self.order = Some(columns.into());
+        self
+    }
+
+    #[must_use]
+    pub fn limit(mut self, arg: impl Into<Arg>) -> Self {
+        self.limit = Some(arg.into());
+        self
+    }
+
+    /// The number of values [`PreparedQuery::bind`] expects (the highest placeholder index).
+    #[must_use]
+    pub fn param_count(&self) -> usize {
+        self.filters
+            .iter()
+            .map(|filter| &filter.arg)
+            .chain(self.limit.as_ref())
+            .filter_map(|arg| match *arg {
+                Arg::Param(index) => Some(index),
+                Arg::Literal(_) => None,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Renders the template in PostgREST query syntax with the placeholders left in place.
+    ///
+    /// A literal `$` is rendered as `$$`, so literals never read as placeholders.
+    #[must_use]
+    pub fn template_key(&self) -> String {
+        let mut key = format!("{}?select={}", self.table, self.columns);
+        // writing into a `String` cannot fail
+        for filter in &self.filters {
+            let _ = write!(
+                key,
+                "&{}={}.{}",
+                filter.column,
+                filter.operator.as_str(),
+                filter.arg
+            );
+        }
+        if let Some(ref order) = self.order {
+            let _ = write!(key, "&order={order}");
+        }
+        if let Some(ref limit) = self.limit {
+            let _ = write!(key, "&limit={limit}");
+        }
+        key
+    }
+
+    /// Binds the placeholder values and returns a request builder ready to be sent.
+    ///
+    /// `params[0]` is bound to `$1`, `params[1]` to `$2` and so on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a placeholder has no value or the bound limit is not a positive number.
+    pub fn bind<S: AsRef<str>>(
+        &self,
+        client: &Postgrest,
+        params: &[S],
+    ) -> Result<Builder, PreparedQueryError> {
+        let resolve = |arg: &Arg| -> Result<String, PreparedQueryError> {
+            match *arg {
+                Arg::Literal(ref value) => Ok(value.clone()),
+                Arg::Param(index) => index
+                    .checked_sub(1)
+                    .and_then(|idx| params.get(idx))
+                    .map(|value| value.as_ref().to_owned())
+                    .ok_or(PreparedQueryError::MissingParameter(index)),
+            }
+        };
+
+        let mut builder = client.from(&self.table).select(self.columns.clone());
+        for filter in &self.filters {
+            let value = resolve(&filter.arg)?;
+            let column = filter.column.as_str();
+            builder = match filter.operator {
+                Operator::Eq => builder.eq(column, value),
+                Operator::Neq => builder.neq(column, value),
+                Operator::Gt => builder.gt(column, value),
+                Operator::Gte => builder.gte(column, value),
+                Operator::Lt => builder.lt(column, value),
+                Operator::Lte => builder.lte(column, value),
+                Operator::Like => builder.like(column, value),
+                Operator::Ilike => builder.ilike(column, value),
+                Operator::Is => builder.is(column, value),
+                Operator::In => builder.in_(column, split_list(&value)),
+            };
+        }
+        if let Some(ref order) = self.order {
+            builder = builder.order(order.clone());
+        }
+        if let Some(ref limit) = self.limit {
+            let value = resolve(limit)?;
+            let count = value
+                .parse::<usize>()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or(PreparedQueryError::InvalidLimit(value))?;
+            builder = builder.limit(count);
+        }
+        Ok(builder)
+    }
+}
+
+/// Splits a PostgREST list on the commas outside of double quotes, keeping the quotes (and the
+/// backslash escapes inside them) so PostgREST reads every item as one value.
+fn split_list(list: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, ch) in list.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&list[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&list[start..]);
+    items
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum PreparedQueryError {
+    #[error("No value bound for placeholder ${0}")]
+    MissingParameter(usize),
+    #[error("Invalid limit value `{0}`")]
+    InvalidLimit(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn query() -> PreparedQuery {
+        PreparedQuery::select("messages", "*")
+            .eq("channel_id", param(1))
+            .neq("deleted", "true")
+            .order("created_at.desc")
+            .limit(param(2))
+    }
+
+    #[test]
+    fn test_bind_placeholders() {
+        let client = Postgrest::new("http://localhost:3000/rest/v1");
+        let request = query()
+            .bind(&client, &["42", "10"])
+            .unwrap()
+            .build()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.url().query(),
+            Some("select=*&channel_id=eq.42&deleted=neq.true&order=created_at.desc")
+        );
+        assert_eq!(request.headers().get("Range").unwrap(), "0-9");
+        assert_eq!(query().param_count(), 2);
+    }
+
+    #[test]
+    fn test_bind_missing_parameter() {
+        let client = Postgrest::new("http://localhost:3000/rest/v1");
+        let err = query().bind(&client, &["42"]).unwrap_err();
+        assert_eq!(err, PreparedQueryError::MissingParameter(2));
+
+        let err = query().bind(&client, &["42", "0"]).unwrap_err();
+        assert_eq!(err, PreparedQueryError::InvalidLimit("0".to_owned()));
+    }
+
+    #[test]
+    fn test_literal_is_not_a_placeholder() {
+        let literal = PreparedQuery::select("messages", "*").eq("body", "$1");
+        let placeholder = PreparedQuery::select("messages", "*").eq("body", param(1));
+        assert_eq!(literal.template_key(), "messages?select=*&body=eq.$$1");
+        assert_eq!(placeholder.template_key(), "messages?select=*&body=eq.$1");
+    }
+
+    #[test]
+    fn test_bind_quoted_list() {
+        let client = Postgrest::new("http://localhost:3000/rest/v1");
+        let request = PreparedQuery::select("cities", "*")
+            .in_("name", param(1))
+            .bind(&client, &[r#""Paris, France","say \"hi\", bye",Berlin"#])
+            .unwrap()
+            .build()
+            .build()
+            .unwrap();
+
+        let query = request.url().query_pairs().into_owned().collect::<Vec<_>>();
+        assert_eq!(
+            query[1],
+            (
+                "name".to_owned(),
+                r#"in.("Paris, France","say \"hi\", bye",Berlin)"#.to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Opt-in acknowledgment tracking for at-least-once processing of realtime events.
+//!
+//! Wrap any event stream (e.g. the output of [`crate::realtime::RealtimeConnection::connect`])
+//! in an [`AckStream`]. Every yielded [`Delivery`] must be [`Delivery::ack`]ed; deliveries that
+//! are not acknowledged before the deadline are re-emitted from the internal replay buffer or
+//! reported as [`AckEvent::Expired`], depending on the [`RedeliveryPolicy`].
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::future::Future as _;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use std::sync::Mutex;
+
+use futures::{Stream, StreamExt as _};
+use tokio::time::{Instant, Sleep};
+
+/// What to do with a delivery that was not acknowledged before its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedeliveryPolicy {
+    /// Re-emit the event until it has been delivered `max_attempts` times, then report it as
+    /// expired.
+    Redeliver { max_attempts: u32 },
+    /// Report the event as expired right away.
+    Report,
+}
+
+/// Items produced by [`AckStream`].
+#[derive(Debug)]
+pub enum AckEvent<T> {
+    /// An event (or a re-delivery of it) that must be acknowledged.
+    Delivery(Delivery<T>),
+    /// An event that was never acknowledged and will not be delivered again.
+    Expired { id: u64, item: T, attempts: u32 },
+}
+
+/// A single delivery of an event.
+#[derive(Debug)]
+pub struct Delivery<T> {
+    pub item: T,
+    id: u64,
+    attempt: u32,
+    pending: Arc<Mutex<PendingMap<T>>>,
+}
+
+impl<T> Delivery<T> {
+    /// Unique id of the event; stays the same across re-deliveries.
+    #[must_use]
+    pub const fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// 1 for the first delivery, incremented on each re-delivery.
+    #[must_use]
+    pub const fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Marks the event as processed, removing it from the replay buffer.
+    ///
+    /// Returns `false` if the event was already acknowledged or has expired.
+    pub fn ack(self) -> bool {
+        self.pending
+            .lock()
+            .map(|mut pending| pending.remove(&self.id).is_some())
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+struct Pending<T> {
+    item: T,
+    deadline: Instant,
+    attempts: u32,
+}
+
+type PendingMap<T> = BTreeMap<u64, Pending<T>>;
+
+/// Stream adapter that tracks acknowledgments of the wrapped stream's items.
+pub struct AckStream<S: Stream> {
+    inner: S,
+    inner_done: bool,
+    ack_deadline: Duration,
+    policy: RedeliveryPolicy,
+    next_id: u64,
+    pending: Arc<Mutex<PendingMap<S::Item>>>,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl<S> AckStream<S>
+where
+    S: Stream + Unpin,
+    S::Item: Clone,
+{
+    #[must_use]
+    pub fn new(inner: S, ack_deadline: Duration, policy: RedeliveryPolicy) -> Self {
+        Self {
+            inner,
+            inner_done: false,
+            ack_deadline,
+            policy,
+            next_id: 0,
+            pending: Arc::new(Mutex::new(BTreeMap::new())),
+            timer: Box::pin(tokio::time::sleep(ack_deadline)),
+        }
+    }
+
+    /// Number of delivered events that still await an acknowledgment.
+    #[must_use]
+    pub fn unacked(&self) -> usize {
+        self.pending
+            .lock()
+            .map(|pending| pending.len())
+            .unwrap_or(0)
+    }
+
+    fn delivery(&self, id: u64, item: S::Item, attempt: u32) -> AckEvent<S::Item> {
+        AckEvent::Delivery(Delivery {
+            item,
+            id,
+            attempt,
+            pending: Arc::clone(&self.pending),
+        })
+    }
+
+    /// Handles the earliest expired delivery, if there is one.
+    fn poll_expired(&self, now: Instant) -> Option<AckEvent<S::Item>> {
+        let mut pending = self.pending.lock().ok()?;
+        let (&id, entry) = pending
+            .iter_mut()
+            .filter(|&(_, ref entry)| entry.deadline <= now)
+            .min_by_key(|&(_, ref entry)| entry.deadline)?;
+
+        let redeliver = match self.policy {
+            RedeliveryPolicy::Redeliver { max_attempts } => entry.attempts < max_attempts,
+            RedeliveryPolicy::Report => false,
+        };
+        if redeliver {
+            entry.attempts = entry.attempts.saturating_add(1);
+            entry.deadline = now + self.ack_deadline;
+            let (item, attempts) = (entry.item.clone(), entry.attempts);
+            drop(pending);
+            tracing::debug!(id, attempts, "re-delivering unacknowledged event");
+            return Some(self.delivery(id, item, attempts));
+        }
+
+        let entry = pending.remove(&id)?;
+        tracing::warn!(
+            id,
+            attempts = entry.attempts,
+            "event was not acknowledged in time"
+        );
+        Some(AckEvent::Expired {
+            id,
+            item: entry.item,
+            attempts: entry.attempts,
+        })
+    }
+
+    fn earliest_deadline(&self) -> Option<Instant> {
+        self.pending
+            .lock()
+            .ok()?
+            .values()
+            .map(|entry| entry.deadline)
+            .min()
+    }
+}
+
+impl<S> Stream for AckStream<S>
+where
+    S: Stream + Unpin,
+    S::Item: Clone,
+{
+    type Item = AckEvent<S::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(event) = self.poll_expired(Instant::now()) {
+                return Poll::Ready(Some(event));
+            }
+
+            if !self.inner_done {
+                match self.inner.poll_next_unpin(cx) {
+                    Poll::Ready(Some(item)) => {
+                        let id = self.next_id;
+                        self.next_id = self.next_id.wrapping_add(1);
+                        let deadline = Instant::now() + self.ack_deadline;
+                        if let Ok(mut pending) = self.pending.lock() {
+                            pending.insert(
+                                id,
+                                Pending {
+                                    item: item.clone(),
+                                    deadline,
+                                    attempts: 1,
+                                },
+                            );
+                        }
+                        return Poll::Ready(Some(self.delivery(id, item, 1)));
+                    }
+                    Poll::Ready(None) => self.inner_done = true,
+                    Poll::Pending => {}
+                }
+            }
+
+            let Some(deadline) = self.earliest_deadline() else {
+                if self.inner_done {
+                    return Poll::Ready(None);
+                }
+                return Poll::Pending;
+            };
+            self.timer.as_mut().reset(deadline);
+            match self.timer.as_mut().poll(cx) {
+                Poll::Ready(()) => continue,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test(tokio::test)]
+    async fn test_acked_events_are_not_redelivered() {
+        let mut stream = AckStream::new(
+            futures::stream::iter([1, 2]),
+            ms(20),
+            RedeliveryPolicy::Redeliver { max_attempts: 3 },
+        );
+        for expected in [1, 2] {
+            let Some(AckEvent::Delivery(delivery)) = stream.next().await else {
+                panic!("expected a delivery");
+            };
+            assert_eq!(delivery.item, expected);
+            assert!(delivery.ack());
+        }
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn test_unacked_events_are_redelivered_then_expire() {
+        let mut stream = AckStream::new(
+            futures::stream::iter([7]),
+            ms(20),
+            RedeliveryPolicy::Redeliver { max_attempts: 2 },
+        );
+        let Some(AckEvent::Delivery(first)) = stream.next().await else {
+            panic!("expected a delivery");
+        };
+        assert_eq!(first.attempt(), 1);
+        drop(first);
+
+        let Some(AckEvent::Delivery(second)) = stream.next().await else {
+            panic!("expected a re-delivery");
+        };
+        assert_eq!((second.item, second.attempt()), (7, 2));
+
+        let Some(AckEvent::Expired { item, attempts, .. }) = stream.next().await else {
+            panic!("expected an expiry");
+        };
+        assert_eq!((item, attempts), (7, 2));
+        assert_eq!(stream.unacked(), 0);
+        assert!(stream.next().await.is_none());
+    }
+}
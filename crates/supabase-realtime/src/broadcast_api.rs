@@ -0,0 +1,275 @@
+//! Sends broadcast messages through the realtime REST endpoint, without holding a websocket.
+//!
+//! Useful for fire-and-forget notifications from servers. Topics are given without the
+//! `realtime:` prefix, so `room-1` reaches the clients of [`RealtimeConnection::new`]`(config,
+//! "room-1")`.
+//!
+//! [`RealtimeConnection::new`]: crate::realtime::RealtimeConnection::new
+
+use rp_supabase_auth::auth_client::ApiClient;
+use rp_supabase_auth::error::AuthError;
+use rp_supabase_auth::jwt_stream::SupabaseAuthConfig;
+use rp_supabase_auth::redact::Secret;
+use serde::Serialize;
+use simd_json::OwnedValue;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BroadcastApiError {
+    #[error("Auth error {0}")]
+    Auth(#[from] AuthError),
+    #[error("Url parse error {0}")]
+    UrlParseError(#[from] url::ParseError),
+    #[error("Serde json error {0}")]
+    SerdeJsonError(#[from] simd_json::Error),
+    #[error("Broadcast rejected with status {status}: {body}")]
+    Rejected { status: u16, body: String },
+}
+
+/// A message for the broadcast endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BroadcastMessage {
+    pub topic: String,
+    pub event: String,
+    pub payload: OwnedValue,
+    /// Send on a private channel, authorized by the RLS policies of `realtime.messages`
+    pub private: bool,
+}
+
+impl BroadcastMessage {
+    /// # Errors
+    ///
+    /// Returns an error if `payload` cannot be serialized.
+    pub fn new<T: Serialize>(
+        topic: &str,
+        event: &str,
+        payload: &T,
+    ) -> Result<Self, BroadcastApiError> {
+        Ok(Self {
+            topic: topic.to_owned(),
+            event: event.to_owned(),
+            payload: simd_json::serde::to_owned_value(payload)?,
+            private: false,
+        })
+    }
+
+    #[must_use]
+    pub const fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BroadcastRequest<'a> {
+    messages: &'a [BroadcastMessage],
+}
+
+/// Client of the `/realtime/v1/api/broadcast` endpoint.
+#[derive(Debug, Clone)]
+pub struct BroadcastApiClient {
+    /// Authorized with the API key
+    client: ApiClient,
+    url: url::Url,
+    access_token: Option<Secret<String>>,
+}
+
+impl BroadcastApiClient {
+    /// Uses the URL, API key, proxy, timeouts and retry policy of the auth config.
+    ///
+    /// Without [`BroadcastApiClient::with_access_token`], requests are authorized with the API
+    /// key; pass the service-role key in `config` to send on private channels from a server.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the endpoint URL or the HTTP client cannot be built.
+    pub fn new(config: &SupabaseAuthConfig) -> Result<Self, BroadcastApiError> {
+        Ok(Self {
+            client: ApiClient::from_config(config)?
+                .authenticated(config.api_key.expose_secret())?,
+            url: config.url.join("realtime/v1/api/broadcast")?,
+            access_token: None,
+        })
+    }
+
+    /// Sends as the user of `access_token`, e.g. to pass the RLS policies of private channels.
+    #[must_use]
+    pub fn with_access_token(mut self, access_token: &str) -> Self {
+        self.access_token = Some(Secret::new(access_token.to_owned()));
+        self
+    }
+
+    /// Sends all `messages` in one request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the server does not accept the messages.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn send(&self, messages: &[BroadcastMessage]) -> Result<(), BroadcastApiError> {
+        let client = match self.access_token {
+            Some(ref access_token) => self.client.authenticated(access_token.expose_secret())?,
+            None => self.client.clone(),
+        };
+        let response = client
+            .build_url_request::<(), (), _>(
+                reqwest::Method::POST,
+                &self.url,
+                &BroadcastRequest { messages },
+            )?
+            .execute()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        Err(BroadcastApiError::Rejected {
+            status: status.as_u16(),
+            body: response.text().await?,
+        })
+    }
+
+    /// Broadcasts `payload` as `event` on the public channel of `topic`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `payload` cannot be serialized or the request fails.
+    pub async fn broadcast_typed<T: Serialize>(
+        &self,
+        topic: &str,
+        event: &str,
+        payload: &T,
+    ) -> Result<(), BroadcastApiError> {
+        self.send(&[BroadcastMessage::new(topic, event, payload)?])
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use mockito::Matcher;
+    use rp_supabase_auth::jwt_stream::RetryPolicy;
+    use test_log::test;
+
+    use super::*;
+
+    fn config(url: url::Url) -> SupabaseAuthConfig {
+        SupabaseAuthConfig::builder()
+            .api_key("api-key".to_owned())
+            .max_reconnect_attempts(1)
+            .reconnect_interval(Duration::from_secs(1))
+            .url(url)
+            .retry_policy(RetryPolicy::none())
+            .build()
+    }
+
+    #[test(tokio::test)]
+    async fn test_send_broadcast() {
+        let mut server = mockito::Server::new_async().await;
+        let broadcast = server
+            .mock("POST", "/realtime/v1/api/broadcast")
+            .match_header("apikey", "api-key")
+            .match_header("authorization", "Bearer user-token")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"messages": [
+                    {"topic": "room-1", "event": "alert", "payload": {"level": 3}, "private": false},
+                    {"topic": "admins", "event": "alert", "payload": {"level": 3}, "private": true}
+                ]}"#
+                .to_owned(),
+            ))
+            .with_status(202)
+            .create_async()
+            .await;
+
+        let client = BroadcastApiClient::new(&config(server.url().parse().unwrap()))
+            .unwrap()
+            .with_access_token("user-token");
+        let payload = simd_json::json!({"level": 3});
+        client
+            .send(&[
+                BroadcastMessage::new("room-1", "alert", &payload).unwrap(),
+                BroadcastMessage::new("admins", "alert", &payload)
+                    .unwrap()
+                    .with_private(true),
+            ])
+            .await
+            .unwrap();
+        broadcast.assert_async().await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_unavailable_broadcast_is_retried() {
+        let mut server = mockito::Server::new_async().await;
+        let unavailable = server
+            .mock("POST", "/realtime/v1/api/broadcast")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let broadcast = server
+            .mock("POST", "/realtime/v1/api/broadcast")
+            .with_status(202)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = SupabaseAuthConfig {
+            retry_policy: RetryPolicy::builder()
+                .max_retries(1)
+                .initial_backoff(Duration::from_millis(10))
+                .build(),
+            ..config(server.url().parse().unwrap())
+        };
+        let client = BroadcastApiClient::new(&config).unwrap();
+        client
+            .broadcast_typed("room-1", "alert", &"hello")
+            .await
+            .unwrap();
+        unavailable.assert_async().await;
+        broadcast.assert_async().await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_rejected_broadcast() {
+        let mut server = mockito::Server::new_async().await;
+        let _broadcast = server
+            .mock("POST", "/realtime/v1/api/broadcast")
+            .match_header("authorization", "Bearer api-key")
+            .with_status(401)
+            .with_body(r#"{"message": "Invalid JWT"}"#)
+            .create_async()
+            .await;
+
+        let client = BroadcastApiClient::new(&config(server.url().parse().unwrap())).unwrap();
+        let err = client
+            .broadcast_typed("room-1", "alert", &"hello")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, BroadcastApiError::Rejected { status: 401, ref body } if body.contains("Invalid JWT")),
+            "{err:?}"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_plain_text_rejection() {
+        let mut server = mockito::Server::new_async().await;
+        let _broadcast = server
+            .mock("POST", "/realtime/v1/api/broadcast")
+            .with_status(502)
+            .with_header("content-type", "text/plain")
+            .with_body("upstream connect error")
+            .create_async()
+            .await;
+
+        let client = BroadcastApiClient::new(&config(server.url().parse().unwrap())).unwrap();
+        let err = client
+            .broadcast_typed("room-1", "alert", &"hello")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, BroadcastApiError::Rejected { status: 502, ref body } if body == "upstream connect error"),
+            "{err:?}"
+        );
+    }
+}
@@ -1,24 +1,231 @@
 use alloc::sync::Arc;
 use core::future::Future;
 
-use bytes::Bytes;
-use fastwebsockets::FragmentCollector;
+use bytes::{Bytes, BytesMut};
+use fastwebsockets::{Frame, OpCode, Payload, Role, WebSocket, WebSocketRead, WebSocketWrite};
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::BoxFuture;
+use futures::FutureExt as _;
 use http_body_util::Empty;
-use hyper::header::{CONNECTION, UPGRADE};
-use hyper::upgrade::Upgraded;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, UPGRADE};
 use hyper::Request;
-use hyper_util::rt::TokioIo;
+use rp_supabase_auth::jwt_stream::ProxyConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
-use crate::error;
+use crate::transport::{Transport, TransportHalves, TransportRead, TransportWrite, WsMessage};
+use crate::{error, proxy};
 
-pub type WsSupabaseConnection = FragmentCollector<TokioIo<Upgraded>>;
+/// The upgraded connection, possibly wrapped by extensions
+pub trait WsIo: AsyncRead + AsyncWrite + Send + Unpin {}
 
-pub async fn connect(url: &url::Url) -> Result<WsSupabaseConnection, error::SupabaseRealtimeError> {
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> WsIo for T {}
+
+/// Reads whole messages, collecting fragmented ones up to a maximum size
+pub struct WsRead {
+    read: WebSocketRead<tokio::io::ReadHalf<Box<dyn WsIo>>>,
+    /// The fragments of the message being received
+    fragments: Option<BytesMut>,
+    max_message_size: usize,
+}
+
+pub type WsWrite = WebSocketWrite<tokio::io::WriteHalf<Box<dyn WsIo>>>;
+
+/// How the websocket is established
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Offer `permessage-deflate`, so that the server may compress large messages
+    #[cfg(feature = "deflate")]
+    pub compression: bool,
+    /// Used for `wss://` URLs instead of a config trusting the platform's native roots
+    pub tls_config: Option<Arc<rustls::ClientConfig>>,
+    /// Tunnels the connection through this proxy; `None` uses the proxy environment variables
+    pub proxy: Option<ProxyConfig>,
+    /// Largest frame accepted from the server, [`ConnectOptions::DEFAULT_MAX_FRAME_SIZE`] if
+    /// `None`
+    pub max_frame_size: Option<usize>,
+    /// Largest message accepted from the server, however many frames it is fragmented into,
+    /// [`ConnectOptions::DEFAULT_MAX_MESSAGE_SIZE`] if `None`
+    pub max_message_size: Option<usize>,
+    /// Sent along with the upgrade request, e.g. for a gateway in front of Realtime
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ConnectOptions {
+    pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 << 20;
+    pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+    pub(crate) fn max_frame_size(&self) -> usize {
+        self.max_frame_size.unwrap_or(Self::DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub(crate) fn max_message_size(&self) -> usize {
+        self.max_message_size
+            .unwrap_or(Self::DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+/// The default [`Transport`], based on `fastwebsockets` and a `hyper` upgrade
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FastWebSockets;
+
+impl Transport for FastWebSockets {
+    fn connect<'a>(
+        &'a self,
+        url: &'a url::Url,
+        options: &'a ConnectOptions,
+    ) -> BoxFuture<'a, Result<TransportHalves, error::SupabaseRealtimeError>> {
+        async move {
+            let (read, write) = connect(url, options).await?;
+            let halves: TransportHalves = (Box::new(read), Box::new(write));
+            Ok(halves)
+        }
+        .boxed()
+    }
+}
+
+impl TransportRead for WsRead {
+    fn read_message<'a>(
+        &'a mut self,
+        replies: &'a UnboundedSender<WsMessage>,
+    ) -> BoxFuture<'a, Result<Option<Bytes>, error::SupabaseRealtimeError>> {
+        async move {
+            let mut obligated_send = |frame: Frame<'_>| {
+                let reply = match frame.opcode {
+                    OpCode::Close => WsMessage::Close,
+                    _ => WsMessage::Pong(Bytes::copy_from_slice(&frame.payload)),
+                };
+                let queued = replies
+                    .unbounded_send(reply)
+                    .map_err(|_err| error::SupabaseRealtimeError::MpscSendError);
+                core::future::ready(queued)
+            };
+            loop {
+                let frame = self.read.read_frame(&mut obligated_send).await?;
+                let (fin, opcode) = (frame.fin, frame.opcode);
+                let message = match (opcode, self.fragments.as_mut()) {
+                    (OpCode::Close, _) => return Ok(None),
+                    (OpCode::Text | OpCode::Binary, None) if fin => {
+                        return Ok(Some(into_bytes(frame.payload)))
+                    }
+                    (OpCode::Text | OpCode::Binary, None) => self.fragments.insert(BytesMut::new()),
+                    (OpCode::Continuation, Some(message)) => message,
+                    (OpCode::Text | OpCode::Binary | OpCode::Continuation, _) => {
+                        return Err(error::SupabaseRealtimeError::InvalidFrame(
+                            "unexpected fragment".to_owned(),
+                        ))
+                    }
+                    (OpCode::Ping | OpCode::Pong, _) => continue,
+                };
+                if message.len() + frame.payload.len() > self.max_message_size {
+                    self.fragments = None;
+                    return Err(error::SupabaseRealtimeError::MessageTooLarge(
+                        self.max_message_size,
+                    ));
+                }
+                message.extend_from_slice(&frame.payload);
+                if fin {
+                    return Ok(self.fragments.take().map(BytesMut::freeze));
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Takes over the read buffer of the frame without copying it, where possible.
+fn into_bytes(payload: Payload<'_>) -> Bytes {
+    match payload {
+        Payload::Bytes(payload) => payload.freeze(),
+        Payload::Owned(payload) => payload.into(),
+        Payload::Borrowed(payload) => Bytes::copy_from_slice(payload),
+        Payload::BorrowedMut(payload) => Bytes::copy_from_slice(payload),
+    }
+}
+
+impl TransportWrite for WsWrite {
+    fn write_message(
+        &mut self,
+        message: WsMessage,
+    ) -> BoxFuture<'_, Result<(), error::SupabaseRealtimeError>> {
+        let frame = match message {
+            WsMessage::Text(payload) => Frame::text(Payload::Owned(payload.into())),
+            WsMessage::Pong(payload) => Frame::pong(Payload::Owned(payload.into())),
+            WsMessage::Close => Frame::close_raw(Payload::Owned(Vec::new())),
+        };
+        async move { Ok(self.write_frame(frame).await?) }.boxed()
+    }
+}
+
+/// Connects to `url` and splits the websocket, so that reading never blocks writing.
+pub async fn connect(
+    url: &url::Url,
+    options: &ConnectOptions,
+) -> Result<(WsRead, WsWrite), error::SupabaseRealtimeError> {
+    let stream = open_stream(url, options).await?;
+    let req = construct_http_ws_upgrade_req(url, options)?;
+    let (con, response) = fastwebsockets::handshake::client(&SpawnExecutor, req, stream).await?;
+    let io: Box<dyn WsIo> = Box::new(con.into_inner());
+    #[cfg(feature = "deflate")]
+    let io: Box<dyn WsIo> = if options.compression && crate::deflate::accepted(response.headers()) {
+        tracing::debug!("permessage-deflate negotiated");
+        Box::new(crate::deflate::InflateStream::new(
+            io,
+            options.max_message_size(),
+        ))
+    } else {
+        io
+    };
+    #[cfg(not(feature = "deflate"))]
+    drop(response);
+    let mut con = WebSocket::after_handshake(io, Role::Client);
+    // fastwebsockets rejects frames of exactly its limit
+    con.set_max_message_size(options.max_frame_size().saturating_add(1));
+    let (read, write) = con.split(tokio::io::split);
+    let read = WsRead {
+        read,
+        fragments: None,
+        max_message_size: options.max_message_size(),
+    };
+    Ok((read, write))
+}
+
+/// Opens the connection to the host of `url`, through a proxy and TLS as configured, ready for
+/// the websocket handshake.
+pub(crate) async fn open_stream(
+    url: &url::Url,
+    options: &ConnectOptions,
+) -> Result<Box<dyn WsIo>, error::SupabaseRealtimeError> {
     let host = url
         .host_str()
         .ok_or(error::SupabaseRealtimeError::HostStringNotPresent)?;
-    let port = url.port().unwrap_or(443);
+    let Some((tls, port)) = transport(url) else {
+        tracing::error!(scheme = url.scheme(), "unsupported Stream API URL scheme");
+        return Err(error::SupabaseRealtimeError::MisconfiguredStreamURL);
+    };
+    let tcp_stream = match proxy::resolve(url, options.proxy.as_ref()) {
+        Some(proxy) => proxy::tunnel(&proxy, host, port).await?,
+        None => connect_direct(host, port).await?,
+    };
+    if !tls {
+        return Ok(Box::new(tcp_stream));
+    }
+    let tls_connector = match &options.tls_config {
+        Some(config) => tokio_rustls::TlsConnector::from(Arc::clone(config)),
+        None => tls_connector()?,
+    };
+    // IPv6 hosts are bracketed in URLs
+    let server_name = host.trim_start_matches('[').trim_end_matches(']');
+    let domain =
+        rustls::pki_types::ServerName::try_from(server_name.to_owned()).map_err(|err| {
+            tracing::error!(?err, "unable to convert domain to server name");
+            error::SupabaseRealtimeError::UnableConvertDomainToServerName
+        })?;
+    let tls_stream = tls_connector.connect(domain, tcp_stream).await?;
+    Ok(Box::new(tls_stream))
+}
+
+async fn connect_direct(host: &str, port: u16) -> Result<TcpStream, error::SupabaseRealtimeError> {
     let socket_addr = tokio::net::lookup_host((host, port))
         .await
         .map_err(|err| {
@@ -29,43 +236,34 @@ pub async fn connect(url: &url::Url) -> Result<WsSupabaseConnection, error::Supa
             }
         })?
         .next();
-    let domain = url.domain();
-    let con = match (domain, socket_addr) {
-        (Some(domain), Some(socket_addr)) => {
-            let tcp_stream = TcpStream::connect(&socket_addr).await?;
-            let tls_connector = tls_connector().unwrap();
-            let domain =
-                rustls::pki_types::ServerName::try_from(domain.to_owned()).map_err(|err| {
-                    tracing::error!(?err, "unable to convert domain to server name");
-                    error::SupabaseRealtimeError::UnableConvertDomainToServerName
-                })?;
-            let tls_stream = tls_connector.connect(domain, tcp_stream).await?;
-            let req = construct_http_ws_upgrade_req(url)?;
-            let (ws, _) =
-                fastwebsockets::handshake::client(&SpawnExecutor, req, tls_stream).await?;
-            ws
-        }
-        (None, Some(socket_addr)) => {
-            let tcp_stream = TcpStream::connect(&socket_addr).await?;
-            let req = construct_http_ws_upgrade_req(url)?;
-            let (ws, _) =
-                fastwebsockets::handshake::client(&SpawnExecutor, req, tcp_stream).await?;
-            ws
-        }
-        params => {
-            tracing::error!(?params, "unable to connect to Stream API");
+    let Some(socket_addr) = socket_addr else {
+        tracing::error!(host, port, "unable to connect to Stream API");
+        return Err(error::SupabaseRealtimeError::UnableToLookUpHost {
+            host: host.to_owned(),
+            port,
+        });
+    };
+    Ok(TcpStream::connect(&socket_addr).await?)
+}
 
-            return Err(error::SupabaseRealtimeError::MisconfiguredStreamURL);
-        }
+/// Whether to use TLS, and the port to connect to, based on the URL scheme.
+///
+/// `ws://` and `http://` (e.g. a local `supabase start` instance) connect in plaintext.
+fn transport(url: &url::Url) -> Option<(bool, u16)> {
+    let tls = match url.scheme() {
+        "wss" | "https" => true,
+        "ws" | "http" => false,
+        _ => return None,
     };
-    let con = FragmentCollector::new(con);
-    Ok(con)
+    let port = url.port_or_known_default()?;
+    Some((tls, port))
 }
 
 fn construct_http_ws_upgrade_req(
     url: &url::Url,
+    options: &ConnectOptions,
 ) -> Result<Request<Empty<Bytes>>, error::SupabaseRealtimeError> {
-    let req = Request::builder()
+    let mut req = Request::builder()
         .method("GET")
         .uri(url.as_str()) //stream we want to subscribe to
         .header("Host", url.host_str().unwrap())
@@ -75,8 +273,18 @@ fn construct_http_ws_upgrade_req(
             "Sec-WebSocket-Key",
             fastwebsockets::handshake::generate_key(),
         )
-        .header("Sec-WebSocket-Version", "13")
-        .body(Empty::<Bytes>::new())?;
+        .header("Sec-WebSocket-Version", "13");
+    for (name, value) in &options.headers {
+        req = req.header(name, value);
+    }
+    #[cfg(feature = "deflate")]
+    if options.compression {
+        req = req.header(
+            hyper::header::SEC_WEBSOCKET_EXTENSIONS,
+            crate::deflate::EXTENSION_OFFER,
+        );
+    }
+    let req = req.body(Empty::<Bytes>::new())?;
     Ok(req)
 }
 
@@ -95,17 +303,113 @@ where
 fn tls_connector() -> Result<tokio_rustls::TlsConnector, error::SupabaseRealtimeError> {
     use tokio_rustls::TlsConnector;
 
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(root_store()?)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// The platform's native root certificates, or the bundled Mozilla roots if there are none and
+/// the `webpki-roots` feature is enabled.
+fn root_store() -> Result<rustls::RootCertStore, error::SupabaseRealtimeError> {
     let mut roots = rustls::RootCertStore::empty();
-    let native_certs = rustls_native_certs::load_native_certs().certs;
-    for cert in native_certs {
+    let native_certs = rustls_native_certs::load_native_certs();
+    for err in &native_certs.errors {
+        tracing::warn!(?err, "Cannot load native certificates");
+    }
+    for cert in native_certs.certs {
         roots.add(cert).map_err(|err| {
             tracing::error!(?err, "Cannot set native certificate");
             error::SupabaseRealtimeError::CannotSetNativeCertificate
         })?;
     }
+    #[cfg(feature = "webpki-roots")]
+    if roots.is_empty() {
+        tracing::debug!("No native root certificates, using the bundled webpki roots");
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    Ok(roots)
+}
 
+/// A TLS config trusting only the Mozilla root certificates bundled with the crate, for
+/// [`RealtimeBaseConnection::with_tls_config`] where the platform's trust store must not be
+/// used at all.
+///
+/// [`RealtimeBaseConnection::with_tls_config`]: crate::realtime::RealtimeBaseConnection::with_tls_config
+#[cfg(feature = "webpki-roots")]
+#[must_use]
+pub fn webpki_roots_tls_config() -> Arc<rustls::ClientConfig> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
     let config = rustls::ClientConfig::builder()
         .with_root_certificates(roots)
         .with_no_client_auth();
-    Ok(TlsConnector::from(Arc::new(config)))
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_transport_follows_scheme() {
+        let cases = [
+            (
+                "https://project.supabase.co/realtime/v1/websocket",
+                Some((true, 443)),
+            ),
+            (
+                "wss://project.supabase.co/realtime/v1/websocket",
+                Some((true, 443)),
+            ),
+            (
+                "http://localhost:54321/realtime/v1/websocket",
+                Some((false, 54321)),
+            ),
+            (
+                "ws://supabase-kong/realtime/v1/websocket",
+                Some((false, 80)),
+            ),
+            (
+                "https://127.0.0.1:8443/realtime/v1/websocket",
+                Some((true, 8443)),
+            ),
+            ("ftp://project.supabase.co/realtime/v1/websocket", None),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(transport(&url.parse().unwrap()), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn test_upgrade_request_carries_headers() {
+        let url = "https://project.supabase.co/realtime/v1/websocket?apikey=key"
+            .parse()
+            .unwrap();
+        let options = ConnectOptions {
+            headers: vec![(
+                HeaderName::from_static("x-gateway-token"),
+                HeaderValue::from_static("secret"),
+            )],
+            ..ConnectOptions::default()
+        };
+        let req = construct_http_ws_upgrade_req(&url, &options).unwrap();
+        assert_eq!(
+            req.uri().to_string(),
+            "https://project.supabase.co/realtime/v1/websocket?apikey=key"
+        );
+        assert_eq!(req.headers()["x-gateway-token"], "secret");
+        assert_eq!(req.headers()[UPGRADE], "websocket");
+    }
+
+    #[cfg(feature = "webpki-roots")]
+    #[test]
+    fn test_webpki_roots_tls_config() {
+        let config = webpki_roots_tls_config();
+        assert!(config.alpn_protocols.is_empty());
+        assert!(!root_store().unwrap().is_empty());
+    }
 }
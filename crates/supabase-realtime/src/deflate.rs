@@ -0,0 +1,402 @@
+//! `permessage-deflate` (RFC 7692) for the realtime socket.
+//!
+//! fastwebsockets rejects frames with the RSV1 bit set, so compressed messages are inflated
+//! before the websocket sees them: [`InflateStream`] rewrites every compressed frame into an
+//! uncompressed one. Outgoing messages are sent uncompressed, which the extension allows.
+
+use core::pin::Pin;
+use core::task::{ready, Context, Poll};
+use std::io;
+
+use bytes::{Buf as _, BytesMut};
+use flate2::{Decompress, FlushDecompress, Status};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Offer sent in the `Sec-WebSocket-Extensions` header of the upgrade request
+pub(crate) const EXTENSION_OFFER: &str = "permessage-deflate; client_no_context_takeover";
+
+/// The empty stored block the sender strips from the end of every compressed message
+const MESSAGE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+
+/// Whether the server accepted the offer in its upgrade response.
+pub(crate) fn accepted(headers: &hyper::HeaderMap) -> bool {
+    headers
+        .get_all(hyper::header::SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|extension| {
+            extension
+                .split(';')
+                .next()
+                .is_some_and(|name| name.trim() == "permessage-deflate")
+        })
+}
+
+/// Inflates the compressed messages read from `inner`; writes pass through unchanged.
+pub(crate) struct InflateStream<S> {
+    inner: S,
+    /// Read from `inner`, not processed yet
+    input: BytesMut,
+    /// Rewritten frames, ready to be read
+    output: BytesMut,
+    /// Payload bytes of an uncompressed frame that still have to be passed through
+    passthrough: u64,
+    /// The fragments of the current message are compressed
+    compressed_message: bool,
+    /// Bytes inflated so far for the fragments of the current message
+    inflated_message: usize,
+    /// Largest message inflated, compressed frames are limited to it as well
+    max_message_size: usize,
+    inflater: Decompress,
+    eof: bool,
+}
+
+struct Header {
+    len: usize,
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    payload_len: u64,
+}
+
+impl<S> InflateStream<S> {
+    /// Fails reading once a message inflates to more than `max_message_size` bytes.
+    pub(crate) fn new(inner: S, max_message_size: usize) -> Self {
+        Self {
+            inner,
+            input: BytesMut::new(),
+            output: BytesMut::new(),
+            passthrough: 0,
+            compressed_message: false,
+            inflated_message: 0,
+            max_message_size,
+            // the window size is not negotiated, so the server uses at most 15 bits
+            inflater: Decompress::new(false),
+            eof: false,
+        }
+    }
+
+    /// Moves the next processable part of `input` to `output`.
+    ///
+    /// Returns `false` if more input is needed.
+    fn process(&mut self) -> io::Result<bool> {
+        if self.passthrough > 0 {
+            if self.input.is_empty() {
+                return Ok(false);
+            }
+            let len = usize::try_from(self.passthrough)
+                .unwrap_or(usize::MAX)
+                .min(self.input.len());
+            self.output.extend_from_slice(&self.input.split_to(len));
+            self.passthrough -= len as u64;
+            return Ok(true);
+        }
+
+        let Some(header) = parse_header(&self.input) else {
+            return Ok(false);
+        };
+        if header.payload_len > self.max_message_size as u64 {
+            return Err(invalid_data("websocket frame too large"));
+        }
+        // control frames are never compressed, and only the first fragment has RSV1 set
+        let compressed = header.opcode < 0x8 &&
+            (header.rsv1 || (header.opcode == OPCODE_CONTINUATION && self.compressed_message));
+        if !compressed {
+            self.output
+                .extend_from_slice(&self.input.split_to(header.len));
+            self.passthrough = header.payload_len;
+            return Ok(true);
+        }
+
+        let frame_len = header.len + header.payload_len as usize;
+        if self.input.len() < frame_len {
+            return Ok(false);
+        }
+        let mut payload = self.input.split_to(frame_len);
+        payload.advance(header.len);
+        if let Some(mask) = header.mask {
+            for (byte, key) in payload.iter_mut().zip(mask.iter().cycle()) {
+                *byte ^= key;
+            }
+        }
+        if header.opcode != OPCODE_CONTINUATION {
+            self.compressed_message = true;
+            self.inflated_message = 0;
+        }
+
+        // a small frame may inflate to gigabytes, so the whole message is limited while inflating
+        let limit = self.max_message_size.saturating_sub(self.inflated_message);
+        let mut inflated = Vec::with_capacity(payload.len().saturating_mul(4).min(limit));
+        inflate(&mut self.inflater, &payload, &mut inflated, limit)?;
+        if header.fin {
+            inflate(&mut self.inflater, &MESSAGE_TAIL, &mut inflated, limit)?;
+            self.compressed_message = false;
+        }
+        self.inflated_message += inflated.len();
+        write_header(&mut self.output, header.fin, header.opcode, inflated.len());
+        self.output.extend_from_slice(&inflated);
+        Ok(true)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn parse_header(input: &[u8]) -> Option<Header> {
+    let (&first, rest) = input.split_first()?;
+    let (&second, rest) = rest.split_first()?;
+    let (payload_len, rest) = match second & 0x7f {
+        126 => {
+            let (len, rest) = rest.split_first_chunk::<2>()?;
+            (u64::from(u16::from_be_bytes(*len)), rest)
+        }
+        127 => {
+            let (len, rest) = rest.split_first_chunk::<8>()?;
+            (u64::from_be_bytes(*len), rest)
+        }
+        len => (u64::from(len), rest),
+    };
+    let (mask, rest) = if second & 0x80 == 0 {
+        (None, rest)
+    } else {
+        let (mask, rest) = rest.split_first_chunk::<4>()?;
+        (Some(*mask), rest)
+    };
+    Some(Header {
+        len: input.len() - rest.len(),
+        fin: first & 0x80 != 0,
+        rsv1: first & 0x40 != 0,
+        opcode: first & 0x0f,
+        mask,
+        payload_len,
+    })
+}
+
+fn write_header(output: &mut BytesMut, fin: bool, opcode: u8, payload_len: usize) {
+    output.extend_from_slice(&[(u8::from(fin) << 7) | opcode]);
+    if let Ok(len @ 0..=125) = u8::try_from(payload_len) {
+        output.extend_from_slice(&[len]);
+    } else if let Ok(len) = u16::try_from(payload_len) {
+        output.extend_from_slice(&[126]);
+        output.extend_from_slice(&len.to_be_bytes());
+    } else {
+        output.extend_from_slice(&[127]);
+        output.extend_from_slice(&(payload_len as u64).to_be_bytes());
+    }
+}
+
+/// Inflates all of `input` into `output`, flushing everything it produces.
+///
+/// Fails as soon as `output` grows beyond `limit` bytes.
+fn inflate(
+    inflater: &mut Decompress,
+    mut input: &[u8],
+    output: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<()> {
+    let mut chunk = [0; 16 * 1024];
+    loop {
+        let (total_in, total_out) = (inflater.total_in(), inflater.total_out());
+        let status = inflater
+            .decompress(input, &mut chunk, FlushDecompress::Sync)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let consumed = usize::try_from(inflater.total_in() - total_in)
+            .map_err(|_err| invalid_data("inflated too much"))?;
+        let produced = usize::try_from(inflater.total_out() - total_out)
+            .map_err(|_err| invalid_data("inflated too much"))?;
+        if output.len() + produced > limit {
+            return Err(invalid_data("inflated message too large"));
+        }
+        output.extend_from_slice(&chunk[..produced]);
+        input = &input[consumed..];
+        if input.is_empty() && produced < chunk.len() {
+            return Ok(());
+        }
+        if status == Status::StreamEnd {
+            // the sender started a new deflate stream
+            inflater.reset(false);
+        } else if consumed == 0 && produced == 0 {
+            return Err(invalid_data("corrupt deflate stream"));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for InflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.output.is_empty() {
+                let len = buf.remaining().min(this.output.len());
+                buf.put_slice(&this.output.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+            if this.process()? {
+                continue;
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                this.eof = true;
+            } else {
+                this.input.extend_from_slice(chunk_buf.filled());
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for InflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fastwebsockets::{OpCode, Role, WebSocket};
+    use flate2::{Compress, Compression, FlushCompress};
+    use pretty_assertions::assert_eq;
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::*;
+    use crate::connection::ConnectOptions;
+
+    /// Compresses `message` the way the server does, without the trailing empty block.
+    fn deflate(compress: &mut Compress, message: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::with_capacity(message.len() + 64);
+        compress
+            .compress_vec(message, &mut compressed, FlushCompress::Sync)
+            .unwrap();
+        assert!(compressed.ends_with(&MESSAGE_TAIL));
+        compressed.truncate(compressed.len() - MESSAGE_TAIL.len());
+        compressed
+    }
+
+    fn frame(fin: bool, rsv1: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = BytesMut::new();
+        write_header(&mut header, fin, opcode, payload.len());
+        if rsv1 {
+            header[0] |= 0x40;
+        }
+        [&header[..], payload].concat()
+    }
+
+    #[test]
+    fn test_accepted() {
+        let mut headers = hyper::HeaderMap::new();
+        assert!(!accepted(&headers));
+        headers.insert(
+            hyper::header::SEC_WEBSOCKET_EXTENSIONS,
+            "permessage-deflate; client_no_context_takeover"
+                .parse()
+                .unwrap(),
+        );
+        assert!(accepted(&headers));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_inflates_compressed_messages() {
+        let large = format!(r#"{{"record": "{}"}}"#, "x".repeat(100_000));
+        let mut compress = Compress::new(Compression::default(), false);
+        let small = deflate(&mut compress, br#"{"event": "small"}"#);
+        // context is taken over from the previous message
+        let fragmented = deflate(&mut compress, large.as_bytes());
+        let (first, second) = fragmented.split_at(fragmented.len() / 2);
+
+        let bytes = [
+            frame(true, true, 0x1, &small),
+            frame(false, true, 0x1, first),
+            frame(true, false, 0x9, b"ping"),
+            frame(true, false, OPCODE_CONTINUATION, second),
+            frame(true, false, 0x1, b"plain"),
+        ]
+        .concat();
+        let (mut server, client) = tokio::io::duplex(bytes.len() + 1024);
+        server.write_all(&bytes).await.unwrap();
+
+        let mut ws = WebSocket::after_handshake(
+            InflateStream::new(client, ConnectOptions::DEFAULT_MAX_MESSAGE_SIZE),
+            Role::Client,
+        );
+        ws.set_auto_pong(false);
+        let frame = ws.read_frame().await.unwrap();
+        assert_eq!(&*frame.payload, br#"{"event": "small"}"#);
+        let frame = ws.read_frame().await.unwrap();
+        assert_eq!((frame.fin, frame.opcode), (false, OpCode::Text));
+        let mut message = frame.payload.to_vec();
+        assert_eq!(ws.read_frame().await.unwrap().opcode, OpCode::Ping);
+        let frame = ws.read_frame().await.unwrap();
+        assert_eq!((frame.fin, frame.opcode), (true, OpCode::Continuation));
+        message.extend_from_slice(&frame.payload);
+        assert_eq!(message, large.as_bytes());
+        assert_eq!(&*ws.read_frame().await.unwrap().payload, b"plain");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_inflating_past_the_message_size_fails() {
+        let limit = 64 << 10;
+        let mut compress = Compress::new(Compression::best(), false);
+        // a few kilobytes inflating to 16 times the limit
+        let bomb = deflate(&mut compress, &vec![0; limit * 16]);
+        assert!(bomb.len() < limit / 8, "{}", bomb.len());
+        // fragments within the limit, but not together
+        let mut compress = Compress::new(Compression::best(), false);
+        let first = deflate(&mut compress, &vec![0; limit * 3 / 4]);
+        let second = deflate(&mut compress, &vec![0; limit * 3 / 4]);
+
+        for frames in [
+            vec![frame(true, true, 0x1, &bomb)],
+            vec![
+                frame(false, true, 0x1, &first),
+                frame(true, false, OPCODE_CONTINUATION, &second),
+            ],
+        ] {
+            let bytes = frames.concat();
+            let (mut server, client) = tokio::io::duplex(bytes.len() + 1024);
+            server.write_all(&bytes).await.unwrap();
+            let mut stream = InflateStream::new(client, limit);
+            let mut read = Vec::new();
+            let err = tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut read)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(read.len() <= limit + 16, "{}", read.len());
+        }
+    }
+}
@@ -20,14 +20,31 @@ pub enum SupabaseRealtimeError {
     UnableConvertDomainToServerName,
     #[error("Unable to look up host {host}:{port}")]
     UnableToLookUpHost { host: String, port: u16 },
+    #[error("Unsupported proxy scheme {0}")]
+    UnsupportedProxyScheme(String),
+    #[error("Proxy failed to open a tunnel: {0}")]
+    ProxyTunnelFailed(String),
     #[error("WS error {0}")]
     WebsocketError(#[from] WebSocketError),
+    #[cfg(feature = "tungstenite")]
+    #[error("Tungstenite error {0}")]
+    TungsteniteError(#[from] tokio_tungstenite::tungstenite::Error),
     #[error("Url parse error {0}")]
     UrlParseError(#[from] url::ParseError),
+    #[error("Message exceeds the maximum size of {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
     #[error("Serde json error {0}")]
     SerdeJsonError(#[from] simd_json::Error),
     #[error("Mpsc send error")]
     MpscSendError,
+    #[error("The connection was lost before the reply arrived")]
+    NoReply,
+    #[error("Send buffer full, the message was dropped")]
+    SendBufferFull,
+    #[error("Rate limit exceeded, the message was dropped")]
+    RateLimited,
     #[error("Jwt Stream closed unexpectedly")]
     JwtStreamClosedUnexpectedly,
     #[error("Refresh stream error")]
@@ -0,0 +1,248 @@
+//! Typed filters for `postgres_changes` subscriptions.
+//!
+//! Renders the `column=operator.value` syntax expected by [`PostgrsChanges::filter`]:
+//!
+//! ```
+//! use rp_supabase_realtime::filter::Filter;
+//!
+//! let filter = Filter::is_in("status", ["open", "pending"]).unwrap();
+//! assert_eq!(filter.to_string(), "status=in.(open,pending)");
+//! ```
+//!
+//! [`PostgrsChanges::filter`]: crate::message::phx_join::PostgrsChanges::filter
+
+use core::fmt;
+
+/// The realtime server rejects `in` filters with more values
+pub const MAX_IN_VALUES: usize = 100;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum FilterError {
+    #[error("Invalid column name {0:?}")]
+    InvalidColumn(String),
+    #[error("An `in` filter needs at least one value")]
+    EmptyInList,
+    #[error("An `in` filter takes at most {MAX_IN_VALUES} values, got {0}")]
+    TooManyValues(usize),
+    #[error("Value {0:?} of an `in` filter must not contain `,`, `(` or `)`")]
+    InvalidInValue(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterOperator {
+    Eq,
+    Neq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    In,
+}
+
+impl FilterOperator {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Eq => "eq",
+            Self::Neq => "neq",
+            Self::Lt => "lt",
+            Self::Lte => "lte",
+            Self::Gt => "gt",
+            Self::Gte => "gte",
+            Self::In => "in",
+        }
+    }
+}
+
+/// A validated `postgres_changes` filter; use its [`fmt::Display`] output as the filter string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    column: String,
+    operator: FilterOperator,
+    value: String,
+}
+
+impl Filter {
+    /// # Errors
+    ///
+    /// Returns an error if `column` is not a plain column name.
+    pub fn eq(column: &str, value: impl fmt::Display) -> Result<Self, FilterError> {
+        Self::compare(column, FilterOperator::Eq, value)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if `column` is not a plain column name.
+    pub fn neq(column: &str, value: impl fmt::Display) -> Result<Self, FilterError> {
+        Self::compare(column, FilterOperator::Neq, value)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if `column` is not a plain column name.
+    pub fn lt(column: &str, value: impl fmt::Display) -> Result<Self, FilterError> {
+        Self::compare(column, FilterOperator::Lt, value)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if `column` is not a plain column name.
+    pub fn lte(column: &str, value: impl fmt::Display) -> Result<Self, FilterError> {
+        Self::compare(column, FilterOperator::Lte, value)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if `column` is not a plain column name.
+    pub fn gt(column: &str, value: impl fmt::Display) -> Result<Self, FilterError> {
+        Self::compare(column, FilterOperator::Gt, value)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if `column` is not a plain column name.
+    pub fn gte(column: &str, value: impl fmt::Display) -> Result<Self, FilterError> {
+        Self::compare(column, FilterOperator::Gte, value)
+    }
+
+    /// Matches rows where `column` is one of `values`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `column` is not a plain column name, there are no or more than
+    /// [`MAX_IN_VALUES`] values, or a value contains a list delimiter.
+    pub fn is_in<I>(column: &str, values: I) -> Result<Self, FilterError>
+    where
+        I: IntoIterator,
+        I::Item: fmt::Display,
+    {
+        let column = validate_column(column)?;
+        let values = values
+            .into_iter()
+            .map(|value| {
+                let value = value.to_string();
+                if value.contains([',', '(', ')']) {
+                    return Err(FilterError::InvalidInValue(value));
+                }
+                Ok(value)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match values.len() {
+            0 => return Err(FilterError::EmptyInList),
+            len if len > MAX_IN_VALUES => return Err(FilterError::TooManyValues(len)),
+            _ => {}
+        }
+        Ok(Self {
+            column,
+            operator: FilterOperator::In,
+            value: format!("({})", values.join(",")),
+        })
+    }
+
+    #[must_use]
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    #[must_use]
+    pub const fn operator(&self) -> FilterOperator {
+        self.operator
+    }
+
+    fn compare(
+        column: &str,
+        operator: FilterOperator,
+        value: impl fmt::Display,
+    ) -> Result<Self, FilterError> {
+        Ok(Self {
+            column: validate_column(column)?,
+            operator,
+            value: value.to_string(),
+        })
+    }
+}
+
+fn validate_column(column: &str) -> Result<String, FilterError> {
+    let valid = column
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_') &&
+        column
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_');
+    if !valid {
+        return Err(FilterError::InvalidColumn(column.to_owned()));
+    }
+    Ok(column.to_owned())
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={}.{}",
+            self.column,
+            self.operator.as_str(),
+            self.value
+        )
+    }
+}
+
+impl From<Filter> for String {
+    fn from(filter: Filter) -> Self {
+        filter.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_render_filters() {
+        let filters = [
+            (
+                Filter::eq("id", "96236356-5ac3-4403-b3ce-c660973330d9"),
+                "id=eq.96236356-5ac3-4403-b3ce-c660973330d9",
+            ),
+            (Filter::neq("status", "closed"), "status=neq.closed"),
+            (Filter::lt("price", 10), "price=lt.10"),
+            (Filter::lte("price", 10), "price=lte.10"),
+            (
+                Filter::gt("created_at", "2024-01-01"),
+                "created_at=gt.2024-01-01",
+            ),
+            (Filter::gte("score", 2.5), "score=gte.2.5"),
+            (Filter::is_in("id", [1, 2, 3]), "id=in.(1,2,3)"),
+        ];
+        for (filter, expected) in filters {
+            assert_eq!(filter.unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        assert_eq!(
+            Filter::eq("id=eq.1&other", 1),
+            Err(FilterError::InvalidColumn("id=eq.1&other".to_owned()))
+        );
+        assert_eq!(
+            Filter::eq("", 1),
+            Err(FilterError::InvalidColumn(String::new()))
+        );
+        assert_eq!(
+            Filter::is_in("id", Vec::<u32>::new()),
+            Err(FilterError::EmptyInList)
+        );
+        assert_eq!(
+            Filter::is_in("id", 0..101),
+            Err(FilterError::TooManyValues(101))
+        );
+        assert_eq!(
+            Filter::is_in("name", ["a,b"]),
+            Err(FilterError::InvalidInValue("a,b".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Round-trip times of the heartbeats of a connection, to notice a degrading link before the
+//! heartbeat timeout declares it dead.
+
+use alloc::sync::Arc;
+use core::time::Duration;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// The heartbeat round trips measured by a connection, shared with its clients
+#[derive(Debug, Clone, Default)]
+pub struct HeartbeatLatency {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    latest: Option<Duration>,
+    smoothed: Option<Duration>,
+    samples: u64,
+}
+
+impl HeartbeatLatency {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The round trip of the last replied heartbeat
+    #[must_use]
+    pub fn latest(&self) -> Option<Duration> {
+        self.lock().latest
+    }
+
+    /// The round trips averaged like TCP's smoothed RTT, each new one weighing 1/8
+    #[must_use]
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.lock().smoothed
+    }
+
+    /// How many heartbeats were replied to
+    #[must_use]
+    pub fn samples(&self) -> u64 {
+        self.lock().samples
+    }
+
+    pub(crate) fn record(&self, rtt: Duration) {
+        let mut inner = self.lock();
+        inner.latest = Some(rtt);
+        inner.smoothed = Some(
+            inner
+                .smoothed
+                .map_or(rtt, |smoothed| (smoothed * 7 + rtt) / 8),
+        );
+        inner.samples += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_smoothed_latency() {
+        let latency = HeartbeatLatency::default();
+        assert_eq!(latency.latest(), None);
+        assert_eq!(latency.smoothed(), None);
+
+        latency.record(Duration::from_millis(80));
+        assert_eq!(latency.smoothed(), Some(Duration::from_millis(80)));
+        latency.record(Duration::from_millis(160));
+        assert_eq!(latency.latest(), Some(Duration::from_millis(160)));
+        assert_eq!(latency.smoothed(), Some(Duration::from_millis(90)));
+        assert_eq!(latency.samples(), 2);
+    }
+}
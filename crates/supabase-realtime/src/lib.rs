@@ -1,8 +1,30 @@
 extern crate alloc;
 
+pub mod ack;
+pub mod broadcast_api;
 mod connection;
+#[cfg(feature = "deflate")]
+mod deflate;
 mod error;
+pub mod filter;
+pub mod latency;
+#[cfg(feature = "local-cache")]
+pub mod local_cache;
+mod memory;
 pub mod message;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(not(feature = "metrics"))]
+mod metrics;
+pub mod presence;
+mod proxy;
+mod queue;
+pub mod rate_limit;
 pub mod realtime;
+pub mod reply;
+pub mod serializer;
+pub mod transport;
+#[cfg(feature = "tungstenite")]
+mod tungstenite;
 
-pub use {futures, rp_supabase_auth, url};
+pub use {futures, rp_supabase_auth, rustls, url};
@@ -0,0 +1,361 @@
+//! Local SQLite mirror of a Supabase table, kept up to date by `postgres_changes` events.
+//!
+//! Every row is stored as its JSON representation keyed by the primary key column, so the mirror
+//! does not need to know the table schema. Use SQLite's `json_extract` through
+//! [`LocalTableCache::connection`] for ad-hoc local queries.
+
+use std::path::Path;
+
+use rusqlite::{params, OptionalExtension as _};
+use serde::de::DeserializeOwned;
+use simd_json::prelude::*;
+use simd_json::OwnedValue;
+
+use crate::message::postgres_changes::{Buffer, Data, PostgresDataChangeEvent};
+use crate::message::{ProtocolMessage, ProtocolPayload};
+
+#[derive(thiserror::Error, Debug)]
+pub enum LocalCacheError {
+    #[error("SQLite error {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Serde json error {0}")]
+    SerdeJsonError(#[from] simd_json::Error),
+    #[error("Record is missing the primary key column `{0}`")]
+    MissingPrimaryKey(String),
+    #[error("Change event does not carry a record")]
+    MissingRecord,
+}
+
+/// Mirror of a single table inside an embedded SQLite database.
+pub struct LocalTableCache {
+    conn: rusqlite::Connection,
+    schema: String,
+    table: String,
+    primary_key: String,
+}
+
+impl LocalTableCache {
+    /// Opens (or creates) the SQLite database at `path` and prepares the mirror table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database cannot be opened or the mirror table cannot be created.
+    pub fn open(
+        path: impl AsRef<Path>,
+        schema: &str,
+        table: &str,
+        primary_key: &str,
+    ) -> Result<Self, LocalCacheError> {
+        let conn = rusqlite::Connection::open(path)?;
+        Self::with_connection(conn, schema, table, primary_key)
+    }
+
+    /// Creates a mirror that lives only in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mirror table cannot be created.
+    pub fn in_memory(
+        schema: &str,
+        table: &str,
+        primary_key: &str,
+    ) -> Result<Self, LocalCacheError> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        Self::with_connection(conn, schema, table, primary_key)
+    }
+
+    /// Uses an already opened connection, e.g. to keep several table mirrors in one database file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mirror table cannot be created.
+    pub fn with_connection(
+        conn: rusqlite::Connection,
+        schema: &str,
+        table: &str,
+        primary_key: &str,
+    ) -> Result<Self, LocalCacheError> {
+        let cache = Self {
+            conn,
+            schema: schema.to_owned(),
+            table: table.to_owned(),
+            primary_key: primary_key.to_owned(),
+        };
+        cache.conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (pk TEXT PRIMARY KEY NOT NULL, record TEXT NOT NULL)",
+            cache.sql_table_name()
+        ))?;
+        Ok(cache)
+    }
+
+    /// Replaces the contents of the mirror with the given rows.
+    ///
+    /// Meant to be fed with an initial snapshot of the table (e.g. a PostgREST `select *`) before
+    /// realtime events start being applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a row does not contain the primary key or the write fails.
+    pub fn hydrate<I>(&mut self, rows: I) -> Result<usize, LocalCacheError>
+    where
+        I: IntoIterator<Item = OwnedValue>,
+    {
+        let table = self.sql_table_name();
+        let tx = self.conn.transaction()?;
+        tx.execute(&format!("DELETE FROM {table}"), [])?;
+        let mut count = 0;
+        {
+            let mut stmt = tx.prepare(&format!(
+                "INSERT OR REPLACE INTO {table} (pk, record) VALUES (?1, ?2)"
+            ))?;
+            for row in rows {
+                let pk = primary_key_of(&row, &self.primary_key)?;
+                stmt.execute(params![pk, simd_json::to_string(&row)?])?;
+                count += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Applies a realtime protocol message to the mirror.
+    ///
+    /// Returns `true` if the message was a change event for the mirrored table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the change event cannot be applied.
+    pub fn apply_message(&mut self, message: &ProtocolMessage) -> Result<bool, LocalCacheError> {
+        let ProtocolPayload::PostgresChanges(ref changes) = message.payload else {
+            return Ok(false);
+        };
+        self.apply(&changes.data)
+    }
+
+    /// Applies a single `postgres_changes` event (insert/update/delete by primary key).
+    ///
+    /// Returns `true` if the event belonged to the mirrored table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the record cannot be parsed or written.
+    pub fn apply(&mut self, change: &Data<Buffer, Buffer>) -> Result<bool, LocalCacheError> {
+        if change.schema != self.schema || change.table != self.table {
+            return Ok(false);
+        }
+        let table = self.sql_table_name();
+        match change.type_ {
+            PostgresDataChangeEvent::Insert | PostgresDataChangeEvent::Update => {
+                let record = change
+                    .record
+                    .as_ref()
+                    .ok_or(LocalCacheError::MissingRecord)?;
+                let record = parse_buffer(record)?;
+                let pk = primary_key_of(&record, &self.primary_key)?;
+                self.conn.execute(
+                    &format!("INSERT OR REPLACE INTO {table} (pk, record) VALUES (?1, ?2)"),
+                    params![pk, simd_json::to_string(&record)?],
+                )?;
+                // an update may change the primary key itself
+                if let Some(old_pk) = change
+                    .old_record
+                    .as_ref()
+                    .filter(|old| !old.0.is_empty())
+                    .map(parse_buffer)
+                    .transpose()?
+                    .and_then(|old| primary_key_of(&old, &self.primary_key).ok())
+                    .filter(|old_pk| *old_pk != pk)
+                {
+                    self.conn
+                        .execute(&format!("DELETE FROM {table} WHERE pk = ?1"), [old_pk])?;
+                }
+            }
+            PostgresDataChangeEvent::Delete => {
+                let old_record = change
+                    .old_record
+                    .as_ref()
+                    .ok_or(LocalCacheError::MissingRecord)?;
+                let old_record = parse_buffer(old_record)?;
+                let pk = primary_key_of(&old_record, &self.primary_key)?;
+                self.conn
+                    .execute(&format!("DELETE FROM {table} WHERE pk = ?1"), [pk])?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Looks up a single row by its primary key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails or the stored row cannot be deserialized into `T`.
+    pub fn get<T: DeserializeOwned>(
+        &self,
+        primary_key: &str,
+    ) -> Result<Option<T>, LocalCacheError> {
+        let record: Option<String> = self
+            .conn
+            .query_row(
+                &format!("SELECT record FROM {} WHERE pk = ?1", self.sql_table_name()),
+                [primary_key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        record
+            .map(|record| simd_json::from_slice(record.into_bytes().as_mut_slice()))
+            .transpose()
+            .map_err(LocalCacheError::from)
+    }
+
+    /// Returns every mirrored row.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails or a stored row cannot be deserialized into `T`.
+    pub fn all<T: DeserializeOwned>(&self) -> Result<Vec<T>, LocalCacheError> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT record FROM {} ORDER BY pk",
+            self.sql_table_name()
+        ))?;
+        let records = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        records
+            .into_iter()
+            .map(|record| {
+                simd_json::from_slice(record.into_bytes().as_mut_slice())
+                    .map_err(LocalCacheError::from)
+            })
+            .collect()
+    }
+
+    /// Number of mirrored rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn len(&self) -> Result<usize, LocalCacheError> {
+        let count: i64 = self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM {}", self.sql_table_name()),
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(usize::try_from(count).unwrap_or_default())
+    }
+
+    /// Returns `true` if the mirror holds no rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn is_empty(&self) -> Result<bool, LocalCacheError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The underlying SQLite connection, for custom queries against the mirror table.
+    #[must_use]
+    pub const fn connection(&self) -> &rusqlite::Connection {
+        &self.conn
+    }
+
+    /// Quoted name of the local mirror table (`"<schema>.<table>"`).
+    #[must_use]
+    pub fn sql_table_name(&self) -> String {
+        let name = format!("{}.{}", self.schema, self.table);
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+fn parse_buffer(buffer: &Buffer) -> Result<OwnedValue, LocalCacheError> {
+    let mut bytes = buffer.0.to_vec();
+    Ok(simd_json::to_owned_value(&mut bytes)?)
+}
+
+fn primary_key_of(record: &OwnedValue, primary_key: &str) -> Result<String, LocalCacheError> {
+    let value = record
+        .get(primary_key)
+        .filter(|value| !value.is_null())
+        .ok_or_else(|| LocalCacheError::MissingPrimaryKey(primary_key.to_owned()))?;
+    match value.as_str() {
+        Some(value) => Ok(value.to_owned()),
+        None => Ok(simd_json::to_string(value)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use simd_json::json;
+
+    use super::*;
+
+    fn change(
+        type_: PostgresDataChangeEvent,
+        record: Option<OwnedValue>,
+        old_record: Option<OwnedValue>,
+    ) -> Data<Buffer, Buffer> {
+        Data {
+            columns: vec![],
+            commit_timestamp: "2024-11-25T12:00:00Z".to_owned(),
+            errors: None,
+            old_record: old_record.map(|value| Buffer::from(simd_json::to_vec(&value).unwrap())),
+            record: record.map(|value| Buffer::from(simd_json::to_vec(&value).unwrap())),
+            schema: "public".to_owned(),
+            table: "messages".to_owned(),
+            type_,
+        }
+    }
+
+    #[test]
+    fn test_hydrate_and_apply_changes() {
+        let mut cache = LocalTableCache::in_memory("public", "messages", "id").unwrap();
+        let hydrated = cache
+            .hydrate([
+                json!({"id": 1, "body": "first"}),
+                json!({"id": 2, "body": "second"}),
+            ])
+            .unwrap();
+        assert_eq!(hydrated, 2);
+
+        cache
+            .apply(&change(
+                PostgresDataChangeEvent::Insert,
+                Some(json!({"id": 3, "body": "third"})),
+                None,
+            ))
+            .unwrap();
+        cache
+            .apply(&change(
+                PostgresDataChangeEvent::Update,
+                Some(json!({"id": 1, "body": "edited"})),
+                Some(json!({"id": 1})),
+            ))
+            .unwrap();
+        cache
+            .apply(&change(
+                PostgresDataChangeEvent::Delete,
+                None,
+                Some(json!({"id": 2})),
+            ))
+            .unwrap();
+
+        assert_eq!(cache.len().unwrap(), 2);
+        let edited = cache.get::<OwnedValue>("1").unwrap().unwrap();
+        assert_eq!(edited, json!({"id": 1, "body": "edited"}));
+        assert!(cache.get::<OwnedValue>("2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_ignores_other_tables() {
+        let mut cache = LocalTableCache::in_memory("public", "profiles", "id").unwrap();
+        let applied = cache
+            .apply(&change(
+                PostgresDataChangeEvent::Insert,
+                Some(json!({"id": 3, "body": "third"})),
+                None,
+            ))
+            .unwrap();
+        assert!(!applied);
+        assert!(cache.is_empty().unwrap());
+    }
+}
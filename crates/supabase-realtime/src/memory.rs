@@ -0,0 +1,338 @@
+//! An in-memory [`Transport`], to test code built on realtime connections without sockets or a
+//! Supabase instance.
+//!
+//! Every (re)connect of the client shows up as a [`MemoryConnection`] on the [`MemoryServer`],
+//! which plays the part of the Realtime server: it reads what the client wrote and sends it
+//! messages. Dropping a [`MemoryConnection`] drops the connection, like a network failure.
+
+use std::io;
+
+use bytes::Bytes;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+
+use crate::connection::ConnectOptions;
+use crate::error::SupabaseRealtimeError;
+use crate::message::ProtocolMessage;
+use crate::serializer::Serializer;
+use crate::transport::{Transport, TransportHalves, TransportRead, TransportWrite, WsMessage};
+
+/// Connects to a [`MemoryServer`] instead of the network.
+#[derive(Debug, Clone)]
+pub struct MemoryTransport {
+    connections: UnboundedSender<MemoryConnection>,
+    serializer: Serializer,
+}
+
+impl MemoryTransport {
+    /// A transport whose connections are accepted by the returned server.
+    #[must_use]
+    pub fn pair() -> (Self, MemoryServer) {
+        let (connections, accepted) = mpsc::unbounded();
+        let transport = Self {
+            connections,
+            serializer: Serializer::V1,
+        };
+        (transport, MemoryServer { accepted })
+    }
+
+    /// Encodes and decodes the messages of [`MemoryConnection`] with `serializer`, which must
+    /// match the serializer of the client.
+    #[must_use]
+    pub const fn with_serializer(mut self, serializer: Serializer) -> Self {
+        self.serializer = serializer;
+        self
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect<'a>(
+        &'a self,
+        url: &'a url::Url,
+        _options: &'a ConnectOptions,
+    ) -> BoxFuture<'a, Result<TransportHalves, SupabaseRealtimeError>> {
+        let (client_tx, received) = mpsc::unbounded();
+        let (sender, client_rx) = mpsc::unbounded();
+        let connection = MemoryConnection {
+            url: url.clone(),
+            received,
+            sender,
+            serializer: self.serializer,
+        };
+        let connected = self
+            .connections
+            .unbounded_send(connection)
+            .map(|()| {
+                let halves: TransportHalves = (
+                    Box::new(MemoryRead(client_rx)),
+                    Box::new(MemoryWrite(client_tx)),
+                );
+                halves
+            })
+            .map_err(|_err| io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        futures::future::ready(connected).boxed()
+    }
+}
+
+struct MemoryRead(UnboundedReceiver<Bytes>);
+
+impl TransportRead for MemoryRead {
+    fn read_message<'a>(
+        &'a mut self,
+        _replies: &'a UnboundedSender<WsMessage>,
+    ) -> BoxFuture<'a, Result<Option<Bytes>, SupabaseRealtimeError>> {
+        async move { Ok(self.0.next().await) }.boxed()
+    }
+}
+
+struct MemoryWrite(UnboundedSender<WsMessage>);
+
+impl TransportWrite for MemoryWrite {
+    fn write_message(
+        &mut self,
+        message: WsMessage,
+    ) -> BoxFuture<'_, Result<(), SupabaseRealtimeError>> {
+        let written = self
+            .0
+            .unbounded_send(message)
+            .map_err(|_err| io::Error::from(io::ErrorKind::BrokenPipe).into());
+        futures::future::ready(written).boxed()
+    }
+}
+
+/// Accepts the connections of a [`MemoryTransport`].
+#[derive(Debug)]
+pub struct MemoryServer {
+    accepted: UnboundedReceiver<MemoryConnection>,
+}
+
+impl MemoryServer {
+    /// The next connection of the client; `None` once every clone of the transport is dropped.
+    pub async fn accept(&mut self) -> Option<MemoryConnection> {
+        self.accepted.next().await
+    }
+}
+
+/// The server end of a connection of a [`MemoryTransport`].
+#[derive(Debug)]
+pub struct MemoryConnection {
+    url: url::Url,
+    received: UnboundedReceiver<WsMessage>,
+    sender: UnboundedSender<Bytes>,
+    serializer: Serializer,
+}
+
+impl MemoryConnection {
+    /// The URL the client connected to, including its `apikey` and `vsn` parameters
+    #[must_use]
+    pub const fn url(&self) -> &url::Url {
+        &self.url
+    }
+
+    /// The next message written by the client; `None` once the client dropped the connection.
+    pub async fn recv(&mut self) -> Option<WsMessage> {
+        self.received.next().await
+    }
+
+    /// The next protocol message of the client, skipping pongs; `None` once the client closed
+    /// or dropped the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message cannot be decoded.
+    pub async fn recv_message(&mut self) -> Result<Option<ProtocolMessage>, SupabaseRealtimeError> {
+        loop {
+            match self.recv().await {
+                Some(WsMessage::Text(frame)) => {
+                    return self.serializer.decode(&mut Vec::from(frame)).map(Some)
+                }
+                Some(WsMessage::Pong(_)) => {}
+                Some(WsMessage::Close) | None => return Ok(None),
+            }
+        }
+    }
+
+    /// Sends the payload of a text or binary frame to the client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client dropped the connection.
+    pub fn send(&self, frame: impl Into<Bytes>) -> Result<(), SupabaseRealtimeError> {
+        self.sender
+            .unbounded_send(frame.into())
+            .map_err(|_err| SupabaseRealtimeError::MpscSendError)
+    }
+
+    /// Sends `message` to the client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `message` cannot be encoded or the client dropped the connection.
+    pub fn send_message(&self, message: &ProtocolMessage) -> Result<(), SupabaseRealtimeError> {
+        self.send(self.serializer.encode(message)?)
+    }
+}
+
+/// Scaffolding shared by the tests of sockets and channels.
+#[cfg(test)]
+pub(crate) mod testing {
+    use alloc::sync::Arc;
+    use core::time::Duration;
+
+    use futures::StreamExt as _;
+    use rp_supabase_auth::jwt_stream::SupabaseAuthConfig;
+
+    use super::{MemoryConnection, MemoryTransport};
+    use crate::message::phx_join::{self, BroadcastConfig, JoinConfig, PhxJoin, PresenceConfig};
+    use crate::message::{phx_reply, ProtocolMessage, ProtocolPayload};
+    use crate::realtime::{RealtimeSocket, RealtimeSocketClient, SocketOptions};
+
+    /// Connects to `url` with the anon key, without reconnecting.
+    pub(crate) fn test_config(url: url::Url) -> SupabaseAuthConfig {
+        SupabaseAuthConfig::builder()
+            .api_key("anon-key".to_owned())
+            .max_reconnect_attempts(0)
+            .reconnect_interval(Duration::from_secs(1))
+            .url(url)
+            .build()
+    }
+
+    /// Connects a socket with `options` over a [`MemoryTransport`], driven by the returned task,
+    /// and accepts its connection.
+    pub(crate) async fn socket_over_memory(
+        options: SocketOptions,
+    ) -> (
+        RealtimeSocketClient,
+        MemoryConnection,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (transport, mut server) = MemoryTransport::pair();
+        let options = SocketOptions {
+            transport: Some(Arc::new(transport)),
+            ..options
+        };
+        let config = test_config(url::Url::parse("https://project.supabase.co/").unwrap());
+        let (socket, socket_client) =
+            RealtimeSocket::connect_with_token(config, "anon-key", options)
+                .await
+                .unwrap();
+        let drive = tokio::spawn(socket.for_each(|_| async {}));
+        let connection = server.accept().await.unwrap();
+        (socket_client, connection, drive)
+    }
+
+    /// A public join without presence, acknowledging broadcasts if `broadcast_ack` is set.
+    pub(crate) fn join(
+        broadcast_ack: bool,
+        postgres_changes: Vec<phx_join::PostgrsChanges>,
+    ) -> PhxJoin {
+        PhxJoin {
+            config: JoinConfig {
+                broadcast: BroadcastConfig {
+                    self_item: false,
+                    ack: broadcast_ack,
+                },
+                presence: PresenceConfig { key: String::new() },
+                postgres_changes,
+                private: false,
+            },
+            access_token: None,
+        }
+    }
+
+    /// The server's `reply` to `request`
+    pub(crate) fn reply(request: &ProtocolMessage, reply: phx_reply::PhxReply) -> ProtocolMessage {
+        ProtocolMessage {
+            topic: request.topic.clone(),
+            payload: ProtocolPayload::PhxReply(reply),
+            ref_field: request.ref_field.clone(),
+            join_ref: request.join_ref.clone(),
+        }
+    }
+
+    /// Accepts `request` without assigning postgres changes subscriptions.
+    pub(crate) fn reply_ok(request: &ProtocolMessage) -> ProtocolMessage {
+        reply(
+            request,
+            phx_reply::PhxReply::Ok(phx_reply::PhxReplyQuery {
+                postgres_changes: vec![],
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::time::Duration;
+
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::testing::{join, reply_ok, socket_over_memory};
+    use super::*;
+    use crate::message::{phx_reply, ProtocolPayload};
+    use crate::realtime::{RealtimeBaseConnection, ReconnectPolicy, SocketOptions};
+
+    #[test(tokio::test)]
+    async fn test_join_flow() {
+        let (socket_client, mut connection, drive) =
+            socket_over_memory(SocketOptions::default()).await;
+        assert_eq!(connection.url().path(), "/realtime/v1/websocket");
+        assert!(connection
+            .url()
+            .query_pairs()
+            .any(|(key, value)| key == "apikey" && value == "anon-key"));
+
+        let (_channel, mut client) = socket_client.channel("room-1");
+        let joined = client
+            .subscribe_to_changes(join(false, vec![]))
+            .await
+            .unwrap();
+        let request = connection.recv_message().await.unwrap().unwrap();
+        assert_eq!(request.topic, "realtime:room-1");
+        connection.send_message(&reply_ok(&request)).unwrap();
+        assert_eq!(
+            joined.await,
+            Ok(phx_reply::PhxReplyQuery {
+                postgres_changes: vec![],
+            })
+        );
+        drive.abort();
+    }
+
+    #[test(tokio::test)]
+    async fn test_reconnect_rejoins() {
+        let (transport, mut server) = MemoryTransport::pair();
+        let join = ProtocolMessage {
+            topic: "realtime:room-1".to_owned(),
+            payload: ProtocolPayload::PhxJoin(join(false, vec![])),
+            ref_field: Some("1".to_owned()),
+            join_ref: Some("1".to_owned()),
+        };
+        let (input_tx, input_rx) = mpsc::unbounded();
+        input_tx.unbounded_send(Ok(join.clone())).unwrap();
+        let output =
+            RealtimeBaseConnection::new(url::Url::parse("https://project.supabase.co/").unwrap())
+                .with_transport(Arc::new(transport))
+                .with_reconnect(ReconnectPolicy {
+                    max_attempts: 1,
+                    initial_backoff: Duration::from_millis(10),
+                    max_backoff: Duration::from_millis(10),
+                    jitter_percent: 0,
+                })
+                .connect(input_rx)
+                .await
+                .unwrap();
+        let drive = tokio::spawn(output.for_each(|_| async {}));
+
+        let mut first = server.accept().await.unwrap();
+        assert_eq!(first.recv_message().await.unwrap(), Some(join.clone()));
+        drop(first);
+
+        let mut second = server.accept().await.unwrap();
+        assert_eq!(second.recv_message().await.unwrap(), Some(join));
+        drive.abort();
+    }
+}
@@ -21,6 +21,8 @@ pub enum ProtocolPayload {
     AccessToken(access_token::AccessToken),
     #[serde(rename = "phx_join")]
     PhxJoin(phx_join::PhxJoin),
+    #[serde(rename = "phx_leave")]
+    PhxLeave(phx_leave::PhxLeave),
     #[serde(rename = "phx_close")]
     PhxClose(phx_close::PhxClose),
     #[serde(rename = "phx_reply")]
@@ -37,6 +39,9 @@ pub enum ProtocolPayload {
     PhxError(phx_error::PhxError),
     #[serde(rename = "postgres_changes")]
     PostgresChanges(postgres_changes::PostgresChangesPayload),
+    /// Yielded by the client itself, never sent or received
+    #[serde(skip)]
+    ChannelRejoined(channel_rejoined::ChannelRejoined),
 }
 
 impl ProtocolMessage {
@@ -75,7 +80,7 @@ pub mod phx_reply {
 
     #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
     pub struct ErrorReply {
-        reason: String,
+        pub reason: String,
     }
 
     #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -86,11 +91,8 @@ pub mod phx_reply {
         pub filter: Option<String>,
         pub id: i32,
     }
-    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-    pub enum PostgresChangetEvent {
-        #[serde(rename = "*")]
-        All,
-    }
+    /// The server echoes the events of the join
+    pub use super::phx_join::PostgresChangetEvent;
 
     #[cfg(test)]
     mod tests {
@@ -177,6 +179,43 @@ pub mod phx_reply {
             assert_eq!(deserialized_struct, expected_struct);
         }
 
+        #[test]
+        fn test_multiple_subscriptions_serialisation() {
+            let json_data = r#"
+            {
+                "event": "phx_reply",
+                "payload": {
+                    "response": {
+                        "postgres_changes": [
+                            {"event": "INSERT", "id": 1, "schema": "public", "table": "profiles"},
+                            {"event": "DELETE", "id": 2, "schema": "public", "table": "todos", "filter": "done=eq.true"}
+                        ]
+                    },
+                    "status": "ok"
+                },
+                "ref": "1",
+                "topic": "realtime:db"
+            } "#;
+
+            let deserialized_struct: ProtocolMessage =
+                simd_json::from_slice(json_data.to_owned().into_bytes().as_mut_slice()).unwrap();
+
+            let ProtocolPayload::PhxReply(PhxReply::Ok(query)) = deserialized_struct.payload else {
+                panic!("expected an ok reply");
+            };
+            assert_eq!(
+                query
+                    .postgres_changes
+                    .iter()
+                    .map(|changes| (changes.id, changes.event.clone()))
+                    .collect::<Vec<_>>(),
+                [
+                    (1, PostgresChangetEvent::Insert),
+                    (2, PostgresChangetEvent::Delete)
+                ]
+            );
+        }
+
         #[test]
         fn test_event_query_serialisation() {
             let json_data = r#"
@@ -286,6 +325,10 @@ pub mod phx_join {
         pub presence: PresenceConfig,
         #[serde(rename = "postgres_changes")]
         pub postgres_changes: Vec<PostgrsChanges>,
+        /// Join a private channel; broadcast and presence are then authorized by the RLS
+        /// policies of `realtime.messages`
+        #[serde(default)]
+        pub private: bool,
     }
 
     #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -360,6 +403,7 @@ pub mod phx_join {
                             table: "profiles".to_owned(),
                             filter: Some("id=eq.83a19c16-fcd8-45d0-9710-d7b06ce6f329".to_owned()),
                         }],
+                        private: false,
                     },
                     access_token: Some("your_access_token".to_owned()),
                 }),
@@ -380,7 +424,9 @@ pub mod phx_join {
 pub mod presence_state {
     use std::collections::HashMap;
 
+    use serde::de::DeserializeOwned;
     use serde::{Deserialize, Serialize};
+    use simd_json::OwnedValue;
 
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct PresenceState(pub HashMap<String, Presence>);
@@ -390,11 +436,20 @@ pub mod presence_state {
         pub metas: Vec<PresenceMeta>,
     }
 
+    /// One tracked connection of a presence key
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct PresenceMeta {
         pub phx_ref: String,
-        pub name: String,
-        pub t: f64,
+        /// Whatever the client tracked, e.g. `{"name": "...", "t": ...}`
+        #[serde(flatten)]
+        pub payload: HashMap<String, OwnedValue>,
+    }
+
+    impl PresenceMeta {
+        /// Parses the tracked payload into `T`.
+        pub fn parse<T: DeserializeOwned>(&self) -> Result<T, simd_json::Error> {
+            simd_json::serde::from_owned_value(self.payload.clone().into_iter().collect())
+        }
     }
 
     #[cfg(test)]
@@ -431,8 +486,10 @@ pub mod presence_state {
                 Presence {
                     metas: vec![PresenceMeta {
                         phx_ref: "GAsCC3FpEhdb4wgk".to_owned(),
-                        name: "service_role_75".to_owned(),
-                        t: 22866011.0,
+                        payload: HashMap::from([
+                            ("name".to_owned(), OwnedValue::from("service_role_75")),
+                            ("t".to_owned(), OwnedValue::from(22_866_011_u64)),
+                        ]),
                     }],
                 },
             );
@@ -562,21 +619,17 @@ pub mod presence_diff {
         pub leaves: HashMap<String, Presence>,
     }
 
+    pub use super::presence_state::PresenceMeta;
+
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct Presence {
         pub metas: Vec<PresenceMeta>,
     }
 
-    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-    pub struct PresenceMeta {
-        pub phx_ref: String,
-        pub name: String,
-        pub t: f64,
-    }
-
     #[cfg(test)]
     mod tests {
         use pretty_assertions::assert_eq;
+        use simd_json::OwnedValue;
 
         use super::*;
         use crate::message::{ProtocolMessage, ProtocolPayload};
@@ -615,8 +668,10 @@ pub mod presence_diff {
                             Presence {
                                 metas: vec![PresenceMeta {
                                     phx_ref: "GAsBN9izrRlb40jh".to_owned(),
-                                    name: "service_role_47".to_owned(),
-                                    t: 21957173.599999905,
+                                    payload: HashMap::from([
+                                        ("name".to_owned(), OwnedValue::from("service_role_47")),
+                                        ("t".to_owned(), OwnedValue::from(21_957_173.599_999_905)),
+                                    ]),
                                 }],
                             },
                         );
@@ -725,6 +780,43 @@ pub mod access_token {
     }
 }
 
+pub mod phx_leave {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct PhxLeave {}
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_phx_leave() {
+            let json_data = r#"
+            {
+               "event": "phx_leave",
+               "topic": "realtime:room-1",
+               "payload": {},
+               "ref": "3",
+               "join_ref": "1"
+            }
+            "#;
+
+            let expected_struct = ProtocolMessage {
+                topic: "realtime:room-1".to_owned(),
+                payload: ProtocolPayload::PhxLeave(PhxLeave {}),
+                ref_field: Some("3".to_owned()),
+                join_ref: Some("1".to_owned()),
+            };
+
+            let deserialized_struct: ProtocolMessage =
+                simd_json::from_slice(json_data.to_owned().into_bytes().as_mut_slice()).unwrap();
+
+            assert_eq!(deserialized_struct, expected_struct);
+        }
+    }
+}
+
 pub mod phx_close {
     use super::*;
 
@@ -782,10 +874,121 @@ pub mod system {
         pub status: String,
     }
 
+    impl System {
+        /// Parses `status` and the Elixir-formatted `message`, like
+        /// `{:error, "Error parsing `filter` params: ..."}`.
+        #[must_use]
+        pub fn parse_status(&self) -> SystemStatus {
+            if self.status == "ok" {
+                return SystemStatus::Ok;
+            }
+            let reason = self
+                .message
+                .strip_prefix("{:error, ")
+                .and_then(|rest| rest.strip_suffix('}'))
+                .and_then(unquote)
+                .unwrap_or_else(|| self.message.clone());
+            let kind = if reason.starts_with("Error parsing `filter` params") {
+                SystemErrorKind::InvalidFilter
+            } else if reason.starts_with("Unable to subscribe to changes with given parameters") {
+                SystemErrorKind::RealtimeDisabled
+            } else {
+                SystemErrorKind::Other
+            };
+            SystemStatus::Error(SystemError { kind, reason })
+        }
+    }
+
+    /// The outcome reported by a [`System`] message
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum SystemStatus {
+        Ok,
+        Error(SystemError),
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+    #[error("{reason}")]
+    pub struct SystemError {
+        pub kind: SystemErrorKind,
+        /// The message of the server, without the Elixir tuple around it
+        pub reason: String,
+    }
+
+    /// The errors of the server that callers can act upon
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SystemErrorKind {
+        /// A `filter` of the `postgres_changes` could not be parsed
+        InvalidFilter,
+        /// The table is not in the `supabase_realtime` publication, or the parameters match no
+        /// table
+        RealtimeDisabled,
+        Other,
+    }
+
+    /// Reads the Elixir string literal `"..."`.
+    fn unquote(literal: &str) -> Option<String> {
+        let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+        let mut unquoted = String::with_capacity(inner.len());
+        let mut chars = inner.chars();
+        while let Some(char) = chars.next() {
+            if char != '\\' {
+                unquoted.push(char);
+                continue;
+            }
+            match chars.next()? {
+                'n' => unquoted.push('\n'),
+                't' => unquoted.push('\t'),
+                escaped => unquoted.push(escaped),
+            }
+        }
+        Some(unquoted)
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
 
+        #[test]
+        fn test_parse_status() {
+            let system = |status: &str, message: &str| System {
+                channel: "db".to_owned(),
+                extension: "postgres_changes".to_owned(),
+                message: message.to_owned(),
+                status: status.to_owned(),
+            };
+
+            assert_eq!(
+                system("ok", "Subscribed to PostgreSQL").parse_status(),
+                SystemStatus::Ok
+            );
+            assert_eq!(
+                system(
+                    "error",
+                    "{:error, \"Error parsing `filter` params: [\\\"\\\"]\"}"
+                )
+                .parse_status(),
+                SystemStatus::Error(SystemError {
+                    kind: SystemErrorKind::InvalidFilter,
+                    reason: "Error parsing `filter` params: [\"\"]".to_owned(),
+                })
+            );
+            assert_eq!(
+                system("error", "{:error, \"Unable to subscribe to changes with given parameters. Please check Realtime is enabled for the given connect parameters: [event: *, schema: public, table: profiles]\"}")
+                    .parse_status(),
+                SystemStatus::Error(SystemError {
+                    kind: SystemErrorKind::RealtimeDisabled,
+                    reason: "Unable to subscribe to changes with given parameters. Please check Realtime is enabled for the given connect parameters: [event: *, schema: public, table: profiles]".to_owned(),
+                })
+            );
+            assert_eq!(
+                system("error", "Too many channels").parse_status(),
+                SystemStatus::Error(SystemError {
+                    kind: SystemErrorKind::Other,
+                    reason: "Too many channels".to_owned(),
+                })
+            );
+        }
+
         #[test]
         fn test_system_subscribe_error_serialization() {
             let json_data = r#"
@@ -905,6 +1108,17 @@ pub mod system {
     }
 }
 
+pub mod channel_rejoined {
+    /// The channel was joined again after the server reported a `phx_error` for it.
+    ///
+    /// Events broadcast while the channel was down are lost.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ChannelRejoined {
+        /// The join attempts it took, starting at 1
+        pub attempts: u8,
+    }
+}
+
 pub mod phx_error {
     use super::*;
 
@@ -949,6 +1163,7 @@ pub mod phx_error {
 pub mod postgres_changes {
     use alloc::fmt;
 
+    use bytes::Bytes;
     use serde::de::{self, DeserializeOwned};
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -1000,7 +1215,7 @@ pub mod postgres_changes {
         pub fn parse_record<T: DeserializeOwned>(self) -> Result<Data<T, O>, simd_json::Error> {
             let record = match self.record {
                 Some(buffer) => {
-                    let mut data = buffer.into_inner();
+                    let mut data = Vec::from(buffer.into_inner());
                     let parsed: T = simd_json::from_slice(&mut data)?;
                     Some(parsed)
                 }
@@ -1024,7 +1239,7 @@ pub mod postgres_changes {
         pub fn parse_old_record<K: DeserializeOwned>(self) -> Result<Data<R, K>, simd_json::Error> {
             let old_record = match self.old_record {
                 Some(buffer) => {
-                    let mut data = buffer.into_inner();
+                    let mut data = Vec::from(buffer.into_inner());
                     let parsed: K = simd_json::from_slice(&mut data)?;
                     Some(parsed)
                 }
@@ -1044,23 +1259,55 @@ pub mod postgres_changes {
         }
     }
 
+    /// A `postgres_changes` event with both records parsed into `T`.
+    ///
+    /// Unless the table uses `REPLICA IDENTITY FULL`, the old record only holds the primary key,
+    /// so fields of `T` that are not part of it need a serde default.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ChangeEvent<T> {
+        pub data: Data<T, T>,
+        /// The ids of the subscriptions that matched the change
+        pub ids: Vec<i64>,
+    }
+
+    impl PostgresChangesPayload {
+        /// Parses `record` and `old_record` into `T`.
+        ///
+        /// # Errors
+        ///
+        /// Returns an error if a record is not a valid `T`.
+        pub fn parse<T: DeserializeOwned>(self) -> Result<ChangeEvent<T>, simd_json::Error> {
+            Ok(ChangeEvent {
+                data: self.data.parse_record()?.parse_old_record()?,
+                ids: self.ids,
+            })
+        }
+    }
+
+    /// The raw JSON of a record, cheap to clone for every subscriber of a change
     #[derive(Debug, Default, Clone, PartialEq, Eq)]
-    pub struct Buffer(pub Vec<u8>);
+    pub struct Buffer(pub Bytes);
 
     impl Buffer {
         #[must_use]
-        pub fn into_inner(self) -> Vec<u8> {
+        pub fn into_inner(self) -> Bytes {
             self.0
         }
     }
 
+    impl From<Vec<u8>> for Buffer {
+        fn from(bytes: Vec<u8>) -> Self {
+            Self(bytes.into())
+        }
+    }
+
     // Implement Serialize for Buffer
     impl Serialize for Buffer {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: Serializer,
         {
-            let mut bytes_copy = self.0.clone();
+            let mut bytes_copy = self.0.to_vec();
             let json_value: simd_json::OwnedValue =
                 simd_json::to_owned_value(&mut bytes_copy).map_err(serde::ser::Error::custom)?;
             json_value.serialize(serializer)
@@ -1088,14 +1335,14 @@ pub mod postgres_changes {
                 {
                     let value = simd_json::BorrowedValue::deserialize(deserializer)?;
                     let buf = simd_json::to_vec(&value).map_err(de::Error::custom)?;
-                    Ok(Buffer(buf))
+                    Ok(Buffer::from(buf))
                 }
 
                 fn visit_none<E>(self) -> Result<Self::Value, E>
                 where
                     E: de::Error,
                 {
-                    Ok(Buffer(Vec::new()))
+                    Ok(Buffer::default())
                 }
 
                 fn visit_unit<E>(self) -> Result<Self::Value, E>
@@ -1112,7 +1359,7 @@ pub mod postgres_changes {
                     let value = serde::de::value::SeqAccessDeserializer::new(seq);
                     let value = simd_json::BorrowedValue::deserialize(value)?;
                     let buf = simd_json::to_vec(&value).map_err(de::Error::custom)?;
-                    Ok(Buffer(buf))
+                    Ok(Buffer::from(buf))
                 }
 
                 fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
@@ -1122,7 +1369,7 @@ pub mod postgres_changes {
                     let value = serde::de::value::MapAccessDeserializer::new(map);
                     let value = simd_json::BorrowedValue::deserialize(value)?;
                     let buf = simd_json::to_vec(&value).map_err(de::Error::custom)?;
-                    Ok(Buffer(buf))
+                    Ok(Buffer::from(buf))
                 }
             }
 
@@ -1202,8 +1449,8 @@ pub mod postgres_changes {
                         ],
                         commit_timestamp: "2024-08-25T17:00:19.009Z".to_owned(),
                         errors: None,
-                        old_record: Some(Buffer(old_record_bytes)),
-                        record: Some(Buffer(record_bytes)),
+                        old_record: Some(Buffer::from(old_record_bytes)),
+                        record: Some(Buffer::from(record_bytes)),
                         schema: "public".to_owned(),
                         table: "profiles".to_owned(),
                         type_: PostgresDataChangeEvent::Update,
@@ -1223,6 +1470,49 @@ pub mod postgres_changes {
             assert_eq!(deserialized_struct, expected_struct);
         }
 
+        #[test]
+        fn test_parse_change_event() {
+            #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+            struct Profile {
+                id: String,
+                #[serde(default)]
+                url: Option<String>,
+            }
+
+            let payload = PostgresChangesPayload {
+                data: Data {
+                    columns: vec![],
+                    commit_timestamp: "2024-08-25T17:00:19.009Z".to_owned(),
+                    errors: None,
+                    old_record: Some(Buffer::from(br#"{"id": "profile-1"}"#.to_vec())),
+                    record: Some(Buffer::from(
+                        br#"{"id": "profile-1", "url": "https://0.0.0.0:3334"}"#.to_vec(),
+                    )),
+                    schema: "public".to_owned(),
+                    table: "profiles".to_owned(),
+                    type_: PostgresDataChangeEvent::Update,
+                },
+                ids: vec![38606455],
+            };
+
+            let event = payload.parse::<Profile>().unwrap();
+            assert_eq!(
+                event.data.record,
+                Some(Profile {
+                    id: "profile-1".to_owned(),
+                    url: Some("https://0.0.0.0:3334".to_owned()),
+                })
+            );
+            assert_eq!(
+                event.data.old_record,
+                Some(Profile {
+                    id: "profile-1".to_owned(),
+                    url: None,
+                })
+            );
+            assert_eq!(event.ids, vec![38606455]);
+        }
+
         #[test]
         fn complex_data_insert() {
             let json_data = r#"
@@ -1290,7 +1580,7 @@ pub mod postgres_changes {
                     data: Data {
                         table: "rooms".to_owned(),
                         type_: PostgresDataChangeEvent::Insert,
-                        record: Some(Buffer(record_bytes)),
+                        record: Some(Buffer::from(record_bytes)),
                         old_record: None,
                         columns: vec![
                             Column {
@@ -1398,7 +1688,7 @@ pub mod postgres_changes {
                         table: "rooms".to_owned(),
                         type_: PostgresDataChangeEvent::Delete,
                         record: None,
-                        old_record: Some(Buffer(old_record_bytes)),
+                        old_record: Some(Buffer::from(old_record_bytes)),
                         columns: vec![
                             Column {
                                 name: "id".to_owned(),
@@ -0,0 +1,57 @@
+//! Hooks for observing a realtime connection, e.g. to export its activity to Prometheus.
+//!
+//! Set a [`MetricsRecorder`] with [`RealtimeBaseConnection::with_metrics`] or
+//! [`RealtimeConnection::with_metrics`].
+//!
+//! [`RealtimeBaseConnection::with_metrics`]: crate::realtime::RealtimeBaseConnection::with_metrics
+//! [`RealtimeConnection::with_metrics`]: crate::realtime::RealtimeConnection::with_metrics
+
+use alloc::sync::Arc;
+use core::fmt::Debug;
+use core::time::Duration;
+
+/// Receives the counters and gauges of a connection.
+///
+/// Every method does nothing by default. They are called from the connection task, so they
+/// should not block.
+pub trait MetricsRecorder: Debug + Send + Sync {
+    /// A message of `bytes` was read from the websocket, after decompression
+    fn frame_received(&self, _bytes: usize) {}
+
+    /// A message of `bytes` was written to the websocket, including pongs and close replies
+    fn frame_sent(&self, _bytes: usize) {}
+
+    /// The websocket was established again after it dropped
+    fn reconnected(&self) {}
+
+    /// The server rejected the `phx_join` of `topic`
+    fn join_failed(&self, _topic: &str) {}
+
+    /// A heartbeat was replied to `rtt` after it was sent
+    fn heartbeat_rtt(&self, _rtt: Duration) {}
+}
+
+/// The recorder of a connection, if any
+#[derive(Debug, Clone)]
+pub(crate) struct Metrics(Option<Arc<dyn MetricsRecorder>>);
+
+impl Metrics {
+    pub(crate) const fn disabled() -> Self {
+        Self(None)
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn new(recorder: Arc<dyn MetricsRecorder>) -> Self {
+        Self(Some(recorder))
+    }
+
+    pub(crate) const fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn record(&self, record: impl FnOnce(&dyn MetricsRecorder)) {
+        if let Some(recorder) = &self.0 {
+            record(recorder.as_ref());
+        }
+    }
+}
@@ -0,0 +1,452 @@
+//! Merged presence state of a channel, kept up to date by `presence_state` and `presence_diff`
+//! events.
+//!
+//! Wrap a channel's stream with [`PresenceHandle::track`] and query the handle at any time:
+//!
+//! ```no_run
+//! # async fn run(stream: impl rp_supabase_realtime::futures::Stream<Item = Result<rp_supabase_realtime::message::ProtocolMessage, impl core::fmt::Debug>>) {
+//! use rp_supabase_realtime::futures::StreamExt as _;
+//! use rp_supabase_realtime::presence::PresenceHandle;
+//!
+//! let presence = PresenceHandle::new();
+//! let mut stream = core::pin::pin!(presence.track(stream));
+//! while let Some(_message) = stream.next().await {
+//!     tracing::info!(online = presence.len(), "presence");
+//! }
+//! # }
+//! ```
+//!
+//! Or react to the joins and leaves as they happen with [`PresenceHandle::events`].
+
+use alloc::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+use futures::{Stream, StreamExt as _};
+
+use crate::message::presence_state::{Presence, PresenceMeta, PresenceState};
+use crate::message::{presence_diff, ProtocolMessage, ProtocolPayload};
+
+/// A change applied to the presence state by a [`PresenceHandle`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PresenceEvent {
+    /// `key` joined, or joined again from another connection, with `metas`
+    Joined {
+        key: String,
+        metas: Vec<PresenceMeta>,
+    },
+    /// The connections of `key` described by `metas` left; `key` is still present if it has
+    /// other connections
+    Left {
+        key: String,
+        metas: Vec<PresenceMeta>,
+    },
+}
+
+impl PresenceEvent {
+    #[must_use]
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Joined { key, .. } | Self::Left { key, .. } => key,
+        }
+    }
+
+    #[must_use]
+    pub fn metas(&self) -> &[PresenceMeta] {
+        match self {
+            Self::Joined { metas, .. } | Self::Left { metas, .. } => metas,
+        }
+    }
+}
+
+/// Shared view of the users present on a channel, by presence key.
+///
+/// Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct PresenceHandle {
+    state: Arc<Mutex<HashMap<String, Presence>>>,
+}
+
+impl PresenceHandle {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A snapshot of the merged presence state
+    #[must_use]
+    pub fn current(&self) -> PresenceState {
+        PresenceState(self.lock().clone())
+    }
+
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<Presence> {
+        self.lock().get(key).cloned()
+    }
+
+    #[must_use]
+    pub fn contains(&self, key: &str) -> bool {
+        self.lock().contains_key(key)
+    }
+
+    /// Number of present keys
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Applies a realtime protocol message to the presence state.
+    ///
+    /// Returns `true` if the message was a `presence_state` or `presence_diff` event.
+    pub fn apply_message(&self, message: &ProtocolMessage) -> bool {
+        match &message.payload {
+            ProtocolPayload::PresenceState(state) => {
+                self.apply_state(state);
+                true
+            }
+            ProtocolPayload::PresenceDiff(diff) => {
+                self.apply_diff(diff);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces the state with the full state sent by the server after joining.
+    ///
+    /// Returns the joins and leaves compared to the previous state, sorted by key.
+    pub fn apply_state(&self, state: &PresenceState) -> Vec<PresenceEvent> {
+        let previous = core::mem::replace(&mut *self.lock(), state.0.clone());
+        let missing_from =
+            |presences: &HashMap<String, Presence>, key: &str, metas: &[PresenceMeta]| {
+                metas
+                    .iter()
+                    .filter(|meta| {
+                        !presences.get(key).is_some_and(|presence| {
+                            presence
+                                .metas
+                                .iter()
+                                .any(|known| known.phx_ref == meta.phx_ref)
+                        })
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+        let mut joins = state
+            .0
+            .iter()
+            .map(|(key, presence)| PresenceEvent::Joined {
+                key: key.clone(),
+                metas: missing_from(&previous, key, &presence.metas),
+            })
+            .collect::<Vec<_>>();
+        let mut leaves = previous
+            .iter()
+            .map(|(key, presence)| PresenceEvent::Left {
+                key: key.clone(),
+                metas: missing_from(&state.0, key, &presence.metas),
+            })
+            .collect::<Vec<_>>();
+        joins.sort_by(|first, second| first.key().cmp(second.key()));
+        leaves.sort_by(|first, second| first.key().cmp(second.key()));
+        joins
+            .into_iter()
+            .chain(leaves)
+            .filter(|event| !event.metas().is_empty())
+            .collect()
+    }
+
+    /// Adds the joined and removes the left metas; keys without metas are removed.
+    ///
+    /// Returns the joins and leaves that changed the state, sorted by key.
+    pub fn apply_diff(&self, diff: &presence_diff::PresenceDiff) -> Vec<PresenceEvent> {
+        let mut state = self.lock();
+        let mut joins = Vec::new();
+        for (key, joined) in &diff.joins {
+            let metas = &mut state
+                .entry(key.clone())
+                .or_insert_with(|| Presence { metas: Vec::new() })
+                .metas;
+            let mut added = Vec::new();
+            for meta in &joined.metas {
+                if !metas.iter().any(|known| known.phx_ref == meta.phx_ref) {
+                    metas.push(meta.clone());
+                    added.push(meta.clone());
+                }
+            }
+            if !added.is_empty() {
+                joins.push(PresenceEvent::Joined {
+                    key: key.clone(),
+                    metas: added,
+                });
+            }
+        }
+        let mut leaves = Vec::new();
+        for (key, left) in &diff.leaves {
+            let Some(presence) = state.get_mut(key) else {
+                continue;
+            };
+            let (gone, kept) = core::mem::take(&mut presence.metas)
+                .into_iter()
+                .partition::<Vec<_>, _>(|meta| {
+                    left.metas.iter().any(|gone| gone.phx_ref == meta.phx_ref)
+                });
+            presence.metas = kept;
+            if presence.metas.is_empty() {
+                state.remove(key);
+            }
+            if !gone.is_empty() {
+                leaves.push(PresenceEvent::Left {
+                    key: key.clone(),
+                    metas: gone,
+                });
+            }
+        }
+        joins.sort_by(|first, second| first.key().cmp(second.key()));
+        leaves.sort_by(|first, second| first.key().cmp(second.key()));
+        joins.into_iter().chain(leaves).collect()
+    }
+
+    /// Applies every message of `stream` to this handle before passing it on.
+    pub fn track<S, E>(&self, stream: S) -> impl Stream<Item = Result<ProtocolMessage, E>>
+    where
+        S: Stream<Item = Result<ProtocolMessage, E>>,
+    {
+        let handle = self.clone();
+        stream.inspect(move |item| {
+            if let Ok(message) = item {
+                handle.apply_message(message);
+            }
+        })
+    }
+
+    /// Applies every message of `stream` to this handle, yielding the joins and leaves instead
+    /// of the messages.
+    ///
+    /// Other messages are dropped and errors are passed through.
+    pub fn events<S, E>(&self, stream: S) -> impl Stream<Item = Result<PresenceEvent, E>>
+    where
+        S: Stream<Item = Result<ProtocolMessage, E>>,
+    {
+        let handle = self.clone();
+        stream
+            .map(move |item| {
+                let events = match item {
+                    Ok(message) => match &message.payload {
+                        ProtocolPayload::PresenceState(state) => handle.apply_state(state),
+                        ProtocolPayload::PresenceDiff(diff) => handle.apply_diff(diff),
+                        _ => Vec::new(),
+                    }
+                    .into_iter()
+                    .map(Ok)
+                    .collect(),
+                    Err(err) => vec![Err(err)],
+                };
+                futures::stream::iter(events)
+            })
+            .flatten()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Presence>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use simd_json::OwnedValue;
+
+    use super::*;
+
+    fn meta(phx_ref: &str, name: &str) -> PresenceMeta {
+        PresenceMeta {
+            phx_ref: phx_ref.to_owned(),
+            payload: HashMap::from([
+                ("name".to_owned(), OwnedValue::from(name)),
+                ("t".to_owned(), OwnedValue::from(1.0)),
+            ]),
+        }
+    }
+
+    fn diff_presence(metas: &[PresenceMeta]) -> presence_diff::Presence {
+        presence_diff::Presence {
+            metas: metas.to_vec(),
+        }
+    }
+
+    fn message(payload: ProtocolPayload) -> ProtocolMessage {
+        ProtocolMessage {
+            topic: "realtime:room".to_owned(),
+            payload,
+            ref_field: None,
+            join_ref: None,
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_merges_state_and_diffs() {
+        let presence = PresenceHandle::new();
+        let messages = [
+            message(ProtocolPayload::PresenceState(PresenceState(
+                HashMap::from([
+                    (
+                        "alice".to_owned(),
+                        Presence {
+                            metas: vec![meta("a1", "alice")],
+                        },
+                    ),
+                    (
+                        "bob".to_owned(),
+                        Presence {
+                            metas: vec![meta("b1", "bob")],
+                        },
+                    ),
+                ]),
+            ))),
+            message(ProtocolPayload::PresenceDiff(presence_diff::PresenceDiff {
+                // alice joins from a second tab, bob leaves
+                joins: HashMap::from([("alice".to_owned(), diff_presence(&[meta("a2", "alice")]))]),
+                leaves: HashMap::from([("bob".to_owned(), diff_presence(&[meta("b1", "bob")]))]),
+            })),
+        ];
+        let stream = futures::stream::iter(messages.map(Ok::<_, ()>));
+        let forwarded = presence.track(stream).collect::<Vec<_>>().await;
+        assert_eq!(forwarded.len(), 2);
+
+        assert_eq!(presence.len(), 1);
+        assert!(presence.contains("alice"));
+        assert!(!presence.contains("bob"));
+        assert_eq!(
+            presence.get("alice").unwrap().metas,
+            vec![meta("a1", "alice"), meta("a2", "alice")]
+        );
+
+        presence.apply_diff(&presence_diff::PresenceDiff {
+            joins: HashMap::new(),
+            leaves: HashMap::from([(
+                "alice".to_owned(),
+                diff_presence(&[meta("a1", "alice"), meta("a2", "alice")]),
+            )]),
+        });
+        assert!(presence.is_empty());
+        assert_eq!(presence.current(), PresenceState(HashMap::new()));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_presence_events() {
+        let presence = PresenceHandle::new();
+        let messages = [
+            Ok(message(ProtocolPayload::PresenceState(PresenceState(
+                HashMap::from([(
+                    "alice".to_owned(),
+                    Presence {
+                        metas: vec![meta("a1", "alice")],
+                    },
+                )]),
+            )))),
+            Err("dropped connection"),
+            Ok(message(ProtocolPayload::PresenceDiff(
+                presence_diff::PresenceDiff {
+                    joins: HashMap::from([
+                        ("bob".to_owned(), diff_presence(&[meta("b1", "bob")])),
+                        // already known
+                        ("alice".to_owned(), diff_presence(&[meta("a1", "alice")])),
+                    ]),
+                    leaves: HashMap::new(),
+                },
+            ))),
+            // the state after a rejoin, in which alice left
+            Ok(message(ProtocolPayload::PresenceState(PresenceState(
+                HashMap::from([(
+                    "bob".to_owned(),
+                    Presence {
+                        metas: vec![meta("b1", "bob")],
+                    },
+                )]),
+            )))),
+            Ok(message(ProtocolPayload::PresenceDiff(
+                presence_diff::PresenceDiff {
+                    joins: HashMap::new(),
+                    leaves: HashMap::from([(
+                        "bob".to_owned(),
+                        diff_presence(&[meta("b1", "bob")]),
+                    )]),
+                },
+            ))),
+        ];
+        let events = presence
+            .events(futures::stream::iter(messages))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                Ok(PresenceEvent::Joined {
+                    key: "alice".to_owned(),
+                    metas: vec![meta("a1", "alice")],
+                }),
+                Err("dropped connection"),
+                Ok(PresenceEvent::Joined {
+                    key: "bob".to_owned(),
+                    metas: vec![meta("b1", "bob")],
+                }),
+                Ok(PresenceEvent::Left {
+                    key: "alice".to_owned(),
+                    metas: vec![meta("a1", "alice")],
+                }),
+                Ok(PresenceEvent::Left {
+                    key: "bob".to_owned(),
+                    metas: vec![meta("b1", "bob")],
+                }),
+            ]
+        );
+        assert!(presence.is_empty());
+    }
+    #[test_log::test(tokio::test)]
+    async fn test_custom_presence_payload() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Status {
+            user_id: u64,
+            online_at: String,
+        }
+
+        let mut diff = br#"{
+            "event": "presence_diff",
+            "topic": "realtime:room",
+            "ref": null,
+            "join_ref": null,
+            "payload": {
+                "joins": {
+                    "user-7": {"metas": [{"phx_ref": "F1", "user_id": 7, "online_at": "2024-11-25T12:00:00Z"}]}
+                },
+                "leaves": {}
+            }
+        }"#
+        .to_vec();
+        let message = simd_json::from_slice::<ProtocolMessage>(&mut diff).unwrap();
+        let presence = PresenceHandle::new();
+        let events = presence
+            .events(futures::stream::iter([Ok::<_, ()>(message)]))
+            .collect::<Vec<_>>()
+            .await;
+
+        let [Ok(PresenceEvent::Joined { key, metas })] = events.as_slice() else {
+            panic!("expected a single join, got {events:?}");
+        };
+        assert_eq!(key, "user-7");
+        assert_eq!(
+            metas[0].parse::<Status>().unwrap(),
+            Status {
+                user_id: 7,
+                online_at: "2024-11-25T12:00:00Z".to_owned(),
+            }
+        );
+    }
+}
@@ -0,0 +1,434 @@
+//! Tunnels the websocket through an HTTP `CONNECT` or SOCKS5 proxy.
+//!
+//! Without an explicit [`ProxyConfig`], the `HTTPS_PROXY` (for `wss://`), `HTTP_PROXY` (for
+//! `ws://`) and `ALL_PROXY` environment variables apply, except for the hosts in `NO_PROXY`.
+
+use base64::prelude::*;
+use rp_supabase_auth::jwt_stream::ProxyConfig;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpStream;
+
+use crate::error::SupabaseRealtimeError;
+
+/// The largest response to a `CONNECT` request that is accepted
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+/// The proxy to reach `url` through, if any.
+pub(crate) fn resolve(url: &url::Url, configured: Option<&ProxyConfig>) -> Option<url::Url> {
+    resolve_with(url, configured, |name| std::env::var(name).ok())
+}
+
+fn resolve_with(
+    url: &url::Url,
+    configured: Option<&ProxyConfig>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Option<url::Url> {
+    let host = url.host_str()?;
+    if let Some(proxy) = configured {
+        return (!proxy.no_proxy.as_deref().is_some_and(|no| bypass(no, host)))
+            .then(|| proxy.url.clone());
+    }
+    let env = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| env(name).filter(|value| !value.is_empty()))
+    };
+    if env(&["NO_PROXY", "no_proxy"]).is_some_and(|no| bypass(&no, host)) {
+        return None;
+    }
+    let tls = matches!(url.scheme(), "wss" | "https");
+    let proxy = if tls {
+        env(&["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"])
+    } else {
+        env(&["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"])
+    }?;
+    // `proxy.internal:3128` is commonly given without a scheme
+    let proxy = if proxy.contains("://") {
+        proxy
+    } else {
+        format!("http://{proxy}")
+    };
+    match url::Url::parse(&proxy) {
+        Ok(proxy) => Some(proxy),
+        Err(err) => {
+            tracing::warn!(?err, "Ignoring the invalid proxy URL of the environment");
+            None
+        }
+    }
+}
+
+/// Whether `host` matches an entry of a `NO_PROXY` list: `*`, the host itself, or a domain it
+/// belongs to (with or without a leading dot).
+fn bypass(no_proxy: &str, host: &str) -> bool {
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            entry == "*" ||
+                host == entry ||
+                host.strip_suffix(&entry)
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+}
+
+/// Opens a TCP tunnel to `host:port` through `proxy`.
+pub(crate) async fn tunnel(
+    proxy: &url::Url,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream, SupabaseRealtimeError> {
+    let remote_dns = match proxy.scheme() {
+        "http" | "socks5h" => true,
+        "socks5" => false,
+        scheme => {
+            return Err(SupabaseRealtimeError::UnsupportedProxyScheme(
+                scheme.to_owned(),
+            ))
+        }
+    };
+    let proxy_host = proxy
+        .host_str()
+        .ok_or(SupabaseRealtimeError::HostStringNotPresent)?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let proxy_port = proxy.port_or_known_default().unwrap_or(1080);
+    tracing::debug!(proxy_host, proxy_port, "Connecting through proxy");
+    let mut stream = TcpStream::connect((proxy_host, proxy_port)).await?;
+    let credentials = credentials(proxy);
+    if proxy.scheme() == "http" {
+        http_connect(&mut stream, host, port, credentials).await?;
+    } else {
+        socks5_connect(&mut stream, host, port, credentials, remote_dns).await?;
+    }
+    Ok(stream)
+}
+
+/// The percent-decoded user and password embedded in the proxy URL
+fn credentials(proxy: &url::Url) -> Option<(String, String)> {
+    if proxy.username().is_empty() {
+        return None;
+    }
+    let decode = |value: &str| {
+        percent_encoding::percent_decode_str(value)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    Some((
+        decode(proxy.username()),
+        decode(proxy.password().unwrap_or_default()),
+    ))
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> Result<(), SupabaseRealtimeError> {
+    let authority = format!("{host}:{port}");
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((user, password)) = credentials {
+        let token = BASE64_STANDARD.encode(format!("{user}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, so that nothing past the response head is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(SupabaseRealtimeError::ProxyTunnelFailed(
+                "response head too large".to_owned(),
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(SupabaseRealtimeError::ProxyTunnelFailed(
+            status_line.to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+    remote_dns: bool,
+) -> Result<(), SupabaseRealtimeError> {
+    const VERSION: u8 = 5;
+    const NO_AUTH: u8 = 0;
+    const USER_PASSWORD: u8 = 2;
+
+    let method = if credentials.is_some() {
+        USER_PASSWORD
+    } else {
+        NO_AUTH
+    };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let mut reply = [0_u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [VERSION, method] {
+        return Err(SupabaseRealtimeError::ProxyTunnelFailed(
+            "no acceptable SOCKS5 authentication method".to_owned(),
+        ));
+    }
+    if let Some((user, password)) = credentials {
+        let mut auth = vec![1];
+        for value in [user, password] {
+            let len = u8::try_from(value.len()).map_err(|_| {
+                SupabaseRealtimeError::ProxyTunnelFailed("SOCKS5 credentials too long".to_owned())
+            })?;
+            auth.push(len);
+            auth.extend_from_slice(value.as_bytes());
+        }
+        stream.write_all(&auth).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(SupabaseRealtimeError::ProxyTunnelFailed(
+                "SOCKS5 authentication rejected".to_owned(),
+            ));
+        }
+    }
+
+    let mut request = vec![VERSION, 1, 0];
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let ip = if remote_dns {
+        host.parse().ok()
+    } else {
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| SupabaseRealtimeError::UnableToLookUpHost {
+                host: host.to_owned(),
+                port,
+            })?;
+        Some(addr.ip())
+    };
+    match ip {
+        Some(core::net::IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Some(core::net::IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        None => {
+            let len = u8::try_from(host.len()).map_err(|_| {
+                SupabaseRealtimeError::ProxyTunnelFailed("host name too long".to_owned())
+            })?;
+            request.push(3);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0_u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        return Err(SupabaseRealtimeError::ProxyTunnelFailed(format!(
+            "SOCKS5 reply code {}",
+            head[1]
+        )));
+    }
+    // skip the bound address and port
+    let address_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => usize::from(stream.read_u8().await?),
+        atyp => {
+            return Err(SupabaseRealtimeError::ProxyTunnelFailed(format!(
+                "unknown SOCKS5 address type {atyp}"
+            )))
+        }
+    };
+    let mut bound = vec![0_u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn url(url: &str) -> url::Url {
+        url.parse().unwrap()
+    }
+
+    #[test]
+    fn test_resolve_proxy() {
+        let env = |name: &str| match name {
+            "HTTPS_PROXY" => Some("proxy.internal:3128".to_owned()),
+            "http_proxy" => Some("socks5h://socks.internal".to_owned()),
+            "NO_PROXY" => Some("localhost, .internal".to_owned()),
+            _ => None,
+        };
+        let cases = [
+            (
+                "wss://project.supabase.co/",
+                Some("http://proxy.internal:3128/"),
+            ),
+            (
+                "ws://project.supabase.co/",
+                Some("socks5h://socks.internal"),
+            ),
+            ("wss://localhost/", None),
+            ("wss://realtime.internal/", None),
+            ("wss://internal/", None),
+            ("wss://notinternal/", Some("http://proxy.internal:3128/")),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(
+                resolve_with(&url(target), None, env),
+                expected.map(url),
+                "{target}"
+            );
+        }
+
+        let configured = ProxyConfig::builder()
+            .url(url("http://configured:8080"))
+            .no_proxy("localhost".to_owned())
+            .build();
+        assert_eq!(
+            resolve_with(&url("wss://project.supabase.co/"), Some(&configured), env),
+            Some(url("http://configured:8080"))
+        );
+        assert_eq!(
+            resolve_with(&url("wss://localhost/"), Some(&configured), env),
+            None
+        );
+    }
+
+    async fn echo(mut stream: TcpStream) {
+        let mut buf = [0_u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    }
+
+    async fn assert_echo(mut stream: TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0_u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test(tokio::test)]
+    async fn test_http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = url(&format!(
+            "http://user:p%40ss@{}",
+            listener.local_addr().unwrap()
+        ));
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            echo(stream).await;
+            String::from_utf8(request).unwrap()
+        });
+
+        assert_echo(tunnel(&proxy, "project.supabase.co", 443).await.unwrap()).await;
+        assert_eq!(
+            server.await.unwrap(),
+            "CONNECT project.supabase.co:443 HTTP/1.1\r\n\
+             Host: project.supabase.co:443\r\n\
+             Proxy-Authorization: Basic dXNlcjpwQHNz\r\n\r\n"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_http_connect_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = url(&format!("http://{}", listener.local_addr().unwrap()));
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let err = tunnel(&proxy, "project.supabase.co", 443)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, SupabaseRealtimeError::ProxyTunnelFailed(ref status) if status.contains("407")),
+            "{err:?}"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_socks5_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = url(&format!(
+            "socks5h://user:pass@{}",
+            listener.local_addr().unwrap()
+        ));
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0_u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0_u8; 11];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = vec![0_u8; 5 + "project.supabase.co".len() + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            echo(stream).await;
+            request
+        });
+
+        assert_echo(tunnel(&proxy, "project.supabase.co", 443).await.unwrap()).await;
+        let mut expected = vec![5, 1, 0, 3, 19];
+        expected.extend_from_slice(b"project.supabase.co");
+        expected.extend_from_slice(&443_u16.to_be_bytes());
+        assert_eq!(server.await.unwrap(), expected);
+    }
+
+    #[test(tokio::test)]
+    async fn test_unsupported_proxy_scheme() {
+        let err = tunnel(&url("https://proxy.internal"), "project.supabase.co", 443)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, SupabaseRealtimeError::UnsupportedProxyScheme(ref scheme) if scheme == "https"),
+            "{err:?}"
+        );
+    }
+}
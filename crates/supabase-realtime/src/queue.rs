@@ -0,0 +1,181 @@
+//! Bounded queue from a [`RealtimeSocket`] to the stream of one of its channels.
+//!
+//! Unlike `futures::channel::mpsc`, a full queue can make room by dropping its oldest items, so a
+//! slow consumer does not stall the other channels of the socket.
+//!
+//! [`RealtimeSocket`]: crate::realtime::RealtimeSocket
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use futures::Stream;
+
+use crate::realtime::Backpressure;
+
+/// Creates a queue holding up to `capacity` items (at least one).
+pub(crate) fn bounded<T>(
+    capacity: usize,
+    backpressure: Backpressure,
+) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        items: VecDeque::new(),
+        capacity: capacity.max(1),
+        receiver_waker: None,
+        sender_waker: None,
+        sender_dropped: false,
+        receiver_dropped: false,
+    }));
+    (
+        QueueSender {
+            shared: Arc::clone(&shared),
+            backpressure,
+        },
+        QueueReceiver { shared },
+    )
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    receiver_waker: Option<Waker>,
+    sender_waker: Option<Waker>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+fn lock<T>(shared: &Mutex<Shared<T>>) -> MutexGuard<'_, Shared<T>> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The receiving stream was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReceiverGone;
+
+#[derive(Debug)]
+pub(crate) struct QueueSender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    backpressure: Backpressure,
+}
+
+impl<T> QueueSender<T> {
+    /// Ready once an item can be pushed: when there is room, or right away if the oldest items
+    /// are dropped instead of waiting.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), ReceiverGone>> {
+        let mut shared = lock(&self.shared);
+        if shared.receiver_dropped {
+            return Poll::Ready(Err(ReceiverGone));
+        }
+        if self.backpressure == Backpressure::DropOldest || shared.items.len() < shared.capacity {
+            return Poll::Ready(Ok(()));
+        }
+        shared.sender_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Pushes `item`, dropping the oldest item if the queue is full.
+    ///
+    /// Hands `item` back if the receiving stream was dropped.
+    pub(crate) fn push(&self, item: T) -> Result<(), T> {
+        let mut shared = lock(&self.shared);
+        if shared.receiver_dropped {
+            return Err(item);
+        }
+        if shared.items.len() >= shared.capacity {
+            shared.items.pop_front();
+            tracing::warn!(
+                capacity = shared.capacity,
+                "Channel stream is lagging behind, dropping its oldest message"
+            );
+        }
+        shared.items.push_back(item);
+        if let Some(waker) = shared.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.sender_dropped = true;
+        if let Some(waker) = shared.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Yields the queued items; ends once the sender is dropped and the queue is drained.
+#[derive(Debug)]
+pub(crate) struct QueueReceiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Stream for QueueReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = lock(&self.shared);
+        if let Some(item) = shared.items.pop_front() {
+            if let Some(waker) = shared.sender_waker.take() {
+                waker.wake();
+            }
+            return Poll::Ready(Some(item));
+        }
+        if shared.sender_dropped {
+            return Poll::Ready(None);
+        }
+        shared.receiver_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.receiver_dropped = true;
+        shared.items.clear();
+        if let Some(waker) = shared.sender_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker_ref;
+    use futures::StreamExt as _;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_drop_oldest() {
+        let (tx, rx) = bounded(2, Backpressure::DropOldest);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        for item in 1..=3 {
+            assert_eq!(tx.poll_ready(&mut cx), Poll::Ready(Ok(())));
+            tx.push(item).unwrap();
+        }
+        drop(tx);
+        assert_eq!(rx.collect::<Vec<_>>().await, vec![2, 3]);
+    }
+
+    #[test_log::test]
+    fn test_await_room() {
+        let (tx, mut rx) = bounded(1, Backpressure::Await);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        tx.push(1).unwrap();
+        assert_eq!(tx.poll_ready(&mut cx), Poll::Pending);
+        assert_eq!(rx.poll_next_unpin(&mut cx), Poll::Ready(Some(1)));
+        assert_eq!(tx.poll_ready(&mut cx), Poll::Ready(Ok(())));
+
+        drop(rx);
+        assert_eq!(tx.poll_ready(&mut cx), Poll::Ready(Err(ReceiverGone)));
+        assert_eq!(tx.push(2), Err(2));
+    }
+}
@@ -0,0 +1,265 @@
+//! Token-bucket limit of the messages sent by the clients of a socket.
+//!
+//! Supabase Realtime enforces per-connection message quotas and disconnects clients that exceed
+//! them, so a broadcaster sending bursts is better off being slowed down on its side. Set a
+//! [`RateLimit`] with [`SocketOptions::rate_limit`] or [`RealtimeConnection::with_rate_limit`].
+//!
+//! [`SocketOptions::rate_limit`]: crate::realtime::SocketOptions::rate_limit
+//! [`RealtimeConnection::with_rate_limit`]: crate::realtime::RealtimeConnection::with_rate_limit
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{ready, Context, Poll};
+use core::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::time::{Instant, Sleep};
+
+use crate::error::SupabaseRealtimeError;
+use crate::message::{ProtocolMessage, ProtocolPayload};
+
+/// How many messages the clients of a socket may send.
+///
+/// Joins and leaves are not limited, so a busy channel cannot keep another one from joining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Messages allowed per second on average, at least one
+    pub messages_per_second: u32,
+    /// Messages that may be sent at once after a quiet period, at least one
+    pub burst: u32,
+    pub exceeded: RateLimitExceeded,
+}
+
+impl RateLimit {
+    /// Allows `messages_per_second`, in bursts of as many messages.
+    #[must_use]
+    pub const fn per_second(messages_per_second: u32) -> Self {
+        Self {
+            messages_per_second,
+            burst: messages_per_second,
+            exceeded: RateLimitExceeded::Await,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_exceeded(mut self, exceeded: RateLimitExceeded) -> Self {
+        self.exceeded = exceeded;
+        self
+    }
+}
+
+impl Default for RateLimit {
+    /// Ten messages per second, like the `eventsPerSecond` of the official clients
+    fn default() -> Self {
+        Self::per_second(10)
+    }
+}
+
+/// What happens to a message sent while the rate limit is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitExceeded {
+    /// Holds the message back until it is allowed; the senders wait once the send queue is full
+    #[default]
+    Await,
+    /// Discards the message and yields [`SupabaseRealtimeError::RateLimited`]
+    Error,
+}
+
+/// Tokens are refilled continuously, one every `1 / messages_per_second` seconds.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        let capacity = f64::from(limit.burst.max(1));
+        Self {
+            capacity,
+            per_second: f64::from(limit.messages_per_second.max(1)),
+            tokens: capacity,
+            refilled: now,
+        }
+    }
+
+    /// Takes a token, or tells how long it takes until one is available.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = elapsed
+            .mul_add(self.per_second, self.tokens)
+            .min(self.capacity);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / self.per_second,
+        ))
+    }
+}
+
+/// Passes the messages of `input` on as allowed by `limit`.
+pub(crate) fn limit<S>(input: S, limit: RateLimit) -> RateLimited<S>
+where
+    S: Stream<Item = ProtocolMessage> + Unpin,
+{
+    RateLimited {
+        input,
+        bucket: TokenBucket::new(limit, Instant::now()),
+        exceeded: limit.exceeded,
+        waiting: None,
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimited<S> {
+    input: S,
+    bucket: TokenBucket,
+    exceeded: RateLimitExceeded,
+    /// The message held back until the bucket has a token for it
+    waiting: Option<(ProtocolMessage, Pin<Box<Sleep>>)>,
+}
+
+impl<S> Stream for RateLimited<S>
+where
+    S: Stream<Item = ProtocolMessage> + Unpin,
+{
+    type Item = Result<ProtocolMessage, SupabaseRealtimeError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let message = if let Some((_, sleep)) = &mut this.waiting {
+                ready!(sleep.as_mut().poll(cx));
+                let Some((message, _)) = this.waiting.take() else {
+                    unreachable!("checked above");
+                };
+                message
+            } else {
+                let Some(message) = ready!(this.input.poll_next_unpin(cx)) else {
+                    return Poll::Ready(None);
+                };
+                if matches!(
+                    message.payload,
+                    ProtocolPayload::PhxJoin(_) | ProtocolPayload::PhxLeave(_)
+                ) {
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                message
+            };
+            let now = Instant::now();
+            match (this.bucket.try_take(now), this.exceeded) {
+                (Ok(()), _) => return Poll::Ready(Some(Ok(message))),
+                (Err(wait), RateLimitExceeded::Await) => {
+                    this.waiting = Some((message, Box::pin(tokio::time::sleep_until(now + wait))));
+                }
+                (Err(_wait), RateLimitExceeded::Error) => {
+                    tracing::warn!(
+                        topic = message.topic,
+                        "Rate limit exceeded, dropping message"
+                    );
+                    return Poll::Ready(Some(Err(SupabaseRealtimeError::RateLimited)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+    use crate::message::heartbeat::Heartbeat;
+    use crate::message::phx_leave::PhxLeave;
+
+    fn message(payload: ProtocolPayload) -> ProtocolMessage {
+        ProtocolMessage {
+            topic: "realtime:room-1".to_owned(),
+            payload,
+            ref_field: None,
+            join_ref: None,
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::per_second(10).with_burst(2), start);
+        assert_eq!(bucket.try_take(start), Ok(()));
+        assert_eq!(bucket.try_take(start), Ok(()));
+        assert_eq!(bucket.try_take(start), Err(Duration::from_millis(100)));
+
+        // half a token was refilled
+        let later = start + Duration::from_millis(50);
+        assert_eq!(bucket.try_take(later), Err(Duration::from_millis(50)));
+        let later = start + Duration::from_millis(100);
+        assert_eq!(bucket.try_take(later), Ok(()));
+
+        // never more than the burst is saved up
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.try_take(later), Ok(()));
+        assert_eq!(bucket.try_take(later), Ok(()));
+        assert!(bucket.try_take(later).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_exceeded_rate_limit_errors() {
+        let input = futures::stream::iter([
+            message(ProtocolPayload::Heartbeat(Heartbeat)),
+            message(ProtocolPayload::Heartbeat(Heartbeat)),
+            message(ProtocolPayload::PhxLeave(PhxLeave {})),
+            message(ProtocolPayload::Heartbeat(Heartbeat)),
+        ]);
+        let limit = RateLimit::per_second(1)
+            .with_burst(2)
+            .with_exceeded(RateLimitExceeded::Error);
+        let sent = super::limit(input, limit)
+            .map(|item| item.map(|message| message.payload))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert!(
+            matches!(
+                sent.as_slice(),
+                [
+                    Ok(ProtocolPayload::Heartbeat(_)),
+                    Ok(ProtocolPayload::Heartbeat(_)),
+                    Ok(ProtocolPayload::PhxLeave(_)),
+                    Err(SupabaseRealtimeError::RateLimited),
+                ]
+            ),
+            "{sent:?}"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_exceeded_rate_limit_awaits() {
+        let input =
+            futures::stream::iter((0..4).map(|_| message(ProtocolPayload::Heartbeat(Heartbeat))));
+        let start = Instant::now();
+        let sent = super::limit(input, RateLimit::per_second(20).with_burst(1))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(sent.len(), 4);
+        assert!(sent.iter().all(Result::is_ok));
+        // the first message is sent right away, the others 50ms apart
+        assert!(
+            start.elapsed() >= Duration::from_millis(150),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+}
@@ -1,316 +1,4322 @@
 use alloc::sync::Arc;
-use core::task::Poll;
+use core::future::Future;
+use core::ops::Range;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{ready, Context, Poll};
+use core::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::PoisonError;
 
-use fastwebsockets::Frame;
-use futures::stream::FuturesUnordered;
-use futures::{SinkExt as _, Stream, StreamExt as _};
+use futures::channel::mpsc::{Sender, UnboundedSender};
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::stream::{BoxStream, FuturesUnordered};
+use futures::{FutureExt as _, SinkExt as _, Stream, StreamExt as _};
+use hyper::header::{HeaderName, HeaderValue};
+use rand::Rng as _;
+use rp_supabase_auth::jwt_stream::ProxyConfig;
 use rp_supabase_auth::types::LoginCredentials;
-use tokio::sync::Mutex;
-use tokio::time::timeout;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::time::{Instant, Sleep};
 use tokio_stream::wrappers::IntervalStream;
 
-use crate::connection::WsSupabaseConnection;
+use crate::connection::ConnectOptions;
 use crate::error::SupabaseRealtimeError;
+use crate::latency::HeartbeatLatency;
 use crate::message::access_token::AccessToken;
-use crate::message::{broadcast, phx_join, ProtocolMessage, ProtocolPayload};
-use crate::{connection, error, message};
+use crate::message::channel_rejoined::ChannelRejoined;
+use crate::message::postgres_changes::{ChangeEvent, PostgresChangesPayload};
+use crate::message::{broadcast, phx_join, phx_leave, phx_reply, ProtocolMessage, ProtocolPayload};
+use crate::metrics::Metrics;
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsRecorder;
+use crate::queue::{self, QueueSender};
+use crate::rate_limit::{self, RateLimit};
+use crate::reply::{PendingReplies, Reply};
+use crate::serializer::Serializer;
+use crate::transport::{
+    FastWebSockets, Transport, TransportHalves, TransportRead, TransportWrite, WsMessage,
+};
+use crate::{error, message};
 
+/// Sends the messages of one channel.
+///
+/// A channel is joined once with all of its features: the `broadcast`, `presence` and
+/// `postgres_changes` settings of the [`phx_join::PhxJoin`] apply to the same topic, so one
+/// client can broadcast, follow table changes and, with a [`PresenceHandle`], track presence.
+///
+/// [`PresenceHandle`]: crate::presence::PresenceHandle
 pub struct RealtimeConnectionClient {
-    tx: futures::channel::mpsc::UnboundedSender<ProtocolPayload>,
+    topic: String,
+    tx: Sender<ProtocolMessage>,
+    state: Arc<SocketState>,
+    private: bool,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    #[error("Join rejected: {reason}")]
+    Rejected { reason: String },
+    #[error("The socket was closed before the join was acknowledged")]
+    SocketClosed,
+}
+
+/// Resolves once the server acknowledged a join with its `phx_reply`.
+///
+/// On success it yields the ids the server assigned to the requested postgres changes.
+#[derive(Debug)]
+pub struct JoinReply {
+    reply: Reply,
+}
+
+impl Future for JoinReply {
+    type Output = Result<phx_reply::PhxReplyQuery, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let reply =
+            ready!(self.reply.poll_unpin(cx)).map_err(|_no_reply| JoinError::SocketClosed)?;
+        Poll::Ready(match reply {
+            phx_reply::PhxReply::Ok(query) => Ok(query),
+            phx_reply::PhxReply::Error(error) => Err(JoinError::Rejected {
+                reason: error.reason,
+            }),
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AckError {
+    #[error("Broadcast rejected: {reason}")]
+    Rejected { reason: String },
+    #[error("The connection was lost before the broadcast was acknowledged")]
+    ConnectionLost,
+}
+
+/// Resolves once the server acknowledged a broadcast with its `phx_reply`.
+#[derive(Debug)]
+pub struct BroadcastAck {
+    reply: Reply,
+}
+
+impl Future for BroadcastAck {
+    type Output = Result<(), AckError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let reply =
+            ready!(self.reply.poll_unpin(cx)).map_err(|_no_reply| AckError::ConnectionLost)?;
+        Poll::Ready(match reply {
+            phx_reply::PhxReply::Ok(_) => Ok(()),
+            phx_reply::PhxReply::Error(error) => Err(AckError::Rejected {
+                reason: error.reason,
+            }),
+        })
+    }
 }
 
 impl RealtimeConnectionClient {
+    /// Sends `phx_join` for the channel.
+    ///
+    /// Returns once the message is queued; await the returned [`JoinReply`] to wait for the
+    /// server's acknowledgment. The socket's stream must be polled for the reply to arrive.
+    ///
+    /// The `postgres_changes` of earlier calls are kept: the channel is joined again with
+    /// all of them, followed by the ones of `join`.
     pub async fn subscribe_to_changes(
+        &mut self,
+        mut join: phx_join::PhxJoin,
+    ) -> Result<JoinReply, futures::channel::mpsc::SendError> {
+        join.config.private |= self.private;
+        let reply_ref = self.state.next_ref();
+        {
+            let mut changes = self
+                .state
+                .changes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let changes = changes.entry(self.topic.clone()).or_default();
+            changes.requested.append(&mut join.config.postgres_changes);
+            join.config.postgres_changes.clone_from(&changes.requested);
+            changes.join_ref = Some(reply_ref.clone());
+        }
+        let reply = self
+            .push_with_ref(reply_ref, ProtocolPayload::PhxJoin(join))
+            .await?;
+        Ok(JoinReply { reply })
+    }
+
+    /// Like [`RealtimeConnectionClient::subscribe_to_changes`], but the changes of the
+    /// `postgres_changes` of `join` are yielded by a stream of their own, with their records
+    /// parsed into `T`, instead of the stream of the channel.
+    ///
+    /// Call it once per table to follow several tables of one channel independently.
+    pub async fn subscribe_to_table<T: DeserializeOwned>(
         &mut self,
         join: phx_join::PhxJoin,
-    ) -> Result<(), futures::channel::mpsc::SendError> {
-        self.tx.send(ProtocolPayload::PhxJoin(join)).await
+    ) -> Result<
+        (
+            JoinReply,
+            impl Stream<Item = Result<ChangeEvent<T>, SupabaseRealtimeError>>,
+        ),
+        futures::channel::mpsc::SendError,
+    > {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        {
+            let mut changes = self
+                .state
+                .changes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let changes = changes.entry(self.topic.clone()).or_default();
+            let start = changes.requested.len();
+            let end = start + join.config.postgres_changes.len();
+            changes.streams.push((start..end, tx));
+        }
+        let reply = self.subscribe_to_changes(join).await?;
+        let changes = rx.map(|changes: PostgresChangesPayload| {
+            changes.parse().map_err(SupabaseRealtimeError::from)
+        });
+        Ok((reply, changes))
+    }
+
+    /// Sends `payload` on the channel and waits for the server's `phx_reply` to it.
+    ///
+    /// The socket's stream must be polled for the reply to arrive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket is gone, or the connection is lost before the reply
+    /// arrives.
+    pub async fn send_and_await(
+        &mut self,
+        payload: ProtocolPayload,
+    ) -> Result<phx_reply::PhxReply, SupabaseRealtimeError> {
+        self.push(payload)
+            .await
+            .map_err(|_err| SupabaseRealtimeError::MpscSendError)?
+            .await
     }
 
     pub async fn broadcast(
         &mut self,
         msg: broadcast::Broadcast,
     ) -> Result<(), futures::channel::mpsc::SendError> {
-        self.tx.send(ProtocolPayload::Broadcast(msg)).await
+        self.send(ProtocolPayload::Broadcast(msg)).await
+    }
+
+    /// Broadcasts `msg`, returning once it is queued; await the returned [`BroadcastAck`] for
+    /// the server to confirm its delivery.
+    ///
+    /// Only channels joined with `ack` set in their [`BroadcastConfig`] are acknowledged; on
+    /// other channels the [`BroadcastAck`] resolves only once the connection is lost.
+    ///
+    /// [`BroadcastConfig`]: phx_join::BroadcastConfig
+    pub async fn broadcast_with_ack(
+        &mut self,
+        msg: broadcast::Broadcast,
+    ) -> Result<BroadcastAck, futures::channel::mpsc::SendError> {
+        let reply = self.push(ProtocolPayload::Broadcast(msg)).await?;
+        Ok(BroadcastAck { reply })
+    }
+
+    /// Broadcasts `payload` as `event`, serialized to JSON.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `payload` cannot be serialized or the socket is gone.
+    pub async fn broadcast_typed<T: Serialize>(
+        &mut self,
+        event: &str,
+        payload: &T,
+    ) -> Result<(), SupabaseRealtimeError> {
+        let payload = simd_json::serde::to_owned_value(payload)?;
+        self.broadcast(broadcast::Broadcast {
+            r#type: "broadcast".to_owned(),
+            event: event.to_owned(),
+            payload,
+        })
+        .await
+        .map_err(|_err| SupabaseRealtimeError::MpscSendError)
+    }
+
+    /// Unsubscribes from the channel by sending `phx_leave`, without closing the socket.
+    ///
+    /// The channel's stream ends and the channel is not joined again after a reconnect.
+    pub async fn leave(mut self) -> Result<(), futures::channel::mpsc::SendError> {
+        self.state
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.topic);
+        self.state
+            .changes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.topic);
+        self.send(ProtocolPayload::PhxLeave(phx_leave::PhxLeave {}))
+            .await
+    }
+
+    /// Authorizes the socket with `token`, for applications that manage their tokens outside
+    /// of the [`JwtStream`](rp_supabase_auth::jwt_stream::JwtStream), e.g. with a session
+    /// imported from a web frontend.
+    ///
+    /// Like a refresh of the auth stream, pushes an `access_token` message to every channel of
+    /// the socket. Later joins and rejoins use `token` until the next refresh of the auth stream.
+    pub async fn set_auth(&mut self, token: &str) -> Result<(), futures::channel::mpsc::SendError> {
+        for message in self.state.access_token_messages(token) {
+            self.tx.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Closes the socket of the channel gracefully, unlike [`RealtimeConnectionClient::leave`]
+    /// which only leaves the channel.
+    ///
+    /// See [`RealtimeSocketClient::close`].
+    pub fn close(mut self) {
+        self.state.close(&mut self.tx);
+    }
+
+    /// The heartbeat round trips of the socket of the channel
+    #[must_use]
+    pub fn heartbeat_latency(&self) -> HeartbeatLatency {
+        self.state.latency.clone()
+    }
+
+    /// The full topic of the channel, e.g. `realtime:room-1`
+    #[must_use]
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Sends `payload` with a fresh `ref`, returning the future of its reply.
+    async fn push(
+        &mut self,
+        payload: ProtocolPayload,
+    ) -> Result<Reply, futures::channel::mpsc::SendError> {
+        let reply_ref = self.state.next_ref();
+        self.push_with_ref(reply_ref, payload).await
+    }
+
+    async fn push_with_ref(
+        &mut self,
+        reply_ref: String,
+        payload: ProtocolPayload,
+    ) -> Result<Reply, futures::channel::mpsc::SendError> {
+        let reply = self.state.replies.register(reply_ref.clone());
+        let sent = self
+            .tx
+            .send(ProtocolMessage {
+                topic: self.topic.clone(),
+                payload,
+                ref_field: Some(reply_ref.clone()),
+                join_ref: None,
+            })
+            .await;
+        if let Err(err) = sent {
+            self.state.replies.cancel(&reply_ref);
+            return Err(err);
+        }
+        Ok(reply)
+    }
+
+    async fn send(
+        &mut self,
+        payload: ProtocolPayload,
+    ) -> Result<(), futures::channel::mpsc::SendError> {
+        self.tx
+            .send(ProtocolMessage {
+                topic: self.topic.clone(),
+                payload,
+                ref_field: None,
+                join_ref: None,
+            })
+            .await
     }
 }
 
 pub struct RealtimeConnection {
     topic: String,
     config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig,
+    options: SocketOptions,
+    private: bool,
 }
 
-type RealtimeStreamType = Result<ProtocolMessage, SupabaseRealtimeError>;
+/// Configures the sockets and channels of a Supabase project once, for any number of topics.
+///
+/// Related settings are grouped, e.g. in [`HeartbeatOptions`] and [`BufferOptions`]; the rest
+/// of the [`SocketOptions`] are set with [`RealtimeConnectionBuilder::with_socket_options`].
+#[derive(Debug, Clone)]
+pub struct RealtimeConnectionBuilder {
+    config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig,
+    options: SocketOptions,
+    private: bool,
+}
 
-impl RealtimeConnection {
-    const HEARTBEAT_PERIOD: core::time::Duration = core::time::Duration::from_secs(20);
+/// When heartbeats are sent and how long their replies may take
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatOptions {
+    pub interval: Duration,
+    /// How long to wait for a reply before the heartbeat counts as missed; `None` disables the
+    /// check
+    pub timeout: Option<Duration>,
+    /// Consecutive missed heartbeats to tolerate before reconnecting
+    pub allowed_missed: u8,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        let options = SocketOptions::default();
+        Self {
+            interval: options.heartbeat_interval,
+            timeout: options.heartbeat_timeout,
+            allowed_missed: options.allowed_missed_heartbeats,
+        }
+    }
+}
+
+/// How many messages are queued on the way in and out of a socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferOptions {
+    /// Messages held back while reconnecting or joining
+    pub send_buffer: SendBufferPolicy,
+    /// Messages queued per channel stream, and for sending, before backpressure applies
+    pub channel_capacity: usize,
+    pub backpressure: Backpressure,
+}
 
+impl Default for BufferOptions {
+    fn default() -> Self {
+        let options = SocketOptions::default();
+        Self {
+            send_buffer: options.send_buffer,
+            channel_capacity: options.channel_capacity,
+            backpressure: options.backpressure,
+        }
+    }
+}
+
+impl RealtimeConnectionBuilder {
     #[must_use]
-    pub fn new_db_updates(config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig) -> Self {
-        const DB_UPDATE_TOPIC: &str = "table-db-changes";
-        Self::new(config, DB_UPDATE_TOPIC)
+    pub fn new(config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig) -> Self {
+        Self {
+            config,
+            options: SocketOptions::default(),
+            private: false,
+        }
     }
 
+    /// Replaces all socket options, including the ones set by the other methods.
     #[must_use]
-    pub fn new(config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig, topic: &str) -> Self {
-        let prefix = "realtime";
-        let topic = [prefix, topic].join(":");
-        Self { topic, config }
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
     }
 
-    #[tracing::instrument(skip_all, err)]
-    pub async fn connect(
-        self,
-        login_info: LoginCredentials,
-    ) -> Result<
-        (
-            impl Stream<Item = RealtimeStreamType>,
-            RealtimeConnectionClient,
-        ),
-        SupabaseRealtimeError,
-    > {
-        let supabase_annon_key = &self.config.api_key;
-        let realtime_url = self.config.url.join(
-            format!("realtime/v1/websocket?apikey={supabase_annon_key}&vsn=1.0.0").as_str(),
-        )?;
-
-        let mut auth_stream = rp_supabase_auth::jwt_stream::JwtStream::new(self.config.clone())
-            .sign_in(login_info)?;
-        let mut latest_access_token = loop {
-            match auth_stream.next().await {
-                Some(Ok(new_latest_access_token)) => {
-                    let Some(access_token) = new_latest_access_token.access_token else {
-                        tracing::error!("access token was not present!");
-                        continue;
-                    };
-                    break access_token;
-                }
-                Some(Err(err)) => {
-                    tracing::error!(?err, "initial jwt fetch err");
-                }
-                None => return Err(error::SupabaseRealtimeError::JwtStreamClosedUnexpectedly),
-            }
-        };
+    #[must_use]
+    pub const fn with_heartbeat(mut self, heartbeat: HeartbeatOptions) -> Self {
+        self.options.heartbeat_interval = heartbeat.interval;
+        self.options.heartbeat_timeout = heartbeat.timeout;
+        self.options.allowed_missed_heartbeats = heartbeat.allowed_missed;
+        self
+    }
 
-        let mut ref_counter = 0_u64;
-        let mut join_ref_counter = 0;
-        let (tx, rx) = futures::channel::mpsc::unbounded();
-        let topic = self.topic.clone();
-        let input_stream = rx
-            .map(move |item| {
-                ref_counter += 1;
-                join_ref_counter += 1;
-                message::ProtocolMessage {
-                    topic: topic.clone(),
-                    payload: item,
-                    ref_field: Some(ref_counter.to_string()),
-                    join_ref: Some(join_ref_counter.to_string()),
-                }
-            })
-            .map(Ok)
-            .boxed();
+    /// Reconnects as allowed by `policy`, instead of following the auth config.
+    #[must_use]
+    pub const fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.options.reconnect = Some(policy);
+        self
+    }
 
-        let heartbeat_stream = {
-            let mut interval = tokio::time::interval(Self::HEARTBEAT_PERIOD);
-            interval.reset();
-            let interval_stream = IntervalStream::new(interval).fuse();
-            interval_stream
-                .map(move |_s| message::ProtocolMessage {
-                    topic: "phoenix".to_owned(),
-                    payload: message::ProtocolPayload::Heartbeat(message::heartbeat::Heartbeat),
-                    ref_field: None,
-                    join_ref: None,
-                })
-                .map(Ok)
-                .boxed()
-        };
+    /// Joins channels again after a `phx_error` as allowed by `policy`, see
+    /// [`RealtimeBaseConnection::with_rejoin`].
+    #[must_use]
+    pub const fn with_rejoin(mut self, policy: ReconnectPolicy) -> Self {
+        self.options.rejoin = policy;
+        self
+    }
 
-        let topic = self.topic.clone();
-        let access_token_stream = {
-            auth_stream
-                .filter_map(move |item| {
-                    let topic = topic.clone();
-                    async move {
-                        item.map(|item| {
-                            if let Some(access_token) = item.access_token {
-                                return Some(message::ProtocolMessage {
-                                    topic: topic.clone(),
-                                    payload: message::ProtocolPayload::AccessToken(AccessToken {
-                                        access_token,
-                                    }),
-                                    ref_field: None,
-                                    join_ref: None,
-                                })
-                            }
-                            None
-                        })
-                        .map_err(SupabaseRealtimeError::from)
-                        .transpose()
-                    }
-                })
-                .boxed()
-        };
-        let input_stream =
-            futures::stream::select_all([input_stream, heartbeat_stream, access_token_stream])
-                .map(move |mut item| {
-                    if let Ok(item) = &mut item {
-                        if let message::ProtocolPayload::AccessToken(at) = &mut item.payload {
-                            latest_access_token = at.access_token.clone();
-                        }
-                        item.set_access_token(&latest_access_token);
-                    }
-                    item
-                })
-                .map(move |mut item| {
-                    ref_counter += 1;
-                    if let Ok(item) = &mut item {
-                        item.ref_field = Some(ref_counter.to_string());
-                    }
-                    item
-                });
+    #[must_use]
+    pub const fn with_buffers(mut self, buffers: BufferOptions) -> Self {
+        self.options.send_buffer = buffers.send_buffer;
+        self.options.channel_capacity = buffers.channel_capacity;
+        self.options.backpressure = buffers.backpressure;
+        self
+    }
 
-        let client = RealtimeConnectionClient { tx };
-        let output_stream = RealtimeBaseConnection::new(realtime_url)
-            .connect(input_stream)
-            .await?;
-        Ok((output_stream, client))
+    /// Limits the messages sent on each socket to `limit`, see [`RateLimit`].
+    #[must_use]
+    pub const fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.options.rate_limit = Some(limit);
+        self
     }
-}
 
-pub struct RealtimeBaseConnection {
-    url: url::Url,
-}
+    /// Joins the channels as private channels, see [`RealtimeSocketClient::private_channel`].
+    #[must_use]
+    pub const fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
 
-impl RealtimeBaseConnection {
+    /// Uses `transport` for the websocket, see [`RealtimeBaseConnection::with_transport`].
     #[must_use]
-    pub const fn new(url: url::Url) -> Self {
-        Self { url }
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.options.transport = Some(transport);
+        self
     }
-    pub async fn connect<S: Stream<Item = RealtimeStreamType> + Unpin>(
-        self,
-        mut input_stream: S,
-    ) -> Result<impl Stream<Item = RealtimeStreamType>, error::SupabaseRealtimeError> {
-        tracing::info!(url =? self.url.as_str(), "Starting RealtimeConnection::connect");
 
-        let con = Arc::new(Mutex::new(connection::connect(&self.url).await?));
-        tracing::info!("WebSocket connection established");
+    /// Uses `config` for TLS, see [`RealtimeBaseConnection::with_tls_config`].
+    #[must_use]
+    pub fn with_tls_config(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.options.tls_config = Some(config);
+        self
+    }
 
-        let mut write_futures = FuturesUnordered::new();
-        let mut reat_future = FuturesUnordered::new();
-        let (tx, mut rx) = futures::channel::mpsc::unbounded();
-        let read_task = {
-            let con = Arc::clone(&con);
-            async move {
-                let con = Arc::clone(&con);
-                read_from_ws(&con, tx).await;
-            }
-        };
-        reat_future.push(read_task);
+    /// Tunnels the websocket through `proxy`, see [`RealtimeBaseConnection::with_proxy`].
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.options.proxy = Some(proxy);
+        self
+    }
 
-        let stream_to_return = futures::stream::poll_fn(move |cx| {
-            match input_stream.poll_next_unpin(cx) {
-                Poll::Ready(Some(message_to_send)) => {
-                    let con = Arc::clone(&con);
-                    match message_to_send {
-                        Ok(message) => {
-                            write_futures.push(async move {
-                                let con = Arc::clone(&con);
-                                send(message, &con).await
-                            });
-                        }
-                        Err(err) => {
-                            cx.waker().wake_by_ref();
-                            return Poll::Ready(Some(Err(err)));
-                        }
-                    }
-                }
-                Poll::Ready(None) => return Poll::Ready(None),
-                Poll::Pending => {}
-            }
+    /// Connects to the websocket at `path` relative to the Supabase URL instead of
+    /// [`SocketOptions::DEFAULT_WEBSOCKET_PATH`], e.g. for a self-hosted Realtime.
+    #[must_use]
+    pub fn with_websocket_path(mut self, path: &str) -> Self {
+        path.clone_into(&mut self.options.websocket_path);
+        self
+    }
 
-            match reat_future.poll_next_unpin(cx) {
-                Poll::Ready(_) => {
-                    tracing::info!("Read task completed");
-                    return Poll::Ready(None);
-                }
-                Poll::Pending => {}
-            };
+    /// Adds `key=value` to the query of the websocket URL, e.g. `log_level=info`.
+    #[must_use]
+    pub fn with_query_param(mut self, key: &str, value: &str) -> Self {
+        self.options
+            .query_params
+            .push((key.to_owned(), value.to_owned()));
+        self
+    }
 
-            match write_futures.poll_next_unpin(cx) {
-                Poll::Ready(Some(res)) => match res {
-                    Ok(()) => {
-                        tracing::debug!("Message sent successfully");
-                    }
-                    Err(err) => {
-                        tracing::error!(?err, "Error sending message");
-                        cx.waker().wake_by_ref();
-                        return Poll::Ready(Some(Err(err)));
-                    }
-                },
-                Poll::Ready(None) => {}
-                Poll::Pending => {}
-            };
+    /// Sends `name: value` with the websocket upgrade request, see
+    /// [`RealtimeBaseConnection::with_header`].
+    #[must_use]
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.options.headers.push((name, value));
+        self
+    }
 
-            match rx.poll_next_unpin(cx) {
-                Poll::Ready(Some(item)) => {
-                    tracing::debug!(?item, "Received item");
-                    cx.waker().wake_by_ref();
-                    Poll::Ready(Some(Ok(item)))
-                }
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Pending => Poll::Pending,
-            }
-        });
-        Ok(stream_to_return)
+    /// A connection to the channel of `topic` (without the `realtime:` prefix), on a socket of
+    /// its own.
+    #[must_use]
+    pub fn channel(&self, topic: &str) -> RealtimeConnection {
+        RealtimeConnection {
+            topic: channel_topic(topic),
+            config: self.config.clone(),
+            options: self.options.clone(),
+            private: self.private,
+        }
+    }
+
+    /// Connects a socket to open channels on, see [`RealtimeSocket::connect_with_options`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if signing in or connecting fails.
+    pub async fn connect_socket(
+        &self,
+        login_info: LoginCredentials,
+    ) -> Result<(RealtimeSocket, RealtimeSocketClient), SupabaseRealtimeError> {
+        RealtimeSocket::connect_with_options(self.config.clone(), login_info, self.options.clone())
+            .await
+    }
+
+    /// Connects a socket authorized by a static `token`, see
+    /// [`RealtimeSocket::connect_with_token`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if connecting fails.
+    pub async fn connect_socket_with_token(
+        &self,
+        token: &str,
+    ) -> Result<(RealtimeSocket, RealtimeSocketClient), SupabaseRealtimeError> {
+        RealtimeSocket::connect_with_token(self.config.clone(), token, self.options.clone()).await
     }
 }
 
-async fn read_from_ws(
-    con: &Mutex<WsSupabaseConnection>,
-    mut tx: futures::channel::mpsc::UnboundedSender<ProtocolMessage>,
-) {
-    tracing::info!("Starting read_from_ws task");
-    let duration = core::time::Duration::from_millis(100);
-    loop {
-        let mut con = con.lock().await;
-        let Ok(frame) = timeout(duration, con.read_frame()).await else {
-            continue;
-        };
-        drop(con);
+/// Tuning of the websocket behind a [`RealtimeSocket`].
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    pub heartbeat_interval: Duration,
+    /// How long to wait for a heartbeat reply before the heartbeat counts as missed; `None`
+    /// disables the check
+    pub heartbeat_timeout: Option<Duration>,
+    /// Consecutive missed heartbeats to tolerate before the connection is considered dead and
+    /// reconnected
+    pub allowed_missed_heartbeats: u8,
+    pub send_buffer: SendBufferPolicy,
+    /// Messages queued per channel stream, and for sending, before backpressure applies
+    pub channel_capacity: usize,
+    /// What happens when a channel stream falls `channel_capacity` messages behind
+    pub backpressure: Backpressure,
+    /// Offer `permessage-deflate` to the server
+    #[cfg(feature = "deflate")]
+    pub compression: bool,
+    /// TLS settings of the websocket, see [`RealtimeBaseConnection::with_tls_config`]
+    pub tls_config: Option<Arc<rustls::ClientConfig>>,
+    /// Proxy of the websocket; by default the proxy of the auth config, then the proxy
+    /// environment variables apply
+    pub proxy: Option<ProxyConfig>,
+    /// Websocket backend, [`FastWebSockets`] by default
+    pub transport: Option<Arc<dyn Transport>>,
+    /// Largest frame accepted from the server, see [`RealtimeBaseConnection::with_max_frame_size`]
+    pub max_frame_size: Option<usize>,
+    /// Largest message accepted from the server, see
+    /// [`RealtimeBaseConnection::with_max_message_size`]
+    pub max_message_size: Option<usize>,
+    /// Wire format of the messages, [`Serializer::V1`] by default
+    pub serializer: Serializer,
+    /// Reconnects of a dropped websocket; by default `max_reconnect_attempts` of the auth config
+    /// apply, backing off from its `reconnect_interval`
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Joins of a channel again after the server reported a `phx_error` for it
+    pub rejoin: ReconnectPolicy,
+    /// Limits the messages sent by the clients, heartbeats excluded; unlimited by default
+    pub rate_limit: Option<RateLimit>,
+    /// Path of the websocket endpoint relative to the Supabase URL,
+    /// [`SocketOptions::DEFAULT_WEBSOCKET_PATH`] by default
+    pub websocket_path: String,
+    /// Added to the query of the websocket URL after `apikey`, e.g. `log_level`
+    pub query_params: Vec<(String, String)>,
+    /// Sent along with the websocket upgrade request
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// Receives the counters and gauges of the websocket
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<dyn MetricsRecorder>>,
+}
 
-        let mut frame = match frame {
-            Ok(frame) => frame,
-            Err(err) => {
-                tracing::error!(?err, "Error reading frame");
-                continue;
-            }
-        };
-        let repr = String::from_utf8_lossy(&frame.payload);
-        tracing::debug!(?repr, "Received frame");
+impl SocketOptions {
+    /// The interval of the official clients, matching the server's default timeout
+    pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+    /// Where Supabase serves Realtime
+    pub const DEFAULT_WEBSOCKET_PATH: &'static str = "realtime/v1/websocket";
+}
 
-        let from_slice = simd_json::from_slice(frame.payload.to_mut());
-        match from_slice {
-            Ok(item) => {
-                let _res = tx.send(item).await;
-            }
-            Err(err) => {
-                let repr = String::from_utf8_lossy(&frame.payload);
-                tracing::error!(?err, payload = ?repr, "Error deserializing data");
-            }
-        };
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Self::DEFAULT_HEARTBEAT_INTERVAL,
+            // a reply is due before the next heartbeat is sent
+            heartbeat_timeout: Some(Self::DEFAULT_HEARTBEAT_INTERVAL),
+            allowed_missed_heartbeats: 0,
+            send_buffer: SendBufferPolicy::default(),
+            channel_capacity: 256,
+            backpressure: Backpressure::default(),
+            #[cfg(feature = "deflate")]
+            compression: false,
+            tls_config: None,
+            proxy: None,
+            transport: None,
+            max_frame_size: None,
+            max_message_size: None,
+            serializer: Serializer::V1,
+            reconnect: None,
+            rejoin: ReconnectPolicy::default(),
+            rate_limit: None,
+            websocket_path: Self::DEFAULT_WEBSOCKET_PATH.to_owned(),
+            query_params: Vec::new(),
+            headers: Vec::new(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
     }
 }
 
-async fn send(
-    message_to_send: ProtocolMessage,
-    con: &Mutex<WsSupabaseConnection>,
-) -> Result<(), error::SupabaseRealtimeError> {
-    tracing::debug!(?message_to_send, "Sending message");
-    let message_bytes = simd_json::to_vec(&message_to_send)?;
-    let payload = fastwebsockets::Payload::<'static>::Owned(message_bytes);
-    let frame = Frame::<'static>::text(payload);
-    let mut con = con.lock().await;
-    con.write_frame(frame).await?;
-    drop(con);
-    Ok(())
+/// Reconnects a dropped websocket.
+///
+/// The delay starts at `initial_backoff` and doubles with every attempt, up to `max_backoff`.
+/// Up to `jitter_percent` of each delay is cut off at random, so that clients that lost their
+/// connection at the same time do not reconnect in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// `0` disables reconnecting
+    pub max_attempts: u8,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Clamped to 100
+    pub jitter_percent: u8,
+}
+
+impl ReconnectPolicy {
+    /// Never reconnect
+    #[must_use]
+    pub const fn none() -> Self {
+        Self {
+            max_attempts: 0,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            jitter_percent: 0,
+        }
+    }
+
+    #[must_use]
+    pub fn from_config(config: &rp_supabase_auth::jwt_stream::SupabaseAuthConfig) -> Self {
+        Self {
+            max_attempts: config.max_reconnect_attempts,
+            initial_backoff: config.reconnect_interval,
+            ..Self::default()
+        }
+    }
+
+    /// The delay before attempt number `attempt` (starting at 0), without jitter
+    #[must_use]
+    pub fn backoff(&self, attempt: u8) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt.into()))
+            .min(self.max_backoff)
+    }
+
+    /// [`ReconnectPolicy::backoff`] with the random jitter applied
+    #[must_use]
+    pub fn jittered_backoff(&self, attempt: u8) -> Duration {
+        let jitter = f64::from(self.jitter_percent.min(100)) / 100.0;
+        let cut = rand::thread_rng().gen_range(0.0..=jitter);
+        self.backoff(attempt).mul_f64(1.0 - cut)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    /// Five attempts, starting with a 1s delay, up to 30s
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            jitter_percent: 20,
+        }
+    }
+}
+
+/// Holds back the messages sent while the socket is reconnecting, or while their channel is
+/// still waiting for the reply to its `phx_join`, and replays them once they can be delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendBufferPolicy {
+    /// Messages held back at most, across all channels
+    pub capacity: usize,
+    pub overflow: BufferOverflow,
+}
+
+impl Default for SendBufferPolicy {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            overflow: BufferOverflow::default(),
+        }
+    }
+}
+
+/// What happens to a message sent while the send buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BufferOverflow {
+    /// Evicts the oldest buffered message to make room
+    #[default]
+    DropOldest,
+    /// Discards the new message
+    DropNewest,
+    /// Discards the new message and yields [`SupabaseRealtimeError::SendBufferFull`]
+    Error,
+}
+
+/// How a [`RealtimeSocket`] deals with a channel stream that is not polled fast enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Stops reading from the socket until the channel catches up; nothing is lost, but every
+    /// channel of the socket waits for the slowest one
+    #[default]
+    Await,
+    /// Drops the oldest queued message of the lagging channel
+    DropOldest,
+}
+
+type RealtimeStreamType = Result<ProtocolMessage, SupabaseRealtimeError>;
+
+/// Topic of the socket-level messages, like heartbeats
+const PHOENIX_TOPIC: &str = "phoenix";
+
+impl RealtimeConnection {
+    /// Configures connections to several topics at once.
+    #[must_use]
+    pub fn builder(
+        config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig,
+    ) -> RealtimeConnectionBuilder {
+        RealtimeConnectionBuilder::new(config)
+    }
+
+    #[must_use]
+    pub fn new_db_updates(config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig) -> Self {
+        const DB_UPDATE_TOPIC: &str = "table-db-changes";
+        Self::new(config, DB_UPDATE_TOPIC)
+    }
+
+    #[must_use]
+    pub fn new(config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig, topic: &str) -> Self {
+        Self {
+            topic: channel_topic(topic),
+            config,
+            options: SocketOptions::default(),
+            private: false,
+        }
+    }
+
+    #[must_use]
+    pub fn with_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sends a heartbeat every `interval`, for deployments whose server times out idle sockets
+    /// sooner or later than the hosted default.
+    #[must_use]
+    pub const fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.options.heartbeat_interval = interval;
+        self
+    }
+
+    /// Tolerates `allowed` consecutive heartbeats without a reply within `timeout` before
+    /// reconnecting; a `timeout` of `None` never reconnects because of heartbeats.
+    #[must_use]
+    pub const fn with_heartbeat_timeout(
+        mut self,
+        timeout: Option<Duration>,
+        allowed_missed: u8,
+    ) -> Self {
+        self.options.heartbeat_timeout = timeout;
+        self.options.allowed_missed_heartbeats = allowed_missed;
+        self
+    }
+
+    /// Sets how many messages are held back while reconnecting or joining.
+    #[must_use]
+    pub const fn with_send_buffer(mut self, policy: SendBufferPolicy) -> Self {
+        self.options.send_buffer = policy;
+        self
+    }
+
+    /// Queues up to `capacity` messages for the channel stream, applying `backpressure` when it
+    /// falls behind.
+    #[must_use]
+    pub const fn with_channel_capacity(
+        mut self,
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> Self {
+        self.options.channel_capacity = capacity;
+        self.options.backpressure = backpressure;
+        self
+    }
+
+    /// Offers `permessage-deflate` to the server, see [`RealtimeBaseConnection::with_compression`].
+    #[cfg(feature = "deflate")]
+    #[must_use]
+    pub const fn with_compression(mut self, enabled: bool) -> Self {
+        self.options.compression = enabled;
+        self
+    }
+
+    /// Uses `config` for TLS, see [`RealtimeBaseConnection::with_tls_config`].
+    #[must_use]
+    pub fn with_tls_config(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.options.tls_config = Some(config);
+        self
+    }
+
+    /// Tunnels the websocket through `proxy`, see [`RealtimeBaseConnection::with_proxy`].
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.options.proxy = Some(proxy);
+        self
+    }
+
+    /// Connects to the websocket at `path` relative to the Supabase URL instead of
+    /// [`SocketOptions::DEFAULT_WEBSOCKET_PATH`], e.g. for a self-hosted Realtime.
+    #[must_use]
+    pub fn with_websocket_path(mut self, path: &str) -> Self {
+        path.clone_into(&mut self.options.websocket_path);
+        self
+    }
+
+    /// Adds `key=value` to the query of the websocket URL, e.g. `log_level=info`.
+    #[must_use]
+    pub fn with_query_param(mut self, key: &str, value: &str) -> Self {
+        self.options
+            .query_params
+            .push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sends `name: value` with the websocket upgrade request, see
+    /// [`RealtimeBaseConnection::with_header`].
+    #[must_use]
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.options.headers.push((name, value));
+        self
+    }
+
+    /// Uses `transport` for the websocket, see [`RealtimeBaseConnection::with_transport`].
+    #[must_use]
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.options.transport = Some(transport);
+        self
+    }
+
+    /// Limits the frames and messages accepted from the server, see
+    /// [`RealtimeBaseConnection::with_max_frame_size`] and
+    /// [`RealtimeBaseConnection::with_max_message_size`].
+    #[must_use]
+    pub const fn with_max_sizes(mut self, max_frame_size: usize, max_message_size: usize) -> Self {
+        self.options.max_frame_size = Some(max_frame_size);
+        self.options.max_message_size = Some(max_message_size);
+        self
+    }
+
+    /// Uses the Phoenix `serializer`, see [`RealtimeBaseConnection::with_serializer`].
+    #[must_use]
+    pub const fn with_serializer(mut self, serializer: Serializer) -> Self {
+        self.options.serializer = serializer;
+        self
+    }
+
+    /// Joins the channel again after a `phx_error` as allowed by `policy`, see
+    /// [`RealtimeBaseConnection::with_rejoin`].
+    #[must_use]
+    pub const fn with_rejoin(mut self, policy: ReconnectPolicy) -> Self {
+        self.options.rejoin = policy;
+        self
+    }
+
+    /// Limits the messages sent on the socket to `limit`, see [`RateLimit`].
+    #[must_use]
+    pub const fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.options.rate_limit = Some(limit);
+        self
+    }
+
+    /// Reports the activity of the websocket to `recorder`, see
+    /// [`RealtimeBaseConnection::with_metrics`].
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_metrics(mut self, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.options.metrics = Some(recorder);
+        self
+    }
+
+    /// Joins the channel as a private channel, see [`RealtimeSocketClient::private_channel`].
+    #[must_use]
+    pub const fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Connects a socket that only carries this connection's channel.
+    ///
+    /// Use [`RealtimeSocket::connect`] to share one socket between several channels.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect(
+        self,
+        login_info: LoginCredentials,
+    ) -> Result<
+        (
+            impl Stream<Item = RealtimeStreamType>,
+            RealtimeConnectionClient,
+        ),
+        SupabaseRealtimeError,
+    > {
+        let (socket, socket_client) =
+            RealtimeSocket::connect_with_options(self.config, login_info, self.options).await?;
+        let (channel, client) = socket_client.channel_for_topic(self.topic, self.private);
+        Ok((futures::stream::select(socket, channel), client))
+    }
+
+    /// Like [`RealtimeConnection::connect`], but authorized by a static `token` instead of
+    /// signing in, see [`RealtimeSocket::connect_with_token`].
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect_with_token(
+        self,
+        token: &str,
+    ) -> Result<
+        (
+            impl Stream<Item = RealtimeStreamType>,
+            RealtimeConnectionClient,
+        ),
+        SupabaseRealtimeError,
+    > {
+        let (socket, socket_client) =
+            RealtimeSocket::connect_with_token(self.config, token, self.options).await?;
+        let (channel, client) = socket_client.channel_for_topic(self.topic, self.private);
+        Ok((futures::stream::select(socket, channel), client))
+    }
+
+    /// Like [`RealtimeConnection::connect`], but yields only the `postgres_changes` events, with
+    /// their records parsed into `T`.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect_typed<T: DeserializeOwned>(
+        self,
+        login_info: LoginCredentials,
+    ) -> Result<
+        (
+            impl Stream<Item = Result<ChangeEvent<T>, SupabaseRealtimeError>>,
+            RealtimeConnectionClient,
+        ),
+        SupabaseRealtimeError,
+    > {
+        let (stream, client) = self.connect(login_info).await?;
+        Ok((typed_changes(stream), client))
+    }
+
+    /// Like [`RealtimeConnection::connect`], but invokes `handlers` for the `postgres_changes`
+    /// events on a spawned task instead of returning the stream.
+    ///
+    /// Dispatching ends when the socket closes; abort the returned handle to stop it sooner.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect_with_handlers<T: DeserializeOwned + Send + 'static>(
+        self,
+        login_info: LoginCredentials,
+        handlers: ChangeHandlers<T>,
+    ) -> Result<(tokio::task::JoinHandle<()>, RealtimeConnectionClient), SupabaseRealtimeError>
+    {
+        let (stream, client) = self.connect(login_info).await?;
+        Ok((tokio::spawn(handlers.dispatch(stream)), client))
+    }
+}
+
+/// Callbacks for the `postgres_changes` events of a channel, by change type, with the records
+/// parsed into `T`.
+///
+/// Pass them to [`RealtimeConnection::connect_with_handlers`], or drive them over the stream of
+/// a [`RealtimeSocket`] channel with [`ChangeHandlers::dispatch`].
+pub struct ChangeHandlers<T> {
+    on_insert: Option<Box<dyn FnMut(T) + Send>>,
+    on_update: Option<Box<dyn FnMut(Option<T>, T) + Send>>,
+    on_delete: Option<Box<dyn FnMut(T) + Send>>,
+    on_error: Option<Box<dyn FnMut(SupabaseRealtimeError) + Send>>,
+}
+
+impl<T> Default for ChangeHandlers<T> {
+    fn default() -> Self {
+        Self {
+            on_insert: None,
+            on_update: None,
+            on_delete: None,
+            on_error: None,
+        }
+    }
+}
+
+impl<T> ChangeHandlers<T> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called with the record of every inserted row.
+    #[must_use]
+    pub fn on_insert(mut self, handler: impl FnMut(T) + Send + 'static) -> Self {
+        self.on_insert = Some(Box::new(handler));
+        self
+    }
+
+    /// Called with the old record, if the server sent one, and the new record of every updated
+    /// row.
+    #[must_use]
+    pub fn on_update(mut self, handler: impl FnMut(Option<T>, T) + Send + 'static) -> Self {
+        self.on_update = Some(Box::new(handler));
+        self
+    }
+
+    /// Called with the old record of every deleted row; unless the table uses
+    /// `REPLICA IDENTITY FULL`, only its primary key is set.
+    #[must_use]
+    pub fn on_delete(mut self, handler: impl FnMut(T) + Send + 'static) -> Self {
+        self.on_delete = Some(Box::new(handler));
+        self
+    }
+
+    /// Called with the errors of the stream and with records that are not a valid `T`, which
+    /// are only logged otherwise.
+    #[must_use]
+    pub fn on_error(mut self, handler: impl FnMut(SupabaseRealtimeError) + Send + 'static) -> Self {
+        self.on_error = Some(Box::new(handler));
+        self
+    }
+
+    /// Invokes the handlers for the events of `stream` until it ends.
+    pub async fn dispatch(mut self, stream: impl Stream<Item = RealtimeStreamType>)
+    where
+        T: DeserializeOwned,
+    {
+        let mut changes = core::pin::pin!(typed_changes::<T>(stream));
+        while let Some(change) = changes.next().await {
+            match change {
+                Ok(change) => self.handle(change),
+                Err(err) => match &mut self.on_error {
+                    Some(handler) => handler(err),
+                    None => tracing::warn!(?err, "Realtime error while dispatching changes"),
+                },
+            }
+        }
+    }
+
+    fn handle(&mut self, change: ChangeEvent<T>) {
+        use crate::message::postgres_changes::PostgresDataChangeEvent;
+
+        let data = change.data;
+        match data.type_ {
+            PostgresDataChangeEvent::Insert => {
+                if let (Some(handler), Some(record)) = (&mut self.on_insert, data.record) {
+                    handler(record);
+                }
+            }
+            PostgresDataChangeEvent::Update => {
+                if let (Some(handler), Some(record)) = (&mut self.on_update, data.record) {
+                    handler(data.old_record, record);
+                }
+            }
+            PostgresDataChangeEvent::Delete => {
+                if let (Some(handler), Some(old_record)) = (&mut self.on_delete, data.old_record) {
+                    handler(old_record);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the `postgres_changes` events of a realtime stream, parsing their records into `T`.
+///
+/// Useful for the channels of a [`RealtimeSocket`]; other messages are dropped and errors are
+/// passed through.
+pub fn typed_changes<T: DeserializeOwned>(
+    stream: impl Stream<Item = RealtimeStreamType>,
+) -> impl Stream<Item = Result<ChangeEvent<T>, SupabaseRealtimeError>> {
+    stream.filter_map(|item| {
+        futures::future::ready(match item {
+            Ok(ProtocolMessage {
+                payload: ProtocolPayload::PostgresChanges(changes),
+                ..
+            }) => Some(changes.parse().map_err(SupabaseRealtimeError::from)),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+    })
+}
+
+/// Routes the `postgres_changes` of a channel to one stream per subscription of its join.
+///
+/// The server assigns an id to every subscription in its join reply and tags each change with
+/// the ids it matched, so one channel can carry several filtered subscriptions, each parsed into
+/// its own type.
+///
+/// ```no_run
+/// # use futures::StreamExt as _;
+/// # use rp_supabase_realtime::realtime::{ChangeRouter, RealtimeSocketClient};
+/// # async fn example(
+/// #     socket_client: RealtimeSocketClient,
+/// #     reply: rp_supabase_realtime::message::phx_reply::PhxReplyQuery,
+/// # ) {
+/// # let (channel, _client) = socket_client.channel("db");
+/// #[derive(serde::Deserialize)]
+/// struct Profile {}
+/// #[derive(serde::Deserialize)]
+/// struct Todo {}
+///
+/// let mut router = ChangeRouter::new(&reply);
+/// let profiles = router.subscription::<Profile>(0).unwrap();
+/// let todos = router.subscription::<Todo>(1).unwrap();
+/// // polling the rest drives the routing
+/// tokio::spawn(router.route(channel).for_each(|_| async {}));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ChangeRouter {
+    subscriptions: Vec<phx_reply::PostgresChanges>,
+    routes: ChangeRoutes,
+}
+
+impl ChangeRouter {
+    /// Reads the subscription ids from the reply to the channel's `phx_join`.
+    #[must_use]
+    pub fn new(reply: &phx_reply::PhxReplyQuery) -> Self {
+        Self {
+            subscriptions: reply.postgres_changes.clone(),
+            routes: ChangeRoutes::default(),
+        }
+    }
+
+    /// The subscriptions accepted by the server, in the order of the join's
+    /// `postgres_changes`
+    #[must_use]
+    pub fn subscriptions(&self) -> &[phx_reply::PostgresChanges] {
+        &self.subscriptions
+    }
+
+    /// The changes of the subscription at `index` of the join's `postgres_changes`, with their
+    /// records parsed into `T`; `None` if the server did not return such a subscription.
+    pub fn subscription<T: DeserializeOwned>(
+        &mut self,
+        index: usize,
+    ) -> Option<impl Stream<Item = Result<ChangeEvent<T>, SupabaseRealtimeError>>> {
+        let id = i64::from(self.subscriptions.get(index)?.id);
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        self.routes.assign(id, tx);
+        Some(rx.map(|changes: PostgresChangesPayload| {
+            changes.parse().map_err(SupabaseRealtimeError::from)
+        }))
+    }
+
+    /// Forwards the changes of the channel's `stream` to the streams of their subscriptions,
+    /// yielding the other messages, errors and the changes of subscriptions without a stream.
+    pub fn route(
+        self,
+        stream: impl Stream<Item = RealtimeStreamType>,
+    ) -> impl Stream<Item = RealtimeStreamType> {
+        stream.filter_map(move |item| {
+            let routed = match &item {
+                Ok(ProtocolMessage {
+                    payload: ProtocolPayload::PostgresChanges(changes),
+                    ..
+                }) => self.routes.route(changes),
+                _ => false,
+            };
+            futures::future::ready((!routed).then_some(item))
+        })
+    }
+}
+
+/// Streams of `postgres_changes` by the subscription ids the server assigned
+#[derive(Debug, Default)]
+struct ChangeRoutes(Vec<(i64, UnboundedSender<PostgresChangesPayload>)>);
+
+impl ChangeRoutes {
+    /// Sends the changes of subscription `id` to `stream` as well.
+    fn assign(&mut self, id: i64, stream: UnboundedSender<PostgresChangesPayload>) {
+        self.0.push((id, stream));
+    }
+
+    /// Sends `changes` to the streams of the subscriptions it matched; `false` if there are none.
+    fn route(&self, changes: &PostgresChangesPayload) -> bool {
+        let mut routed = false;
+        for (_, stream) in self.0.iter().filter(|(id, _)| changes.ids.contains(id)) {
+            // a dropped stream is no longer interested
+            let _ignored = stream.unbounded_send(changes.clone());
+            routed = true;
+        }
+        routed
+    }
+}
+
+/// Keeps the broadcasts of `event` from a realtime stream, deserializing their payload into `T`.
+///
+/// Other messages are dropped and errors are passed through.
+pub fn typed_broadcasts<T: DeserializeOwned>(
+    stream: impl Stream<Item = RealtimeStreamType>,
+    event: &str,
+) -> impl Stream<Item = Result<T, SupabaseRealtimeError>> {
+    let event = event.to_owned();
+    stream.filter_map(move |item| {
+        futures::future::ready(match item {
+            Ok(ProtocolMessage {
+                payload: ProtocolPayload::Broadcast(broadcast),
+                ..
+            }) if broadcast.event == event => Some(
+                simd_json::serde::from_owned_value(broadcast.payload)
+                    .map_err(SupabaseRealtimeError::from),
+            ),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+    })
+}
+
+fn channel_topic(topic: &str) -> String {
+    let prefix = "realtime";
+    [prefix, topic].join(":")
+}
+
+/// State shared between a socket and the clients of its channels
+#[derive(Debug, Default)]
+struct SocketState {
+    channels: std::sync::Mutex<HashMap<String, QueueSender<ProtocolMessage>>>,
+    /// Messages awaiting their `phx_reply`, resolved by the connection task
+    replies: PendingReplies,
+    /// Round trips of the heartbeats, measured by the connection task
+    latency: HeartbeatLatency,
+    refs: AtomicU64,
+    /// A newer access token, from the auth stream or `set_auth`, adopted by the next message
+    /// sent
+    refreshed_token: std::sync::Mutex<Option<String>>,
+    /// Stops the heartbeats and token refreshes once the socket is closed
+    shutdown: std::sync::Mutex<Option<oneshot::Sender<()>>>,
+    /// The `postgres_changes` subscriptions of each channel
+    changes: std::sync::Mutex<HashMap<String, ChannelChanges>>,
+}
+
+/// The `postgres_changes` subscriptions of a channel, and the streams of the ones routed apart
+/// from the channel's stream
+#[derive(Debug, Default)]
+struct ChannelChanges {
+    /// Every subscription requested so far, sent with each `phx_join` of the channel
+    requested: Vec<phx_join::PostgrsChanges>,
+    /// The ref of the latest `phx_join`, whose reply assigns the subscription ids
+    join_ref: Option<String>,
+    /// The positions in `requested` of the subscriptions of each stream
+    streams: Vec<(Range<usize>, UnboundedSender<PostgresChangesPayload>)>,
+    /// The streams by the ids the server assigned to their subscriptions
+    routes: ChangeRoutes,
+}
+
+impl ChannelChanges {
+    fn assign_ids(&mut self, reply: &phx_reply::PhxReplyQuery) {
+        let mut routes = ChangeRoutes::default();
+        for (positions, stream) in &self.streams {
+            let subscriptions = reply
+                .postgres_changes
+                .get(positions.clone())
+                .unwrap_or_default();
+            for subscription in subscriptions {
+                routes.assign(i64::from(subscription.id), stream.clone());
+            }
+        }
+        self.routes = routes;
+    }
+}
+
+impl SocketState {
+    fn next_ref(&self) -> String {
+        (self.refs.fetch_add(1, Ordering::Relaxed) + 1).to_string()
+    }
+
+    /// Adopts `access_token` for the messages that follow and returns the `access_token` message
+    /// of every open channel.
+    fn access_token_messages(&self, access_token: &str) -> Vec<ProtocolMessage> {
+        self.refreshed_token
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(access_token.to_owned());
+        self.channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .map(|topic| ProtocolMessage {
+                topic: topic.clone(),
+                payload: ProtocolPayload::AccessToken(AccessToken {
+                    access_token: access_token.to_owned(),
+                }),
+                ref_field: None,
+                join_ref: None,
+            })
+            .collect()
+    }
+
+    /// Takes the subscription ids from the replies to the joins, and hands the changes of the
+    /// subscriptions with streams of their own to them; `None` if it did.
+    fn route_changes(&self, message: ProtocolMessage) -> Option<ProtocolMessage> {
+        let mut changes = self.changes.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(changes) = changes.get_mut(&message.topic) else {
+            return Some(message);
+        };
+        match &message.payload {
+            ProtocolPayload::PhxReply(phx_reply::PhxReply::Ok(reply))
+                if message.ref_field.is_some() && message.ref_field == changes.join_ref =>
+            {
+                changes.assign_ids(reply);
+            }
+            ProtocolPayload::PostgresChanges(payload) if changes.routes.route(payload) => {
+                return None
+            }
+            _ => {}
+        }
+        Some(message)
+    }
+
+    /// Ends the input of the socket: the messages already queued on `tx` are still sent,
+    /// followed by a close frame.
+    fn close(&self, tx: &mut Sender<ProtocolMessage>) {
+        tx.close_channel();
+        let shutdown = self
+            .shutdown
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(shutdown) = shutdown {
+            // the socket may be gone already
+            let _ignored = shutdown.send(());
+        }
+    }
+}
+
+/// A websocket that carries many channels, like the single connection of `supabase-js`.
+///
+/// The socket is a stream that has to be polled to drive the connection. It routes messages to
+/// the channels created through its [`RealtimeSocketClient`] and yields everything else: errors,
+/// heartbeat replies and messages for topics without a channel.
+pub struct RealtimeSocket {
+    output: BoxStream<'static, RealtimeStreamType>,
+    state: Arc<SocketState>,
+    /// A message waiting for room in the queue of its channel
+    undelivered: Option<ProtocolMessage>,
+}
+
+/// Creates channels on a [`RealtimeSocket`].
+#[derive(Clone)]
+pub struct RealtimeSocketClient {
+    tx: Sender<ProtocolMessage>,
+    state: Arc<SocketState>,
+    channel_capacity: usize,
+    backpressure: Backpressure,
+}
+
+impl RealtimeSocketClient {
+    /// Opens the channel of `topic` (without the `realtime:` prefix).
+    ///
+    /// Returns the messages of the channel and a client to join and broadcast on it. Only one
+    /// stream receives a topic's messages; opening a topic again replaces the previous stream.
+    #[must_use]
+    pub fn channel(
+        &self,
+        topic: &str,
+    ) -> (
+        impl Stream<Item = RealtimeStreamType>,
+        RealtimeConnectionClient,
+    ) {
+        self.channel_for_topic(channel_topic(topic), false)
+    }
+
+    /// Like [`RealtimeSocketClient::channel`], but joins with `private: true`, so that only
+    /// users allowed by the RLS policies of `realtime.messages` can broadcast and track
+    /// presence.
+    #[must_use]
+    pub fn private_channel(
+        &self,
+        topic: &str,
+    ) -> (
+        impl Stream<Item = RealtimeStreamType>,
+        RealtimeConnectionClient,
+    ) {
+        self.channel_for_topic(channel_topic(topic), true)
+    }
+
+    /// The round trips of the socket's heartbeats, updated with every reply
+    #[must_use]
+    pub fn heartbeat_latency(&self) -> HeartbeatLatency {
+        self.state.latency.clone()
+    }
+
+    /// Closes the socket gracefully.
+    ///
+    /// The messages already sent by the channels are written, followed by a close frame, after
+    /// which the socket's stream ends; it must be polled for this to happen. Sending on any of
+    /// the channels fails from now on.
+    pub fn close(mut self) {
+        self.state.close(&mut self.tx);
+    }
+
+    fn channel_for_topic(
+        &self,
+        topic: String,
+        private: bool,
+    ) -> (
+        impl Stream<Item = RealtimeStreamType>,
+        RealtimeConnectionClient,
+    ) {
+        let (tx, rx) = queue::bounded(self.channel_capacity, self.backpressure);
+        self.state
+            .channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(topic.clone(), tx);
+        let client = RealtimeConnectionClient {
+            topic,
+            tx: self.tx.clone(),
+            state: Arc::clone(&self.state),
+            private,
+        };
+        (rx.map(Ok), client)
+    }
+}
+
+impl RealtimeSocket {
+    pub async fn connect(
+        config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig,
+        login_info: LoginCredentials,
+    ) -> Result<(Self, RealtimeSocketClient), SupabaseRealtimeError> {
+        Self::connect_with_options(config, login_info, SocketOptions::default()).await
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect_with_options(
+        config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig,
+        login_info: LoginCredentials,
+        options: SocketOptions,
+    ) -> Result<(Self, RealtimeSocketClient), SupabaseRealtimeError> {
+        let mut auth_stream =
+            rp_supabase_auth::jwt_stream::JwtStream::new(config.clone()).sign_in(login_info)?;
+        let access_token = loop {
+            match auth_stream.next().await {
+                Some(Ok(new_latest_access_token)) => {
+                    let Some(access_token) = new_latest_access_token.access_token else {
+                        tracing::error!("access token was not present!");
+                        continue;
+                    };
+                    break access_token.expose_secret().clone();
+                }
+                Some(Err(err)) => {
+                    tracing::error!(?err, "initial jwt fetch err");
+                }
+                None => return Err(error::SupabaseRealtimeError::JwtStreamClosedUnexpectedly),
+            }
+        };
+        let refreshed_tokens = auth_stream
+            .map(|item| {
+                item.map(|item| {
+                    item.access_token
+                        .map(|access_token| access_token.expose_secret().clone())
+                })
+                .map_err(SupabaseRealtimeError::from)
+            })
+            .boxed();
+        Self::connect_with_access_tokens(config, access_token, refreshed_tokens, options).await
+    }
+
+    /// Connects with a `token` that is never refreshed, without signing in: the anon key for
+    /// public channels, or the service role key of a backend.
+    ///
+    /// Replace the token with [`RealtimeConnectionClient::set_auth`] if it has to change.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if connecting fails.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect_with_token(
+        config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig,
+        token: &str,
+        options: SocketOptions,
+    ) -> Result<(Self, RealtimeSocketClient), SupabaseRealtimeError> {
+        Self::connect_with_access_tokens(
+            config,
+            token.to_owned(),
+            futures::stream::empty().boxed(),
+            options,
+        )
+        .await
+    }
+
+    /// Connects with `access_token`, telling the channels about the `refreshed_tokens`.
+    async fn connect_with_access_tokens(
+        config: rp_supabase_auth::jwt_stream::SupabaseAuthConfig,
+        mut latest_access_token: String,
+        refreshed_tokens: BoxStream<'static, Result<Option<String>, SupabaseRealtimeError>>,
+        options: SocketOptions,
+    ) -> Result<(Self, RealtimeSocketClient), SupabaseRealtimeError> {
+        let supabase_annon_key = config.api_key.expose_secret();
+        let mut realtime_url = config.url.join(&options.websocket_path)?;
+        realtime_url
+            .query_pairs_mut()
+            .append_pair("apikey", supabase_annon_key)
+            .extend_pairs(&options.query_params);
+
+        let state = Arc::new(SocketState::default());
+        let (tx, rx) = futures::channel::mpsc::channel(options.channel_capacity);
+        let input_stream = match options.rate_limit {
+            Some(limit) => rate_limit::limit(rx, limit).boxed(),
+            None => rx.map(Ok).boxed(),
+        };
+
+        let heartbeat_stream = {
+            let mut interval = tokio::time::interval(options.heartbeat_interval);
+            interval.reset();
+            let interval_stream = IntervalStream::new(interval).fuse();
+            interval_stream
+                .map(move |_s| message::ProtocolMessage {
+                    topic: PHOENIX_TOPIC.to_owned(),
+                    payload: message::ProtocolPayload::Heartbeat(message::heartbeat::Heartbeat),
+                    ref_field: None,
+                    join_ref: None,
+                })
+                .map(Ok)
+                .boxed()
+        };
+
+        // every open channel is told about a refreshed access token
+        let access_token_stream = {
+            let state = Arc::clone(&state);
+            refreshed_tokens
+                .map(move |item| {
+                    let messages = match item {
+                        Ok(access_token) => access_token
+                            .map(|access_token| {
+                                state
+                                    .access_token_messages(&access_token)
+                                    .into_iter()
+                                    .map(Ok)
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        Err(err) => vec![Err(err)],
+                    };
+                    futures::stream::iter(messages)
+                })
+                .flatten()
+                .boxed()
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        state
+            .shutdown
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(shutdown_tx);
+        let background_stream = futures::stream::select(heartbeat_stream, access_token_stream)
+            .take_until(shutdown_rx)
+            .boxed();
+        let input_stream = futures::stream::select_all([input_stream, background_stream])
+            .map({
+                let state = Arc::clone(&state);
+                move |mut item| {
+                    if let Some(access_token) = state
+                        .refreshed_token
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .take()
+                    {
+                        latest_access_token = access_token;
+                    }
+                    if let Ok(item) = &mut item {
+                        item.set_access_token(&latest_access_token);
+                    }
+                    item
+                }
+            })
+            .map({
+                let state = Arc::clone(&state);
+                // the `join_ref` of every joined channel: the `ref` of its `phx_join`, carried by
+                // the messages of the channel until it is left
+                let mut join_refs = HashMap::<String, String>::new();
+                move |mut item| {
+                    if let Ok(item) = &mut item {
+                        // joins already carry the ref their reply is matched by
+                        let item_ref = item
+                            .ref_field
+                            .get_or_insert_with(|| state.next_ref())
+                            .clone();
+                        item.join_ref = match &item.payload {
+                            ProtocolPayload::PhxJoin(_) => {
+                                join_refs.insert(item.topic.clone(), item_ref.clone());
+                                Some(item_ref)
+                            }
+                            ProtocolPayload::PhxLeave(_) => join_refs.remove(&item.topic),
+                            _ => join_refs.get(&item.topic).cloned(),
+                        };
+                    }
+                    item
+                }
+            });
+
+        let mut base = RealtimeBaseConnection::new(realtime_url)
+            .with_reconnect(
+                options
+                    .reconnect
+                    .unwrap_or_else(|| ReconnectPolicy::from_config(&config)),
+            )
+            .with_rejoin(options.rejoin)
+            .with_serializer(options.serializer)
+            .with_pending_replies(state.replies.clone())
+            .with_heartbeat_latency(state.latency.clone())
+            .with_send_buffer(options.send_buffer)
+            .with_channel_capacity(options.channel_capacity);
+        #[cfg(feature = "deflate")]
+        {
+            base = base.with_compression(options.compression);
+        }
+        if let Some(tls_config) = options.tls_config {
+            base = base.with_tls_config(tls_config);
+        }
+        if let Some(proxy) = options.proxy.or_else(|| config.proxy.clone()) {
+            base = base.with_proxy(proxy);
+        }
+        if let Some(transport) = options.transport {
+            base = base.with_transport(transport);
+        }
+        if let Some(max_frame_size) = options.max_frame_size {
+            base = base.with_max_frame_size(max_frame_size);
+        }
+        if let Some(max_message_size) = options.max_message_size {
+            base = base.with_max_message_size(max_message_size);
+        }
+        for (name, value) in options.headers {
+            base = base.with_header(name, value);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(recorder) = options.metrics {
+            base = base.with_metrics(recorder);
+        }
+        if let Some(heartbeat_timeout) = options.heartbeat_timeout {
+            base = base
+                .with_heartbeat_timeout(heartbeat_timeout)
+                .with_allowed_missed_heartbeats(options.allowed_missed_heartbeats);
+        }
+        let output = base.connect(input_stream).await?.boxed();
+        let socket = Self {
+            output,
+            state: Arc::clone(&state),
+            undelivered: None,
+        };
+        let client = RealtimeSocketClient {
+            tx,
+            state,
+            channel_capacity: options.channel_capacity,
+            backpressure: options.backpressure,
+        };
+        Ok((socket, client))
+    }
+}
+
+impl Stream for RealtimeSocket {
+    type Item = RealtimeStreamType;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = if let Some(message) = self.undelivered.take() {
+                message
+            } else {
+                match ready!(self.output.poll_next_unpin(cx)) {
+                    Some(Ok(message)) => match self.state.route_changes(message) {
+                        Some(message) => message,
+                        None => continue,
+                    },
+                    Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                    None => {
+                        // the streams of the channels end with the socket
+                        self.state
+                            .channels
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .clear();
+                        self.state
+                            .changes
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .clear();
+                        return Poll::Ready(None);
+                    }
+                }
+            };
+            let mut channels = self
+                .state
+                .channels
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let Some(channel) = channels.get(&message.topic) else {
+                drop(channels);
+                return Poll::Ready(Some(Ok(message)));
+            };
+            match channel.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    if let Err(message) = channel.push(message) {
+                        channels.remove(&message.topic);
+                    }
+                }
+                Poll::Ready(Err(_gone)) => {
+                    // the stream of the channel was dropped
+                    channels.remove(&message.topic);
+                }
+                Poll::Pending => {
+                    drop(channels);
+                    self.undelivered = Some(message);
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+pub struct RealtimeBaseConnection {
+    url: url::Url,
+    reconnect: ReconnectPolicy,
+    rejoin: ReconnectPolicy,
+    heartbeat_timeout: Option<Duration>,
+    allowed_missed_heartbeats: u8,
+    send_buffer: SendBufferPolicy,
+    channel_capacity: usize,
+    connect_options: ConnectOptions,
+    transport: Option<Arc<dyn Transport>>,
+    serializer: Serializer,
+    replies: Option<PendingReplies>,
+    latency: Option<HeartbeatLatency>,
+    metrics: Metrics,
+}
+
+impl RealtimeBaseConnection {
+    #[must_use]
+    pub const fn new(url: url::Url) -> Self {
+        Self {
+            url,
+            reconnect: ReconnectPolicy::none(),
+            rejoin: ReconnectPolicy::none(),
+            heartbeat_timeout: None,
+            allowed_missed_heartbeats: 0,
+            send_buffer: SendBufferPolicy {
+                capacity: 100,
+                overflow: BufferOverflow::DropOldest,
+            },
+            channel_capacity: 256,
+            connect_options: ConnectOptions {
+                #[cfg(feature = "deflate")]
+                compression: false,
+                tls_config: None,
+                proxy: None,
+                max_frame_size: None,
+                max_message_size: None,
+                headers: Vec::new(),
+            },
+            transport: None,
+            serializer: Serializer::V1,
+            replies: None,
+            latency: None,
+            metrics: Metrics::disabled(),
+        }
+    }
+
+    /// Considers the connection dead when a heartbeat gets no reply within `timeout`.
+    ///
+    /// Only heartbeats sent with a `ref` are tracked. A dead connection is reconnected if
+    /// [`RealtimeBaseConnection::with_reconnect`] allows it, otherwise the stream ends.
+    #[must_use]
+    pub const fn with_heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    /// Only considers the connection dead after more than `allowed` consecutive heartbeats
+    /// went without a reply. Defaults to `0`.
+    #[must_use]
+    pub const fn with_allowed_missed_heartbeats(mut self, allowed: u8) -> Self {
+        self.allowed_missed_heartbeats = allowed;
+        self
+    }
+
+    /// Reconnects when the websocket drops, as allowed by `policy`.
+    ///
+    /// After reconnecting, `phx_join` is sent again for every channel that was joined, with the
+    /// latest access token, so the output stream resumes without the caller noticing.
+    #[must_use]
+    pub const fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Joins a channel again when the server reports a `phx_error` for it, backing off between
+    /// attempts as `policy` allows.
+    ///
+    /// Once the channel is joined again, its stream yields a
+    /// [`ChannelRejoined`](message::channel_rejoined::ChannelRejoined) event.
+    #[must_use]
+    pub const fn with_rejoin(mut self, policy: ReconnectPolicy) -> Self {
+        self.rejoin = policy;
+        self
+    }
+
+    /// Holds back the messages sent while reconnecting, or to a channel whose `phx_join` has
+    /// not been acknowledged yet, as allowed by `policy`.
+    ///
+    /// Heartbeats and access tokens are not buffered; joins are sent again after reconnecting
+    /// anyway.
+    #[must_use]
+    pub const fn with_send_buffer(mut self, policy: SendBufferPolicy) -> Self {
+        self.send_buffer = policy;
+        self
+    }
+
+    /// Offers `permessage-deflate`, so that the server may compress large messages like
+    /// `postgres_changes` of wide rows.
+    ///
+    /// Compressed messages are inflated transparently; falls back to uncompressed messages if the
+    /// server declines.
+    #[cfg(feature = "deflate")]
+    #[must_use]
+    pub const fn with_compression(mut self, enabled: bool) -> Self {
+        self.connect_options.compression = enabled;
+        self
+    }
+
+    /// Uses `config` for TLS instead of trusting the platform's native root certificates, e.g.
+    /// to pin roots, present a client certificate or verify through a corporate proxy's CA.
+    ///
+    /// The websocket is upgraded over HTTP/1.1, so `config` must not negotiate `h2` through
+    /// ALPN.
+    #[must_use]
+    pub fn with_tls_config(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.connect_options.tls_config = Some(config);
+        self
+    }
+
+    /// Tunnels the websocket through an HTTP `CONNECT` (`http://`) or SOCKS5 (`socks5://`,
+    /// `socks5h://`) proxy, unless the host is listed in its `no_proxy`.
+    ///
+    /// Without it, `HTTPS_PROXY` (for `wss://`), `HTTP_PROXY` (for `ws://`) and `ALL_PROXY`
+    /// apply, except for the hosts in `NO_PROXY`.
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.connect_options.proxy = Some(proxy);
+        self
+    }
+
+    /// Sends `name: value` along with the websocket upgrade request, e.g. the credentials of
+    /// a gateway in front of a self-hosted Realtime. Repeated names are all sent.
+    #[must_use]
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.connect_options.headers.push((name, value));
+        self
+    }
+
+    /// Closes the connection when the server sends a frame larger than `bytes`. Defaults to
+    /// [`ConnectOptions::DEFAULT_MAX_FRAME_SIZE`].
+    #[must_use]
+    pub const fn with_max_frame_size(mut self, bytes: usize) -> Self {
+        self.connect_options.max_frame_size = Some(bytes);
+        self
+    }
+
+    /// Closes the connection when the server sends a message larger than `bytes`, so that a
+    /// message fragmented into many frames cannot grow without bound. Defaults to
+    /// [`ConnectOptions::DEFAULT_MAX_MESSAGE_SIZE`].
+    #[must_use]
+    pub const fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.connect_options.max_message_size = Some(bytes);
+        self
+    }
+
+    /// Establishes the websocket with `transport` instead of [`FastWebSockets`], e.g.
+    /// `TungsteniteTransport` of the `tungstenite` feature.
+    #[must_use]
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Reports frames, reconnects, rejected joins and heartbeat round trips to `recorder`.
+    ///
+    /// Heartbeats are timed even without [`RealtimeBaseConnection::with_heartbeat_timeout`], as
+    /// long as they are sent with a `ref`.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_metrics(mut self, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.metrics = Metrics::new(recorder);
+        self
+    }
+
+    /// Resolves the [`Reply`] futures of `replies` with the `phx_reply` messages read from the
+    /// websocket.
+    ///
+    /// Register a message's `ref` before sending it through the input stream. Replies still
+    /// pending when the connection drops fail, unless their message is sent again after
+    /// reconnecting, and so do all replies once the stream ends.
+    #[must_use]
+    pub fn with_pending_replies(mut self, replies: PendingReplies) -> Self {
+        self.replies = Some(replies);
+        self
+    }
+
+    /// Records the round trip of every replied heartbeat in `latency`.
+    ///
+    /// Heartbeats are timed even without [`RealtimeBaseConnection::with_heartbeat_timeout`], as
+    /// long as they are sent with a `ref`.
+    #[must_use]
+    pub fn with_heartbeat_latency(mut self, latency: HeartbeatLatency) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Exchanges messages in the format of `serializer`, setting the `vsn` parameter of the URL
+    /// to match. Defaults to [`Serializer::V1`].
+    #[must_use]
+    pub fn with_serializer(mut self, serializer: Serializer) -> Self {
+        let pairs = self
+            .url
+            .query_pairs()
+            .filter(|(key, _)| key != "vsn")
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect::<Vec<_>>();
+        self.url
+            .query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair("vsn", serializer.vsn());
+        self.serializer = serializer;
+        self
+    }
+
+    /// Reads up to `capacity` messages ahead of the output stream, after which reading from
+    /// the websocket pauses until the stream is polled.
+    #[must_use]
+    pub const fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Connects and sends the messages of `input_stream`, returning the stream of received
+    /// messages.
+    ///
+    /// Once `input_stream` ends, the messages queued so far are written, followed by a close
+    /// frame, and the returned stream ends.
+    pub async fn connect<S: Stream<Item = RealtimeStreamType> + Unpin>(
+        self,
+        mut input_stream: S,
+    ) -> Result<impl Stream<Item = RealtimeStreamType>, error::SupabaseRealtimeError> {
+        tracing::info!(url =? self.url.as_str(), "Starting RealtimeConnection::connect");
+
+        let transport = self
+            .transport
+            .clone()
+            .unwrap_or_else(|| Arc::new(FastWebSockets));
+        let (read, write) = transport.connect(&self.url, &self.connect_options).await?;
+        tracing::info!("WebSocket connection established");
+
+        let mut write_futures = FuturesUnordered::new();
+        let mut reat_future = FuturesUnordered::new();
+        let (tx, mut rx) = futures::channel::mpsc::channel(self.channel_capacity);
+        // the reader and the writer of a connection run independently, so sends never wait for
+        // the next frame to arrive
+        let serializer = self.serializer;
+        let replies = CloseOnDrop(self.replies.clone().unwrap_or_default());
+        let metrics = self.metrics.clone();
+        let start_tasks = move |(read, write): TransportHalves| {
+            // fed from the bounded input stream, as fast as the writer takes the frames
+            let (frames_tx, frames_rx) = futures::channel::mpsc::unbounded();
+            let read_task = read_from_ws(
+                read,
+                serializer,
+                tx.clone(),
+                frames_tx.clone(),
+                metrics.clone(),
+            );
+            let write_task = write_to_ws(write, frames_rx, metrics.clone());
+            (frames_tx, read_task, write_task)
+        };
+        let (mut frames, read_task, write_task) = start_tasks((read, write));
+        reat_future.push(read_task);
+        write_futures.push(write_task);
+
+        // the `phx_join` of every joined channel, sent again after reconnecting
+        let mut joined = HashMap::<String, ProtocolMessage>::new();
+        // topics whose `phx_join` awaits its reply
+        let mut joining = HashSet::<String>::new();
+        // messages held back until the connection is up and their channel is joined
+        let mut buffer = VecDeque::<ProtocolMessage>::new();
+        let mut latest_access_token = None::<String>;
+        let mut reconnecting =
+            None::<BoxFuture<'static, Result<TransportHalves, SupabaseRealtimeError>>>;
+        let mut closed = false;
+        // the input ended, the queued frames and a close frame are being written
+        let mut closing = false;
+        // refs of the heartbeats awaiting a reply and when they were sent, oldest first
+        let mut pending_heartbeats = VecDeque::<(String, Instant)>::new();
+        let track_heartbeats =
+            self.heartbeat_timeout.is_some() || self.latency.is_some() || self.metrics.is_enabled();
+        let mut heartbeat_timer = None::<Pin<Box<Sleep>>>;
+        let mut missed_heartbeats = 0_u8;
+        // the failed join attempts of the channels that errored, and when to join them again
+        let mut rejoin_attempts = HashMap::<String, u8>::new();
+        let mut rejoin_timers = FuturesUnordered::<BoxFuture<'static, String>>::new();
+        // events of the connection itself, yielded before anything else
+        let mut events = VecDeque::<ProtocolMessage>::new();
+
+        let stream_to_return = futures::stream::poll_fn(move |cx| {
+            if closed {
+                return Poll::Ready(None);
+            }
+            if let Some(event) = events.pop_front() {
+                cx.waker().wake_by_ref();
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if closing {
+                let flushed = match write_futures.poll_next_unpin(cx) {
+                    Poll::Ready(Some(Err(err))) => {
+                        tracing::warn!(?err, "Error flushing the connection before closing");
+                        Some(Err(err))
+                    }
+                    Poll::Ready(Some(Ok(()))) | Poll::Ready(None) => None,
+                    Poll::Pending => return Poll::Pending,
+                };
+                tracing::info!("Connection closed");
+                closed = true;
+                reat_future.clear();
+                replies.0.close();
+                return Poll::Ready(flushed);
+            }
+
+            if let Some(reconnect) = &mut reconnecting {
+                match reconnect.poll_unpin(cx) {
+                    Poll::Ready(Ok(halves)) => {
+                        tracing::info!("WebSocket connection re-established");
+                        self.metrics.record(|recorder| recorder.reconnected());
+                        reconnecting = None;
+                        let (frames_tx, read_task, write_task) = start_tasks(halves);
+                        frames = frames_tx;
+                        reat_future.push(read_task);
+                        write_futures.push(write_task);
+                        joining.clear();
+                        // every channel is joined again anyway
+                        rejoin_attempts.clear();
+                        rejoin_timers.clear();
+                        for mut join in joined.values().cloned() {
+                            if let Some(access_token) = &latest_access_token {
+                                join.set_access_token(access_token);
+                            }
+                            if join.ref_field.is_some() {
+                                joining.insert(join.topic.clone());
+                            }
+                            if let Err(err) = queue_frame(&frames, self.serializer, &join) {
+                                cx.waker().wake_by_ref();
+                                return Poll::Ready(Some(Err(err)));
+                            }
+                        }
+                        // the rest is replayed once the channels are joined again
+                        let replayed =
+                            flush_buffer(&mut buffer, &frames, self.serializer, |message| {
+                                !joining.contains(&message.topic)
+                            });
+                        if let Err(err) = replayed {
+                            cx.waker().wake_by_ref();
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                    Poll::Ready(Err(err)) => {
+                        tracing::error!(?err, "Unable to reconnect");
+                        closed = true;
+                        replies.0.close();
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    Poll::Pending => {}
+                }
+            }
+
+            loop {
+                let message = match input_stream.poll_next_unpin(cx) {
+                    Poll::Ready(Some(Ok(message))) => message,
+                    Poll::Ready(Some(Err(err))) => {
+                        cx.waker().wake_by_ref();
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    Poll::Ready(None) if reconnecting.is_some() => {
+                        closed = true;
+                        replies.0.close();
+                        return Poll::Ready(None);
+                    }
+                    Poll::Ready(None) => {
+                        tracing::info!("Input stream ended, closing the connection");
+                        // the writer stops once it wrote everything queued before the close frame
+                        let _ignored = frames.unbounded_send(WsMessage::Close);
+                        frames.close_channel();
+                        closing = true;
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                    Poll::Pending => break,
+                };
+                let connected = reconnecting.is_none();
+                match &message.payload {
+                    ProtocolPayload::PhxJoin(_) => {
+                        joined.insert(message.topic.clone(), message.clone());
+                        if connected && message.ref_field.is_some() {
+                            joining.insert(message.topic.clone());
+                        }
+                    }
+                    ProtocolPayload::PhxLeave(_) => {
+                        joined.remove(&message.topic);
+                        rejoin_attempts.remove(&message.topic);
+                        joining.remove(&message.topic);
+                        buffer.retain(|buffered| buffered.topic != message.topic);
+                    }
+                    ProtocolPayload::AccessToken(token) => {
+                        latest_access_token = Some(token.access_token.clone());
+                    }
+                    ProtocolPayload::Heartbeat(_) => {
+                        if let (true, true, Some(heartbeat_ref)) =
+                            (connected, track_heartbeats, &message.ref_field)
+                        {
+                            let sent = Instant::now();
+                            pending_heartbeats.push_back((heartbeat_ref.clone(), sent));
+                            if let (None, Some(timeout)) =
+                                (&heartbeat_timer, self.heartbeat_timeout)
+                            {
+                                heartbeat_timer =
+                                    Some(Box::pin(tokio::time::sleep_until(sent + timeout)));
+                            }
+                        }
+                    }
+                    _ if !connected || joining.contains(&message.topic) => {
+                        if let Err(err) = buffer_message(&mut buffer, self.send_buffer, message) {
+                            cx.waker().wake_by_ref();
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+                // joins are sent once reconnected, the rest is stale by then
+                if !connected {
+                    continue;
+                }
+                if let Err(err) = queue_frame(&frames, self.serializer, &message) {
+                    tracing::error!(?err, "Error sending message");
+                    cx.waker().wake_by_ref();
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+            if reconnecting.is_some() {
+                return Poll::Pending;
+            }
+
+            while let Poll::Ready(Some(topic)) = rejoin_timers.poll_next_unpin(cx) {
+                // unless the channel was left in the meantime
+                let Some(mut join) = joined.get(&topic).cloned() else {
+                    continue;
+                };
+                tracing::info!(topic, "Joining the channel again");
+                if let Some(access_token) = &latest_access_token {
+                    join.set_access_token(access_token);
+                }
+                joining.insert(topic);
+                if let Err(err) = queue_frame(&frames, self.serializer, &join) {
+                    cx.waker().wake_by_ref();
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+
+            let mut heartbeat_timed_out = false;
+            if let (Some(timer), Some(timeout)) = (&mut heartbeat_timer, self.heartbeat_timeout) {
+                if timer.as_mut().poll(cx).is_ready() {
+                    let now = Instant::now();
+                    while pending_heartbeats
+                        .front()
+                        .is_some_and(|&(_, sent)| sent + timeout <= now)
+                    {
+                        pending_heartbeats.pop_front();
+                        missed_heartbeats = missed_heartbeats.saturating_add(1);
+                        tracing::warn!(missed_heartbeats, "No heartbeat reply received in time");
+                    }
+                    heartbeat_timed_out = missed_heartbeats > self.allowed_missed_heartbeats;
+                    heartbeat_timer = pending_heartbeats
+                        .front()
+                        .filter(|_| !heartbeat_timed_out)
+                        .map(|&(_, sent)| Box::pin(tokio::time::sleep_until(sent + timeout)));
+                    if heartbeat_timer.is_some() {
+                        cx.waker().wake_by_ref();
+                    }
+                }
+            }
+            let read_status = if heartbeat_timed_out {
+                tracing::warn!("No heartbeat reply received, the connection is dead");
+                // stop reading from the dead connection
+                reat_future.clear();
+                pending_heartbeats.clear();
+                Poll::Ready(None)
+            } else {
+                reat_future.poll_next_unpin(cx)
+            };
+
+            match read_status {
+                Poll::Ready(_) if self.reconnect.max_attempts > 0 => {
+                    tracing::warn!("WebSocket connection dropped, reconnecting");
+                    // buffered messages and joins are sent again, the rest is lost
+                    replies.0.connection_lost(|reply_ref| {
+                        let resent = |message: &ProtocolMessage| {
+                            message.ref_field.as_deref() == Some(reply_ref)
+                        };
+                        buffer.iter().any(resent) || joined.values().any(resent)
+                    });
+                    pending_heartbeats.clear();
+                    heartbeat_timer = None;
+                    missed_heartbeats = 0;
+                    rejoin_attempts.clear();
+                    rejoin_timers.clear();
+                    // frames queued for the dead connection are dropped with its writer
+                    write_futures.clear();
+                    reconnecting = Some(
+                        reconnect(
+                            Arc::clone(&transport),
+                            self.url.clone(),
+                            self.connect_options.clone(),
+                            self.reconnect,
+                        )
+                        .boxed(),
+                    );
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Poll::Ready(_) => {
+                    // deliver what the reader queued before it stopped
+                    if let Poll::Ready(Some(item)) = rx.poll_next_unpin(cx) {
+                        replies.0.resolve(&item);
+                        return Poll::Ready(Some(Ok(item)));
+                    }
+                    tracing::info!("Read task completed");
+                    closed = true;
+                    replies.0.close();
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => {}
+            };
+
+            match write_futures.poll_next_unpin(cx) {
+                Poll::Ready(Some(Err(err))) => {
+                    tracing::error!(?err, "Error sending message");
+                    cx.waker().wake_by_ref();
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Ready(Some(Ok(()))) => {
+                    tracing::debug!("Write task completed");
+                }
+                Poll::Ready(None) | Poll::Pending => {}
+            };
+
+            match rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    tracing::debug!(?item, "Received item");
+                    replies.0.resolve(&item);
+                    if let (ProtocolPayload::PhxReply(_), Some(reply_ref)) =
+                        (&item.payload, &item.ref_field)
+                    {
+                        if item.topic == PHOENIX_TOPIC {
+                            let replied = pending_heartbeats
+                                .iter()
+                                .position(|(heartbeat_ref, _)| heartbeat_ref == reply_ref)
+                                .and_then(|position| pending_heartbeats.remove(position));
+                            if let Some((_, sent)) = replied {
+                                let rtt = sent.elapsed();
+                                tracing::debug!(?rtt, "Heartbeat replied");
+                                if let Some(latency) = &self.latency {
+                                    latency.record(rtt);
+                                }
+                                self.metrics.record(|recorder| recorder.heartbeat_rtt(rtt));
+                            }
+                            // even a late reply shows the connection is alive
+                            missed_heartbeats = 0;
+                        }
+                    }
+                    if let (ProtocolPayload::PhxReply(reply), Some(reply_ref)) =
+                        (&item.payload, &item.ref_field)
+                    {
+                        let join_ref = joined
+                            .get(&item.topic)
+                            .and_then(|join| join.ref_field.as_ref());
+                        if join_ref == Some(reply_ref) && joining.remove(&item.topic) {
+                            match reply {
+                                phx_reply::PhxReply::Ok(_) => {
+                                    if let Some(attempts) = rejoin_attempts.remove(&item.topic) {
+                                        tracing::info!(topic = item.topic, "Channel rejoined");
+                                        events.push_back(ProtocolMessage {
+                                            topic: item.topic.clone(),
+                                            payload: ProtocolPayload::ChannelRejoined(
+                                                ChannelRejoined { attempts },
+                                            ),
+                                            ref_field: None,
+                                            join_ref: item.join_ref.clone(),
+                                        });
+                                    }
+                                    let replayed = flush_buffer(
+                                        &mut buffer,
+                                        &frames,
+                                        self.serializer,
+                                        |message| message.topic == item.topic,
+                                    );
+                                    if let Err(err) = replayed {
+                                        tracing::error!(?err, "Error replaying buffered messages");
+                                    }
+                                }
+                                // the buffered messages wait for the next attempt
+                                phx_reply::PhxReply::Error(_)
+                                    if rejoin_attempts.contains_key(&item.topic) &&
+                                        schedule_rejoin(
+                                            &mut rejoin_attempts,
+                                            &rejoin_timers,
+                                            self.rejoin,
+                                            &item.topic,
+                                        ) =>
+                                {
+                                    self.metrics
+                                        .record(|recorder| recorder.join_failed(&item.topic));
+                                    joining.insert(item.topic.clone());
+                                }
+                                phx_reply::PhxReply::Error(_) => {
+                                    self.metrics
+                                        .record(|recorder| recorder.join_failed(&item.topic));
+                                    let before = buffer.len();
+                                    buffer.retain(|message| message.topic != item.topic);
+                                    tracing::warn!(
+                                        topic = item.topic,
+                                        dropped = before - buffer.len(),
+                                        "Join rejected, dropping the buffered messages"
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    if let ProtocolPayload::PhxError(_) = &item.payload {
+                        let topic = &item.topic;
+                        if joined.contains_key(topic) &&
+                            !rejoin_attempts.contains_key(topic) &&
+                            schedule_rejoin(
+                                &mut rejoin_attempts,
+                                &rejoin_timers,
+                                self.rejoin,
+                                topic,
+                            )
+                        {
+                            tracing::warn!(topic, "Channel errored, joining it again");
+                            // messages to the channel are held back until it is joined again
+                            joining.insert(topic.clone());
+                        }
+                    }
+                    cx.waker().wake_by_ref();
+                    Poll::Ready(Some(Ok(item)))
+                }
+                Poll::Ready(None) => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            }
+        });
+        Ok(stream_to_return)
+    }
+}
+
+/// Joins `topic` again after the backoff of its next attempt; `false` if `policy` gave up on it.
+fn schedule_rejoin(
+    attempts: &mut HashMap<String, u8>,
+    timers: &FuturesUnordered<BoxFuture<'static, String>>,
+    policy: ReconnectPolicy,
+    topic: &str,
+) -> bool {
+    let attempt = attempts.entry(topic.to_owned()).or_insert(0);
+    if *attempt >= policy.max_attempts {
+        if *attempt > 0 {
+            tracing::error!(
+                topic,
+                attempts = *attempt,
+                "Giving up on joining the channel again"
+            );
+        }
+        attempts.remove(topic);
+        return false;
+    }
+    let backoff = policy.jittered_backoff(*attempt);
+    *attempt += 1;
+    let topic = topic.to_owned();
+    timers.push(
+        async move {
+            tokio::time::sleep(backoff).await;
+            topic
+        }
+        .boxed(),
+    );
+    true
+}
+
+/// Fails the pending replies once the connection task is dropped.
+struct CloseOnDrop(PendingReplies);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Re-runs the handshake, backing off between attempts as `policy` allows.
+async fn reconnect(
+    transport: Arc<dyn Transport>,
+    url: url::Url,
+    options: ConnectOptions,
+    policy: ReconnectPolicy,
+) -> Result<TransportHalves, error::SupabaseRealtimeError> {
+    let mut attempt = 0;
+    loop {
+        tokio::time::sleep(policy.jittered_backoff(attempt)).await;
+        attempt += 1;
+        match transport.connect(&url, &options).await {
+            Ok(halves) => return Ok(halves),
+            Err(err) if attempt < policy.max_attempts => {
+                tracing::warn!(?err, attempt, "Reconnect attempt failed");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Forwards the received messages to `tx` until the connection is lost.
+///
+/// Pongs and close replies required by the protocol are queued on `frames`.
+async fn read_from_ws(
+    mut read: Box<dyn TransportRead>,
+    serializer: Serializer,
+    mut tx: Sender<ProtocolMessage>,
+    frames: UnboundedSender<WsMessage>,
+    metrics: Metrics,
+) {
+    tracing::info!("Starting read_from_ws task");
+    loop {
+        let payload = match read.read_message(&frames).await {
+            Ok(Some(payload)) => payload,
+            Ok(None) => {
+                tracing::warn!("Connection closed by the server");
+                return;
+            }
+            Err(err) => {
+                tracing::error!(?err, "Error reading frame, connection lost");
+                return;
+            }
+        };
+        let repr = String::from_utf8_lossy(&payload);
+        tracing::debug!(?repr, "Received frame");
+        metrics.record(|recorder| recorder.frame_received(payload.len()));
+
+        // parsed in place, which is free unless the transport still shares the buffer
+        let mut payload = Vec::from(payload);
+        match serializer.decode(&mut payload) {
+            Ok(item) => {
+                if tx.send(item).await.is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                let repr = String::from_utf8_lossy(&payload);
+                tracing::error!(?err, payload = ?repr, "Error deserializing data");
+            }
+        };
+    }
+}
+
+/// Writes the queued frames until every sender is gone.
+async fn write_to_ws(
+    mut write: Box<dyn TransportWrite>,
+    mut frames: futures::channel::mpsc::UnboundedReceiver<WsMessage>,
+    metrics: Metrics,
+) -> Result<(), error::SupabaseRealtimeError> {
+    while let Some(frame) = frames.next().await {
+        let bytes = match &frame {
+            WsMessage::Text(payload) | WsMessage::Pong(payload) => payload.len(),
+            WsMessage::Close => 0,
+        };
+        write.write_message(frame).await?;
+        metrics.record(|recorder| recorder.frame_sent(bytes));
+        tracing::debug!("Message sent successfully");
+    }
+    Ok(())
+}
+
+/// Holds back `message`, applying the overflow policy when the buffer is full.
+fn buffer_message(
+    buffer: &mut VecDeque<ProtocolMessage>,
+    policy: SendBufferPolicy,
+    message: ProtocolMessage,
+) -> Result<(), error::SupabaseRealtimeError> {
+    if buffer.len() < policy.capacity {
+        buffer.push_back(message);
+        return Ok(());
+    }
+    match policy.overflow {
+        BufferOverflow::DropOldest => {
+            if let Some(dropped) = buffer.pop_front() {
+                tracing::warn!(
+                    topic = dropped.topic,
+                    "Send buffer full, dropping the oldest message"
+                );
+                buffer.push_back(message);
+            } else {
+                tracing::warn!(
+                    topic = message.topic,
+                    "Send buffer disabled, dropping the message"
+                );
+            }
+            Ok(())
+        }
+        BufferOverflow::DropNewest => {
+            tracing::warn!(
+                topic = message.topic,
+                "Send buffer full, dropping the message"
+            );
+            Ok(())
+        }
+        BufferOverflow::Error => Err(error::SupabaseRealtimeError::SendBufferFull),
+    }
+}
+
+/// Queues the buffered messages that are `ready`, in order, keeping the rest.
+fn flush_buffer(
+    buffer: &mut VecDeque<ProtocolMessage>,
+    frames: &UnboundedSender<WsMessage>,
+    serializer: Serializer,
+    ready: impl Fn(&ProtocolMessage) -> bool,
+) -> Result<(), error::SupabaseRealtimeError> {
+    let mut kept = VecDeque::with_capacity(buffer.len());
+    for message in buffer.drain(..) {
+        if ready(&message) {
+            queue_frame(frames, serializer, &message)?;
+        } else {
+            kept.push_back(message);
+        }
+    }
+    *buffer = kept;
+    Ok(())
+}
+
+/// Queues `message` for the writer task of the connection.
+fn queue_frame(
+    frames: &UnboundedSender<WsMessage>,
+    serializer: Serializer,
+    message: &ProtocolMessage,
+) -> Result<(), error::SupabaseRealtimeError> {
+    tracing::debug!(?message, "Sending message");
+    let message_bytes = serializer.encode(message)?;
+    frames
+        .unbounded_send(WsMessage::Text(message_bytes.into()))
+        .map_err(|_err| SupabaseRealtimeError::MpscSendError)
+}
+
+#[cfg(test)]
+mod tests {
+    use fastwebsockets::{Frame, OpCode, Payload, Role, WebSocket};
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::memory::testing::{join, reply, reply_ok, socket_over_memory, test_config};
+    use crate::message::phx_close::PhxClose;
+    use crate::message::phx_join::PhxJoin;
+
+    const TOPIC: &str = "realtime:test";
+
+    async fn accept(listener: &TcpListener) -> WebSocket<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        handshake(stream).await
+    }
+
+    async fn handshake<S>(mut stream: S) -> WebSocket<S>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        WebSocket::after_handshake(stream, Role::Server)
+    }
+
+    fn test_policy(max_attempts: u8) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            jitter_percent: 0,
+        }
+    }
+
+    async fn read_message<S>(ws: &mut WebSocket<S>) -> ProtocolMessage
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut frame = ws.read_frame().await.unwrap();
+        simd_json::from_slice(frame.payload.to_mut()).unwrap()
+    }
+
+    fn message(payload: ProtocolPayload) -> ProtocolMessage {
+        ProtocolMessage {
+            topic: TOPIC.to_owned(),
+            payload,
+            ref_field: None,
+            join_ref: None,
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_reconnect_rejoins_with_latest_access_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let join = message(ProtocolPayload::PhxJoin(PhxJoin {
+            access_token: Some("token-1".to_owned()),
+            ..join(false, vec![])
+        }));
+        let server = tokio::spawn({
+            let join = join.clone();
+            async move {
+                let mut ws = accept(&listener).await;
+                assert_eq!(read_message(&mut ws).await, join);
+                read_message(&mut ws).await;
+                // drop the connection without a close frame
+                drop(ws);
+
+                let mut ws = accept(&listener).await;
+                let rejoin = read_message(&mut ws).await;
+                let close =
+                    simd_json::to_vec(&message(ProtocolPayload::PhxClose(PhxClose {}))).unwrap();
+                ws.write_frame(Frame::text(Payload::Owned(close)))
+                    .await
+                    .unwrap();
+                (ws, rejoin)
+            }
+        });
+
+        let (mut tx, rx) = futures::channel::mpsc::unbounded();
+        tx.send(Ok(join.clone())).await.unwrap();
+        tx.send(Ok(message(ProtocolPayload::AccessToken(AccessToken {
+            access_token: "token-2".to_owned(),
+        }))))
+        .await
+        .unwrap();
+        let mut stream = Box::pin(
+            RealtimeBaseConnection::new(url)
+                .with_reconnect(test_policy(2))
+                .connect(rx)
+                .await
+                .unwrap(),
+        );
+
+        let received = stream.next().await.unwrap().unwrap();
+        assert_eq!(received, message(ProtocolPayload::PhxClose(PhxClose {})));
+        let (_ws, rejoin) = server.await.unwrap();
+        let mut expected = join;
+        expected.set_access_token("token-2");
+        assert_eq!(rejoin, expected);
+    }
+
+    #[test(tokio::test)]
+    async fn test_missing_heartbeat_reply_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let heartbeat = |heartbeat_ref: &str| {
+            Ok(ProtocolMessage {
+                topic: PHOENIX_TOPIC.to_owned(),
+                payload: ProtocolPayload::Heartbeat(crate::message::heartbeat::Heartbeat),
+                ref_field: Some(heartbeat_ref.to_owned()),
+                join_ref: None,
+            })
+        };
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(heartbeat("1")).unwrap();
+        let mut server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let first = read_message(&mut ws).await;
+            let reply = ProtocolMessage {
+                topic: PHOENIX_TOPIC.to_owned(),
+                payload: ProtocolPayload::PhxReply(phx_reply::PhxReply::Ok(
+                    phx_reply::PhxReplyQuery {
+                        postgres_changes: vec![],
+                    },
+                )),
+                ref_field: first.ref_field,
+                join_ref: None,
+            };
+            ws.write_frame(Frame::text(Payload::Owned(
+                simd_json::to_vec(&reply).unwrap(),
+            )))
+            .await
+            .unwrap();
+
+            // the replied heartbeat keeps the connection alive
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            tx.unbounded_send(heartbeat("2")).unwrap();
+            let second = read_message(&mut ws).await;
+
+            // the unanswered one makes the client reconnect
+            let _ws = tokio::time::timeout(Duration::from_secs(5), accept(&listener))
+                .await
+                .unwrap();
+            (second.ref_field, tx)
+        });
+        let mut stream = Box::pin(
+            RealtimeBaseConnection::new(url)
+                .with_reconnect(test_policy(1))
+                .with_heartbeat_timeout(Duration::from_millis(300))
+                .connect(rx)
+                .await
+                .unwrap(),
+        );
+
+        let second_ref = loop {
+            tokio::select! {
+                res = &mut server => break res.unwrap().0,
+                _item = stream.next() => {}
+            }
+        };
+        assert_eq!(second_ref.as_deref(), Some("2"));
+    }
+
+    #[test(tokio::test)]
+    async fn test_missed_heartbeats_are_tolerated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let heartbeat = |heartbeat_ref: &str| {
+            Ok(ProtocolMessage {
+                topic: PHOENIX_TOPIC.to_owned(),
+                payload: ProtocolPayload::Heartbeat(crate::message::heartbeat::Heartbeat),
+                ref_field: Some(heartbeat_ref.to_owned()),
+                join_ref: None,
+            })
+        };
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(heartbeat("1")).unwrap();
+        let mut server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            read_message(&mut ws).await;
+            // one missed heartbeat is tolerated
+            let reconnected =
+                tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+            assert!(
+                reconnected.is_err(),
+                "reconnected after one missed heartbeat"
+            );
+
+            tx.unbounded_send(heartbeat("2")).unwrap();
+            read_message(&mut ws).await;
+            // the second one is not
+            let _ws = tokio::time::timeout(Duration::from_secs(5), accept(&listener))
+                .await
+                .unwrap();
+            tx
+        });
+        let mut stream = Box::pin(
+            RealtimeBaseConnection::new(url)
+                .with_reconnect(test_policy(1))
+                .with_heartbeat_timeout(Duration::from_millis(100))
+                .with_allowed_missed_heartbeats(1)
+                .connect(rx)
+                .await
+                .unwrap(),
+        );
+
+        let _tx = loop {
+            tokio::select! {
+                res = &mut server => break res.unwrap(),
+                _item = stream.next() => {}
+            }
+        };
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = ReconnectPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            jitter_percent: 50,
+        };
+        let backoffs = (0..5)
+            .map(|attempt| policy.backoff(attempt))
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, [1, 2, 4, 5, 5].map(Duration::from_secs).to_vec());
+        for attempt in 0..5 {
+            let delay = policy.jittered_backoff(attempt);
+            let backoff = policy.backoff(attempt);
+            assert!(delay <= backoff && delay >= backoff / 2, "{delay:?}");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_socket_routes_messages_to_channels() {
+        let (output_tx, output_rx) = futures::channel::mpsc::unbounded();
+        let (tx, mut sent) = futures::channel::mpsc::channel(16);
+        let state = Arc::new(SocketState::default());
+        let mut socket = RealtimeSocket {
+            output: output_rx.boxed(),
+            state: Arc::clone(&state),
+            undelivered: None,
+        };
+        let socket_client = RealtimeSocketClient {
+            tx,
+            state,
+            channel_capacity: 16,
+            backpressure: Backpressure::Await,
+        };
+        let (mut first, _first_client) = socket_client.channel("first");
+        let (mut second, mut second_client) = socket_client.channel("second");
+
+        let close = |topic: &str| ProtocolMessage {
+            topic: topic.to_owned(),
+            ..message(ProtocolPayload::PhxClose(PhxClose {}))
+        };
+        for topic in ["realtime:second", "phoenix", "realtime:first"] {
+            output_tx.unbounded_send(Ok(close(topic))).unwrap();
+        }
+        drop(output_tx);
+
+        assert_eq!(socket.next().await.unwrap().unwrap(), close("phoenix"));
+        assert!(socket.next().await.is_none());
+        assert_eq!(
+            first.next().await.unwrap().unwrap(),
+            close("realtime:first")
+        );
+        assert_eq!(
+            second.next().await.unwrap().unwrap(),
+            close("realtime:second")
+        );
+
+        second_client
+            .broadcast(broadcast::Broadcast {
+                event: "message".to_owned(),
+                payload: simd_json::json!({"text": "hi"}),
+                r#type: "broadcast".to_owned(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(sent.next().await.unwrap().topic, "realtime:second");
+
+        second_client.leave().await.unwrap();
+        let leave = sent.next().await.unwrap();
+        assert_eq!(
+            (leave.topic.as_str(), leave.payload),
+            (
+                "realtime:second",
+                ProtocolPayload::PhxLeave(phx_leave::PhxLeave {})
+            )
+        );
+        assert!(second.next().await.is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn test_typed_broadcasts() {
+        #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+        struct Typing {
+            user: String,
+            typing: bool,
+        }
+
+        let (tx, mut sent) = futures::channel::mpsc::channel(16);
+        let socket_client = RealtimeSocketClient {
+            tx,
+            state: Arc::new(SocketState::default()),
+            channel_capacity: 16,
+            backpressure: Backpressure::Await,
+        };
+        let (_channel, mut client) = socket_client.channel("room");
+        let typing = Typing {
+            user: "alice".to_owned(),
+            typing: true,
+        };
+        client.broadcast_typed("typing", &typing).await.unwrap();
+        client
+            .broadcast_typed("message", &simd_json::json!({"text": "hi"}))
+            .await
+            .unwrap();
+        drop(client);
+        drop(socket_client);
+
+        // echo the sent broadcasts back, as the server does with `self: true`
+        let received = typed_broadcasts::<Typing>((&mut sent).map(Ok), "typing")
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received.into_iter().next().unwrap().unwrap(), typing);
+    }
+
+    #[test(tokio::test)]
+    async fn test_private_channel_join() {
+        let (tx, mut sent) = futures::channel::mpsc::channel(16);
+        let socket_client = RealtimeSocketClient {
+            tx,
+            state: Arc::new(SocketState::default()),
+            channel_capacity: 16,
+            backpressure: Backpressure::Await,
+        };
+        let (_channel, mut client) = socket_client.private_channel("admins");
+        let _reply = client
+            .subscribe_to_changes(join(false, vec![]))
+            .await
+            .unwrap();
+
+        let join = sent.next().await.unwrap();
+        let ProtocolPayload::PhxJoin(join_payload) = &join.payload else {
+            panic!("expected a join, got {join:?}");
+        };
+        assert!(join_payload.config.private);
+        let json = simd_json::to_string(&join).unwrap();
+        assert!(json.contains(r#""private":true"#), "{json}");
+    }
+
+    #[test(tokio::test)]
+    async fn test_set_auth() {
+        let (tx, mut sent) = futures::channel::mpsc::channel(16);
+        let state = Arc::new(SocketState::default());
+        let socket_client = RealtimeSocketClient {
+            tx,
+            state: Arc::clone(&state),
+            channel_capacity: 16,
+            backpressure: Backpressure::Await,
+        };
+        let (_channel, mut client) = socket_client.channel("room");
+        let (_lobby, _lobby_client) = socket_client.channel("lobby");
+        client.set_auth("imported-token").await.unwrap();
+
+        // every channel of the socket is re-authorized
+        let mut topics = Vec::new();
+        for _ in 0..2 {
+            let sent = sent.next().await.unwrap();
+            assert_eq!(
+                sent.payload,
+                ProtocolPayload::AccessToken(AccessToken {
+                    access_token: "imported-token".to_owned(),
+                })
+            );
+            topics.push(sent.topic);
+        }
+        topics.sort_unstable();
+        assert_eq!(topics, vec!["realtime:lobby", "realtime:room"]);
+        // adopted by the messages that follow, so the auth stream does not overwrite it
+        assert_eq!(
+            state
+                .refreshed_token
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_deref(),
+            Some("imported-token")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_join_reply_is_matched_by_ref() {
+        let (output_tx, output_rx) = futures::channel::mpsc::unbounded();
+        let (tx, mut sent) = futures::channel::mpsc::channel(16);
+        let state = Arc::new(SocketState::default());
+        let mut socket = RealtimeSocket {
+            output: output_rx.boxed(),
+            state: Arc::clone(&state),
+            undelivered: None,
+        };
+        let socket_client = RealtimeSocketClient {
+            tx,
+            state: Arc::clone(&state),
+            channel_capacity: 16,
+            backpressure: Backpressure::Await,
+        };
+        let (_channel, mut client) = socket_client.channel("test");
+        let join = join(false, vec![]);
+
+        let accepted = client.subscribe_to_changes(join.clone()).await.unwrap();
+        let rejected = client.subscribe_to_changes(join).await.unwrap();
+        let accepted_ref = sent.next().await.unwrap().ref_field;
+        let rejected_ref = sent.next().await.unwrap().ref_field;
+        assert_ne!(accepted_ref, rejected_ref);
+
+        let reply = |ref_field, reply| ProtocolMessage {
+            ref_field,
+            ..message(ProtocolPayload::PhxReply(reply))
+        };
+        let changes = phx_reply::PhxReplyQuery {
+            postgres_changes: vec![phx_reply::PostgresChanges {
+                event: phx_reply::PostgresChangetEvent::All,
+                schema: "public".to_owned(),
+                table: "messages".to_owned(),
+                filter: None,
+                id: 42,
+            }],
+        };
+        // as the connection task does while reading
+        let replies = [
+            reply(
+                rejected_ref,
+                phx_reply::PhxReply::Error(phx_reply::ErrorReply {
+                    reason: "Invalid JWT".to_owned(),
+                }),
+            ),
+            reply(accepted_ref, phx_reply::PhxReply::Ok(changes.clone())),
+        ];
+        for reply in replies {
+            state.replies.resolve(&reply);
+            output_tx.unbounded_send(Ok(reply)).unwrap();
+        }
+        drop(output_tx);
+        while socket.next().await.is_some() {}
+
+        assert_eq!(accepted.await.unwrap(), changes);
+        assert_eq!(
+            rejected.await.unwrap_err(),
+            JoinError::Rejected {
+                reason: "Invalid JWT".to_owned()
+            }
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_writes_do_not_wait_for_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let broadcast = message(ProtocolPayload::Broadcast(broadcast::Broadcast {
+            r#type: "broadcast".to_owned(),
+            event: "ping".to_owned(),
+            payload: simd_json::json!({}),
+        }));
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            ws.set_auto_pong(false);
+            // the reader answers pings through the writer
+            ws.write_frame(Frame::new(
+                true,
+                OpCode::Ping,
+                None,
+                Payload::Owned(b"hi".to_vec()),
+            ))
+            .await
+            .unwrap();
+            let mut pongs = Vec::new();
+            let mut received = Vec::new();
+            // the server stays silent, yet sends go through
+            while pongs.is_empty() || received.len() < 2 {
+                let mut frame = ws.read_frame().await.unwrap();
+                match frame.opcode {
+                    OpCode::Pong => pongs.push(frame.payload.to_vec()),
+                    _ => received.push(
+                        simd_json::from_slice::<ProtocolMessage>(frame.payload.to_mut()).unwrap(),
+                    ),
+                }
+            }
+            (ws, pongs, received)
+        });
+
+        let (mut tx, rx) = futures::channel::mpsc::unbounded();
+        let mut stream = Box::pin(RealtimeBaseConnection::new(url).connect(rx).await.unwrap());
+        tx.send(Ok(broadcast.clone())).await.unwrap();
+        tx.send(Ok(broadcast.clone())).await.unwrap();
+        let (_ws, pongs, received) = tokio::select! {
+            server = server => server.unwrap(),
+            _ = stream.next() => panic!("the stream ended"),
+        };
+        assert_eq!(pongs, vec![b"hi".to_vec()]);
+        assert_eq!(received, vec![broadcast.clone(), broadcast]);
+    }
+
+    fn join_message(join_ref: &str) -> ProtocolMessage {
+        ProtocolMessage {
+            ref_field: Some(join_ref.to_owned()),
+            ..message(ProtocolPayload::PhxJoin(join(false, vec![])))
+        }
+    }
+
+    fn broadcast_message(event: &str) -> ProtocolMessage {
+        message(ProtocolPayload::Broadcast(broadcast::Broadcast {
+            r#type: "broadcast".to_owned(),
+            event: event.to_owned(),
+            payload: simd_json::json!({}),
+        }))
+    }
+
+    async fn write_reply_ok(ws: &mut WebSocket<TcpStream>, ref_field: Option<String>) {
+        let reply = ProtocolMessage {
+            ref_field,
+            ..message(ProtocolPayload::PhxReply(phx_reply::PhxReply::Ok(
+                phx_reply::PhxReplyQuery {
+                    postgres_changes: vec![],
+                },
+            )))
+        };
+        ws.write_frame(Frame::text(Payload::Owned(
+            simd_json::to_vec(&reply).unwrap(),
+        )))
+        .await
+        .unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_messages_wait_for_join_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let mut server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let join = read_message(&mut ws).await;
+            let early = tokio::time::timeout(Duration::from_millis(200), ws.read_frame()).await;
+            assert!(early.is_err(), "sent before the join was acknowledged");
+            write_reply_ok(&mut ws, join.ref_field).await;
+            let first = read_message(&mut ws).await;
+            let second = read_message(&mut ws).await;
+            (ws, first, second)
+        });
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok(join_message("1"))).unwrap();
+        tx.unbounded_send(Ok(broadcast_message("first"))).unwrap();
+        tx.unbounded_send(Ok(broadcast_message("second"))).unwrap();
+        let mut stream = Box::pin(RealtimeBaseConnection::new(url).connect(rx).await.unwrap());
+        let (_ws, first, second) = loop {
+            tokio::select! {
+                res = &mut server => break res.unwrap(),
+                _item = stream.next() => {}
+            }
+        };
+        assert_eq!(first, broadcast_message("first"));
+        assert_eq!(second, broadcast_message("second"));
+    }
+
+    #[test(tokio::test)]
+    async fn test_messages_sent_while_reconnecting_are_replayed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let (dropped_tx, dropped_rx) = futures::channel::oneshot::channel();
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let join = read_message(&mut ws).await;
+            write_reply_ok(&mut ws, join.ref_field).await;
+            drop(ws);
+            dropped_tx.send(()).unwrap();
+
+            let mut ws = accept(&listener).await;
+            let rejoin = read_message(&mut ws).await;
+            write_reply_ok(&mut ws, rejoin.ref_field.clone()).await;
+            let replayed = read_message(&mut ws).await;
+            (ws, rejoin, replayed)
+        });
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok(join_message("1"))).unwrap();
+        let stream = RealtimeBaseConnection::new(url)
+            .with_reconnect(ReconnectPolicy {
+                max_attempts: 1,
+                initial_backoff: Duration::from_millis(300),
+                max_backoff: Duration::from_millis(300),
+                jitter_percent: 0,
+            })
+            .connect(rx)
+            .await
+            .unwrap();
+        let driver = tokio::spawn(async move {
+            let mut stream = core::pin::pin!(stream);
+            while stream.next().await.is_some() {}
+        });
+
+        dropped_rx.await.unwrap();
+        // sent during the reconnect backoff
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.unbounded_send(Ok(broadcast_message("offline"))).unwrap();
+
+        let (_ws, rejoin, replayed) = server.await.unwrap();
+        driver.abort();
+        assert_eq!(rejoin, join_message("1"));
+        assert_eq!(replayed, broadcast_message("offline"));
+    }
+
+    #[test]
+    fn test_send_buffer_overflow() {
+        let buffered = |overflow| {
+            let policy = SendBufferPolicy {
+                capacity: 2,
+                overflow,
+            };
+            let mut buffer = VecDeque::new();
+            let results = ["1", "2", "3"]
+                .map(|event| buffer_message(&mut buffer, policy, broadcast_message(event)).is_ok());
+            (buffer.into_iter().collect::<Vec<_>>(), results)
+        };
+
+        assert_eq!(
+            buffered(BufferOverflow::DropOldest),
+            (
+                vec![broadcast_message("2"), broadcast_message("3")],
+                [true, true, true]
+            )
+        );
+        assert_eq!(
+            buffered(BufferOverflow::DropNewest),
+            (
+                vec![broadcast_message("1"), broadcast_message("2")],
+                [true, true, true]
+            )
+        );
+        assert_eq!(
+            buffered(BufferOverflow::Error),
+            (
+                vec![broadcast_message("1"), broadcast_message("2")],
+                [true, true, false]
+            )
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_lagging_channel_applies_backpressure() {
+        let (output_tx, output_rx) = futures::channel::mpsc::unbounded();
+        let (tx, _sent) = futures::channel::mpsc::channel(16);
+        let state = Arc::new(SocketState::default());
+        let mut socket = RealtimeSocket {
+            output: output_rx.boxed(),
+            state: Arc::clone(&state),
+            undelivered: None,
+        };
+        let socket_client = RealtimeSocketClient {
+            tx,
+            state,
+            channel_capacity: 1,
+            backpressure: Backpressure::Await,
+        };
+        let (mut channel, _client) = socket_client.channel("test");
+
+        output_tx
+            .unbounded_send(Ok(broadcast_message("1")))
+            .unwrap();
+        output_tx
+            .unbounded_send(Ok(broadcast_message("2")))
+            .unwrap();
+        let heartbeat_reply = ProtocolMessage {
+            topic: PHOENIX_TOPIC.to_owned(),
+            ..message(ProtocolPayload::PhxClose(PhxClose {}))
+        };
+        output_tx
+            .unbounded_send(Ok(heartbeat_reply.clone()))
+            .unwrap();
+
+        // the second message waits for the channel to catch up
+        assert!(futures::poll!(socket.next()).is_pending());
+        assert_eq!(
+            channel.next().await.unwrap().unwrap(),
+            broadcast_message("1")
+        );
+        assert_eq!(socket.next().await.unwrap().unwrap(), heartbeat_reply);
+        assert_eq!(
+            channel.next().await.unwrap().unwrap(),
+            broadcast_message("2")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_custom_tls_config() {
+        use rustls::pki_types::pem::PemObject as _;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+        let cert =
+            CertificateDer::from_pem_slice(include_bytes!("../fixtures/localhost.crt")).unwrap();
+        let key =
+            PrivateKeyDer::from_pem_slice(include_bytes!("../fixtures/localhost.key")).unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "wss://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = handshake(acceptor.accept(stream).await.unwrap()).await;
+            (read_message(&mut ws).await, ws)
+        });
+
+        // the self-signed certificate is only trusted by the injected config
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok(broadcast_message("secure"))).unwrap();
+        let mut stream = Box::pin(
+            RealtimeBaseConnection::new(url)
+                .with_tls_config(Arc::new(client_config))
+                .connect(rx)
+                .await
+                .unwrap(),
+        );
+        let received = tokio::select! {
+            received = server => received.unwrap().0,
+            _ = stream.next() => panic!("the stream ended"),
+        };
+        assert_eq!(received, broadcast_message("secure"));
+    }
+
+    #[test(tokio::test)]
+    async fn test_change_handlers() {
+        use std::sync::Mutex;
+
+        use crate::message::postgres_changes::{
+            Buffer, Data, PostgresChangesPayload, PostgresDataChangeEvent,
+        };
+
+        #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+        struct Profile {
+            id: String,
+            #[serde(default)]
+            url: Option<String>,
+        }
+
+        fn change(
+            type_: PostgresDataChangeEvent,
+            record: Option<&str>,
+            old_record: Option<&str>,
+        ) -> RealtimeStreamType {
+            let buffer = |record: &str| Buffer::from(record.as_bytes().to_vec());
+            Ok(message(ProtocolPayload::PostgresChanges(
+                PostgresChangesPayload {
+                    data: Data {
+                        columns: vec![],
+                        commit_timestamp: "2024-08-25T17:00:19.009Z".to_owned(),
+                        errors: None,
+                        old_record: old_record.map(buffer),
+                        record: record.map(buffer),
+                        schema: "public".to_owned(),
+                        table: "profiles".to_owned(),
+                        type_,
+                    },
+                    ids: vec![1],
+                },
+            )))
+        }
+
+        let stream = futures::stream::iter([
+            change(
+                PostgresDataChangeEvent::Insert,
+                Some(r#"{"id": "1"}"#),
+                None,
+            ),
+            Ok(broadcast_message("ignored")),
+            change(
+                PostgresDataChangeEvent::Update,
+                Some(r#"{"id": "1", "url": "https://example.com"}"#),
+                Some(r#"{"id": "1"}"#),
+            ),
+            change(PostgresDataChangeEvent::Insert, Some(r#"{"url": 1}"#), None),
+            change(
+                PostgresDataChangeEvent::Delete,
+                None,
+                Some(r#"{"id": "1"}"#),
+            ),
+            Err(SupabaseRealtimeError::MpscSendError),
+        ]);
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (inserts, updates, deletes, errors) = (
+            Arc::clone(&calls),
+            Arc::clone(&calls),
+            Arc::clone(&calls),
+            Arc::clone(&calls),
+        );
+        ChangeHandlers::<Profile>::new()
+            .on_insert(move |new| inserts.lock().unwrap().push(format!("insert {new:?}")))
+            .on_update(move |old, new| {
+                updates
+                    .lock()
+                    .unwrap()
+                    .push(format!("update {old:?} -> {new:?}"));
+            })
+            .on_delete(move |old| deletes.lock().unwrap().push(format!("delete {old:?}")))
+            .on_error(move |_err| errors.lock().unwrap().push("error".to_owned()))
+            .dispatch(stream)
+            .await;
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                r#"insert Profile { id: "1", url: None }"#.to_owned(),
+                r#"update Some(Profile { id: "1", url: None }) -> Profile { id: "1", url: Some("https://example.com") }"#.to_owned(),
+                "error".to_owned(),
+                r#"delete Profile { id: "1", url: None }"#.to_owned(),
+                "error".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_serializer_sets_vsn() {
+        let url =
+            url::Url::parse("wss://project.supabase.co/realtime/v1/websocket?apikey=key&vsn=1.0.0")
+                .unwrap();
+        let base = RealtimeBaseConnection::new(url).with_serializer(Serializer::V2);
+        assert_eq!(base.url.query(), Some("apikey=key&vsn=2.0.0"));
+        assert_eq!(base.serializer, Serializer::V2);
+    }
+
+    #[test(tokio::test)]
+    async fn test_connection_resolves_pending_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let answered = read_message(&mut ws).await;
+            let _unanswered = read_message(&mut ws).await;
+            write_reply_ok(&mut ws, answered.ref_field).await;
+            // the connection drops without answering the second message
+        });
+
+        let replies = PendingReplies::default();
+        let answered = replies.register("1".to_owned());
+        let unanswered = replies.register("2".to_owned());
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        for reply_ref in ["1", "2"] {
+            tx.unbounded_send(Ok(ProtocolMessage {
+                ref_field: Some(reply_ref.to_owned()),
+                ..broadcast_message("ping")
+            }))
+            .unwrap();
+        }
+        let stream = RealtimeBaseConnection::new(url)
+            .with_pending_replies(replies)
+            .connect(rx)
+            .await
+            .unwrap();
+        let drive = tokio::spawn(stream.for_each(|_| async {}));
+
+        assert!(matches!(answered.await, Ok(phx_reply::PhxReply::Ok(_))));
+        assert!(matches!(
+            unanswered.await,
+            Err(SupabaseRealtimeError::NoReply)
+        ));
+        server.await.unwrap();
+        drive.await.unwrap();
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test(tokio::test)]
+    async fn test_metrics_are_recorded() {
+        use std::sync::Mutex;
+
+        #[derive(Debug, Default)]
+        struct Recorder {
+            received: Mutex<Vec<usize>>,
+            sent: Mutex<Vec<usize>>,
+            failed_joins: Mutex<Vec<String>>,
+            heartbeat_rtts: Mutex<Vec<Duration>>,
+        }
+
+        impl MetricsRecorder for Recorder {
+            fn frame_received(&self, bytes: usize) {
+                self.received.lock().unwrap().push(bytes);
+            }
+
+            fn frame_sent(&self, bytes: usize) {
+                self.sent.lock().unwrap().push(bytes);
+            }
+
+            fn join_failed(&self, topic: &str) {
+                self.failed_joins.lock().unwrap().push(topic.to_owned());
+            }
+
+            fn heartbeat_rtt(&self, rtt: Duration) {
+                self.heartbeat_rtts.lock().unwrap().push(rtt);
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let join = ProtocolMessage {
+            ref_field: Some("1".to_owned()),
+            ..message(ProtocolPayload::PhxJoin(join(false, vec![])))
+        };
+        let heartbeat = ProtocolMessage {
+            topic: PHOENIX_TOPIC.to_owned(),
+            payload: ProtocolPayload::Heartbeat(crate::message::heartbeat::Heartbeat),
+            ref_field: Some("2".to_owned()),
+            join_ref: None,
+        };
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let join = read_message(&mut ws).await;
+            let rejected = ProtocolMessage {
+                ref_field: join.ref_field,
+                ..message(ProtocolPayload::PhxReply(phx_reply::PhxReply::Error(
+                    phx_reply::ErrorReply {
+                        reason: "Unauthorized".to_owned(),
+                    },
+                )))
+            };
+            ws.write_frame(Frame::text(Payload::Owned(
+                simd_json::to_vec(&rejected).unwrap(),
+            )))
+            .await
+            .unwrap();
+            let heartbeat = read_message(&mut ws).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let reply = ProtocolMessage {
+                topic: PHOENIX_TOPIC.to_owned(),
+                payload: ProtocolPayload::PhxReply(phx_reply::PhxReply::Ok(
+                    phx_reply::PhxReplyQuery {
+                        postgres_changes: vec![],
+                    },
+                )),
+                ref_field: heartbeat.ref_field,
+                join_ref: None,
+            };
+            ws.write_frame(Frame::text(Payload::Owned(
+                simd_json::to_vec(&reply).unwrap(),
+            )))
+            .await
+            .unwrap();
+            ws
+        });
+
+        let recorder = Arc::new(Recorder::default());
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok(join.clone())).unwrap();
+        tx.unbounded_send(Ok(heartbeat.clone())).unwrap();
+        let stream = RealtimeBaseConnection::new(url)
+            .with_metrics(Arc::clone(&recorder) as Arc<dyn MetricsRecorder>)
+            .connect(rx)
+            .await
+            .unwrap();
+        let received = stream.take(2).collect::<Vec<_>>().await;
+        let _ws = server.await.unwrap();
+
+        assert!(received.iter().all(Result::is_ok), "{received:?}");
+        assert_eq!(recorder.received.lock().unwrap().len(), 2);
+        assert_eq!(
+            *recorder.sent.lock().unwrap(),
+            [&join, &heartbeat]
+                .map(|message| simd_json::to_vec(message).unwrap().len())
+                .to_vec()
+        );
+        assert_eq!(*recorder.failed_joins.lock().unwrap(), [TOPIC.to_owned()]);
+        let rtts = recorder.heartbeat_rtts.lock().unwrap();
+        assert_eq!(rtts.len(), 1);
+        assert!(rtts[0] >= Duration::from_millis(50), "{rtts:?}");
+    }
+
+    #[test(tokio::test)]
+    async fn test_ending_input_closes_gracefully() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            ws.set_auto_close(false);
+            let first = read_message(&mut ws).await;
+            let second = read_message(&mut ws).await;
+            let close = ws.read_frame().await.unwrap();
+            (first, second, close.opcode)
+        });
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        for event in ["first", "second"] {
+            tx.unbounded_send(Ok(broadcast_message(event))).unwrap();
+        }
+        drop(tx);
+        let stream = RealtimeBaseConnection::new(url).connect(rx).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), stream.collect::<Vec<_>>())
+            .await
+            .unwrap();
+        assert_eq!(received.len(), 0);
+
+        let (first, second, close) = server.await.unwrap();
+        assert_eq!(first, broadcast_message("first"));
+        assert_eq!(second, broadcast_message("second"));
+        assert_eq!(close, OpCode::Close);
+    }
+
+    #[test(tokio::test)]
+    async fn test_client_close_ends_the_socket() {
+        let (output_tx, output_rx) = futures::channel::mpsc::unbounded();
+        let (tx, mut sent) = futures::channel::mpsc::channel(16);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let state = Arc::new(SocketState {
+            shutdown: std::sync::Mutex::new(Some(shutdown_tx)),
+            ..SocketState::default()
+        });
+        let mut socket = RealtimeSocket {
+            output: output_rx.boxed(),
+            state: Arc::clone(&state),
+            undelivered: None,
+        };
+        let socket_client = RealtimeSocketClient {
+            tx,
+            state,
+            channel_capacity: 16,
+            backpressure: Backpressure::Await,
+        };
+        let (mut channel, mut client) = socket_client.channel("room");
+        client
+            .broadcast(broadcast::Broadcast {
+                event: "message".to_owned(),
+                payload: simd_json::json!({}),
+                r#type: "broadcast".to_owned(),
+            })
+            .await
+            .unwrap();
+        socket_client.close();
+
+        // what was sent before closing is still delivered, then the input ends
+        assert_eq!(sent.next().await.unwrap().topic, "realtime:room");
+        assert!(sent.next().await.is_none());
+        shutdown_rx.await.unwrap();
+        assert!(client
+            .broadcast(broadcast::Broadcast {
+                event: "message".to_owned(),
+                payload: simd_json::json!({}),
+                r#type: "broadcast".to_owned(),
+            })
+            .await
+            .is_err());
+
+        // the channels end with the socket
+        drop(output_tx);
+        assert!(socket.next().await.is_none());
+        assert!(channel.next().await.is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn test_heartbeat_latency_is_measured() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let heartbeat = read_message(&mut ws).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let reply = ProtocolMessage {
+                topic: PHOENIX_TOPIC.to_owned(),
+                ref_field: heartbeat.ref_field,
+                ..message(ProtocolPayload::PhxReply(phx_reply::PhxReply::Ok(
+                    phx_reply::PhxReplyQuery {
+                        postgres_changes: vec![],
+                    },
+                )))
+            };
+            ws.write_frame(Frame::text(Payload::Owned(
+                simd_json::to_vec(&reply).unwrap(),
+            )))
+            .await
+            .unwrap();
+            ws
+        });
+
+        let latency = HeartbeatLatency::default();
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok(ProtocolMessage {
+            topic: PHOENIX_TOPIC.to_owned(),
+            payload: ProtocolPayload::Heartbeat(crate::message::heartbeat::Heartbeat),
+            ref_field: Some("1".to_owned()),
+            join_ref: None,
+        }))
+        .unwrap();
+        let mut stream = Box::pin(
+            RealtimeBaseConnection::new(url)
+                .with_heartbeat_latency(latency.clone())
+                .connect(rx)
+                .await
+                .unwrap(),
+        );
+        stream.next().await.unwrap().unwrap();
+        let _ws = server.await.unwrap();
+
+        assert_eq!(latency.samples(), 1);
+        let rtt = latency.latest().unwrap();
+        assert!(rtt >= Duration::from_millis(50), "{rtt:?}");
+        assert_eq!(latency.smoothed(), Some(rtt));
+    }
+
+    #[test(tokio::test)]
+    async fn test_change_router() {
+        use crate::message::phx_join::PostgresChangetEvent;
+        use crate::message::postgres_changes::{Buffer, Data, PostgresDataChangeEvent};
+
+        #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+        struct Profile {
+            id: String,
+        }
+
+        #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+        struct Todo {
+            title: String,
+        }
+
+        fn change(table: &str, record: &str, ids: Vec<i64>) -> RealtimeStreamType {
+            Ok(message(ProtocolPayload::PostgresChanges(
+                PostgresChangesPayload {
+                    data: Data {
+                        columns: vec![],
+                        commit_timestamp: "2024-08-25T17:00:19.009Z".to_owned(),
+                        errors: None,
+                        old_record: None,
+                        record: Some(Buffer::from(record.as_bytes().to_vec())),
+                        schema: "public".to_owned(),
+                        table: table.to_owned(),
+                        type_: PostgresDataChangeEvent::Insert,
+                    },
+                    ids,
+                },
+            )))
+        }
+
+        let subscription = |table: &str, id| phx_reply::PostgresChanges {
+            event: PostgresChangetEvent::Insert,
+            schema: "public".to_owned(),
+            table: table.to_owned(),
+            filter: None,
+            id,
+        };
+        let mut router = ChangeRouter::new(&phx_reply::PhxReplyQuery {
+            postgres_changes: vec![subscription("profiles", 10), subscription("todos", 11)],
+        });
+        assert!(router.subscription::<Profile>(2).is_none());
+        let profiles = router.subscription::<Profile>(0).unwrap();
+        let todos = router.subscription::<Todo>(1).unwrap();
+
+        let stream = futures::stream::iter([
+            change("profiles", r#"{"id": "1"}"#, vec![10]),
+            change("todos", r#"{"title": "buy milk"}"#, vec![11]),
+            Ok(broadcast_message("passed-through")),
+            change("other", r#"{}"#, vec![12]),
+        ]);
+        let rest = router.route(stream).collect::<Vec<_>>().await;
+        assert_eq!(rest.len(), 2);
+        assert_eq!(
+            rest[0].as_ref().unwrap(),
+            &broadcast_message("passed-through")
+        );
+        assert!(matches!(
+            &rest[1],
+            Ok(ProtocolMessage {
+                payload: ProtocolPayload::PostgresChanges(changes),
+                ..
+            }) if changes.ids == [12]
+        ));
+
+        let profiles = profiles
+            .map(|change| change.unwrap().data.record.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(profiles, [Profile { id: "1".to_owned() }]);
+        let todos = todos
+            .map(|change| change.unwrap().data.record.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            todos,
+            [Todo {
+                title: "buy milk".to_owned()
+            }]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_fragmented_messages_are_capped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let message = simd_json::to_vec(&broadcast_message("fragmented")).unwrap();
+        let max_message_size = message.len();
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let (head, tail) = message.split_at(message.len() / 2);
+            for (fin, opcode, payload) in [
+                (false, OpCode::Text, head),
+                (true, OpCode::Continuation, tail),
+                // one byte over the limit, spread over several frames
+                (false, OpCode::Text, head),
+                (false, OpCode::Continuation, tail),
+                (true, OpCode::Continuation, b" "),
+            ] {
+                ws.write_frame(Frame::new(
+                    fin,
+                    opcode,
+                    None,
+                    Payload::Owned(payload.to_vec()),
+                ))
+                .await
+                .unwrap();
+            }
+            ws
+        });
+
+        let (_tx, rx) = futures::channel::mpsc::unbounded();
+        let stream = RealtimeBaseConnection::new(url)
+            .with_max_message_size(max_message_size)
+            .connect(rx)
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), stream.collect::<Vec<_>>())
+            .await
+            .unwrap();
+        let _ws = server.await.unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            received[0].as_ref().unwrap(),
+            &broadcast_message("fragmented")
+        );
+    }
+
+    #[test]
+    fn test_connection_builder() {
+        let config = rp_supabase_auth::jwt_stream::SupabaseAuthConfig::builder()
+            .api_key("api-key".to_owned())
+            .max_reconnect_attempts(1)
+            .reconnect_interval(Duration::from_secs(1))
+            .url(url::Url::parse("http://localhost:54321").unwrap())
+            .build();
+        let heartbeat = HeartbeatOptions {
+            interval: Duration::from_secs(5),
+            timeout: None,
+            allowed_missed: 2,
+        };
+        let buffers = BufferOptions {
+            channel_capacity: 16,
+            backpressure: Backpressure::DropOldest,
+            ..BufferOptions::default()
+        };
+        let builder = RealtimeConnection::builder(config.clone())
+            .with_heartbeat(heartbeat)
+            .with_buffers(buffers)
+            .with_reconnect(ReconnectPolicy::none())
+            .with_private(true);
+
+        let rooms = builder.channel("room-1");
+        let presence = builder.channel("lobby");
+        assert_eq!(rooms.topic, "realtime:room-1");
+        assert_eq!(presence.topic, "realtime:lobby");
+        for connection in [rooms, presence] {
+            assert_eq!(connection.config, config);
+            assert!(connection.private);
+            let options = connection.options;
+            assert_eq!(
+                (
+                    options.heartbeat_interval,
+                    options.heartbeat_timeout,
+                    options.allowed_missed_heartbeats
+                ),
+                (Duration::from_secs(5), None, 2)
+            );
+            assert_eq!(
+                (options.channel_capacity, options.backpressure),
+                (16, Backpressure::DropOldest)
+            );
+            assert_eq!(options.send_buffer, SendBufferPolicy::default());
+            assert_eq!(options.reconnect, Some(ReconnectPolicy::none()));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_connect_with_static_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = test_config(
+            url::Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap(),
+        );
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let join = read_message(&mut ws).await;
+            (ws, join)
+        });
+
+        let (socket, socket_client) =
+            RealtimeSocket::connect_with_token(config, "anon-key", SocketOptions::default())
+                .await
+                .unwrap();
+        let drive = tokio::spawn(socket.for_each(|_| async {}));
+        let (_channel, mut client) = socket_client.channel("public-room");
+        let _reply = client
+            .subscribe_to_changes(join(false, vec![]))
+            .await
+            .unwrap();
+
+        let (_ws, join) = server.await.unwrap();
+        assert_eq!(join.topic, "realtime:public-room");
+        let ProtocolPayload::PhxJoin(join) = join.payload else {
+            panic!("expected a join");
+        };
+        assert_eq!(join.access_token.as_deref(), Some("anon-key"));
+        drive.abort();
+    }
+
+    #[test(tokio::test)]
+    async fn test_join_ref_is_stable_per_channel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = test_config(
+            url::Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap(),
+        );
+        let server = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            let join = read_message(&mut ws).await;
+            write_reply_ok(&mut ws, join.ref_field.clone()).await;
+            let mut sent = vec![join];
+            for _ in 0..3 {
+                sent.push(read_message(&mut ws).await);
+            }
+            (ws, sent)
+        });
+
+        let (socket, socket_client) =
+            RealtimeSocket::connect_with_token(config, "anon-key", SocketOptions::default())
+                .await
+                .unwrap();
+        let drive = tokio::spawn(socket.for_each(|_| async {}));
+        let (_channel, mut client) = socket_client.channel("test");
+        let joined = client
+            .subscribe_to_changes(join(false, vec![]))
+            .await
+            .unwrap();
+        joined.await.unwrap();
+        client.broadcast_typed("first", &1).await.unwrap();
+        client.broadcast_typed("second", &2).await.unwrap();
+        client.leave().await.unwrap();
+
+        let (_ws, sent) = server.await.unwrap();
+        let join_ref = sent[0].ref_field.clone();
+        assert!(join_ref.is_some());
+        assert_eq!(
+            sent.iter()
+                .map(|message| message.join_ref.clone())
+                .collect::<Vec<_>>(),
+            vec![join_ref; 4]
+        );
+        // only `ref` changes from message to message
+        let refs = sent
+            .iter()
+            .map(|message| message.ref_field.clone())
+            .collect::<HashSet<_>>();
+        assert_eq!(refs.len(), 4);
+        drive.abort();
+    }
+
+    #[test(tokio::test)]
+    async fn test_broadcast_ack_is_matched_by_ref() {
+        let (socket_client, mut connection, drive) =
+            socket_over_memory(SocketOptions::default()).await;
+
+        let (_channel, mut client) = socket_client.channel("test");
+        let joined = client
+            .subscribe_to_changes(join(true, vec![]))
+            .await
+            .unwrap();
+        let join = connection.recv_message().await.unwrap().unwrap();
+        connection.send_message(&reply_ok(&join)).unwrap();
+        joined.await.unwrap();
+
+        let broadcast = |event: &str| broadcast::Broadcast {
+            r#type: "broadcast".to_owned(),
+            event: event.to_owned(),
+            payload: simd_json::json!({}),
+        };
+        let first = client.broadcast_with_ack(broadcast("first")).await.unwrap();
+        let second = client
+            .broadcast_with_ack(broadcast("second"))
+            .await
+            .unwrap();
+        let first_sent = connection.recv_message().await.unwrap().unwrap();
+        let second_sent = connection.recv_message().await.unwrap().unwrap();
+        assert_ne!(first_sent.ref_field, second_sent.ref_field);
+
+        // acknowledged out of order
+        let rejected = phx_reply::PhxReply::Error(phx_reply::ErrorReply {
+            reason: "RLS policy violation".to_owned(),
+        });
+        connection
+            .send_message(&reply(&second_sent, rejected))
+            .unwrap();
+        connection.send_message(&reply_ok(&first_sent)).unwrap();
+        assert_eq!(
+            second.await,
+            Err(AckError::Rejected {
+                reason: "RLS policy violation".to_owned(),
+            })
+        );
+        assert_eq!(first.await, Ok(()));
+        drive.abort();
+    }
+
+    #[test(tokio::test)]
+    async fn test_channel_is_rejoined_after_phx_error() {
+        let (socket_client, mut connection, drive) = socket_over_memory(SocketOptions {
+            rejoin: test_policy(3),
+            ..SocketOptions::default()
+        })
+        .await;
+
+        let (mut channel, mut client) = socket_client.channel("test");
+        let joined = client
+            .subscribe_to_changes(join(false, vec![]))
+            .await
+            .unwrap();
+        let join = connection.recv_message().await.unwrap().unwrap();
+        connection.send_message(&reply_ok(&join)).unwrap();
+        joined.await.unwrap();
+
+        connection
+            .send_message(&ProtocolMessage {
+                ref_field: join.ref_field.clone(),
+                join_ref: join.join_ref.clone(),
+                ..message(ProtocolPayload::PhxError(
+                    crate::message::phx_error::PhxError,
+                ))
+            })
+            .unwrap();
+        // the error follows the reply to the join
+        let replied = channel.next().await.unwrap().unwrap();
+        assert!(matches!(replied.payload, ProtocolPayload::PhxReply(_)));
+        let errored = channel.next().await.unwrap().unwrap();
+        assert_eq!(
+            errored.payload,
+            ProtocolPayload::PhxError(crate::message::phx_error::PhxError)
+        );
+        // held back until the channel is joined again
+        client.broadcast_typed("while-down", &1).await.unwrap();
+
+        // the first attempt is rejected, the second one succeeds
+        let rejoin = connection.recv_message().await.unwrap().unwrap();
+        assert_eq!(rejoin.payload, join.payload);
+        let rejected = phx_reply::PhxReply::Error(phx_reply::ErrorReply {
+            reason: "Realtime was unable to connect to the project database".to_owned(),
+        });
+        connection.send_message(&reply(&rejoin, rejected)).unwrap();
+        let rejoin = connection.recv_message().await.unwrap().unwrap();
+        assert_eq!(rejoin.payload, join.payload);
+        connection.send_message(&reply_ok(&rejoin)).unwrap();
+
+        let sent = connection.recv_message().await.unwrap().unwrap();
+        assert!(
+            matches!(&sent.payload, ProtocolPayload::Broadcast(broadcast) if broadcast.event == "while-down"),
+            "{sent:?}"
+        );
+        // the replies to the attempts come first
+        let rejoined = loop {
+            let item = channel.next().await.unwrap().unwrap();
+            if let ProtocolPayload::ChannelRejoined(rejoined) = item.payload {
+                break rejoined;
+            }
+        };
+        assert_eq!(rejoined, ChannelRejoined { attempts: 2 });
+        drive.abort();
+    }
+
+    #[test(tokio::test)]
+    async fn test_table_subscriptions_have_streams_of_their_own() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Record {
+            id: i32,
+        }
+
+        let (socket_client, mut connection, drive) =
+            socket_over_memory(SocketOptions::default()).await;
+        let table = |table: &str| phx_join::PostgrsChanges {
+            event: phx_join::PostgresChangetEvent::All,
+            schema: "public".to_owned(),
+            table: table.to_owned(),
+            filter: None,
+        };
+        let join_tables =
+            |tables: &[&str]| join(false, tables.iter().map(|name| table(name)).collect());
+        // the server assigns new ids on every join
+        let reply_with_ids = |request: &ProtocolMessage, ids: &[i32]| {
+            let ProtocolPayload::PhxJoin(join) = &request.payload else {
+                panic!("expected a join, got {request:?}");
+            };
+            let postgres_changes = join
+                .config
+                .postgres_changes
+                .iter()
+                .zip(ids)
+                .map(|(changes, &id)| phx_reply::PostgresChanges {
+                    event: changes.event.clone(),
+                    schema: changes.schema.clone(),
+                    table: changes.table.clone(),
+                    filter: None,
+                    id,
+                })
+                .collect();
+            reply(
+                request,
+                phx_reply::PhxReply::Ok(phx_reply::PhxReplyQuery { postgres_changes }),
+            )
+        };
+        let change = |table: &str, id: i32, ids: &[i64]| {
+            format!(
+                r#"{{"event": "postgres_changes", "topic": "realtime:db", "ref": null,
+                    "payload": {{"ids": {ids:?}, "data": {{"columns": [],
+                    "commit_timestamp": "2024-01-01T00:00:00Z", "errors": null,
+                    "record": {{"id": {id}}}, "schema": "public", "table": "{table}",
+                    "type": "INSERT"}}}}}}"#
+            )
+            .into_bytes()
+        };
+
+        let (mut channel, mut client) = socket_client.channel("db");
+        let (joined, mut profiles) = client
+            .subscribe_to_table::<Record>(join_tables(&["profiles"]))
+            .await
+            .unwrap();
+        let request = connection.recv_message().await.unwrap().unwrap();
+        connection
+            .send_message(&reply_with_ids(&request, &[11]))
+            .unwrap();
+        joined.await.unwrap();
+
+        let (joined, mut todos) = client
+            .subscribe_to_table::<Record>(join_tables(&["todos"]))
+            .await
+            .unwrap();
+        let request = connection.recv_message().await.unwrap().unwrap();
+        let ProtocolPayload::PhxJoin(rejoin) = &request.payload else {
+            panic!("expected a join, got {request:?}");
+        };
+        assert_eq!(rejoin.config, join_tables(&["profiles", "todos"]).config);
+        connection
+            .send_message(&reply_with_ids(&request, &[21, 22]))
+            .unwrap();
+        joined.await.unwrap();
+
+        connection.send(change("todos", 1, &[22])).unwrap();
+        connection.send(change("profiles", 2, &[21])).unwrap();
+        connection.send(change("audit", 3, &[99])).unwrap();
+
+        let todo = todos.next().await.unwrap().unwrap();
+        assert_eq!(todo.data.record, Some(Record { id: 1 }));
+        let profile = profiles.next().await.unwrap().unwrap();
+        assert_eq!(profile.data.record, Some(Record { id: 2 }));
+        // changes of no subscription stream stay on the channel
+        let unrouted = loop {
+            let item = channel.next().await.unwrap().unwrap();
+            if let ProtocolPayload::PostgresChanges(changes) = item.payload {
+                break changes;
+            }
+        };
+        assert_eq!(unrouted.ids, vec![99]);
+        drive.abort();
+    }
+
+    #[test(tokio::test)]
+    async fn test_one_join_combines_broadcast_presence_and_changes() {
+        let (socket_client, mut connection, drive) =
+            socket_over_memory(SocketOptions::default()).await;
+
+        let (channel, mut client) = socket_client.channel("room-1");
+        let presence = crate::presence::PresenceHandle::new();
+        let mut channel = Box::pin(presence.track(channel));
+        let mut request = join(
+            false,
+            vec![phx_join::PostgrsChanges {
+                event: phx_join::PostgresChangetEvent::All,
+                schema: "public".to_owned(),
+                table: "messages".to_owned(),
+                filter: None,
+            }],
+        );
+        request.config.presence.key = "user-1".to_owned();
+        let joined = client.subscribe_to_changes(request).await.unwrap();
+        let join = connection.recv_message().await.unwrap().unwrap();
+        connection.send_message(&reply_ok(&join)).unwrap();
+        joined.await.unwrap();
+
+        client
+            .broadcast(broadcast::Broadcast {
+                r#type: "broadcast".to_owned(),
+                event: "typing".to_owned(),
+                payload: simd_json::json!({}),
+            })
+            .await
+            .unwrap();
+        let sent = connection.recv_message().await.unwrap().unwrap();
+        assert_eq!(sent.topic, join.topic);
+        assert_eq!(sent.join_ref, join.join_ref);
+        assert!(matches!(sent.payload, ProtocolPayload::Broadcast(_)));
+
+        for frame in [
+            r#"{"event": "presence_state", "topic": "realtime:room-1", "ref": null,
+                "payload": {"user-1": {"metas": [{"phx_ref": "a", "name": "user-1", "t": 1.0}]}}}"#,
+            r#"{"event": "broadcast", "topic": "realtime:room-1", "ref": null,
+                "payload": {"type": "broadcast", "event": "typing", "payload": {}}}"#,
+            r#"{"event": "postgres_changes", "topic": "realtime:room-1", "ref": null,
+                "payload": {"ids": [1], "data": {"columns": [],
+                "commit_timestamp": "2024-01-01T00:00:00Z", "errors": null,
+                "record": {"id": 1}, "schema": "public", "table": "messages",
+                "type": "INSERT"}}}"#,
+        ] {
+            connection.send(frame.as_bytes().to_vec()).unwrap();
+        }
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let item = channel.next().await.unwrap().unwrap();
+            match item.payload {
+                ProtocolPayload::PresenceState(_) => received.push("presence_state"),
+                ProtocolPayload::Broadcast(_) => received.push("broadcast"),
+                ProtocolPayload::PostgresChanges(_) => received.push("postgres_changes"),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            received,
+            vec!["presence_state", "broadcast", "postgres_changes"]
+        );
+        assert!(presence.contains("user-1"));
+        drive.abort();
+    }
+
+    #[test(tokio::test)]
+    async fn test_connect_url_is_configurable() {
+        let (transport, mut server) = crate::transport::MemoryTransport::pair();
+        let config = test_config(url::Url::parse("https://gateway.example.com/supabase/").unwrap());
+        let builder = RealtimeConnection::builder(config)
+            .with_transport(Arc::new(transport))
+            .with_websocket_path("realtime/socket")
+            .with_query_param("log_level", "info")
+            .with_header(
+                HeaderName::from_static("x-gateway-token"),
+                HeaderValue::from_static("secret"),
+            );
+        assert_eq!(
+            builder.options.headers,
+            vec![(
+                HeaderName::from_static("x-gateway-token"),
+                HeaderValue::from_static("secret"),
+            )]
+        );
+        let (socket, _socket_client) = builder.connect_socket_with_token("anon-key").await.unwrap();
+        let drive = tokio::spawn(socket.for_each(|_| async {}));
+
+        let connection = server.accept().await.unwrap();
+        assert_eq!(connection.url().path(), "/supabase/realtime/socket");
+        assert_eq!(
+            connection.url().query(),
+            Some("apikey=anon-key&log_level=info&vsn=1.0.0")
+        );
+        drive.abort();
+    }
 }
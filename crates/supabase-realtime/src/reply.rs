@@ -0,0 +1,151 @@
+//! Matches `phx_reply` messages to the messages they answer, by `ref`.
+//!
+//! The connection task of a [`RealtimeBaseConnection`] resolves the replies as they are read,
+//! so any message sent with a `ref` can be awaited, see
+//! [`RealtimeConnectionClient::send_and_await`].
+//!
+//! [`RealtimeBaseConnection`]: crate::realtime::RealtimeBaseConnection
+//! [`RealtimeConnectionClient::send_and_await`]: crate::realtime::RealtimeConnectionClient::send_and_await
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{ready, Context, Poll};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use futures::channel::oneshot;
+use futures::FutureExt as _;
+
+use crate::error::SupabaseRealtimeError;
+use crate::message::{phx_reply, ProtocolMessage, ProtocolPayload};
+
+/// Messages awaiting their `phx_reply`, shared between a connection and its senders
+#[derive(Debug, Clone, Default)]
+pub struct PendingReplies {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    waiting: HashMap<String, oneshot::Sender<phx_reply::PhxReply>>,
+    /// The connection task is gone, nothing will be resolved anymore
+    closed: bool,
+}
+
+impl PendingReplies {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits for the reply to the message sent with `reply_ref`.
+    ///
+    /// Register before sending the message, so that a fast reply is not missed.
+    #[must_use]
+    pub fn register(&self, reply_ref: String) -> Reply {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.lock();
+        if !inner.closed {
+            inner.waiting.insert(reply_ref, tx);
+        }
+        Reply { reply: rx }
+    }
+
+    /// Stops waiting for the reply to `reply_ref`, e.g. because its message was never sent.
+    pub fn cancel(&self, reply_ref: &str) {
+        self.lock().waiting.remove(reply_ref);
+    }
+
+    /// Completes the [`Reply`] that `message` answers, if any.
+    pub(crate) fn resolve(&self, message: &ProtocolMessage) {
+        if let (ProtocolPayload::PhxReply(reply), Some(reply_ref)) =
+            (&message.payload, &message.ref_field)
+        {
+            let pending = self.lock().waiting.remove(reply_ref);
+            if let Some(pending) = pending {
+                // the reply may have been dropped
+                let _ignored = pending.send(reply.clone());
+            }
+        }
+    }
+
+    /// Fails the replies of the messages lost with a dropped connection, keeping the ones for
+    /// which `resent` holds.
+    pub(crate) fn connection_lost(&self, resent: impl Fn(&str) -> bool) {
+        self.lock().waiting.retain(|reply_ref, _| resent(reply_ref));
+    }
+
+    /// Fails every pending and future reply.
+    pub(crate) fn close(&self) {
+        let mut inner = self.lock();
+        inner.closed = true;
+        inner.waiting.clear();
+    }
+}
+
+/// Resolves to the `phx_reply` of a message.
+#[derive(Debug)]
+pub struct Reply {
+    reply: oneshot::Receiver<phx_reply::PhxReply>,
+}
+
+impl Future for Reply {
+    type Output = Result<phx_reply::PhxReply, SupabaseRealtimeError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let reply = ready!(self.reply.poll_unpin(cx));
+        Poll::Ready(reply.map_err(|_canceled| SupabaseRealtimeError::NoReply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker_ref;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn reply(reply_ref: &str) -> ProtocolMessage {
+        ProtocolMessage {
+            topic: "realtime:room-1".to_owned(),
+            payload: ProtocolPayload::PhxReply(phx_reply::PhxReply::Ok(phx_reply::PhxReplyQuery {
+                postgres_changes: vec![],
+            })),
+            ref_field: Some(reply_ref.to_owned()),
+            join_ref: None,
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_replies_are_matched_by_ref() {
+        let replies = PendingReplies::default();
+        let first = replies.register("1".to_owned());
+        let second = replies.register("2".to_owned());
+        let lost = replies.register("3".to_owned());
+
+        replies.resolve(&reply("2"));
+        replies.resolve(&reply("1"));
+        assert!(matches!(first.await, Ok(phx_reply::PhxReply::Ok(_))));
+        assert!(matches!(second.await, Ok(phx_reply::PhxReply::Ok(_))));
+
+        replies.connection_lost(|reply_ref| reply_ref != "3");
+        assert!(matches!(lost.await, Err(SupabaseRealtimeError::NoReply)));
+    }
+
+    #[test_log::test]
+    fn test_closed_registry_fails_replies() {
+        let replies = PendingReplies::default();
+        let mut pending = replies.register("1".to_owned());
+        replies.close();
+        let mut late = replies.register("2".to_owned());
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        for reply in [&mut pending, &mut late] {
+            assert!(matches!(
+                reply.poll_unpin(&mut cx),
+                Poll::Ready(Err(SupabaseRealtimeError::NoReply))
+            ));
+        }
+        assert_eq!(replies.lock().waiting.len(), 0);
+    }
+}
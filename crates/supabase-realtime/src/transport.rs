@@ -0,0 +1,70 @@
+//! The websocket library behind a [`RealtimeBaseConnection`].
+//!
+//! [`FastWebSockets`] is used unless [`RealtimeBaseConnection::with_transport`] picks another
+//! backend, like `TungsteniteTransport` of the `tungstenite` feature. [`MemoryTransport`] connects
+//! to a [`MemoryServer`] within the process, for tests.
+//!
+//! [`RealtimeBaseConnection`]: crate::realtime::RealtimeBaseConnection
+//! [`RealtimeBaseConnection::with_transport`]: crate::realtime::RealtimeBaseConnection::with_transport
+
+use core::fmt::Debug;
+
+use bytes::Bytes;
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::BoxFuture;
+
+#[cfg(feature = "webpki-roots")]
+pub use crate::connection::webpki_roots_tls_config;
+pub use crate::connection::{ConnectOptions, FastWebSockets};
+use crate::error::SupabaseRealtimeError;
+pub use crate::memory::{MemoryConnection, MemoryServer, MemoryTransport};
+#[cfg(feature = "tungstenite")]
+pub use crate::tungstenite::TungsteniteTransport;
+
+/// A message written to the websocket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsMessage {
+    /// A serialized protocol message
+    Text(Bytes),
+    /// Reply to a ping of the server
+    Pong(Bytes),
+    /// Reply to the close frame of the server
+    Close,
+}
+
+/// The independent halves of an established websocket
+pub type TransportHalves = (Box<dyn TransportRead>, Box<dyn TransportWrite>);
+
+/// Establishes websockets for a [`RealtimeBaseConnection`], on every (re)connect.
+///
+/// [`RealtimeBaseConnection`]: crate::realtime::RealtimeBaseConnection
+pub trait Transport: Debug + Send + Sync {
+    /// Connects to `url`, honouring the TLS, proxy and compression settings of `options` where
+    /// the backend supports them.
+    fn connect<'a>(
+        &'a self,
+        url: &'a url::Url,
+        options: &'a ConnectOptions,
+    ) -> BoxFuture<'a, Result<TransportHalves, SupabaseRealtimeError>>;
+}
+
+/// The reading half of a websocket
+pub trait TransportRead: Send {
+    /// The payload of the next text or binary message; `None` once the server closed the
+    /// connection.
+    ///
+    /// Replies required by the protocol, like pongs, are queued on `replies` for the writing
+    /// half, unless the backend sends them by itself.
+    fn read_message<'a>(
+        &'a mut self,
+        replies: &'a UnboundedSender<WsMessage>,
+    ) -> BoxFuture<'a, Result<Option<Bytes>, SupabaseRealtimeError>>;
+}
+
+/// The writing half of a websocket
+pub trait TransportWrite: Send {
+    fn write_message(
+        &mut self,
+        message: WsMessage,
+    ) -> BoxFuture<'_, Result<(), SupabaseRealtimeError>>;
+}
@@ -0,0 +1,176 @@
+//! [`Transport`] backed by `tokio-tungstenite`, for applications that already depend on it.
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{FutureExt as _, SinkExt as _, StreamExt as _};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest as _;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::connection::{self, ConnectOptions, WsIo};
+use crate::error::SupabaseRealtimeError;
+use crate::transport::{Transport, TransportHalves, TransportRead, TransportWrite, WsMessage};
+
+type Stream = WebSocketStream<Box<dyn WsIo>>;
+
+/// Connects with `tokio-tungstenite`, through the same proxy and TLS setup as
+/// [`FastWebSockets`](crate::transport::FastWebSockets).
+///
+/// `permessage-deflate` is not supported, so compression is never offered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TungsteniteTransport;
+
+impl Transport for TungsteniteTransport {
+    fn connect<'a>(
+        &'a self,
+        url: &'a url::Url,
+        options: &'a ConnectOptions,
+    ) -> BoxFuture<'a, Result<TransportHalves, SupabaseRealtimeError>> {
+        async move {
+            #[cfg(feature = "deflate")]
+            if options.compression {
+                tracing::warn!("The tungstenite transport does not support compression");
+            }
+            let stream = connection::open_stream(url, options).await?;
+            // tungstenite only accepts websocket schemes
+            let mut ws_url = url.clone();
+            let scheme = match url.scheme() {
+                "https" => "wss",
+                "http" => "ws",
+                scheme => scheme,
+            };
+            ws_url
+                .set_scheme(scheme)
+                .map_err(|()| SupabaseRealtimeError::MisconfiguredStreamURL)?;
+            let mut request = ws_url.as_str().into_client_request()?;
+            for (name, value) in &options.headers {
+                request.headers_mut().append(name, value.clone());
+            }
+            let config = WebSocketConfig {
+                max_frame_size: Some(options.max_frame_size()),
+                max_message_size: Some(options.max_message_size()),
+                ..WebSocketConfig::default()
+            };
+            let (ws, _response) =
+                tokio_tungstenite::client_async_with_config(request, stream, Some(config)).await?;
+            let (write, read) = ws.split();
+            let halves: TransportHalves = (
+                Box::new(TungsteniteRead(read)),
+                Box::new(TungsteniteWrite(write)),
+            );
+            Ok(halves)
+        }
+        .boxed()
+    }
+}
+
+struct TungsteniteRead(SplitStream<Stream>);
+
+impl TransportRead for TungsteniteRead {
+    fn read_message<'a>(
+        &'a mut self,
+        _replies: &'a futures::channel::mpsc::UnboundedSender<WsMessage>,
+    ) -> BoxFuture<'a, Result<Option<Bytes>, SupabaseRealtimeError>> {
+        // tungstenite answers pings and close frames by itself, flushing the replies on reads
+        async move {
+            while let Some(message) = self.0.next().await {
+                match message? {
+                    Message::Text(text) => return Ok(Some(text.into_bytes().into())),
+                    Message::Binary(payload) => return Ok(Some(payload.into())),
+                    Message::Close(_) => return Ok(None),
+                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+                }
+            }
+            Ok(None)
+        }
+        .boxed()
+    }
+}
+
+struct TungsteniteWrite(SplitSink<Stream, Message>);
+
+impl TransportWrite for TungsteniteWrite {
+    fn write_message(
+        &mut self,
+        message: WsMessage,
+    ) -> BoxFuture<'_, Result<(), SupabaseRealtimeError>> {
+        let message = match message {
+            // serialized protocol messages are always valid UTF-8
+            WsMessage::Text(payload) => Message::Text(
+                String::from_utf8(payload.into())
+                    .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned()),
+            ),
+            WsMessage::Pong(payload) => Message::Pong(payload.into()),
+            WsMessage::Close => Message::Close(None),
+        };
+        async move { Ok(self.0.send(message).await?) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::message::{broadcast, ProtocolMessage, ProtocolPayload};
+    use crate::realtime::RealtimeBaseConnection;
+
+    fn broadcast_message(event: &str) -> ProtocolMessage {
+        ProtocolMessage {
+            topic: "realtime:test".to_owned(),
+            payload: ProtocolPayload::Broadcast(broadcast::Broadcast {
+                r#type: "broadcast".to_owned(),
+                event: event.to_owned(),
+                payload: simd_json::json!({}),
+            }),
+            ref_field: None,
+            join_ref: None,
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_tungstenite_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "ws://{}/realtime/v1/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let Message::Text(sent) = ws.next().await.unwrap().unwrap() else {
+                panic!("expected a text message");
+            };
+            ws.send(Message::Ping(b"hi".to_vec())).await.unwrap();
+            let reply = simd_json::to_string(&broadcast_message("from-server")).unwrap();
+            ws.send(Message::Text(reply)).await.unwrap();
+            let pong = ws.next().await.unwrap().unwrap();
+            (sent, pong)
+        });
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok(broadcast_message("from-client")))
+            .unwrap();
+        let mut stream = Box::pin(
+            RealtimeBaseConnection::new(url)
+                .with_transport(Arc::new(TungsteniteTransport))
+                .connect(rx)
+                .await
+                .unwrap(),
+        );
+        let received = stream.next().await.unwrap().unwrap();
+        assert_eq!(received, broadcast_message("from-server"));
+
+        let (sent, pong) = server.await.unwrap();
+        let sent: ProtocolMessage = simd_json::from_slice(&mut sent.into_bytes()).unwrap();
+        assert_eq!(sent, broadcast_message("from-client"));
+        assert_eq!(pong, Message::Pong(b"hi".to_vec()));
+    }
+}
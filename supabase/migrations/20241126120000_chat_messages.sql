@@ -0,0 +1,27 @@
+create table public.messages (
+  id bigint generated by default as identity primary key,
+  room text not null,
+  author_id uuid not null default auth.uid() references auth.users on delete cascade,
+  author_email text not null default (auth.jwt() ->> 'email'),
+  body text not null,
+  created_at timestamptz not null default now()
+);
+
+alter table public.messages enable row level security;
+
+create policy "authenticated users can read messages"
+  on public.messages for select
+  to authenticated
+  using (true);
+
+create policy "authenticated users can post as themselves"
+  on public.messages for insert
+  to authenticated
+  with check (author_id = auth.uid());
+
+create policy "authors can delete their messages"
+  on public.messages for delete
+  to authenticated
+  using (author_id = auth.uid());
+
+alter publication supabase_realtime add table public.messages;